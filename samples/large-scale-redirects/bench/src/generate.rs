@@ -0,0 +1,297 @@
+//! Generation of synthetic rule sets with realistic paths.
+//!
+//! Paths are made up of random words, and targets often share a prefix with their source, like
+//! rules migrating a section of a site do. Targets can be the sources of other rules, so the rules
+//! contain chains, but never loops.
+
+use std::collections::{HashMap, HashSet};
+
+// Word lists for realistic paths. These can be expanded with words relevant to an application.
+const NOUNS: &[&str] = &[
+    "article",
+    "product",
+    "blog",
+    "user",
+    "category",
+    "item",
+    "service",
+    "document",
+    "guide",
+    "tutorial",
+    "news",
+    "event",
+    "gallery",
+    "portfolio",
+    "team",
+    "contact",
+    "about",
+    "faq",
+    "help",
+    "support",
+    "download",
+    "resource",
+    "case-study",
+    "whitepaper",
+    "report",
+    "press-release",
+    "feature",
+    "integration",
+    "partner",
+    "testimonial",
+    "review",
+    "pricing",
+    "plan",
+    "offer",
+    "promotion",
+    "coupon",
+    "api",
+    "developer",
+    "sdk",
+    "reference",
+    "changelog",
+    "release-notes",
+    "webinar",
+    "conference",
+    "workshop",
+    "meetup",
+    "career",
+    "job",
+    "opening",
+    "internship",
+    "newsletter",
+    "subscription",
+    "membership",
+    "account",
+    "profile",
+    "dashboard",
+    "settings",
+    "preferences",
+    "forum",
+    "community",
+    "discussion",
+    "thread",
+    "comment",
+    "ebook",
+    "brochure",
+    "datasheet",
+    "specification",
+    "manual",
+    "announcement",
+    "update",
+    "alert",
+    "notification",
+    "demo",
+    "example",
+    "sample",
+    "template",
+    "snippet",
+];
+const ADJECTIVES: &[&str] = &[
+    "new",
+    "featured",
+    "popular",
+    "latest",
+    "archived",
+    "updated",
+    "important",
+    "technical",
+    "creative",
+    "business",
+    "marketing",
+    "sales",
+    "engineering",
+];
+const ACTIONS: &[&str] = &[
+    "view", "edit", "create", "list", "details", "overview", "summary", "index",
+];
+
+/// Attempts at finding a target that doesn't create a loop before giving up on a source.
+const TARGET_ATTEMPTS: usize = 50;
+
+#[derive(clap::Args, Debug, Clone)]
+pub(crate) struct GenerateArgs {
+    /// The number of rules to generate
+    #[arg(short = 'n', long, default_value_t = 1000)]
+    pub(crate) num_rules: usize,
+
+    /// The maximum number of segments in a path
+    #[arg(short = 'd', long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+    pub(crate) max_depth: u32,
+
+    /// Probability (0.0 to 1.0) that a target shares a prefix with its source
+    #[arg(short = 'p', long, default_value_t = 0.7, value_parser = parse_probability)]
+    pub(crate) prefix_prob: f64,
+
+    /// Seed for the random number generator. The same seed always generates the same rules.
+    #[arg(long, default_value_t = 1)]
+    pub(crate) seed: u64,
+}
+
+fn parse_probability(input: &str) -> Result<f64, String> {
+    input
+        .parse::<f64>()
+        .ok()
+        .filter(|p| (0.0..=1.0).contains(p))
+        .ok_or_else(|| "Probability must be a number between 0.0 and 1.0".to_string())
+}
+
+/// A small, fast, and seedable random number generator (SplitMix64), so rule sets are
+/// reproducible across runs and platforms.
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in `0..n`.
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// Returns a number in `low..=high`.
+    fn between(&mut self, low: usize, high: usize) -> usize {
+        low + self.below(high - low + 1)
+    }
+
+    /// Returns true with probability `p`.
+    fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+/// Generates random paths made up of the words above.
+pub(crate) struct PathGenerator {
+    words: Vec<&'static str>,
+    max_depth: usize,
+}
+
+impl PathGenerator {
+    pub(crate) fn new(max_depth: u32) -> Self {
+        let mut words = [NOUNS, ADJECTIVES, ACTIONS].concat();
+        words.sort_unstable();
+        words.dedup();
+        Self {
+            words,
+            max_depth: max_depth as usize,
+        }
+    }
+
+    /// Returns a path with between one and `max_depth` segments, e.g. `/product/new/details`.
+    pub(crate) fn path(&self, rng: &mut Rng) -> String {
+        let depth = rng.between(1, self.max_depth);
+        self.with_segments(rng, &[], depth)
+    }
+
+    /// Returns a path starting with `prefix`, followed by `count` random segments.
+    fn with_segments(&self, rng: &mut Rng, prefix: &[&str], count: usize) -> String {
+        let mut path = String::new();
+        for segment in prefix {
+            path.push('/');
+            path.push_str(segment);
+        }
+        for _ in 0..count {
+            path.push('/');
+            path.push_str(self.words[rng.below(self.words.len())]);
+        }
+        path
+    }
+
+    /// The number of distinct paths this generator can produce.
+    fn path_count(&self) -> f64 {
+        (1..=self.max_depth as i32)
+            .map(|depth| (self.words.len() as f64).powi(depth))
+            .sum()
+    }
+}
+
+/// Generates up to `args.num_rules` rules without loops, as `source target` lines in sorted order.
+/// Fewer rules are returned if the path namespace is exhausted.
+pub(crate) fn generate_rules(args: &GenerateArgs) -> Vec<String> {
+    let mut rng = Rng::new(args.seed);
+    let paths = PathGenerator::new(args.max_depth);
+    if args.num_rules as f64 > paths.path_count() * 0.9 {
+        eprintln!(
+            "Warning: Requested rules ({}) is high relative to possible unique paths (~{}). \
+             Generation may be slow or stop early if it can't find unique source paths.",
+            args.num_rules,
+            paths.path_count()
+        );
+    }
+
+    let mut rules = Vec::with_capacity(args.num_rules);
+    let mut sources = HashSet::new();
+    // The next hop of every source, compressed to point directly to the end of its chain
+    let mut next_hops: HashMap<String, String> = HashMap::new();
+    while rules.len() < args.num_rules {
+        // 1. Generate a unique source path
+        let mut source = paths.path(&mut rng);
+        let mut attempts = 0;
+        while sources.contains(&source) {
+            source = paths.path(&mut rng);
+            attempts += 1;
+            if attempts > args.num_rules * 2 && args.num_rules > 1000 {
+                eprintln!(
+                    "Error: Could not find a unique source path after {attempts} attempts. The \
+                     path namespace may be exhausted. Generated {} rules.",
+                    rules.len()
+                );
+                return sorted(rules);
+            }
+        }
+
+        // 2. Find a target that doesn't create a loop
+        for _ in 0..TARGET_ATTEMPTS {
+            let segments = source[1..].split('/').collect::<Vec<_>>();
+            let target = if segments.len() > 1 && rng.chance(args.prefix_prob) {
+                let prefix = &segments[..rng.between(1, segments.len() - 1)];
+                let count = match paths.max_depth - prefix.len() {
+                    0 => 0,
+                    remaining => rng.between(1, remaining),
+                };
+                paths.with_segments(&mut rng, prefix, count)
+            } else {
+                paths.path(&mut rng)
+            };
+
+            if target != source && chain_end(&target, &mut next_hops) != source {
+                rules.push(format!("{source} {target}"));
+                next_hops.insert(source.clone(), target);
+                sources.insert(source);
+                if rules.len() % 100_000 == 0 {
+                    eprintln!("Generated {}/{} rules...", rules.len(), args.num_rules);
+                }
+                break;
+            }
+        }
+    }
+    sorted(rules)
+}
+
+fn sorted(mut rules: Vec<String>) -> Vec<String> {
+    rules.sort_unstable();
+    rules
+}
+
+/// Follows the chain of rules starting at `path` to its end, pointing every rule on the way
+/// directly to it so later lookups are fast.
+fn chain_end(path: &str, next_hops: &mut HashMap<String, String>) -> String {
+    let mut end = path.to_string();
+    let mut chain = Vec::new();
+    while let Some(next) = next_hops.get(&end) {
+        chain.push(end);
+        end = next.clone();
+    }
+    for node in chain {
+        next_hops.insert(node, end.clone());
+    }
+    end
+}
@@ -0,0 +1,229 @@
+//! Benchmarks for large rule sets.
+//!
+//! `generate` prints a synthetic rule set, and `run` builds bundles of several sizes with
+//! `rules-manager` and measures them and lookups in them natively, printing the results as JSON so
+//! they can be compared between commits.
+
+mod generate;
+
+use anyhow::{Context, Result, bail, ensure};
+use clap::{Parser, Subcommand};
+use generate::{GenerateArgs, PathGenerator, Rng, generate_rules};
+use redirects_core::bundle::Validation;
+use redirects_core::native::NativeRedirects;
+use serde_json::json;
+use std::collections::HashSet;
+use std::hint::black_box;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Benchmarks for large redirect rule sets")]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Print a synthetic rules file to stdout
+    Generate(GenerateArgs),
+    /// Build bundles of synthetic rules and measure them, printing the results as JSON
+    Run(RunArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct RunArgs {
+    /// The numbers of rules to benchmark, comma-separated
+    #[arg(long, value_delimiter = ',', default_value = "10000,100000,1000000")]
+    rules: Vec<usize>,
+
+    /// The maximum number of segments in a path
+    #[arg(short = 'd', long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+    max_depth: u32,
+
+    /// Probability (0.0 to 1.0) that a target shares a prefix with its source
+    #[arg(short = 'p', long, default_value_t = 0.7)]
+    prefix_prob: f64,
+
+    /// Seed for the random number generator
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+
+    /// The number of lookups to time, for both hits and misses
+    #[arg(long, default_value_t = 100_000, value_parser = clap::value_parser!(u64).range(1..))]
+    lookups: u64,
+
+    /// Path to the `rules-manager` binary (default: next to this binary)
+    #[arg(long)]
+    rules_manager: Option<PathBuf>,
+
+    /// A label included in the results, e.g. a commit hash
+    #[arg(long)]
+    label: Option<String>,
+
+    /// Write the results to this file instead of stdout
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    match Cli::parse().command {
+        Commands::Generate(args) => {
+            for rule in generate_rules(&args) {
+                println!("{rule}");
+            }
+            Ok(())
+        }
+        Commands::Run(args) => run(&args),
+    }
+}
+
+fn run(args: &RunArgs) -> Result<()> {
+    let rules_manager = match &args.rules_manager {
+        Some(path) => path.clone(),
+        None => std::env::current_exe()?
+            .with_file_name(format!("rules-manager{}", std::env::consts::EXE_SUFFIX)),
+    };
+    ensure!(
+        rules_manager.is_file(),
+        "rules-manager not found at '{}'. Build it with `cargo build --release -p rules-manager` \
+         or pass its path with --rules-manager",
+        rules_manager.display()
+    );
+
+    let mut results = Vec::new();
+    for &num_rules in &args.rules {
+        eprintln!("Benchmarking {num_rules} rules...");
+        results.push(bench(args, &rules_manager, num_rules)?);
+    }
+
+    let report = json!({
+        "label": args.label,
+        "seed": args.seed,
+        "max_depth": args.max_depth,
+        "prefix_probability": args.prefix_prob,
+        "lookups": args.lookups,
+        "results": results,
+    });
+    let report = serde_json::to_string_pretty(&report)?;
+    match &args.output {
+        Some(path) => std::fs::write(path, report + "\n")
+            .with_context(|| format!("Failed to write results to '{}'", path.display()))?,
+        None => println!("{report}"),
+    }
+    Ok(())
+}
+
+/// Builds a bundle of `num_rules` synthetic rules and measures it.
+fn bench(args: &RunArgs, rules_manager: &Path, num_rules: usize) -> Result<serde_json::Value> {
+    let rules = generate_rules(&GenerateArgs {
+        num_rules,
+        max_depth: args.max_depth,
+        prefix_prob: args.prefix_prob,
+        seed: args.seed,
+    });
+    let dir = tempfile::tempdir()?;
+    let rules_file = dir.path().join("rules.txt");
+    std::fs::write(&rules_file, rules.join("\n") + "\n")?;
+
+    // Build the bundle
+    let start = Instant::now();
+    let status = Command::new(rules_manager)
+        .arg("--add-rules")
+        .arg(&rules_file)
+        .arg("--output-dir")
+        .arg(dir.path())
+        // Keep stdout for the results
+        .stdout(std::io::stderr())
+        .status()
+        .context("Failed to run rules-manager")?;
+    let build_time = start.elapsed();
+    ensure!(status.success(), "rules-manager failed with {status}");
+
+    let bytes = std::fs::read(dir.path().join("redirects.bundle"))?;
+    let bundle_bytes = bytes.len();
+
+    // Bundles from a key-value store are decoded for every request, and only checked against
+    // their checksum
+    let start = Instant::now();
+    NativeRedirects::decode_with(bytes.clone(), Validation::Checksum)
+        .map_err(anyhow::Error::msg)?;
+    let checksum_decode_time = start.elapsed();
+
+    let start = Instant::now();
+    let redirects = NativeRedirects::decode(bytes).map_err(anyhow::Error::msg)?;
+    let decode_time = start.elapsed();
+
+    // Lookups of sources, in random order so they don't benefit from caching more than requests
+    // would
+    let sources = rules
+        .iter()
+        .filter_map(|rule| rule.split_once(' ').map(|(source, _)| source))
+        .collect::<Vec<_>>();
+    ensure!(!sources.is_empty(), "No rules were generated");
+    let mut rng = Rng::new(args.seed);
+    let hits = (0..args.lookups)
+        .map(|_| sources[rng.below(sources.len())])
+        .collect::<Vec<_>>();
+    let start = Instant::now();
+    for path in &hits {
+        if black_box(redirects.lookup(None, black_box(path))).is_none() {
+            bail!("No rule found for source '{path}'");
+        }
+    }
+    let hit_time = start.elapsed();
+
+    // Lookups of paths that aren't sources
+    let known = sources.iter().copied().collect::<HashSet<_>>();
+    let paths = PathGenerator::new(args.max_depth);
+    let misses = (0..args.lookups)
+        .map(|_| {
+            loop {
+                let path = format!("{}/missing", paths.path(&mut rng));
+                if !known.contains(path.as_str()) {
+                    break path;
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    let start = Instant::now();
+    for path in &misses {
+        black_box(redirects.lookup(None, black_box(path)));
+    }
+    let miss_time = start.elapsed();
+
+    // Sizes of the decoded targets
+    let target_sizes = sources
+        .iter()
+        .filter_map(|path| redirects.lookup(None, path))
+        .map(|(_, target)| target.len())
+        .collect::<Vec<_>>();
+    let total_target_bytes = target_sizes.iter().sum::<usize>();
+
+    Ok(json!({
+        "rules": redirects.rule_count(),
+        "distinct_targets": redirects.target_count(),
+        "build_seconds": build_time.as_secs_f64(),
+        "bundle_bytes": bundle_bytes,
+        "sources_bytes": redirects.sources_size(),
+        "targets_bytes": redirects.targets_size(),
+        "encoded_bytes": redirects.encoded_size(),
+        "bundle_bytes_per_rule": bundle_bytes as f64 / num_rules.max(1) as f64,
+        "decode_seconds": decode_time.as_secs_f64(),
+        "checksum_decode_seconds": checksum_decode_time.as_secs_f64(),
+        "hit_ns_per_lookup": nanos_per_lookup(hit_time, args.lookups),
+        "miss_ns_per_lookup": nanos_per_lookup(miss_time, args.lookups),
+        "hit_lookups_per_second": args.lookups as f64 / hit_time.as_secs_f64(),
+        "decoded_target_bytes": {
+            "total": total_target_bytes,
+            "mean": total_target_bytes as f64 / target_sizes.len().max(1) as f64,
+            "max": target_sizes.iter().max().copied().unwrap_or(0),
+        },
+    }))
+}
+
+fn nanos_per_lookup(elapsed: Duration, lookups: u64) -> f64 {
+    elapsed.as_nanos() as f64 / lookups as f64
+}
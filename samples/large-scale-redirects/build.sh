@@ -4,18 +4,28 @@ set -e
 ENABLE_WASM_OPT="${ENABLE_WASM_OPT:-true}"
 
 # Check if the correct number of arguments is provided
-if [ "$#" -ne 4 ]; then
-    echo "Usage: $0 <sources.fst file> <targets.fcsd file> <default status code> <output wasm file>"
+if [ "$#" -lt 2 ]; then
+    echo "Usage: $0 <redirects.bundle file> <output wasm file> [options...]"
+    echo "       $0 kv:<store label> <output wasm file> [options...]"
+    echo "Options:"
+    echo "  query=<exact|ignore|forward>  Default query string handling for rules (default: exact)"
+    echo "  miss=<not-found|body:<file>|redirect:<target>|proxy:<origin URL>>"
+    echo "                                How to handle requests not matching any rule (default: not-found)"
+    echo "  analytics=<log|kv:<label>>    Record rule hits and misses as log lines or in a key-value store (default: off)"
+    echo "  sample=<n>                    Only record analytics for one in n requests (default: 1)"
+    echo "  strict-methods=<true|false>   Only redirect GET and HEAD requests for 301 and 302 rules (default: false)"
+    echo "  cache-permanent=<seconds>     Cache duration for 301 and 308 redirects (default: 86400)"
+    echo "  cache-temporary=<seconds>     Cache duration for other redirects, 0 disables caching (default: 0)"
     exit 1
 fi
 
 
 cargo build --target wasm32-wasip1 --release
-echo "$1 $2 $3" | wizer --allow-wasi --wasm-bulk-memory true --dir . -o "$4" target/wasm32-wasip1/release/redirects_rs.wasm
+echo "$1 ${*:3}" | wizer --allow-wasi --wasm-bulk-memory true --dir . -o "$2" target/wasm32-wasip1/release/redirects_rs.wasm
 # If wasm-opt is installed, run it to optimize the output
 if [[ "${ENABLE_WASM_OPT}" == "true" ]] && command -v wasm-opt &> /dev/null
 then
-    wasm-opt -O3 --enable-bulk-memory-opt -o "$4" "$4"
+    wasm-opt -O3 --enable-bulk-memory-opt -o "$2" "$2"
 fi
 echo -n "Component size: "
-ls -lh "$4" | awk '{print $5}'
\ No newline at end of file
+ls -lh "$2" | awk '{print $5}'
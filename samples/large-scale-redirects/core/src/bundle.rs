@@ -0,0 +1,1479 @@
+//! The bundle file format the encoded redirects are distributed in.
+//!
+//! A bundle starts with a fixed-size header, followed by the encoded sources (an FST map), the
+//! encoded targets (an FCSD set), the rule records, and the regex rules. All integers are
+//! little-endian:
+//!
+//! | Offset | Size | Field                                       |
+//! |--------|------|---------------------------------------------|
+//! | 0      | 4    | Magic bytes `RDRB`                          |
+//! | 4      | 2    | Format version                              |
+//! | 6      | 2    | Default status code                         |
+//! | 8      | 4    | Flags                                       |
+//! | 12     | 4    | CRC32 checksum of all sections              |
+//! | 16     | 8    | Number of rules                             |
+//! | 24     | 8    | Build timestamp, in seconds since the epoch |
+//! | 32     | 8    | Length of the sources section               |
+//! | 40     | 8    | Length of the targets section               |
+//! | 48     | 8    | Length of the rule records section          |
+//! | 56     | 8    | Length of the regex rules section           |
+//!
+//! Values in the sources FST store the index of the rule's target in the lower 32 bits, and the
+//! index of its record in the bits above that. Records hold everything about a rule except its
+//! source and target, and are shared by all rules with the same settings, so there usually are only
+//! a few of them. The records section starts with the number of records as a `u32`, followed by
+//! each record:
+//!
+//! | Size | Field                                                                   |
+//! |------|-------------------------------------------------------------------------|
+//! | 2    | Status code                                                             |
+//! | 1    | Query mode: 0 for the component's default, 1 exact, 2 ignore, 3 forward |
+//! | 1    | Bitmask of the methods the rule is restricted to, 0 for all methods     |
+//! | 2    | Number of header overrides, followed by each one's name and value       |
+//! | 8    | Time the rule becomes active, in seconds since the epoch, 0 for none    |
+//! | 8    | Time the rule expires, in seconds since the epoch, 0 for none           |
+//! | 2    | Number of conditional targets, followed by each one                     |
+//!
+//! Header names and values are `u16` length-prefixed. Each conditional target consists of its
+//! condition kind as a `u8`, its `u16` length-prefixed name and value, and the `u32` index of its
+//! target. Condition kinds are 1 for a language tag, stored as the name with an empty value, 2 for
+//! a header, and 3 for a cookie.
+//!
+//! The regex rules section starts with the number of regex rules as a `u32`, followed by each rule
+//! in order of precedence: its host and its pattern without the `~` prefix, both `u16`
+//! length-prefixed and the host empty for rules without one, and a `u64` value like the ones in
+//! the sources FST.
+//!
+//! Older format versions can still be decoded, but are never written anymore. Each version added
+//! to the layout of the previous one:
+//!
+//! | Version | Added                                                                       |
+//! |---------|-----------------------------------------------------------------------------|
+//! | 1       | Sources and targets sections, and the header up to their lengths            |
+//! | 2       | Rule records section, with the status code, query mode, methods and headers |
+//! | 3       | Regex rules section                                                         |
+//! | 4       | Activation and expiry times of records                                      |
+//! | 5       | Conditional targets of records                                              |
+//!
+//! Without records, the FST values of format version 1 store the query mode and methods directly
+//! in the bits above the target index, and targets of rules with a non-default status code end in
+//! a space and the status code.
+
+use crate::conditions::Condition;
+use crate::regex_rules::{self, REGEX_PREFIX};
+use crate::rule::{MethodSet, QueryMode, Rule, RuleOptions, split_host};
+use fst::Streamer;
+use regex_automata::meta::Regex;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+pub const MAGIC: &[u8; 4] = b"RDRB";
+pub const FORMAT_VERSION: u16 = 5;
+pub const HEADER_LEN: usize = header_len(FORMAT_VERSION);
+/// The oldest format version that is still supported when decoding bundles.
+pub const MIN_FORMAT_VERSION: u16 = 1;
+
+/// Values in the sources FST store the index of the rule's target in the lower 32 bits and the
+/// index of its record above that.
+pub const RECORD_SHIFT: u32 = 32;
+pub const TARGET_MASK: u64 = (1 << RECORD_SHIFT) - 1;
+
+/// Set if the sources were normalized, so request paths need to be normalized as well.
+pub const FLAG_NORMALIZED_SOURCES: u32 = 1;
+
+/// The first format version whose records have activation and expiry times.
+const SCHEDULES_FORMAT_VERSION: u16 = 4;
+
+/// The first format version whose records have conditional targets.
+const CONDITIONS_FORMAT_VERSION: u16 = 5;
+
+/// Returns the number of sections in bundles of the given format version, whose lengths are
+/// listed in the header.
+const fn section_count(format_version: u16) -> usize {
+    match format_version {
+        1 => 2,
+        2 => 3,
+        _ => 4,
+    }
+}
+
+const fn header_len(format_version: u16) -> usize {
+    32 + 8 * section_count(format_version)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub default_status_code: u16,
+    pub flags: u32,
+    pub rule_count: u64,
+    pub timestamp: u64,
+}
+
+/// Everything about a rule except its source and target.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RuleRecord {
+    pub status_code: u16,
+    /// 0 for the component's default, 1 for exact, 2 for ignore, 3 for forward
+    pub query_mode: u8,
+    /// Bitmask of the methods the rule is restricted to, 0 for all methods
+    pub methods: u8,
+    /// Headers to add to the redirect response, overriding the component's defaults
+    pub headers: Vec<(String, String)>,
+    /// The time the rule becomes active, in seconds since the epoch
+    pub valid_from: Option<u64>,
+    /// The time the rule expires, in seconds since the epoch
+    pub valid_until: Option<u64>,
+    /// Conditions with the indices of the targets taken instead of the rule's own if they hold, in
+    /// the order they're evaluated in
+    pub branches: Vec<(Condition, u32)>,
+}
+
+/// A rule matching request paths against a regex instead of a literal source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegexRule {
+    pub host: Option<String>,
+    /// The pattern without the `~` prefix
+    pub pattern: String,
+    /// Target and record index, like the values in the sources FST
+    pub value: u64,
+}
+
+/// A rule decoded from a bundle. Sources of bundles with normalized sources are normalized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedRule {
+    pub from: String,
+    pub to: String,
+    pub status_code: u16,
+    pub options: RuleOptions,
+}
+
+impl DecodedRule {
+    /// Borrows the rule as if it had been parsed from a rules file.
+    pub fn rule(&self) -> Rule<'_> {
+        Rule {
+            from: &self.from,
+            to: &self.to,
+            status_code: self.status_code,
+            options: self.options.clone(),
+        }
+    }
+}
+
+/// A parsed bundle, with the encoded sections already validated. The sources are stored in `D`,
+/// which is either borrowed from the encoded bundle or owned.
+pub struct Bundle<D> {
+    pub header: Header,
+    pub format_version: u16,
+    pub sources: fst::Map<D>,
+    pub targets: fcsd::Set,
+    /// Empty for bundles of format version 1
+    pub records: Vec<RuleRecord>,
+    /// In order of precedence
+    pub regex_rules: Vec<RegexRule>,
+    /// The patterns of the regex rules compiled into a single automaton, see
+    /// `regex_rules::compile`. `None` if there are no regex rules.
+    pub regex_set: Option<Regex>,
+}
+
+impl<D: AsRef<[u8]>> Bundle<D> {
+    /// Returns the record of the rule with the given value from the sources FST or a regex rule,
+    /// or `None` for bundles of format version 1, which have no records.
+    pub fn record(&self, value: u64) -> Option<&RuleRecord> {
+        // Indices are validated when decoding the bundle
+        self.records.get((value >> RECORD_SHIFT) as usize)
+    }
+
+    /// Returns the status code and main target of the rule with the given value from the sources
+    /// FST or a regex rule.
+    pub fn resolve(&self, value: u64) -> (u16, Vec<u8>) {
+        let target = self.targets.decoder().run((value & TARGET_MASK) as usize);
+        match self.record(value) {
+            Some(record) => (record.status_code, target),
+            None => legacy_status_code(target, self.header.default_status_code),
+        }
+    }
+
+    /// Returns the status code and main target of the rule stored under exactly `key`, without
+    /// considering wildcard rules or query strings.
+    pub fn get(&self, key: &str) -> Option<(u16, Vec<u8>)> {
+        Some(self.resolve(self.sources.get(key)?))
+    }
+
+    /// Returns every rule in the bundle: the literal and wildcard rules in the order of their keys,
+    /// followed by the regex rules in order of precedence.
+    pub fn rules(&self) -> Vec<DecodedRule> {
+        let mut rules = Vec::with_capacity(self.sources.len() + self.regex_rules.len());
+        let mut stream = self.sources.stream();
+        while let Some((key, value)) = stream.next() {
+            let key = String::from_utf8_lossy(key);
+            let (host, path) = split_host(&key);
+            let host = (!host.is_empty()).then(|| host.to_string());
+            rules.push(self.decode_rule(path.to_string(), host, value));
+        }
+        for rule in &self.regex_rules {
+            let from = format!("{REGEX_PREFIX}{}", rule.pattern);
+            rules.push(self.decode_rule(from, rule.host.clone(), rule.value));
+        }
+        rules
+    }
+
+    fn decode_rule(&self, from: String, host: Option<String>, value: u64) -> DecodedRule {
+        let target = |index: u32| {
+            let target = self.targets.decoder().run(index as usize);
+            String::from_utf8_lossy(&target).into_owned()
+        };
+        let (status_code, to) = self.resolve(value);
+        let options = match self.record(value) {
+            Some(record) => RuleOptions::from_record(record, host, target),
+            // Legacy bundles store the query mode and methods in the bits above the target index
+            None => {
+                let bits = (value >> RECORD_SHIFT) as u8;
+                RuleOptions {
+                    host,
+                    query: QueryMode::decode(bits & 0b11),
+                    methods: MethodSet::decode(bits >> 2),
+                    ..RuleOptions::default()
+                }
+            }
+        };
+        DecodedRule {
+            from,
+            to: String::from_utf8_lossy(&to).into_owned(),
+            status_code,
+            options,
+        }
+    }
+}
+
+/// Splits the status code off a target from a bundle of format version 1, where targets of rules
+/// with a non-default status code end in a space and the status code.
+pub fn legacy_status_code(target: Vec<u8>, default_status_code: u16) -> (u16, Vec<u8>) {
+    if target.len() > 4
+        && target[target.len() - 4] == b' '
+        && let Some(code) = std::str::from_utf8(&target[target.len() - 3..])
+            .ok()
+            .and_then(|code| code.parse::<u16>().ok())
+    {
+        return (code, target[..target.len() - 4].to_vec());
+    }
+    (default_status_code, target)
+}
+
+#[derive(Debug)]
+pub enum BundleError {
+    TooShort(usize),
+    BadMagic,
+    UnsupportedVersion(u16),
+    LengthMismatch { expected: u64, actual: usize },
+    ChecksumMismatch { expected: u32, actual: u32 },
+    InvalidStatusCode(u16),
+    InvalidSources(String),
+    InvalidTargets(String),
+    InvalidRecords(String),
+    InvalidRegexRules(String),
+    RuleCountMismatch { expected: u64, actual: usize },
+}
+
+impl Display for BundleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BundleError::TooShort(len) => write!(
+                f,
+                "file is too short to be a redirects bundle ({len} bytes)"
+            ),
+            BundleError::BadMagic => write!(f, "file is not a redirects bundle"),
+            BundleError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported bundle format version {version}, expected {FORMAT_VERSION}"
+            ),
+            BundleError::LengthMismatch { expected, actual } => write!(
+                f,
+                "bundle is truncated or corrupt: expected {expected} bytes, found {actual}"
+            ),
+            BundleError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "bundle is corrupt: checksum is {actual:08x}, expected {expected:08x}"
+            ),
+            BundleError::InvalidStatusCode(code) => {
+                write!(f, "bundle has invalid default status code {code}")
+            }
+            BundleError::InvalidSources(error) => {
+                write!(f, "bundle contains invalid sources: {error}")
+            }
+            BundleError::InvalidTargets(error) => {
+                write!(f, "bundle contains invalid targets: {error}")
+            }
+            BundleError::InvalidRecords(error) => {
+                write!(f, "bundle contains invalid rule records: {error}")
+            }
+            BundleError::InvalidRegexRules(error) => {
+                write!(f, "bundle contains invalid regex rules: {error}")
+            }
+            BundleError::RuleCountMismatch { expected, actual } => write!(
+                f,
+                "bundle is corrupt: header lists {expected} rules, but sources contain {actual}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BundleError {}
+
+/// Encodes rules into a bundle. `rules` are the literal and wildcard rules with the keys they're
+/// looked up by, see `Rule::key`, and `regex_rules` are the regex rules in order of precedence.
+pub fn build<'r, 'a: 'r>(
+    default_status_code: u16,
+    flags: u32,
+    timestamp: u64,
+    rules: impl IntoIterator<Item = (&'r str, &'r Rule<'a>)>,
+    regex_rules: impl IntoIterator<Item = &'r Rule<'a>>,
+) -> Result<Vec<u8>, String> {
+    let mut rules = rules.into_iter().collect::<Vec<_>>();
+    rules.sort_by_key(|(key, _)| *key);
+    let regex_rules = regex_rules.into_iter().collect::<Vec<_>>();
+
+    // Conditional targets are stored alongside the main ones, and referred to by records
+    let mut targets = rules
+        .iter()
+        .map(|(_, rule)| *rule)
+        .chain(regex_rules.iter().copied())
+        .flat_map(|rule| rule.targets().map(|(to, _)| to))
+        .collect::<Vec<_>>();
+    targets.sort();
+    targets.dedup();
+    let target_index = |to: &str| targets.binary_search(&to).unwrap() as u32;
+
+    // Rules with the same settings share a record
+    let mut records = Records::default();
+
+    // Encode redirect sources using fst
+    let mut build = fst::MapBuilder::memory();
+    for (key, rule) in &rules {
+        build
+            .insert(key.as_bytes(), records.value(rule, target_index))
+            .map_err(|e| format!("Failed to encode source '{key}': {e}"))?;
+    }
+    let sources = build.into_inner().map_err(|e| e.to_string())?;
+    let regex_rules = regex_rules
+        .iter()
+        .map(|rule| RegexRule {
+            host: rule.options.host.clone(),
+            pattern: rule.from[REGEX_PREFIX.len_utf8()..].to_string(),
+            value: records.value(rule, target_index),
+        })
+        .collect::<Vec<_>>();
+
+    // Encode redirect targets using fcsd
+    let target_set = fcsd::Set::with_bucket_size(targets.as_slice(), 128)
+        .map_err(|e| format!("Failed to encode targets: {e}"))?;
+    let mut encoded_targets = Vec::new();
+    target_set
+        .serialize_into(&mut encoded_targets)
+        .map_err(|e| e.to_string())?;
+
+    let header = Header {
+        default_status_code,
+        flags,
+        rule_count: rules.len() as u64,
+        timestamp,
+    };
+    encode(
+        &header,
+        &sources,
+        &encoded_targets,
+        &records.into_vec(),
+        &regex_rules,
+    )
+}
+
+/// The records of encoded rules, shared by all rules with the same settings.
+#[derive(Debug, Default)]
+pub struct Records {
+    records: Vec<RuleRecord>,
+    indices: HashMap<RuleRecord, u64>,
+}
+
+impl Records {
+    /// Returns the value of `rule` in the sources FST, adding its record if it's new.
+    /// `target_index` returns the index of a target in the bundle's targets.
+    pub fn value(&mut self, rule: &Rule, target_index: impl Fn(&str) -> u32) -> u64 {
+        let record = rule.options.record(rule.status_code, &target_index);
+        let record_index = match self.indices.get(&record) {
+            Some(&index) => index,
+            None => {
+                let index = self.records.len() as u64;
+                self.records.push(record.clone());
+                self.indices.insert(record, index);
+                index
+            }
+        };
+        // The index of the target in the sorted list, together with the index of the rule's record
+        target_index(rule.to) as u64 | record_index << RECORD_SHIFT
+    }
+
+    pub fn into_vec(self) -> Vec<RuleRecord> {
+        self.records
+    }
+}
+
+/// Serializes a bundle from the header, the encoded sources and targets, the rule records and the
+/// regex rules. Fails if a count or string doesn't fit into the format, like a header value longer
+/// than 65535 bytes.
+pub fn encode(
+    header: &Header,
+    sources: &[u8],
+    targets: &[u8],
+    records: &[RuleRecord],
+    regex_rules: &[RegexRule],
+) -> Result<Vec<u8>, String> {
+    let mut bundle = Cursor::new(Vec::with_capacity(
+        HEADER_LEN + sources.len() + targets.len(),
+    ));
+    // Writing to memory only fails on values that don't fit
+    write(&mut bundle, header, sources, targets, records, regex_rules)
+        .map_err(|e| e.to_string())?;
+    Ok(bundle.into_inner())
+}
+
+/// Writes a bundle like `encode` does, but reads the encoded sources from `sources`, so that they
+/// don't have to fit into memory. The header is written last, once the checksum is known, which
+/// is why `out` has to be seekable. Values that don't fit into the format are reported as
+/// `InvalidInput` errors.
+pub fn write<W: Write + Seek>(
+    out: &mut W,
+    header: &Header,
+    mut sources: impl Read,
+    targets: &[u8],
+    records: &[RuleRecord],
+    regex_rules: &[RegexRule],
+) -> io::Result<()> {
+    let start = out.stream_position()?;
+    out.write_all(&[0; HEADER_LEN])?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut sources_len = 0;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = match sources.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        };
+        hasher.update(&buffer[..read]);
+        out.write_all(&buffer[..read])?;
+        sources_len += read as u64;
+    }
+    let invalid = |error| io::Error::new(io::ErrorKind::InvalidInput, error);
+    let records = encode_records(records, FORMAT_VERSION).map_err(invalid)?;
+    let regex_rules = encode_regex_rules(regex_rules).map_err(invalid)?;
+    let sections = [targets, &records, &regex_rules];
+    for section in sections {
+        hasher.update(section);
+        out.write_all(section)?;
+    }
+    let end = out.stream_position()?;
+
+    let mut encoded = Vec::with_capacity(HEADER_LEN);
+    encoded.extend_from_slice(MAGIC);
+    encoded.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    encoded.extend_from_slice(&header.default_status_code.to_le_bytes());
+    encoded.extend_from_slice(&header.flags.to_le_bytes());
+    encoded.extend_from_slice(&hasher.finalize().to_le_bytes());
+    encoded.extend_from_slice(&header.rule_count.to_le_bytes());
+    encoded.extend_from_slice(&header.timestamp.to_le_bytes());
+    encoded.extend_from_slice(&sources_len.to_le_bytes());
+    for section in sections {
+        encoded.extend_from_slice(&(section.len() as u64).to_le_bytes());
+    }
+    debug_assert_eq!(encoded.len(), HEADER_LEN);
+    out.seek(SeekFrom::Start(start))?;
+    out.write_all(&encoded)?;
+    out.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+fn encode_records(records: &[RuleRecord], format_version: u16) -> Result<Vec<u8>, String> {
+    let mut encoded = Vec::new();
+    encoded.extend_from_slice(&count::<u32>(records.len(), "rule records")?.to_le_bytes());
+    for record in records {
+        encoded.extend_from_slice(&record.status_code.to_le_bytes());
+        encoded.push(record.query_mode);
+        encoded.push(record.methods);
+        encoded.extend_from_slice(&count::<u16>(record.headers.len(), "headers")?.to_le_bytes());
+        for (name, value) in &record.headers {
+            put_string(&mut encoded, name)?;
+            put_string(&mut encoded, value)?;
+        }
+        if format_version >= SCHEDULES_FORMAT_VERSION {
+            for time in [record.valid_from, record.valid_until] {
+                encoded.extend_from_slice(&time.unwrap_or(0).to_le_bytes());
+            }
+        }
+        if format_version >= CONDITIONS_FORMAT_VERSION {
+            let branches = count::<u16>(record.branches.len(), "conditional targets")?;
+            encoded.extend_from_slice(&branches.to_le_bytes());
+            for (condition, target) in &record.branches {
+                let (kind, name, value) = condition.encoded();
+                encoded.push(kind);
+                put_string(&mut encoded, name)?;
+                put_string(&mut encoded, value)?;
+                encoded.extend_from_slice(&target.to_le_bytes());
+            }
+        }
+    }
+    Ok(encoded)
+}
+
+fn encode_regex_rules(rules: &[RegexRule]) -> Result<Vec<u8>, String> {
+    let mut encoded = Vec::new();
+    encoded.extend_from_slice(&count::<u32>(rules.len(), "regex rules")?.to_le_bytes());
+    for rule in rules {
+        put_string(&mut encoded, rule.host.as_deref().unwrap_or(""))?;
+        put_string(&mut encoded, &rule.pattern)?;
+        encoded.extend_from_slice(&rule.value.to_le_bytes());
+    }
+    Ok(encoded)
+}
+
+/// Converts the length of a list to the integer type it's stored as.
+fn count<T: TryFrom<usize>>(len: usize, what: &str) -> Result<T, String> {
+    T::try_from(len).map_err(|_| format!("Too many {what}: {len}"))
+}
+
+fn put_string(encoded: &mut Vec<u8>, value: &str) -> Result<(), String> {
+    let len = u16::try_from(value.len()).map_err(|_| {
+        let start = value
+            .char_indices()
+            .nth(20)
+            .map_or(value, |(i, _)| &value[..i]);
+        format!("'{start}...' is longer than {} bytes", u16::MAX)
+    })?;
+    encoded.extend_from_slice(&len.to_le_bytes());
+    encoded.extend_from_slice(value.as_bytes());
+    Ok(())
+}
+
+fn take<'b>(bytes: &mut &'b [u8], len: usize) -> Result<&'b [u8], String> {
+    if bytes.len() < len {
+        return Err("unexpected end of section".to_string());
+    }
+    let (taken, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(taken)
+}
+
+fn take_u16(bytes: &mut &[u8]) -> Result<u16, String> {
+    Ok(u16::from_le_bytes(take(bytes, 2)?.try_into().unwrap()))
+}
+
+fn take_u32(bytes: &mut &[u8]) -> Result<u32, String> {
+    Ok(u32::from_le_bytes(take(bytes, 4)?.try_into().unwrap()))
+}
+
+fn take_u64(bytes: &mut &[u8]) -> Result<u64, String> {
+    Ok(u64::from_le_bytes(take(bytes, 8)?.try_into().unwrap()))
+}
+
+fn take_string(bytes: &mut &[u8]) -> Result<String, String> {
+    let len = take_u16(bytes)? as usize;
+    String::from_utf8(take(bytes, len)?.to_vec()).map_err(|e| e.to_string())
+}
+
+/// Parses the rule records section, checking that it contains nothing else.
+fn decode_records(mut bytes: &[u8], format_version: u16) -> Result<Vec<RuleRecord>, String> {
+    let count = take_u32(&mut bytes)?;
+    let mut records = Vec::new();
+    for _ in 0..count {
+        let status_code = take_u16(&mut bytes)?;
+        if !(301..400).contains(&status_code) {
+            return Err(format!("invalid status code {status_code}"));
+        }
+        let [query_mode, methods] = take(&mut bytes, 2)?.try_into().unwrap();
+        if query_mode > 3 {
+            return Err(format!("invalid query mode {query_mode}"));
+        }
+        let header_count = take_u16(&mut bytes)?;
+        let mut headers = Vec::new();
+        for _ in 0..header_count {
+            headers.push((take_string(&mut bytes)?, take_string(&mut bytes)?));
+        }
+        let (valid_from, valid_until) = if format_version >= SCHEDULES_FORMAT_VERSION {
+            let time = |time: u64| Some(time).filter(|time| *time != 0);
+            (time(take_u64(&mut bytes)?), time(take_u64(&mut bytes)?))
+        } else {
+            (None, None)
+        };
+        if let (Some(from), Some(until)) = (valid_from, valid_until)
+            && from >= until
+        {
+            return Err(format!(
+                "record expires at {until}, before becoming valid at {from}"
+            ));
+        }
+        let mut branches = Vec::new();
+        if format_version >= CONDITIONS_FORMAT_VERSION {
+            for _ in 0..take_u16(&mut bytes)? {
+                let [kind] = take(&mut bytes, 1)?.try_into().unwrap();
+                let condition =
+                    Condition::decode(kind, take_string(&mut bytes)?, take_string(&mut bytes)?)?;
+                branches.push((condition, take_u32(&mut bytes)?));
+            }
+        }
+        records.push(RuleRecord {
+            status_code,
+            query_mode,
+            methods,
+            headers,
+            valid_from,
+            valid_until,
+            branches,
+        });
+    }
+    if !bytes.is_empty() {
+        return Err(format!("{} unexpected trailing bytes", bytes.len()));
+    }
+    Ok(records)
+}
+
+/// Parses the regex rules section, checking that it contains nothing else.
+fn decode_regex_rules(mut bytes: &[u8]) -> Result<Vec<RegexRule>, String> {
+    let count = take_u32(&mut bytes)?;
+    let mut rules = Vec::new();
+    for _ in 0..count {
+        let host = Some(take_string(&mut bytes)?).filter(|host| !host.is_empty());
+        let pattern = take_string(&mut bytes)?;
+        let value = take_u64(&mut bytes)?;
+        rules.push(RegexRule {
+            host,
+            pattern,
+            value,
+        });
+    }
+    if !bytes.is_empty() {
+        return Err(format!("{} unexpected trailing bytes", bytes.len()));
+    }
+    Ok(rules)
+}
+
+/// How much of a bundle decoding checks.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Validation {
+    /// Check everything, so that every rule can be looked up without further checks
+    Full,
+    /// Only check the header, the section lengths and the checksum, and whatever decoding the
+    /// sections checks anyway, skipping the walk over all sources. Only for bundles that were
+    /// fully checked before and can't have changed since, like the ones `rules-manager publish`
+    /// writes to a key-value store after checking them.
+    Checksum,
+}
+
+/// Parses and validates a bundle as much as `validation` says, taking ownership of its bytes to
+/// store the sources in without copying them.
+pub fn decode_owned(
+    mut bytes: Vec<u8>,
+    validation: Validation,
+) -> Result<Bundle<Vec<u8>>, BundleError> {
+    let Bundle {
+        header,
+        format_version,
+        sources,
+        targets,
+        records,
+        regex_rules,
+        regex_set,
+    } = decode_with(&bytes, validation)?;
+    let sources_start = header_len(format_version);
+    let sources_end = sources_start + sources.as_fst().as_bytes().len();
+    bytes.truncate(sources_end);
+    bytes.drain(..sources_start);
+    Ok(Bundle {
+        header,
+        format_version,
+        sources: fst::Map::new(bytes).expect("sources were already validated"),
+        targets,
+        records,
+        regex_rules,
+        regex_set,
+    })
+}
+
+/// Parses and validates a bundle.
+pub fn decode(bytes: &[u8]) -> Result<Bundle<&[u8]>, BundleError> {
+    decode_with(bytes, Validation::Full)
+}
+
+/// Parses a bundle, validating it as much as `validation` says.
+pub fn decode_with(bytes: &[u8], validation: Validation) -> Result<Bundle<&[u8]>, BundleError> {
+    if bytes.len() < header_len(MIN_FORMAT_VERSION) {
+        return Err(BundleError::TooShort(bytes.len()));
+    }
+    if &bytes[0..4] != MAGIC {
+        return Err(BundleError::BadMagic);
+    }
+    let u16_at = |offset: usize| u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap());
+    let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    let u64_at = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+
+    let format_version = u16_at(4);
+    if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&format_version) {
+        return Err(BundleError::UnsupportedVersion(format_version));
+    }
+    let header_len = header_len(format_version);
+    if bytes.len() < header_len {
+        return Err(BundleError::TooShort(bytes.len()));
+    }
+    let header = Header {
+        default_status_code: u16_at(6),
+        flags: u32_at(8),
+        rule_count: u64_at(16),
+        timestamp: u64_at(24),
+    };
+    let checksum = u32_at(12);
+    // Sources, targets, records and regex rules, with the sections missing from older versions
+    // left empty
+    let mut section_lens = [0u64; 4];
+    for (i, len) in section_lens
+        .iter_mut()
+        .take(section_count(format_version))
+        .enumerate()
+    {
+        *len = u64_at(32 + 8 * i);
+    }
+
+    let expected = section_lens
+        .iter()
+        .fold(header_len as u64, |sum, len| sum.saturating_add(*len));
+    if expected != bytes.len() as u64 {
+        return Err(BundleError::LengthMismatch {
+            expected,
+            actual: bytes.len(),
+        });
+    }
+    let mut rest = &bytes[header_len..];
+    let [sources, targets, records, regex_rules] = section_lens.map(|len| {
+        let (section, remaining) = rest.split_at(len as usize);
+        rest = remaining;
+        section
+    });
+
+    let mut hasher = crc32fast::Hasher::new();
+    for section in [sources, targets, records, regex_rules] {
+        hasher.update(section);
+    }
+    let actual = hasher.finalize();
+    if actual != checksum {
+        return Err(BundleError::ChecksumMismatch {
+            expected: checksum,
+            actual,
+        });
+    }
+
+    if !(301..400).contains(&header.default_status_code) {
+        return Err(BundleError::InvalidStatusCode(header.default_status_code));
+    }
+    let sources = fst::Map::new(sources).map_err(|e| BundleError::InvalidSources(e.to_string()))?;
+    if sources.len() as u64 != header.rule_count {
+        return Err(BundleError::RuleCountMismatch {
+            expected: header.rule_count,
+            actual: sources.len(),
+        });
+    }
+    let targets = fcsd::Set::deserialize_from(targets)
+        .map_err(|e| BundleError::InvalidTargets(e.to_string()))?;
+    let records = if format_version == MIN_FORMAT_VERSION {
+        Vec::new()
+    } else {
+        decode_records(records, format_version).map_err(BundleError::InvalidRecords)?
+    };
+    if let Some((condition, target)) = records
+        .iter()
+        .flat_map(|record| &record.branches)
+        .find(|(_, target)| *target as usize >= targets.len())
+    {
+        return Err(BundleError::InvalidRecords(format!(
+            "target {target} for condition '{condition}' is missing"
+        )));
+    }
+    // Make sure every rule can be looked up, so the component doesn't have to check. Bundles of
+    // format version 1 store the query mode and methods of a rule in the bits of its record index.
+    let record_count = if format_version == MIN_FORMAT_VERSION {
+        1 << 8
+    } else {
+        records.len() as u64
+    };
+    let in_range = |value: u64| {
+        let target = value & TARGET_MASK;
+        let record = value >> RECORD_SHIFT;
+        target < targets.len() as u64 && record < record_count
+    };
+    // This walks over every rule, which takes most of the time for large bundles
+    if validation == Validation::Full {
+        let mut stream = sources.stream();
+        while let Some((source, value)) = stream.next() {
+            if !in_range(value) {
+                return Err(BundleError::InvalidSources(format!(
+                    "rule '{}' refers to a missing target or record",
+                    String::from_utf8_lossy(source)
+                )));
+            }
+        }
+    }
+    let regex_rules = if regex_rules.is_empty() {
+        Vec::new()
+    } else {
+        decode_regex_rules(regex_rules).map_err(BundleError::InvalidRegexRules)?
+    };
+    if let Some(rule) = regex_rules.iter().find(|rule| !in_range(rule.value)) {
+        return Err(BundleError::InvalidRegexRules(format!(
+            "rule '~{}' refers to a missing target or record",
+            rule.pattern
+        )));
+    }
+    let regex_set = if regex_rules.is_empty() {
+        None
+    } else {
+        let patterns = regex_rules
+            .iter()
+            .map(|rule| rule.pattern.as_str())
+            .collect::<Vec<_>>();
+        Some(regex_rules::compile(&patterns).map_err(BundleError::InvalidRegexRules)?)
+    };
+
+    Ok(Bundle {
+        header,
+        format_version,
+        sources,
+        targets,
+        records,
+        regex_rules,
+        regex_set,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn test_records() -> Vec<RuleRecord> {
+        vec![
+            RuleRecord {
+                status_code: 308,
+                query_mode: 0,
+                methods: 0,
+                headers: vec![],
+                valid_from: None,
+                valid_until: None,
+                branches: vec![],
+            },
+            RuleRecord {
+                status_code: 301,
+                query_mode: 3,
+                methods: 0b11,
+                headers: vec![("X-Robots-Tag".to_string(), "noindex".to_string())],
+                valid_from: Some(1_764_288_000),
+                valid_until: Some(1_764_374_400),
+                branches: vec![
+                    (Condition::Language("de".to_string()), 0),
+                    (
+                        Condition::Cookie {
+                            name: "region".to_string(),
+                            value: "eu".to_string(),
+                        },
+                        0,
+                    ),
+                ],
+            },
+        ]
+    }
+
+    fn test_bundle() -> Vec<u8> {
+        let mut build = fst::MapBuilder::memory();
+        build.insert("/a", 0).unwrap();
+        build.insert("/b", 1 | 1 << RECORD_SHIFT).unwrap();
+        let sources = build.into_inner().unwrap();
+        let mut targets = Vec::new();
+        fcsd::Set::new(["/c", "/d"])
+            .unwrap()
+            .serialize_into(&mut targets)
+            .unwrap();
+        let header = Header {
+            default_status_code: 308,
+            flags: FLAG_NORMALIZED_SOURCES,
+            rule_count: 2,
+            timestamp: 1_700_000_000,
+        };
+        encode(
+            &header,
+            &sources,
+            &targets,
+            &test_records(),
+            &test_regex_rules(),
+        )
+        .unwrap()
+    }
+
+    fn test_regex_rules() -> Vec<RegexRule> {
+        vec![
+            RegexRule {
+                host: None,
+                pattern: r"/product\.php\?id=(\d+)".to_string(),
+                value: 1,
+            },
+            RegexRule {
+                host: Some("example.com".to_string()),
+                pattern: "/old/.*".to_string(),
+                value: 1 << RECORD_SHIFT,
+            },
+        ]
+    }
+
+    /// Encodes the test bundle in the layout of a format version between 2 and 4, leaving out what
+    /// that version can't store.
+    fn legacy_version_bundle(format_version: u16) -> Vec<u8> {
+        let bytes = test_bundle();
+        let bundle = decode(&bytes).unwrap();
+        let mut targets = Vec::new();
+        bundle.targets.serialize_into(&mut targets).unwrap();
+        let mut sections = vec![
+            bundle.sources.as_fst().as_bytes().to_vec(),
+            targets,
+            encode_records(&test_records(), format_version).unwrap(),
+        ];
+        if format_version >= 3 {
+            sections.push(encode_regex_rules(&test_regex_rules()).unwrap());
+        }
+        let mut hasher = crc32fast::Hasher::new();
+        for section in &sections {
+            hasher.update(section);
+        }
+
+        let mut encoded = bytes[..32].to_vec();
+        encoded[4..6].copy_from_slice(&format_version.to_le_bytes());
+        encoded[12..16].copy_from_slice(&hasher.finalize().to_le_bytes());
+        for section in &sections {
+            encoded.extend_from_slice(&(section.len() as u64).to_le_bytes());
+        }
+        assert_eq!(encoded.len(), header_len(format_version));
+        for section in &sections {
+            encoded.extend_from_slice(section);
+        }
+        encoded
+    }
+
+    /// Checks that the test bundle decodes from the layout of a format version between 2 and 4,
+    /// with the fields that version doesn't have left empty.
+    fn assert_legacy_version_decodes(format_version: u16) {
+        let bytes = legacy_version_bundle(format_version);
+        let records = test_records()
+            .into_iter()
+            .map(|mut record| {
+                if format_version < SCHEDULES_FORMAT_VERSION {
+                    record.valid_from = None;
+                    record.valid_until = None;
+                }
+                record.branches.clear();
+                record
+            })
+            .collect::<Vec<_>>();
+        // Format version 3 added the regex rules section
+        let regex_rules = if format_version >= 3 {
+            test_regex_rules()
+        } else {
+            Vec::new()
+        };
+
+        let bundle = decode(&bytes).unwrap();
+        assert_eq!(bundle.format_version, format_version);
+        assert_eq!(bundle.header.flags, FLAG_NORMALIZED_SOURCES);
+        assert_eq!(bundle.header.rule_count, 2);
+        assert_eq!(bundle.sources.get("/b"), Some(1 | 1 << RECORD_SHIFT));
+        assert_eq!(bundle.records, records);
+        assert_eq!(bundle.regex_rules, regex_rules);
+        assert_eq!(bundle.get("/b"), Some((301, b"/d".to_vec())));
+
+        let owned = decode_owned(bytes, Validation::Full).unwrap();
+        assert_eq!(owned.sources.get("/a"), Some(0));
+        assert_eq!(owned.records, records);
+        assert_eq!(owned.regex_set.is_some(), format_version >= 3);
+    }
+
+    /// A bundle in the legacy format, with the status code appended to the target.
+    fn legacy_bundle() -> Vec<u8> {
+        let mut build = fst::MapBuilder::memory();
+        build.insert("/a", 3 << 32).unwrap();
+        let sources = build.into_inner().unwrap();
+        let mut targets = Vec::new();
+        fcsd::Set::new(["/b 301"])
+            .unwrap()
+            .serialize_into(&mut targets)
+            .unwrap();
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&sources);
+        hasher.update(&targets);
+
+        let mut bundle = Vec::new();
+        bundle.extend_from_slice(MAGIC);
+        bundle.extend_from_slice(&MIN_FORMAT_VERSION.to_le_bytes());
+        bundle.extend_from_slice(&302u16.to_le_bytes());
+        bundle.extend_from_slice(&0u32.to_le_bytes());
+        bundle.extend_from_slice(&hasher.finalize().to_le_bytes());
+        bundle.extend_from_slice(&1u64.to_le_bytes());
+        bundle.extend_from_slice(&0u64.to_le_bytes());
+        bundle.extend_from_slice(&(sources.len() as u64).to_le_bytes());
+        bundle.extend_from_slice(&(targets.len() as u64).to_le_bytes());
+        bundle.extend_from_slice(&sources);
+        bundle.extend_from_slice(&targets);
+        bundle
+    }
+
+    #[test]
+    fn test_bundle_round_trip() {
+        let bytes = test_bundle();
+        let bundle = decode(&bytes).unwrap();
+        assert_eq!(
+            bundle.header,
+            Header {
+                default_status_code: 308,
+                flags: FLAG_NORMALIZED_SOURCES,
+                rule_count: 2,
+                timestamp: 1_700_000_000,
+            }
+        );
+        assert_eq!(bundle.format_version, FORMAT_VERSION);
+        assert_eq!(bundle.sources.get("/b"), Some(1 | 1 << RECORD_SHIFT));
+        assert_eq!(bundle.targets.decoder().run(1), b"/d");
+        assert_eq!(bundle.records, test_records());
+        assert_eq!(bundle.regex_rules, test_regex_rules());
+        assert_eq!(bundle.get("/b"), Some((301, b"/d".to_vec())));
+        assert_eq!(bundle.get("/c"), None);
+
+        let owned = decode_owned(bytes.clone(), Validation::Full).unwrap();
+        assert_eq!(owned.sources.get("/b"), Some(1 | 1 << RECORD_SHIFT));
+        assert_eq!(owned.records, test_records());
+        assert_eq!(owned.regex_set.unwrap().pattern_len(), 2);
+    }
+
+    #[test]
+    fn test_write_bundle() {
+        let bytes = test_bundle();
+        let bundle = decode(&bytes).unwrap();
+        let header = &bytes[..HEADER_LEN];
+        let sources = bundle.sources.as_fst().as_bytes();
+        let mut targets = Vec::new();
+        bundle.targets.serialize_into(&mut targets).unwrap();
+
+        // Bundles are written from the current position, with the header written last
+        let mut written = Cursor::new(b"prefix".to_vec());
+        written.seek(SeekFrom::End(0)).unwrap();
+        write(
+            &mut written,
+            &bundle.header,
+            sources,
+            &targets,
+            &test_records(),
+            &test_regex_rules(),
+        )
+        .unwrap();
+        let written = written.into_inner();
+        assert_eq!(&written[..6], b"prefix");
+        assert_eq!(&written[6..6 + HEADER_LEN], header);
+        assert_eq!(&written[6..], bytes);
+    }
+
+    #[test]
+    fn test_values_too_long() {
+        let value = "x".repeat(usize::from(u16::MAX) + 1);
+        let line = format!("/a /b header=X-Long:{value}");
+        let rule = Rule::parse(&line, 302, false).unwrap();
+        assert_eq!(
+            build(302, 0, 0, [("/a", &rule)], []),
+            Err("'xxxxxxxxxxxxxxxxxxxx...' is longer than 65535 bytes".to_string())
+        );
+
+        let header = Header {
+            default_status_code: 302,
+            flags: 0,
+            rule_count: 0,
+            timestamp: 0,
+        };
+        let mut record = test_records()[0].clone();
+        record.headers = vec![("X-Many".to_string(), "1".to_string()); 1 << 16];
+        let error = write(
+            &mut Cursor::new(Vec::new()),
+            &header,
+            &[][..],
+            &[],
+            &[record],
+            &[],
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(error.to_string(), "Too many headers: 65536");
+    }
+
+    #[test]
+    fn test_format_version_1() {
+        let bytes = legacy_bundle();
+        let bundle = decode(&bytes).unwrap();
+        assert_eq!(bundle.format_version, MIN_FORMAT_VERSION);
+        assert_eq!(bundle.header.default_status_code, 302);
+        assert_eq!(bundle.sources.get("/a"), Some(3 << 32));
+        assert_eq!(bundle.targets.decoder().run(0), b"/b 301");
+        assert!(bundle.records.is_empty());
+        // The status code is split off the target
+        assert_eq!(bundle.get("/a"), Some((301, b"/b".to_vec())));
+
+        let result = decode(&bytes[..bytes.len() - 1]);
+        assert!(matches!(result, Err(BundleError::LengthMismatch { .. })));
+    }
+
+    #[test]
+    fn test_format_version_2() {
+        assert_legacy_version_decodes(2);
+    }
+
+    #[test]
+    fn test_format_version_3() {
+        assert_legacy_version_decodes(3);
+    }
+
+    #[test]
+    fn test_format_version_4() {
+        assert_legacy_version_decodes(4);
+    }
+
+    #[test]
+    fn test_invalid_bundles() {
+        let bytes = test_bundle();
+
+        let result = decode(&bytes[..10]);
+        assert!(matches!(result, Err(BundleError::TooShort(10))));
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(decode(&bad_magic), Err(BundleError::BadMagic)));
+
+        let mut bad_version = bytes.clone();
+        bad_version[4] = 6;
+        assert!(matches!(
+            decode(&bad_version),
+            Err(BundleError::UnsupportedVersion(6))
+        ));
+
+        let result = decode(&bytes[..bytes.len() - 1]);
+        assert!(matches!(result, Err(BundleError::LengthMismatch { .. })));
+
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().unwrap() ^= 0xff;
+        assert!(matches!(
+            decode(&corrupt),
+            Err(BundleError::ChecksumMismatch { .. })
+        ));
+        assert!(matches!(
+            decode_with(&corrupt, Validation::Checksum),
+            Err(BundleError::ChecksumMismatch { .. })
+        ));
+
+        let mut bad_status = bytes.clone();
+        bad_status[6..8].copy_from_slice(&200u16.to_le_bytes());
+        assert!(matches!(
+            decode(&bad_status),
+            Err(BundleError::InvalidStatusCode(200))
+        ));
+
+        let mut bad_count = bytes.clone();
+        bad_count[16..24].copy_from_slice(&3u64.to_le_bytes());
+        assert!(matches!(
+            decode(&bad_count),
+            Err(BundleError::RuleCountMismatch {
+                expected: 3,
+                actual: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn test_invalid_records() {
+        let header = Header {
+            default_status_code: 302,
+            flags: 0,
+            rule_count: 1,
+            timestamp: 0,
+        };
+        let mut targets = Vec::new();
+        fcsd::Set::new(["/b"])
+            .unwrap()
+            .serialize_into(&mut targets)
+            .unwrap();
+        let sources = |value: u64| {
+            let mut build = fst::MapBuilder::memory();
+            build.insert("/a", value).unwrap();
+            build.into_inner().unwrap()
+        };
+
+        // Rules referring to records or targets that don't exist
+        let records = &test_records()[..1];
+        let bytes = encode(&header, &sources(1 << RECORD_SHIFT), &targets, records, &[]).unwrap();
+        assert!(matches!(
+            decode(&bytes),
+            Err(BundleError::InvalidSources(_))
+        ));
+        let bytes = encode(&header, &sources(1), &targets, records, &[]).unwrap();
+        assert!(matches!(
+            decode(&bytes),
+            Err(BundleError::InvalidSources(_))
+        ));
+        // Checking the checksum only relies on the bundle having been validated before
+        assert!(decode_with(&bytes, Validation::Checksum).is_ok());
+
+        let mut bad_record = test_records()[0].clone();
+        bad_record.status_code = 200;
+        let bytes = encode(&header, &sources(0), &targets, &[bad_record], &[]).unwrap();
+        assert!(matches!(
+            decode(&bytes),
+            Err(BundleError::InvalidRecords(_))
+        ));
+
+        let mut bad_record = test_records()[1].clone();
+        bad_record.valid_until = bad_record.valid_from;
+        let bytes = encode(&header, &sources(0), &targets, &[bad_record], &[]).unwrap();
+        assert!(matches!(
+            decode(&bytes),
+            Err(BundleError::InvalidRecords(_))
+        ));
+
+        // Conditional targets that don't exist
+        let mut bad_record = test_records()[1].clone();
+        bad_record.branches[1].1 = 1;
+        let bytes = encode(&header, &sources(0), &targets, &[bad_record], &[]).unwrap();
+        assert!(matches!(
+            decode(&bytes),
+            Err(BundleError::InvalidRecords(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_regex_rules() {
+        let header = Header {
+            default_status_code: 302,
+            flags: 0,
+            rule_count: 0,
+            timestamp: 0,
+        };
+        let sources = fst::MapBuilder::memory().into_inner().unwrap();
+        let mut targets = Vec::new();
+        fcsd::Set::new(["/b"])
+            .unwrap()
+            .serialize_into(&mut targets)
+            .unwrap();
+        let records = &test_records()[..1];
+        let rule = |pattern: &str, value: u64| RegexRule {
+            host: None,
+            pattern: pattern.to_string(),
+            value,
+        };
+
+        let bytes = encode(&header, &sources, &targets, records, &[rule("/a/(.*)", 0)]).unwrap();
+        assert_eq!(decode(&bytes).unwrap().regex_rules.len(), 1);
+
+        let bytes = encode(&header, &sources, &targets, records, &[rule("/a", 1)]).unwrap();
+        assert!(matches!(
+            decode(&bytes),
+            Err(BundleError::InvalidRegexRules(_))
+        ));
+        let bytes = encode(&header, &sources, &targets, records, &[rule("/(a", 0)]).unwrap();
+        assert!(matches!(
+            decode(&bytes),
+            Err(BundleError::InvalidRegexRules(_))
+        ));
+    }
+
+    /// A small seeded random number generator (SplitMix64) for generating rules.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = self.0;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn chance(&mut self, percent: u64) -> bool {
+            self.next() % 100 < percent
+        }
+
+        fn pick<'s>(&mut self, items: &[&'s str]) -> &'s str {
+            items[self.below(items.len())]
+        }
+    }
+
+    fn random_path(rng: &mut Rng) -> String {
+        const SEGMENTS: &[&str] = &["a", "B", "blog", "%7Euser", "Docs", "2024", "x-y", ""];
+        let segments = (0..1 + rng.below(3))
+            .map(|_| rng.pick(SEGMENTS))
+            .collect::<Vec<_>>();
+        format!("/{}", segments.join("/"))
+    }
+
+    /// Generates a line of a rules file with random settings. Not every line is a valid rule, since
+    /// e.g. a source and its target can end up the same.
+    fn random_rule(rng: &mut Rng) -> String {
+        let target = if rng.chance(30) {
+            format!("https://example.net{}", random_path(rng))
+        } else {
+            random_path(rng)
+        };
+        let mut line = if rng.chance(10) {
+            format!(r"~/r{}/(\d+) /s/$1", random_path(rng))
+        } else if rng.chance(20) {
+            format!("{}/* {target}/$1", random_path(rng))
+        } else if rng.chance(10) {
+            format!("{}?id={} {target}", random_path(rng), rng.below(10))
+        } else {
+            format!("{} {target}", random_path(rng))
+        };
+        if rng.chance(70) {
+            line += &format!(" {}", rng.pick(&["301", "302", "303", "307", "308"]));
+        }
+        if rng.chance(20) {
+            line += &format!(" host={}", rng.pick(&["example.com", "Shop.example.com"]));
+        }
+        if rng.chance(20) {
+            line += &format!(" query={}", rng.pick(&["exact", "ignore", "forward"]));
+        }
+        if rng.chance(20) {
+            line += &format!(" methods={}", rng.pick(&["GET", "post,PUT", "DELETE"]));
+        }
+        if rng.chance(20) {
+            line += " header=X-Robots-Tag:noindex";
+        }
+        if rng.chance(20) {
+            line += &format!(" valid_from={}", rng.pick(&["2025-01-01", "1700000000"]));
+        }
+        if rng.chance(20) {
+            line += " valid_until=2030-01-01T00:00:00Z";
+        }
+        if rng.chance(20) {
+            line += &format!(" when=lang:DE:/de{}", random_path(rng));
+        }
+        if rng.chance(20) {
+            line += " when=cookie:region=eu:https://eu.example.net/";
+        }
+        line
+    }
+
+    /// Checks that every rule resolves to exactly the status code, target, and settings it was
+    /// given.
+    fn assert_resolves<D: AsRef<[u8]>>(
+        bundle: &Bundle<D>,
+        rules: &BTreeMap<String, Rule>,
+        regex_rules: &[Rule],
+    ) {
+        let check = |rule: &Rule, value: u64| {
+            assert_eq!(
+                bundle.resolve(value),
+                (rule.status_code, rule.to.as_bytes().to_vec()),
+                "{rule:?}"
+            );
+            let expected = rule.options.record(rule.status_code, |to| {
+                let mut locator = bundle.targets.locator();
+                locator.run(to).expect("conditional target is missing") as u32
+            });
+            assert_eq!(bundle.record(value), Some(&expected), "{rule:?}");
+        };
+        for (key, rule) in rules {
+            check(rule, bundle.sources.get(key).expect("rule is missing"));
+        }
+        assert_eq!(bundle.regex_rules.len(), regex_rules.len());
+        for (encoded, rule) in bundle.regex_rules.iter().zip(regex_rules) {
+            assert_eq!(encoded.pattern, rule.from[1..]);
+            assert_eq!(encoded.host, rule.options.host);
+            check(rule, encoded.value);
+        }
+    }
+
+    #[test]
+    fn test_build_round_trip() {
+        let mut accepted = 0;
+        for seed in 0..100 {
+            let mut rng = Rng(seed);
+            let normalize = rng.chance(50);
+            let default_status_code = [301, 302, 308][rng.below(3)];
+            let lines = (0..50).map(|_| random_rule(&mut rng)).collect::<Vec<_>>();
+
+            // Later rules replace earlier ones with the same key
+            let mut rules = BTreeMap::new();
+            let mut regex_rules = Vec::new();
+            for line in &lines {
+                let Ok(rule) = Rule::parse(line, default_status_code, normalize) else {
+                    continue;
+                };
+                accepted += 1;
+                // Writing the rule back out gives the same rule
+                let written = format!(
+                    "{} {} {}{}",
+                    rule.from, rule.to, rule.status_code, rule.options
+                );
+                assert_eq!(Rule::parse(&written, 399, normalize).as_ref(), Ok(&rule));
+                if rule.is_regex() {
+                    regex_rules.push(rule);
+                } else {
+                    rules.insert(rule.key(normalize).into_owned(), rule);
+                }
+            }
+
+            let flags = if normalize {
+                FLAG_NORMALIZED_SOURCES
+            } else {
+                0
+            };
+            let keys = rules.iter().map(|(key, rule)| (key.as_str(), rule));
+            let bytes = build(default_status_code, flags, 1, keys, &regex_rules).unwrap();
+            let bundle = decode(&bytes).unwrap();
+            assert_eq!(
+                bundle.header,
+                Header {
+                    default_status_code,
+                    flags,
+                    rule_count: rules.len() as u64,
+                    timestamp: 1,
+                }
+            );
+            assert_resolves(&bundle, &rules, &regex_rules);
+
+            // Decoding the rules gives them back with the keys' sources
+            let expected = rules
+                .iter()
+                .map(|(key, rule)| (split_host(key).1, rule))
+                .chain(regex_rules.iter().map(|rule| (rule.from, rule)))
+                .map(|(from, rule)| Rule {
+                    from,
+                    ..rule.clone()
+                })
+                .collect::<Vec<_>>();
+            let decoded = bundle.rules();
+            assert_eq!(
+                decoded.iter().map(DecodedRule::rule).collect::<Vec<_>>(),
+                expected
+            );
+            assert_resolves(
+                &decode_owned(bytes, Validation::Full).unwrap(),
+                &rules,
+                &regex_rules,
+            );
+        }
+        // Most generated rules are valid, so they're actually checked
+        assert!(
+            accepted > 100 * 50 / 2,
+            "only {accepted} rules were accepted"
+        );
+    }
+}
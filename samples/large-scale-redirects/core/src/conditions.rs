@@ -0,0 +1,405 @@
+//! Conditional targets, letting a rule redirect to different targets depending on the request.
+//!
+//! Each `when=<condition>:<target>` option of a rule adds a branch, which the Wasm component takes
+//! instead of the rule's main target if its condition holds. Conditions are one of:
+//!
+//! - `lang:<tag>`: the request's `Accept-Language` header includes the language tag, or a more
+//!   specific one, like `de-CH` for `de`. Of all language branches, the one for the language the
+//!   client prefers most is taken.
+//! - `header:<name>=<value>`: the request has a header with the given value, compared
+//!   case-insensitively, e.g. a country code set by a CDN.
+//! - `cookie:<name>=<value>`: the request has a cookie with the given value.
+//!
+//! Branches are evaluated in the order they're given, and the main target is used if none of them
+//! applies.
+
+use std::fmt::{Display, Formatter};
+
+/// Encoded kinds of conditions in bundle records.
+const KIND_LANGUAGE: u8 = 1;
+const KIND_HEADER: u8 = 2;
+const KIND_COOKIE: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Condition {
+    /// A lowercased language tag
+    Language(String),
+    Header {
+        name: String,
+        value: String,
+    },
+    Cookie {
+        name: String,
+        value: String,
+    },
+}
+
+impl Condition {
+    /// Parses the value of a `when=` option into a condition and its target.
+    pub fn parse_branch(input: &str) -> Result<(Self, &str), String> {
+        let invalid = || {
+            format!(
+                "Invalid condition '{input}', expected 'lang:<tag>:<target>', \
+                 'header:<name>=<value>:<target>', or 'cookie:<name>=<value>:<target>'"
+            )
+        };
+        let (kind, rest) = input.split_once(':').ok_or_else(invalid)?;
+        let (condition, target) = rest.split_once(':').ok_or_else(invalid)?;
+        let condition = match kind {
+            "lang" => {
+                if !is_valid_language_tag(condition) {
+                    return Err(format!("Invalid language tag '{condition}'"));
+                }
+                Self::Language(condition.to_ascii_lowercase())
+            }
+            "header" | "cookie" => {
+                let (name, value) = condition.split_once('=').ok_or_else(invalid)?;
+                if !is_token(name) {
+                    return Err(format!("Invalid {kind} name '{name}'"));
+                }
+                if value.is_empty() || !value.bytes().all(|b| is_value_byte(kind, b)) {
+                    return Err(format!("Invalid value for {kind} '{name}'"));
+                }
+                let (name, value) = (name.to_string(), value.to_string());
+                if kind == "header" {
+                    Self::Header { name, value }
+                } else {
+                    Self::Cookie { name, value }
+                }
+            }
+            _ => return Err(invalid()),
+        };
+        Ok((condition, target))
+    }
+
+    /// Returns the kind, name, and value the condition is stored as in bundle records.
+    pub fn encoded(&self) -> (u8, &str, &str) {
+        match self {
+            Self::Language(tag) => (KIND_LANGUAGE, tag, ""),
+            Self::Header { name, value } => (KIND_HEADER, name, value),
+            Self::Cookie { name, value } => (KIND_COOKIE, name, value),
+        }
+    }
+
+    /// The inverse of `encoded`.
+    pub fn decode(kind: u8, name: String, value: String) -> Result<Self, String> {
+        match kind {
+            KIND_LANGUAGE if value.is_empty() => Ok(Self::Language(name)),
+            KIND_HEADER => Ok(Self::Header { name, value }),
+            KIND_COOKIE => Ok(Self::Cookie { name, value }),
+            _ => Err(format!("invalid condition kind {kind}")),
+        }
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Language(tag) => write!(f, "lang:{tag}"),
+            Self::Header { name, value } => write!(f, "header:{name}={value}"),
+            Self::Cookie { name, value } => write!(f, "cookie:{name}={value}"),
+        }
+    }
+}
+
+/// Returns the `Vary` header value for responses of a rule with the given branches, listing every
+/// request header its target depends on.
+pub fn vary(branches: &[(Condition, u64)]) -> Vec<u8> {
+    let mut names: Vec<&str> = Vec::new();
+    for (condition, _) in branches {
+        let name = match condition {
+            Condition::Language(_) => "Accept-Language",
+            Condition::Header { name, .. } => name,
+            Condition::Cookie { .. } => "Cookie",
+        };
+        if !names.iter().any(|other| other.eq_ignore_ascii_case(name)) {
+            names.push(name);
+        }
+    }
+    names.join(", ").into_bytes()
+}
+
+/// Returns the target index of the first branch whose condition holds, reading request headers
+/// with `header`. A language branch only holds if no other language branch matches a language the
+/// client prefers more, so the order of languages in `Accept-Language` takes precedence over the
+/// order of the branches.
+pub fn select(branches: &[(Condition, u64)], header: impl Fn(&str) -> Vec<Vec<u8>>) -> Option<u64> {
+    let has = |kind: fn(&Condition) -> bool| branches.iter().any(|(condition, _)| kind(condition));
+    // Headers are only read if a condition depends on them
+    let languages = if has(|c| matches!(c, Condition::Language(_))) {
+        accepted_languages(&header("Accept-Language"))
+    } else {
+        Vec::new()
+    };
+    // The index of the language branch for the most preferred language, with earlier branches
+    // winning ties
+    let mut best_language = None;
+    let mut best_quality = 0.0;
+    for (i, (condition, _)) in branches.iter().enumerate() {
+        if let Condition::Language(tag) = condition {
+            let quality = language_quality(&languages, tag);
+            if quality > best_quality {
+                best_language = Some(i);
+                best_quality = quality;
+            }
+        }
+    }
+
+    let cookies = if has(|c| matches!(c, Condition::Cookie { .. })) {
+        header("Cookie")
+    } else {
+        Vec::new()
+    };
+    branches
+        .iter()
+        .enumerate()
+        .find(|(i, (condition, _))| match condition {
+            Condition::Language(_) => best_language == Some(*i),
+            Condition::Header { name, value } => header(name)
+                .iter()
+                .any(|actual| actual.trim_ascii().eq_ignore_ascii_case(value.as_bytes())),
+            Condition::Cookie { name, value } => cookies
+                .iter()
+                .flat_map(|cookies| cookies.split(|b| *b == b';'))
+                .filter_map(|cookie| {
+                    let cookie = cookie.trim_ascii();
+                    let separator = cookie.iter().position(|b| *b == b'=')?;
+                    Some((&cookie[..separator], &cookie[separator + 1..]))
+                })
+                .any(|(actual_name, actual_value)| {
+                    actual_name == name.as_bytes() && actual_value == value.as_bytes()
+                }),
+        })
+        .map(|(_, (_, target))| *target)
+}
+
+/// Parses `Accept-Language` header values into lowercased language ranges with their quality,
+/// skipping ranges the client doesn't accept.
+fn accepted_languages(values: &[Vec<u8>]) -> Vec<(String, f32)> {
+    values
+        .iter()
+        .filter_map(|value| std::str::from_utf8(value).ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim().to_ascii_lowercase();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|quality| quality.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (!tag.is_empty() && quality > 0.0 && quality <= 1.0).then_some((tag, quality))
+        })
+        .collect()
+}
+
+/// Returns the highest quality of the accepted language ranges matching `tag`, either exactly or
+/// by being more specific, or 0 if there are none.
+fn language_quality(languages: &[(String, f32)], tag: &str) -> f32 {
+    languages
+        .iter()
+        .filter(|(range, _)| {
+            range
+                .strip_prefix(tag)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('-'))
+        })
+        .map(|(_, quality)| *quality)
+        .fold(0.0, f32::max)
+}
+
+/// Checks for a BCP 47 language tag, made up of alphanumeric subtags of up to 8 characters
+/// separated by `-`, starting with a language.
+fn is_valid_language_tag(tag: &str) -> bool {
+    tag.split('-').enumerate().all(|(i, subtag)| {
+        (1..=8).contains(&subtag.len())
+            && if i == 0 {
+                subtag.bytes().all(|b| b.is_ascii_alphabetic())
+            } else {
+                subtag.bytes().all(|b| b.is_ascii_alphanumeric())
+            }
+    })
+}
+
+/// Checks for an HTTP token, as used for header and cookie names.
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Values end at the `:` before the target, and cookie values can't contain separators either.
+fn is_value_byte(kind: &str, b: u8) -> bool {
+    b.is_ascii_graphic() && b != b':' && (kind != "cookie" || !b"\";,\\".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_branch() {
+        assert_eq!(
+            Condition::parse_branch("lang:de-CH:/de-ch/"),
+            Ok((Condition::Language("de-ch".to_string()), "/de-ch/"))
+        );
+        assert_eq!(
+            Condition::parse_branch("header:CF-IPCountry=CH:https://example.ch/"),
+            Ok((
+                Condition::Header {
+                    name: "CF-IPCountry".to_string(),
+                    value: "CH".to_string()
+                },
+                "https://example.ch/"
+            ))
+        );
+        let (condition, target) = Condition::parse_branch("cookie:locale=it:/it/").unwrap();
+        assert_eq!(condition.to_string(), "cookie:locale=it");
+        assert_eq!(target, "/it/");
+
+        for (input, message) in [
+            ("lang:/de/", "Invalid condition"),
+            ("lang:deutschland:/de/", "Invalid language tag"),
+            ("lang:d3:/de/", "Invalid language tag"),
+            ("header:=x:/x", "Invalid header name"),
+            ("header:Bad@Name=x:/x", "Invalid header name"),
+            ("cookie:locale=a;b:/x", "Invalid value for cookie"),
+            ("cookie:locale:/x", "Invalid condition"),
+            ("geo:CH:/ch/", "Invalid condition"),
+        ] {
+            let error = Condition::parse_branch(input).unwrap_err();
+            assert!(error.starts_with(message), "{input}: {error}");
+        }
+    }
+
+    #[test]
+    fn test_encoded_conditions() {
+        for condition in [
+            Condition::Language("fr".to_string()),
+            Condition::Header {
+                name: "X-Country".to_string(),
+                value: "FR".to_string(),
+            },
+            Condition::Cookie {
+                name: "lang".to_string(),
+                value: "fr".to_string(),
+            },
+        ] {
+            let (kind, name, value) = condition.encoded();
+            let decoded = Condition::decode(kind, name.to_string(), value.to_string());
+            assert_eq!(decoded, Ok(condition));
+        }
+        assert!(Condition::decode(4, String::new(), String::new()).is_err());
+    }
+
+    /// Returns the values of the header `name` among `headers`.
+    fn header<'h>(headers: &'h [(&str, &str)]) -> impl Fn(&str) -> Vec<Vec<u8>> + 'h {
+        |name| {
+            headers
+                .iter()
+                .filter(|(other, _)| other.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_bytes().to_vec())
+                .collect()
+        }
+    }
+
+    fn language(tag: &str, target: u64) -> (Condition, u64) {
+        (Condition::Language(tag.to_string()), target)
+    }
+
+    #[test]
+    fn test_select_language() {
+        let branches = [language("fr", 1), language("de", 2)];
+        let select =
+            |accept_language| select(&branches, header(&[("Accept-Language", accept_language)]));
+
+        assert_eq!(select("de"), Some(2));
+        // More specific ranges match, and ranges are compared case-insensitively
+        assert_eq!(select("DE-ch"), Some(2));
+        assert_eq!(select("deu"), None);
+        // The client's preferences take precedence over the order of the branches
+        assert_eq!(select("fr;q=0.5, de;q=0.9"), Some(2));
+        assert_eq!(select("de;q=0.5, fr"), Some(1));
+        // Earlier branches win ties
+        assert_eq!(select("de, fr"), Some(1));
+        // Ranges with a quality of 0 or an invalid one aren't accepted
+        assert_eq!(select("de;q=0, it"), None);
+        assert_eq!(select("de;q=2"), None);
+        assert_eq!(select("de;q=abc"), Some(2));
+        assert_eq!(select(""), None);
+    }
+
+    #[test]
+    fn test_select_headers_and_cookies() {
+        let branches = [
+            language("it", 1),
+            (
+                Condition::Header {
+                    name: "X-Country".to_string(),
+                    value: "CH".to_string(),
+                },
+                2,
+            ),
+            (
+                Condition::Cookie {
+                    name: "locale".to_string(),
+                    value: "it".to_string(),
+                },
+                3,
+            ),
+        ];
+        let select = |headers: &[(&str, &str)]| select(&branches, header(headers));
+
+        assert_eq!(select(&[]), None);
+        assert_eq!(select(&[("x-country", " ch ")]), Some(2));
+        assert_eq!(select(&[("X-Country", "DE")]), None);
+        assert_eq!(select(&[("Cookie", "a=1; locale=it")]), Some(3));
+        assert_eq!(
+            select(&[("Cookie", "a=1"), ("Cookie", "locale=it")]),
+            Some(3)
+        );
+        assert_eq!(select(&[("Cookie", "locale=itx; xlocale=it")]), None);
+        // Without a matching language, later conditions are checked in order
+        assert_eq!(
+            select(&[
+                ("Accept-Language", "en"),
+                ("X-Country", "CH"),
+                ("Cookie", "locale=it")
+            ]),
+            Some(2)
+        );
+        assert_eq!(
+            select(&[("Accept-Language", "it"), ("X-Country", "CH")]),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_vary() {
+        let branches = [
+            language("de", 1),
+            (
+                Condition::Header {
+                    name: "X-Country".to_string(),
+                    value: "CH".to_string(),
+                },
+                2,
+            ),
+            language("fr", 3),
+            (
+                Condition::Header {
+                    name: "x-country".to_string(),
+                    value: "DE".to_string(),
+                },
+                4,
+            ),
+            (
+                Condition::Cookie {
+                    name: "locale".to_string(),
+                    value: "it".to_string(),
+                },
+                5,
+            ),
+        ];
+        assert_eq!(vary(&branches), b"Accept-Language, X-Country, Cookie");
+    }
+}
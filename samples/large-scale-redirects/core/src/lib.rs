@@ -0,0 +1,20 @@
+//! The redirect rules shared by `rules-manager` and the Wasm component.
+//!
+//! This crate owns everything both sides have to agree on:
+//! - the rule model, and parsing and validating rules from the text format (`rule`)
+//! - conditional targets (`conditions`), regex rules (`regex_rules`) and schedules (`schedule`)
+//! - the bundle format the rules are encoded into and decoded from (`bundle`)
+//! - matching requests against a decoded bundle (`lookup`) and their methods (`methods`), which
+//!   the component serves and `native` exposes to benchmarks and `rules-manager inspect`
+//!
+//! Deciding what to do about invalid rules, loops, and chains is left to `rules-manager`, and
+//! serving the responses to the component.
+
+pub mod bundle;
+pub mod conditions;
+pub mod lookup;
+pub mod methods;
+pub mod native;
+pub mod regex_rules;
+pub mod rule;
+pub mod schedule;
@@ -0,0 +1,723 @@
+//! Lookups of requests in decoded bundles, as the Wasm component answers them.
+//!
+//! `decode` converts the rule records of a bundle decoded by `bundle` into the form requests are
+//! handled with, and `resolve` finds the rule handling a request and builds its redirect: literal
+//! rules first, then rules ignoring the query string, wildcard rules with the longest matching
+//! prefix, and finally regex rules. Everything that depends on the request itself is passed in, so
+//! the component and native tools like `rules-manager lookup` share the same code.
+
+use crate::bundle::{
+    self as format, BundleError, FLAG_NORMALIZED_SOURCES, RECORD_SHIFT, RuleRecord, TARGET_MASK,
+    Validation, legacy_status_code,
+};
+use crate::conditions::{self, Condition};
+use crate::regex_rules::{RegexRule, RegexRules};
+use crate::rule::{QueryMode, normalize_request, strip_trailing_slash};
+use fst::raw::Output;
+
+/// A decoded bundle, ready for looking up requests.
+pub struct Bundle {
+    pub default_status_code: u16,
+    pub normalize_sources: bool,
+    pub timestamp: u64,
+    pub sources: fst::Map<Vec<u8>>,
+    pub targets: fcsd::Set,
+    records: Vec<Record>,
+    /// `None` if the bundle has no regex rules
+    regex_rules: Option<RegexRules>,
+}
+
+/// Everything about a rule except its source and target, shared by all rules with the same
+/// settings.
+pub struct Record {
+    /// `None` for rules from legacy bundles, whose targets end in a space and the status code if
+    /// it isn't the default
+    pub status_code: Option<u16>,
+    /// `None` if the rule uses the default query mode
+    pub query_mode: Option<QueryMode>,
+    /// Methods the rule is restricted to, see `methods::check`
+    pub methods: u8,
+    /// Headers to add to the redirect response, starting with `Vary` for rules with conditional
+    /// targets
+    pub headers: Vec<(String, Vec<u8>)>,
+    /// The time the rule becomes active, in seconds since the epoch
+    valid_from: Option<u64>,
+    /// The time the rule expires, in seconds since the epoch
+    pub valid_until: Option<u64>,
+    /// Conditions with the target indices taken instead of the rule's own if they hold, see
+    /// `conditions::select`
+    branches: Vec<(Condition, u64)>,
+}
+
+impl Record {
+    fn new(record: RuleRecord) -> Self {
+        let branches = record
+            .branches
+            .into_iter()
+            .map(|(condition, target)| {
+                let condition = match condition {
+                    Condition::Language(tag) => Condition::Language(tag.to_ascii_lowercase()),
+                    condition => condition,
+                };
+                (condition, target as u64)
+            })
+            .collect::<Vec<_>>();
+        let mut headers = record
+            .headers
+            .into_iter()
+            .map(|(name, value)| (name, value.into_bytes()))
+            .collect::<Vec<_>>();
+        // Caches must not reuse a redirect whose target depends on the request's headers for
+        // other requests. Rules can still override it like any other header.
+        if !branches.is_empty() {
+            headers.insert(0, ("Vary".to_string(), conditions::vary(&branches)));
+        }
+        Self {
+            status_code: Some(record.status_code),
+            query_mode: QueryMode::decode(record.query_mode),
+            methods: record.methods,
+            headers,
+            valid_from: record.valid_from,
+            valid_until: record.valid_until,
+            branches,
+        }
+    }
+
+    /// Whether the rule applies at `now`, in seconds since the epoch.
+    pub fn is_active(&self, now: u64) -> bool {
+        self.valid_from.is_none_or(|from| now >= from)
+            && self.valid_until.is_none_or(|until| now < until)
+    }
+}
+
+impl Bundle {
+    /// Returns the record of the rule with the given FST value.
+    pub fn record(&self, value: u64) -> &Record {
+        // Indices are validated when decoding the bundle, or before publishing it
+        &self.records[(value >> RECORD_SHIFT) as usize]
+    }
+}
+
+/// Parses a bundle, validating it as much as `validation` says.
+pub fn decode(bytes: Vec<u8>, validation: Validation) -> Result<Bundle, BundleError> {
+    let bundle = format::decode_owned(bytes, validation)?;
+    let records = if bundle.format_version == format::MIN_FORMAT_VERSION {
+        legacy_records()
+    } else {
+        bundle.records.into_iter().map(Record::new).collect()
+    };
+    let regex_rules = match bundle.regex_set {
+        Some(set) => {
+            let rules = bundle.regex_rules.into_iter().map(RegexRule::new).collect();
+            Some(RegexRules::new(rules, set).map_err(BundleError::InvalidRegexRules)?)
+        }
+        None => None,
+    };
+
+    Ok(Bundle {
+        default_status_code: bundle.header.default_status_code,
+        normalize_sources: bundle.header.flags & FLAG_NORMALIZED_SOURCES != 0,
+        timestamp: bundle.header.timestamp,
+        sources: bundle.sources,
+        targets: bundle.targets,
+        records,
+        regex_rules,
+    })
+}
+
+/// Legacy bundles store the query mode and methods of a rule directly in the bits above its target
+/// index, which are interpreted as a record index instead. Listing every combination of them as a
+/// record makes both formats work the same way.
+fn legacy_records() -> Vec<Record> {
+    (0..=u8::MAX)
+        .map(|bits| Record {
+            status_code: None,
+            query_mode: QueryMode::decode(bits & 0b11),
+            methods: bits >> 2,
+            headers: Vec::new(),
+            valid_from: None,
+            valid_until: None,
+            branches: Vec::new(),
+        })
+        .collect()
+}
+
+/// The parts of a request rules are looked up by.
+pub struct LookupKey {
+    /// The lowercased host, without any port
+    host: Option<String>,
+    /// The path with the query string
+    path: RequestPath,
+    /// Host-specific rules are stored with the host prefixed to the source path
+    host_path: Option<RequestPath>,
+    /// For bundles with normalized sources, the path before normalization, which wildcard rules
+    /// take the suffix for `$1` from, and the offsets of the normalized path's bytes in it, see
+    /// `normalize_request`
+    original: Option<(String, Vec<usize>)>,
+}
+
+/// A path to look up rules for, possibly prefixed with the request's host.
+struct RequestPath {
+    /// The path sources are looked up by, normalized if the bundle's sources are
+    exact: String,
+    /// The path wildcard sources are matched against, if it's different from `exact`: normalized
+    /// paths keep their trailing slash for this, since wildcard sources end in one
+    prefixed: Option<String>,
+    /// The length of the host in front of the path
+    host_len: usize,
+}
+
+impl LookupKey {
+    /// Takes the request's authority, which may include a port, and its path with the query
+    /// string.
+    pub fn new(bundle: &Bundle, authority: Option<&str>, path: String) -> Self {
+        let host = authority.map(request_host);
+        let (prefixed, original) = if bundle.normalize_sources {
+            let (normalized, offsets) = normalize_request(&path);
+            (normalized, Some((path, offsets)))
+        } else {
+            (path, None)
+        };
+        let host_path = host
+            .as_deref()
+            .map(|host| RequestPath::new(host, &prefixed, original.is_some()));
+        Self {
+            path: RequestPath::new("", &prefixed, original.is_some()),
+            host,
+            host_path,
+            original,
+        }
+    }
+
+    /// The path the request is looked up by, without its host, normalized if the bundle's
+    /// sources are.
+    pub fn path(&self) -> &str {
+        &self.path.exact
+    }
+}
+
+impl RequestPath {
+    fn new(host: &str, path: &str, normalized: bool) -> Self {
+        let prefixed = format!("{host}{path}");
+        let mut exact = prefixed.clone();
+        if normalized {
+            strip_trailing_slash(&mut exact);
+        }
+        Self {
+            prefixed: (exact != prefixed).then_some(prefixed),
+            exact,
+            host_len: host.len(),
+        }
+    }
+
+    fn prefixed(&self) -> &str {
+        self.prefixed.as_deref().unwrap_or(&self.exact)
+    }
+}
+
+/// The redirect a request is answered with.
+pub struct Redirect<'a> {
+    pub record: &'a Record,
+    pub code: u16,
+    pub target: Vec<u8>,
+    /// The rule's key for analytics
+    pub hit_key: &'a str,
+    /// Whether the rule is a wildcard rule, for analytics
+    pub wildcard: bool,
+}
+
+/// Finds the rule handling a request at `now`, in seconds since the epoch, and builds its
+/// redirect. Rules without a `query` option use `query_mode`. `header` returns the values of a
+/// request header, for rules with conditional targets.
+pub fn resolve<'a>(
+    bundle: &'a Bundle,
+    key: &'a LookupKey,
+    now: u64,
+    query_mode: QueryMode,
+    header: impl Fn(&str) -> Vec<Vec<u8>>,
+) -> Option<Redirect<'a>> {
+    let found = key
+        .host_path
+        .as_ref()
+        .and_then(|host_path| find_rule(bundle, host_path, key, now, query_mode))
+        .or_else(|| find_rule(bundle, &key.path, key, now, query_mode));
+    if let Some(found) = found {
+        let target = branch_target(found.record, found.target, &header);
+        let (code, target) = rule_target(bundle, target, found.record);
+        // Wildcard rules substitute the unmatched rest of the path for `$1`
+        let target = match found.wildcard_suffix {
+            Some(suffix) => substitute_suffix(&target, suffix.as_bytes()),
+            None => target,
+        };
+        let target = match found.forwarded_query {
+            Some(query) => append_query(&target, query.as_bytes()),
+            None => target,
+        };
+        return Some(Redirect {
+            record: found.record,
+            code,
+            target,
+            hit_key: found.source,
+            wildcard: found.wildcard_suffix.is_some(),
+        });
+    }
+
+    // Regex rules are only consulted if no other rule matches
+    let found =
+        bundle
+            .regex_rules
+            .as_ref()?
+            .find(key.host.as_deref(), &key.path.exact, |value| {
+                bundle.record(value).is_active(now)
+            })?;
+    let record = bundle.record(found.rule.value);
+    let target = branch_target(record, found.rule.value & TARGET_MASK, &header);
+    let (code, target) = rule_target(bundle, target, record);
+    Some(Redirect {
+        record,
+        code,
+        target: found.substitute(&target, &key.path.exact),
+        hit_key: &found.rule.key,
+        wildcard: false,
+    })
+}
+
+/// Returns the index of the target a rule with the given record and main target index redirects
+/// to, which is one of its conditional targets if any of their conditions hold for the request
+/// headers returned by `header`.
+fn branch_target(record: &Record, target: u64, header: &impl Fn(&str) -> Vec<Vec<u8>>) -> u64 {
+    conditions::select(&record.branches, header).unwrap_or(target)
+}
+
+/// Returns the status code and target of the rule with the given target index and record.
+fn rule_target(bundle: &Bundle, target: u64, record: &Record) -> (u16, Vec<u8>) {
+    let redirect = bundle.targets.decoder().run(target as usize);
+    match record.status_code {
+        Some(code) => (code, redirect),
+        None => legacy_status_code(redirect, bundle.default_status_code),
+    }
+}
+
+/// A rule matching an incoming request.
+struct RuleMatch<'a> {
+    /// Index of the rule's target in the targets set
+    target: u64,
+    /// The rule's settings
+    record: &'a Record,
+    /// The part of the path matching the rule's source, without the `*` of wildcard rules
+    source: &'a str,
+    /// For wildcard rules, the rest of the path to substitute for `$1` in the target
+    wildcard_suffix: Option<&'a str>,
+    /// The request's query string, if the rule forwards it to the target
+    forwarded_query: Option<&'a str>,
+}
+
+/// Finds the rule for `path`, which includes the query string if there is one, and is part of
+/// `key`.
+///
+/// Rules whose source matches the full path and query always apply. Otherwise, rules matching the
+/// path without the query apply according to their query mode, and wildcard rules are used last.
+fn find_rule<'a>(
+    bundle: &'a Bundle,
+    path: &'a RequestPath,
+    key: &'a LookupKey,
+    now: u64,
+    default_query_mode: QueryMode,
+) -> Option<RuleMatch<'a>> {
+    let sources = &bundle.sources;
+    let active = |value: &u64| bundle.record(*value).is_active(now);
+    let query_mode = |value: u64| {
+        bundle
+            .record(value)
+            .query_mode
+            .unwrap_or(default_query_mode)
+    };
+    let exact = path.exact.as_str();
+    // Paths ending in `*` would match a wildcard rule's key exactly, so skip the exact lookup
+    if !exact.ends_with('*')
+        && let Some(value) = sources.get(exact).filter(active)
+    {
+        return Some(RuleMatch {
+            target: value & TARGET_MASK,
+            record: bundle.record(value),
+            source: exact,
+            wildcard_suffix: None,
+            forwarded_query: None,
+        });
+    }
+
+    if let Some((path_only, query)) = exact.split_once('?') {
+        let path_only_match = sources
+            .get(path_only)
+            .filter(|value| !path_only.ends_with('*') && active(value));
+        if let Some(value) = path_only_match {
+            match query_mode(value) {
+                QueryMode::Exact => {}
+                mode => {
+                    return Some(RuleMatch {
+                        target: value & TARGET_MASK,
+                        record: bundle.record(value),
+                        source: path_only,
+                        wildcard_suffix: None,
+                        forwarded_query: Some(query).filter(|_| mode == QueryMode::Forward),
+                    });
+                }
+            }
+        }
+    }
+
+    let prefixed = path.prefixed();
+    let (prefix_len, value) = longest_prefix_match(sources, prefixed.as_bytes(), active)?;
+    // The rest of the path after the matched prefix, taken from the path before normalization
+    let rest = match &key.original {
+        Some((original, offsets)) => &original[offsets[prefix_len - path.host_len]..],
+        None => &prefixed[prefix_len..],
+    };
+    let mode = query_mode(value);
+    // The query string is only part of the captured suffix for rules matching it exactly, or if
+    // the matched prefix extends into it
+    let in_query = prefixed[..prefix_len].contains('?');
+    let (suffix, query) = match rest.split_once('?') {
+        Some((suffix, query)) if mode != QueryMode::Exact && !in_query => (suffix, Some(query)),
+        _ => (rest, None),
+    };
+    Some(RuleMatch {
+        target: value & TARGET_MASK,
+        record: bundle.record(value),
+        source: &prefixed[..prefix_len],
+        wildcard_suffix: Some(suffix),
+        forwarded_query: query.filter(|_| mode == QueryMode::Forward),
+    })
+}
+
+/// Finds the wildcard rule with the longest source prefix matching `path`, among the rules whose
+/// values are `active`.
+///
+/// Wildcard rules are stored in the FST with their source prefix followed by `*`, so while walking
+/// the FST along `path`, every node with a final `*` transition marks a matching prefix.
+/// Returns the length of the matched prefix and the rule's target index.
+fn longest_prefix_match(
+    sources: &fst::Map<Vec<u8>>,
+    path: &[u8],
+    active: impl Fn(&u64) -> bool,
+) -> Option<(usize, u64)> {
+    let fst = sources.as_fst();
+    let mut node = fst.root();
+    let mut output = Output::zero();
+    let mut best = None;
+    for i in 0..=path.len() {
+        if let Some(wildcard) = node.find_input(b'*') {
+            let transition = node.transition(wildcard);
+            let target = fst.node(transition.addr);
+            if target.is_final() {
+                let value = output.cat(transition.out).cat(target.final_output());
+                best = Some((i, value.value()))
+                    .filter(|(_, value)| active(value))
+                    .or(best);
+            }
+        }
+        let Some(byte) = path.get(i) else { break };
+        let Some(next) = node.find_input(*byte) else {
+            break;
+        };
+        let transition = node.transition(next);
+        output = output.cat(transition.out);
+        node = fst.node(transition.addr);
+    }
+    best
+}
+
+/// Returns the lowercased host of a request's authority, without any port.
+fn request_host(authority: &str) -> String {
+    let host = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => authority,
+    };
+    host.to_ascii_lowercase()
+}
+
+/// Appends `query` to the query string of `target`, keeping any fragment at the end.
+fn append_query(target: &[u8], query: &[u8]) -> Vec<u8> {
+    if query.is_empty() {
+        return target.to_vec();
+    }
+    let fragment_start = target
+        .iter()
+        .position(|&b| b == b'#')
+        .unwrap_or(target.len());
+    let (base, fragment) = target.split_at(fragment_start);
+    let mut result = Vec::with_capacity(target.len() + query.len() + 1);
+    result.extend_from_slice(base);
+    match base.iter().position(|&b| b == b'?') {
+        None => result.push(b'?'),
+        Some(_) if base.ends_with(b"?") || base.ends_with(b"&") => {}
+        Some(_) => result.push(b'&'),
+    }
+    result.extend_from_slice(query);
+    result.extend_from_slice(fragment);
+    result
+}
+
+/// Replaces every `$1` in `target` with `suffix`.
+fn substitute_suffix(target: &[u8], suffix: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(target.len() + suffix.len());
+    let mut rest = target;
+    while let Some(pos) = rest.windows(2).position(|w| w == b"$1") {
+        result.extend_from_slice(&rest[..pos]);
+        result.extend_from_slice(suffix);
+        rest = &rest[pos + 2..];
+    }
+    result.extend_from_slice(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rule::Rule;
+
+    /// Builds a bundle of the given rules, one per line, and decodes it like the component does.
+    fn test_bundle(rules: &str) -> Bundle {
+        build_bundle(rules, false)
+    }
+
+    /// Like `test_bundle`, normalizing sources if `normalize` is set.
+    fn build_bundle(rules: &str, normalize: bool) -> Bundle {
+        let rules = rules
+            .lines()
+            .map(|line| Rule::parse(line, 302, normalize).unwrap())
+            .collect::<Vec<_>>();
+        let keys = rules
+            .iter()
+            .map(|rule| rule.key(normalize))
+            .collect::<Vec<_>>();
+        let flags = if normalize {
+            FLAG_NORMALIZED_SOURCES
+        } else {
+            0
+        };
+        let bytes = format::build(
+            302,
+            flags,
+            0,
+            keys.iter().map(|key| key.as_ref()).zip(&rules),
+            [],
+        )
+        .unwrap();
+        decode(bytes, Validation::Full).unwrap()
+    }
+
+    /// Returns the status code and target a request is redirected to at `now`.
+    fn lookup(bundle: &Bundle, host: Option<&str>, path: &str, now: u64) -> Option<(u16, String)> {
+        let key = LookupKey::new(bundle, host, path.to_string());
+        let redirect = resolve(bundle, &key, now, QueryMode::Exact, |_| Vec::new())?;
+        Some((redirect.code, String::from_utf8(redirect.target).unwrap()))
+    }
+
+    fn target(bundle: &Bundle, path: &str) -> Option<String> {
+        lookup(bundle, None, path, 0).map(|(_, target)| target)
+    }
+
+    #[test]
+    fn test_substitute_suffix() {
+        assert_eq!(substitute_suffix(b"/new/$1", b"a/b?c=d"), b"/new/a/b?c=d");
+        assert_eq!(substitute_suffix(b"/$1/and/$1", b"x"), b"/x/and/x");
+        assert_eq!(substitute_suffix(b"/new/$1", b""), b"/new/");
+        assert_eq!(substitute_suffix(b"/new/$2", b"x"), b"/new/$2");
+        assert_eq!(substitute_suffix(b"/fixed", b"x"), b"/fixed");
+    }
+
+    #[test]
+    fn test_append_query() {
+        assert_eq!(append_query(b"/b", b"utm=x"), b"/b?utm=x");
+        assert_eq!(append_query(b"/b?v=2", b"utm=x"), b"/b?v=2&utm=x");
+        assert_eq!(append_query(b"/b?", b"utm=x"), b"/b?utm=x");
+        assert_eq!(append_query(b"/b?v=2&", b"utm=x"), b"/b?v=2&utm=x");
+        assert_eq!(append_query(b"/b#top", b"utm=x"), b"/b?utm=x#top");
+        assert_eq!(append_query(b"/b?v=2#top", b"utm=x"), b"/b?v=2&utm=x#top");
+        assert_eq!(append_query(b"/b#top", b""), b"/b#top");
+    }
+
+    #[test]
+    fn test_request_host() {
+        assert_eq!(request_host("Example.COM"), "example.com");
+        assert_eq!(request_host("example.com:8080"), "example.com");
+        assert_eq!(request_host("[::1]:8080"), "[::1]");
+        // Anything after the last colon that isn't a port is part of the host
+        assert_eq!(request_host("example.com:"), "example.com:");
+        assert_eq!(request_host("example.com:http"), "example.com:http");
+    }
+
+    #[test]
+    fn test_longest_prefix_match() {
+        let bundle = test_bundle("/* https://example.com/$1\n/a/* /x/$1\n/a/b/* /y/$1\n/a/b/c /z");
+        let all = |_: &u64| true;
+        let prefix_len = |path: &str| {
+            longest_prefix_match(&bundle.sources, path.as_bytes(), all).map(|(len, _)| len)
+        };
+        assert_eq!(prefix_len("/a/b/c/d"), Some(5));
+        assert_eq!(prefix_len("/a/b/"), Some(5));
+        // Wildcard rules only match below their prefix's trailing slash
+        assert_eq!(prefix_len("/a/b"), Some(3));
+        assert_eq!(prefix_len("/other"), Some(1));
+        assert_eq!(prefix_len("other"), None);
+
+        // Rules that aren't active are skipped in favor of shorter prefixes
+        let (_, nested) = longest_prefix_match(&bundle.sources, b"/a/b/c", all).unwrap();
+        let (len, _) =
+            longest_prefix_match(&bundle.sources, b"/a/b/c", |value| *value != nested).unwrap();
+        assert_eq!(len, 3);
+
+        // Exact rules take priority, and wildcard rules substitute the rest of the path
+        assert_eq!(target(&bundle, "/a/b/c").unwrap(), "/z");
+        assert_eq!(target(&bundle, "/a/b/c/d").unwrap(), "/y/c/d");
+        assert_eq!(target(&bundle, "/a/b/").unwrap(), "/y/");
+        assert_eq!(target(&bundle, "/a/other").unwrap(), "/x/other");
+        assert_eq!(target(&bundle, "/").unwrap(), "https://example.com/");
+    }
+
+    #[test]
+    fn test_normalized_sources() {
+        let bundle = build_bundle(
+            "/Docs/* /new/$1\n/About /about-us\n/Blog/* /news/$1 host=www.example.com",
+            true,
+        );
+        let target = |host, path| lookup(&bundle, host, path, 0).map(|(_, target)| target);
+
+        assert_eq!(target(None, "/about/").unwrap(), "/about-us");
+        // Wildcard sources match paths ending in their prefix's trailing slash
+        assert_eq!(target(None, "/docs/").unwrap(), "/new/");
+        assert_eq!(target(None, "/docs/?utm=x").unwrap(), "/new/?utm=x");
+        // The suffix is taken from the path as requested, not from the normalized path
+        assert_eq!(target(None, "/DOCS/Read-Me").unwrap(), "/new/Read-Me");
+        assert_eq!(target(None, "/docs//A%7Eb/").unwrap(), "/new/A%7Eb/");
+        assert_eq!(
+            target(Some("www.example.com"), "/blog/Post").unwrap(),
+            "/news/Post"
+        );
+    }
+
+    #[test]
+    fn test_query_modes() {
+        let bundle = test_bundle(
+            "/exact /e query=exact\n/ignore /i 301 query=ignore\n/forward /f?v=2 query=forward\n\
+             /default /d\n/q?a=1 /with-query\n/w/* /v/$1 query=forward\n/iw/* /iv/$1 query=ignore\n\
+             /ew/* /ev/$1",
+        );
+
+        // Sources with a query string have to match exactly
+        assert_eq!(target(&bundle, "/q?a=1").unwrap(), "/with-query");
+        assert_eq!(target(&bundle, "/q?a=2"), None);
+
+        assert_eq!(target(&bundle, "/exact").unwrap(), "/e");
+        assert_eq!(target(&bundle, "/exact?utm=x"), None);
+        assert_eq!(
+            lookup(&bundle, None, "/ignore?utm=x", 0).unwrap(),
+            (301, "/i".to_string())
+        );
+        assert_eq!(target(&bundle, "/forward?utm=x").unwrap(), "/f?v=2&utm=x");
+        assert_eq!(target(&bundle, "/forward").unwrap(), "/f?v=2");
+        // Rules without a query mode use the component's default, which is exact here
+        assert_eq!(target(&bundle, "/default?utm=x"), None);
+
+        // For wildcard rules, the query is only part of the suffix if it has to match exactly
+        assert_eq!(target(&bundle, "/w/page?utm=x").unwrap(), "/v/page?utm=x");
+        assert_eq!(target(&bundle, "/iw/page?utm=x").unwrap(), "/iv/page");
+        assert_eq!(target(&bundle, "/ew/page?utm=x").unwrap(), "/ev/page?utm=x");
+    }
+
+    #[test]
+    fn test_host_scoped_rules() {
+        let bundle = test_bundle(
+            "/sale /offers host=shop.example.com\n/sale /promotions\n\
+             /blog/* /news/$1 host=www.example.com",
+        );
+        let target = |host, path| lookup(&bundle, host, path, 0).map(|(_, target)| target);
+
+        assert_eq!(
+            target(Some("Shop.Example.com:8443"), "/sale").unwrap(),
+            "/offers"
+        );
+        // Requests for other hosts, or without one, fall back to host-agnostic rules
+        assert_eq!(
+            target(Some("www.example.com"), "/sale").unwrap(),
+            "/promotions"
+        );
+        assert_eq!(target(None, "/sale").unwrap(), "/promotions");
+        assert_eq!(
+            target(Some("www.example.com"), "/blog/a").unwrap(),
+            "/news/a"
+        );
+        assert_eq!(target(Some("shop.example.com"), "/blog/a"), None);
+        assert_eq!(target(None, "/blog/a"), None);
+    }
+
+    #[test]
+    fn test_scheduled_rules() {
+        let bundle = test_bundle("/sale/now /now valid_from=100 valid_until=200\n/sale/* /sale-$1");
+        assert_eq!(
+            lookup(&bundle, None, "/sale/now", 99).unwrap().1,
+            "/sale-now"
+        );
+        assert_eq!(lookup(&bundle, None, "/sale/now", 100).unwrap().1, "/now");
+        assert_eq!(lookup(&bundle, None, "/sale/now", 199).unwrap().1, "/now");
+        // Inactive rules fall through to other matching rules
+        assert_eq!(
+            lookup(&bundle, None, "/sale/now", 200).unwrap().1,
+            "/sale-now"
+        );
+    }
+
+    fn record(valid_from: Option<u64>, valid_until: Option<u64>) -> Record {
+        Record::new(RuleRecord {
+            status_code: 302,
+            query_mode: 0,
+            methods: 0,
+            headers: Vec::new(),
+            valid_from,
+            valid_until,
+            branches: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn test_is_active() {
+        let scheduled = record(Some(100), Some(200));
+        assert!(!scheduled.is_active(99));
+        assert!(scheduled.is_active(100));
+        assert!(scheduled.is_active(199));
+        // Rules expire at the start of `valid_until`
+        assert!(!scheduled.is_active(200));
+
+        assert!(record(Some(100), None).is_active(u64::MAX));
+        assert!(!record(None, Some(100)).is_active(100));
+        assert!(record(None, Some(100)).is_active(0));
+        assert!(record(None, None).is_active(0));
+    }
+
+    #[test]
+    fn test_vary_header() {
+        let mut rule_record = RuleRecord {
+            status_code: 302,
+            query_mode: 0,
+            methods: 0,
+            headers: vec![("Cache-Control".to_string(), "no-store".to_string())],
+            valid_from: None,
+            valid_until: None,
+            branches: vec![(Condition::Language("de-CH".to_string()), 1)],
+        };
+        let record = Record::new(rule_record.clone());
+        assert_eq!(
+            record.branches,
+            vec![(Condition::Language("de-ch".to_string()), 1)]
+        );
+        assert_eq!(
+            record.headers[0],
+            ("Vary".to_string(), b"Accept-Language".to_vec())
+        );
+        assert_eq!(record.headers.len(), 2);
+
+        rule_record.branches.clear();
+        assert_eq!(Record::new(rule_record).headers.len(), 1);
+    }
+}
@@ -0,0 +1,143 @@
+//! Request method handling for matched rules.
+//!
+//! Rules can be restricted to a set of methods with the `methods=` rule option, which is stored as
+//! a bit mask in the rule's record. Requests with other methods get a 405 response, and `OPTIONS`
+//! requests are answered with the methods a rule accepts. Since clients are allowed to change the
+//! method to `GET` when following 301 and 302 redirects, rules with these status codes can also be
+//! limited to `GET` and `HEAD` requests for the whole bundle, leaving 307 and 308 for rules that
+//! need to preserve the method.
+
+use crate::rule::METHODS;
+
+const METHODS_MASK: u8 = (1 << METHODS.len()) - 1;
+const GET_AND_HEAD: u8 = 0b11;
+
+/// How to respond to a request matching a rule.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MethodCheck {
+    /// Redirect the request
+    Redirect,
+    /// Answer an `OPTIONS` request with the given `Allow` header
+    Options(String),
+    /// Reject the request with a 405 and the given `Allow` header
+    NotAllowed(String),
+}
+
+/// Checks `method` against the methods a rule with status `code` accepts, given as a bit mask over
+/// `METHODS`. A mask of 0 means the rule isn't restricted. `method` is an uppercase method name.
+/// If `strict` is set, 301 and 302 rules only redirect `GET` and `HEAD` requests.
+pub fn check(method: &str, rule_methods: u8, code: u16, strict: bool) -> MethodCheck {
+    check_methods(method, rule_methods, strict && matches!(code, 301 | 302))
+}
+
+/// Like `check`, limiting the rule to `GET` and `HEAD` requests if `strict` is set.
+fn check_methods(method: &str, rule_methods: u8, strict: bool) -> MethodCheck {
+    // Unrestricted rules redirect any method, including ones that can't be listed in a rule
+    let unrestricted = rule_methods == 0 && !strict;
+    let mut allowed = if rule_methods == 0 {
+        METHODS_MASK
+    } else {
+        rule_methods
+    };
+    if strict {
+        allowed &= GET_AND_HEAD;
+    }
+    let bit = match method {
+        "GET" => 1,
+        "HEAD" => 1 << 1,
+        "POST" => 1 << 2,
+        "PUT" => 1 << 3,
+        "DELETE" => 1 << 4,
+        "PATCH" => 1 << 5,
+        "OPTIONS" => return MethodCheck::Options(allow_header(allowed)),
+        _ => 0,
+    };
+    if unrestricted || allowed & bit != 0 {
+        MethodCheck::Redirect
+    } else {
+        MethodCheck::NotAllowed(allow_header(allowed))
+    }
+}
+
+/// Lists the methods in the `allowed` mask, plus `OPTIONS`, for an `Allow` header.
+fn allow_header(allowed: u8) -> String {
+    let mut methods = METHODS
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| allowed & (1 << i) != 0)
+        .map(|(_, method)| *method)
+        .collect::<Vec<_>>();
+    methods.push("OPTIONS");
+    methods.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unrestricted_rules() {
+        for method in ["GET", "POST", "PROPFIND"] {
+            assert_eq!(check(method, 0, 301, false), MethodCheck::Redirect);
+        }
+        assert_eq!(
+            check("OPTIONS", 0, 308, false),
+            MethodCheck::Options("GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS".to_string())
+        );
+    }
+
+    #[test]
+    fn test_restricted_rules() {
+        let get_and_head = "GET, HEAD, OPTIONS".to_string();
+        assert_eq!(
+            check("HEAD", GET_AND_HEAD, 308, false),
+            MethodCheck::Redirect
+        );
+        assert_eq!(
+            check("POST", GET_AND_HEAD, 308, false),
+            MethodCheck::NotAllowed(get_and_head.clone())
+        );
+        // Methods that can't be listed in a rule are never allowed by restricted rules
+        assert_eq!(
+            check("PROPFIND", GET_AND_HEAD, 308, false),
+            MethodCheck::NotAllowed(get_and_head.clone())
+        );
+        assert_eq!(
+            check("OPTIONS", GET_AND_HEAD, 308, false),
+            MethodCheck::Options(get_and_head)
+        );
+        assert_eq!(
+            check("OPTIONS", 1 << 2, 307, false),
+            MethodCheck::Options("POST, OPTIONS".to_string())
+        );
+    }
+
+    #[test]
+    fn test_strict_methods() {
+        let get_and_head = "GET, HEAD, OPTIONS".to_string();
+        assert_eq!(check_methods("GET", 0, true), MethodCheck::Redirect);
+        assert_eq!(
+            check_methods("POST", 0, true),
+            MethodCheck::NotAllowed(get_and_head.clone())
+        );
+        assert_eq!(
+            check_methods("PROPFIND", 0, true),
+            MethodCheck::NotAllowed(get_and_head.clone())
+        );
+        assert_eq!(
+            check_methods("OPTIONS", 0, true),
+            MethodCheck::Options(get_and_head)
+        );
+        // Strict mode narrows the methods of restricted rules as well
+        assert_eq!(
+            check_methods("POST", 0b101, true),
+            MethodCheck::NotAllowed("GET, OPTIONS".to_string())
+        );
+        // It only applies to 301 and 302 rules
+        assert_eq!(check("POST", 0, 308, true), MethodCheck::Redirect);
+        assert_eq!(
+            check("POST", 0, 302, true),
+            MethodCheck::NotAllowed("GET, HEAD, OPTIONS".to_string())
+        );
+    }
+}
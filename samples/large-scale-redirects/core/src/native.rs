@@ -0,0 +1,157 @@
+//! Bundle decoding and rule lookups outside of a Wasm runtime, for benchmarking and inspecting
+//! them natively.
+//!
+//! Lookups go through the same code as requests handled by the Wasm component. `lookup` leaves out the
+//! parts depending on the request itself: conditional targets always fall back to the rule's main
+//! target, and the default query mode is `exact`. `inspect` takes them from a `NativeRequest`.
+
+use crate::bundle::Validation;
+use crate::lookup::{self, Bundle, LookupKey, resolve};
+use crate::methods::{self, MethodCheck};
+use crate::rule::QueryMode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A decoded bundle.
+pub struct NativeRedirects {
+    bundle: Bundle,
+    /// The default query mode for rules without one
+    query_mode: QueryMode,
+}
+
+impl NativeRedirects {
+    /// Decodes and validates a bundle, like the component does during initialization.
+    pub fn decode(bytes: Vec<u8>) -> Result<Self, String> {
+        Self::decode_with(bytes, Validation::Full)
+    }
+
+    /// Decodes a bundle, validating it as much as `validation` says. The component only checks
+    /// the checksum of bundles it reads from a key-value store.
+    pub fn decode_with(bytes: Vec<u8>, validation: Validation) -> Result<Self, String> {
+        let bundle = lookup::decode(bytes, validation).map_err(|e| e.to_string())?;
+        Ok(Self {
+            bundle,
+            query_mode: QueryMode::Exact,
+        })
+    }
+
+    /// Like `decode`, with `query_mode` as the default query mode for rules without one.
+    pub fn decode_with_query_mode(bytes: Vec<u8>, query_mode: QueryMode) -> Result<Self, String> {
+        Ok(Self {
+            query_mode,
+            ..Self::decode(bytes)?
+        })
+    }
+
+    /// Returns the status code and target a request for `path` on `host` is redirected to, or
+    /// `None` if no rule matches.
+    pub fn lookup(&self, host: Option<&str>, path: &str) -> Option<(u16, Vec<u8>)> {
+        let key = LookupKey::new(&self.bundle, host, path.to_string());
+        let redirect = resolve(&self.bundle, &key, now(), self.query_mode, |_| Vec::new())?;
+        Some((redirect.code, redirect.target))
+    }
+
+    /// Returns how the component responds to `request`, or `None` if no rule matches and the
+    /// request is handled as a miss.
+    pub fn inspect(&self, request: &NativeRequest) -> Option<NativeResponse> {
+        let key = LookupKey::new(&self.bundle, request.host, request.path.to_string());
+        let header = |name: &str| {
+            request
+                .headers
+                .iter()
+                .filter(|(other, _)| other.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_bytes().to_vec())
+                .collect()
+        };
+        let redirect = resolve(&self.bundle, &key, request.now, self.query_mode, header)?;
+        let (status_code, target, headers) = match methods::check(
+            &request.method.to_ascii_uppercase(),
+            redirect.record.methods,
+            redirect.code,
+            false,
+        ) {
+            MethodCheck::Redirect => (
+                redirect.code,
+                Some(redirect.target),
+                redirect.record.headers.clone(),
+            ),
+            MethodCheck::Options(allow) => (204, None, vec![allow_header(allow)]),
+            MethodCheck::NotAllowed(allow) => (405, None, vec![allow_header(allow)]),
+        };
+        Some(NativeResponse {
+            rule: redirect.hit_key.to_string(),
+            wildcard: redirect.wildcard,
+            status_code,
+            target,
+            headers,
+            valid_until: redirect.record.valid_until,
+        })
+    }
+
+    /// The number of literal and wildcard rules.
+    pub fn rule_count(&self) -> usize {
+        self.bundle.sources.len()
+    }
+
+    /// The number of distinct targets.
+    pub fn target_count(&self) -> usize {
+        self.bundle.targets.len()
+    }
+
+    /// The memory used by the encoded sources, in bytes, which is the size of their section of the
+    /// bundle.
+    pub fn sources_size(&self) -> usize {
+        self.bundle.sources.as_fst().as_bytes().len()
+    }
+
+    /// The memory used by the encoded targets, in bytes, which is the size of their section of the
+    /// bundle.
+    pub fn targets_size(&self) -> usize {
+        self.bundle.targets.size_in_bytes()
+    }
+
+    /// The memory used by the encoded sources and targets, in bytes.
+    pub fn encoded_size(&self) -> usize {
+        self.sources_size() + self.targets_size()
+    }
+}
+
+/// A request to look up, with everything the rule matching it and its response can depend on.
+pub struct NativeRequest<'a> {
+    /// The request's authority, which may include a port
+    pub host: Option<&'a str>,
+    /// The path with the query string, if there is one
+    pub path: &'a str,
+    pub method: &'a str,
+    /// Request headers, for rules with conditional targets
+    pub headers: &'a [(String, String)],
+    /// The time of the request, in seconds since the epoch
+    pub now: u64,
+}
+
+/// How the component responds to a request matching a rule.
+pub struct NativeResponse {
+    /// The key of the matched rule, as recorded by analytics
+    pub rule: String,
+    /// Whether the matched rule is a wildcard rule
+    pub wildcard: bool,
+    /// The rule's status code, or the one of the response to a method it doesn't redirect
+    pub status_code: u16,
+    /// The redirect target, or `None` for responses to methods the rule doesn't redirect
+    pub target: Option<Vec<u8>>,
+    /// Headers the rule adds to the response, or the `Allow` header of responses to methods it
+    /// doesn't redirect
+    pub headers: Vec<(String, Vec<u8>)>,
+    /// The time the rule expires, in seconds since the epoch
+    pub valid_until: Option<u64>,
+}
+
+/// Returns the current time in seconds since the epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+fn allow_header(allow: String) -> (String, Vec<u8>) {
+    ("Allow".to_string(), allow.into_bytes())
+}
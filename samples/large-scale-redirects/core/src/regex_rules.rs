@@ -0,0 +1,284 @@
+//! Regex rules, for legacy URL schemes that can't be expressed as literal or wildcard sources.
+//!
+//! A rule whose source starts with `~` matches the whole request path, including any query string,
+//! against the rest of the source as a regular expression, and substitutes the captured groups for
+//! `$1` to `$9` in its target. Lookups compile all patterns into a single automaton reporting every
+//! matching rule, which is only consulted for requests that don't match any other rule, so its
+//! cost doesn't grow with the number of rules. If multiple regex rules match, the first one wins,
+//! and only its captures are extracted afterwards.
+//!
+//! Patterns are compiled into finite automata, so they can't backtrack catastrophically. They can
+//! still be slow to compile and match if they expand into huge automata, though, which is why large
+//! counted repetitions and overly complex patterns are rejected.
+
+use crate::bundle;
+use regex_automata::meta::Regex;
+use regex_automata::util::syntax;
+use regex_automata::{Anchored, Input, MatchKind, PatternID, PatternSet};
+use regex_syntax::hir::{Hir, HirKind};
+use std::ops::Range;
+
+/// Prefix marking a rule's source as a regex.
+pub const REGEX_PREFIX: char = '~';
+
+/// Longer paths are never matched against regex rules, bounding the time spent on a request.
+const MAX_PATH_LEN: usize = 4096;
+
+/// Largest allowed upper bound of counted repetitions like `a{1,100}`.
+const MAX_REPETITION: u32 = 100;
+
+/// Largest allowed heap size of a single pattern's compiled automaton, in bytes.
+const NFA_SIZE_LIMIT: usize = 256 * 1024;
+
+/// Checks that `pattern` is a valid regex that can be matched efficiently, and returns its number
+/// of capture groups.
+pub fn validate(pattern: &str) -> Result<usize, String> {
+    let hir = regex_syntax::ParserBuilder::new()
+        .unicode(false)
+        .utf8(false)
+        .build()
+        .parse(pattern)
+        .map_err(|e| format!("Invalid regex '{pattern}': {}", first_line(&e.to_string())))?;
+    if let Some(max) = largest_repetition(&hir).filter(|max| *max > MAX_REPETITION) {
+        return Err(format!(
+            "Regex '{pattern}' repeats {max} times, more than the supported {MAX_REPETITION}"
+        ));
+    }
+    Regex::builder()
+        .syntax(syntax_config())
+        .configure(Regex::config().nfa_size_limit(Some(NFA_SIZE_LIMIT)))
+        .build(&anchored(pattern))
+        .map_err(|_| format!("Regex '{pattern}' is too complex"))?;
+    Ok(hir.properties().explicit_captures_len())
+}
+
+/// Compiles `patterns` into a single automaton reporting every matching pattern.
+pub fn compile(patterns: &[&str]) -> Result<Regex, String> {
+    build(patterns, MatchKind::All)
+}
+
+/// Compiles `patterns` into a single automaton for extracting the captures of a pattern known to
+/// match.
+pub fn compile_for_captures(patterns: &[&str]) -> Result<Regex, String> {
+    build(patterns, MatchKind::LeftmostFirst)
+}
+
+fn build(patterns: &[&str], match_kind: MatchKind) -> Result<Regex, String> {
+    Regex::builder()
+        .syntax(syntax_config())
+        .configure(Regex::config().match_kind(match_kind))
+        .build_many(&patterns.iter().map(|p| anchored(p)).collect::<Vec<_>>())
+        .map_err(|e| format!("Failed to compile regex rules: {e}"))
+}
+
+/// Returns the indices of all patterns in `regex` matching `path`, in ascending order.
+pub fn matching_patterns(regex: &Regex, path: &str) -> Vec<usize> {
+    let mut matches = PatternSet::new(regex.pattern_len());
+    regex.which_overlapping_matches(&Input::new(path), &mut matches);
+    matches.iter().map(|pattern| pattern.as_usize()).collect()
+}
+
+/// Returns the highest `$n` placeholder used in `target`.
+pub fn max_placeholder(target: &str) -> usize {
+    target
+        .as_bytes()
+        .windows(2)
+        .filter(|w| w[0] == b'$' && w[1].is_ascii_digit())
+        .map(|w| (w[1] - b'0') as usize)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Patterns always have to match the whole path.
+fn anchored(pattern: &str) -> String {
+    format!(r"\A(?:{pattern})\z")
+}
+
+/// Paths are matched as bytes, which keeps the automata small.
+fn syntax_config() -> syntax::Config {
+    syntax::Config::new().unicode(false).utf8(false)
+}
+
+fn largest_repetition(hir: &Hir) -> Option<u32> {
+    match hir.kind() {
+        HirKind::Repetition(repetition) => {
+            let own = repetition.max.unwrap_or(repetition.min);
+            Some(largest_repetition(&repetition.sub).map_or(own, |inner| inner.max(own)))
+        }
+        HirKind::Capture(capture) => largest_repetition(&capture.sub),
+        HirKind::Concat(hirs) | HirKind::Alternation(hirs) => {
+            hirs.iter().filter_map(largest_repetition).max()
+        }
+        _ => None,
+    }
+}
+
+/// regex-syntax errors span multiple lines, pointing at the error in the pattern.
+fn first_line(message: &str) -> &str {
+    message
+        .lines()
+        .rfind(|line| line.starts_with("error: "))
+        .map_or(message, |line| line.trim_start_matches("error: "))
+}
+
+/// A regex rule from the bundle.
+pub struct RegexRule {
+    /// `None` for rules applying to all hosts
+    host: Option<String>,
+    /// The rule's key for analytics, its host followed by its source, like `rules-manager` writes
+    /// it
+    pub key: String,
+    /// Target and record index, like the values in the sources FST
+    pub value: u64,
+}
+
+impl RegexRule {
+    pub fn new(rule: bundle::RegexRule) -> Self {
+        let host = rule.host.as_deref().unwrap_or("");
+        Self {
+            key: format!("{host}~{}", rule.pattern),
+            host: rule.host,
+            value: rule.value,
+        }
+    }
+
+    fn pattern(&self) -> &str {
+        &self.key[self.key.find('~').unwrap() + 1..]
+    }
+}
+
+/// All regex rules of a bundle, in order of precedence.
+pub struct RegexRules {
+    rules: Vec<RegexRule>,
+    /// Reports every rule matching a path
+    set: Regex,
+    /// The same patterns, for extracting the captures of a single rule
+    captures: Regex,
+}
+
+/// A regex rule matching a request.
+pub struct RegexMatch<'a> {
+    pub rule: &'a RegexRule,
+    /// Byte ranges of the capture groups in the path, starting with the first explicit group
+    groups: Vec<Option<Range<usize>>>,
+}
+
+impl RegexRules {
+    /// Takes the rules of a bundle with the automaton `bundle` compiled their
+    /// patterns into, and compiles them again for extracting captures.
+    pub fn new(rules: Vec<RegexRule>, set: Regex) -> Result<Self, String> {
+        let patterns = rules.iter().map(|rule| rule.pattern()).collect::<Vec<_>>();
+        Ok(Self {
+            captures: compile_for_captures(&patterns)?,
+            set,
+            rules,
+        })
+    }
+
+    /// Finds the first rule for `host` matching `path`, falling back to the first matching rule
+    /// without a host. Rules whose values aren't `active` are skipped.
+    pub fn find(
+        &self,
+        host: Option<&str>,
+        path: &str,
+        active: impl Fn(u64) -> bool,
+    ) -> Option<RegexMatch<'_>> {
+        if path.len() > MAX_PATH_LEN {
+            return None;
+        }
+        let mut matches = PatternSet::new(self.set.pattern_len());
+        self.set
+            .which_overlapping_matches(&Input::new(path), &mut matches);
+        let rule_for_host = |host: Option<&str>| {
+            matches
+                .iter()
+                .map(|pattern| pattern.as_usize())
+                .find(|index| {
+                    let rule = &self.rules[*index];
+                    rule.host.as_deref() == host && active(rule.value)
+                })
+        };
+        let index = host
+            .and_then(|host| rule_for_host(Some(host)))
+            .or_else(|| rule_for_host(None))?;
+
+        let mut captures = self.captures.create_captures();
+        let input = Input::new(path).anchored(Anchored::Pattern(PatternID::new(index).unwrap()));
+        self.captures.search_captures(&input, &mut captures);
+        let groups = (1..captures.group_len())
+            .map(|group| captures.get_group(group).map(|span| span.range()))
+            .collect();
+        Some(RegexMatch {
+            rule: &self.rules[index],
+            groups,
+        })
+    }
+}
+
+impl RegexMatch<'_> {
+    /// Replaces `$1` to `$9` in `target` with the corresponding groups captured from `path`, or
+    /// nothing for groups that didn't participate in the match.
+    pub fn substitute(&self, target: &[u8], path: &str) -> Vec<u8> {
+        let mut result = Vec::with_capacity(target.len() + path.len());
+        let mut i = 0;
+        while i < target.len() {
+            match target.get(i + 1) {
+                Some(digit @ b'1'..=b'9') if target[i] == b'$' => {
+                    let group = self.groups.get((digit - b'1') as usize).cloned().flatten();
+                    if let Some(range) = group {
+                        result.extend_from_slice(&path.as_bytes()[range]);
+                    }
+                    i += 2;
+                }
+                _ => {
+                    result.push(target[i]);
+                    i += 1;
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(validate(r"/product\.php\?id=(\d+)"), Ok(1));
+        assert_eq!(validate(r"/(?:a|b)/(\w+)-(\d{4})"), Ok(2));
+        assert!(
+            validate(r"/(unclosed")
+                .unwrap_err()
+                .starts_with("Invalid regex")
+        );
+        assert!(
+            validate(r"/a{1000}")
+                .unwrap_err()
+                .contains("repeats 1000 times")
+        );
+        assert!(validate(r"/(?:a{50}){50}").is_ok());
+        assert!(
+            validate(r"/((((a{100}){100}){100}){100})")
+                .unwrap_err()
+                .contains("too complex")
+        );
+    }
+
+    #[test]
+    fn test_matching_patterns() {
+        let regex = compile(&[r"/a/(\d+)", r"/a/.*", r"/b"]).unwrap();
+        assert_eq!(matching_patterns(&regex, "/a/123"), vec![0, 1]);
+        assert_eq!(matching_patterns(&regex, "/a/x"), vec![1]);
+        // Patterns have to match the whole path
+        assert_eq!(matching_patterns(&regex, "/b/c"), Vec::<usize>::new());
+        assert_eq!(matching_patterns(&regex, "/xb"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_max_placeholder() {
+        assert_eq!(max_placeholder("/products/$1?color=$3"), 3);
+        assert_eq!(max_placeholder("/products"), 0);
+    }
+}
@@ -0,0 +1,875 @@
+//! Redirect rules, and parsing and validating them.
+//!
+//! Each line of a rules file is a rule `<source> <target> [<status code>] [<key>=<value>...]`.
+//! Sources are paths, optionally ending in `*` for wildcard rules, or regexes prefixed with `~`.
+//! Targets are paths or absolute `http(s)` URLs. Rules are validated one at a time here, while
+//! checks involving several rules, like loop detection, are up to `rules-manager`.
+
+use crate::bundle::RuleRecord;
+use crate::conditions::Condition;
+use crate::regex_rules::{self, REGEX_PREFIX};
+use crate::schedule;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fmt::{Display, Formatter};
+use std::sync::LazyLock;
+use url::Url;
+
+/// A rule parsed from a line of a rules file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule<'a> {
+    /// The source as written in the rules file, before any normalization
+    pub from: &'a str,
+    pub to: &'a str,
+    pub status_code: u16,
+    pub options: RuleOptions,
+}
+
+/// Why a rule was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleError {
+    pub message: String,
+    pub kind: RuleErrorKind,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RuleErrorKind {
+    /// The rule is malformed, or uses settings that aren't supported
+    Invalid,
+    /// The rule always redirects back to its own source
+    SelfLoop,
+}
+
+impl RuleError {
+    fn invalid(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            kind: RuleErrorKind::Invalid,
+        }
+    }
+
+    fn self_loop(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            kind: RuleErrorKind::SelfLoop,
+        }
+    }
+}
+
+impl Display for RuleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for RuleError {}
+
+impl<'a> Rule<'a> {
+    /// Parses and validates a rule from a line without comments. Rules without a status code get
+    /// `default_status_code`. If `normalize` is set, sources are compared to targets after
+    /// normalizing both, like they are for bundles with normalized sources.
+    pub fn parse(
+        line: &'a str,
+        default_status_code: u16,
+        normalize: bool,
+    ) -> Result<Self, RuleError> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let (from, to) = match parts[..] {
+            [] => return Err(RuleError::invalid("Empty line")),
+            [_] => return Err(RuleError::invalid("Missing target for redirect")),
+            [from, to, ..] => (from, to),
+        };
+        // The status code is optional, and followed by any number of `key=value` options
+        let (status_part, option_parts) = match parts.get(2) {
+            Some(part) if !part.contains('=') => (Some(*part), &parts[3..]),
+            _ => (None, &parts[2..]),
+        };
+        let status_code = match status_part {
+            Some(part) => part
+                .parse::<u16>()
+                .ok()
+                .filter(|&code| (301..=399).contains(&code)),
+            None => Some(default_status_code),
+        };
+        let invalid_status = || RuleError::invalid(format!("Invalid status code: '{}'", parts[2]));
+        let options = RuleOptions::parse(option_parts);
+
+        if let Some(pattern) = from.strip_prefix(REGEX_PREFIX) {
+            let options = options.map_err(RuleError::invalid)?;
+            let status_code = status_code.ok_or_else(invalid_status)?;
+            check_regex_rule(pattern, to, &options)?;
+            if let Some(message) = changed_method(status_code, &options) {
+                return Err(RuleError::invalid(message));
+            }
+            return Ok(Self {
+                from,
+                to,
+                status_code,
+                options,
+            });
+        }
+
+        if from == to || source_key(from, normalize) == source_key(to, normalize) {
+            return Err(RuleError::self_loop("Source and target cannot be the same"));
+        }
+        match (is_valid_redirect_source(from), is_valid_redirect_target(to)) {
+            (false, false) => {
+                return Err(RuleError::invalid(format!(
+                    "Invalid format for source and target: '{from}' -> '{to}'"
+                )));
+            }
+            (false, true) => {
+                return Err(RuleError::invalid(format!(
+                    "Invalid format for source: '{from}'"
+                )));
+            }
+            (true, false) => {
+                return Err(RuleError::invalid(format!(
+                    "Invalid format for target: '{to}'"
+                )));
+            }
+            (true, true) => {}
+        }
+        if from[..from.len() - 1].contains('*') {
+            return Err(RuleError::invalid(format!(
+                "Wildcards are only supported at the end of a source: '{from}'"
+            )));
+        }
+        if redirects_into_prefix(from, to, normalize) {
+            return Err(RuleError::self_loop(
+                "Wildcard target redirects back into the source prefix",
+            ));
+        }
+        let options = options.map_err(RuleError::invalid)?;
+        if is_wildcard_source(from)
+            && let Some(message) = std::iter::once(to)
+                .chain(options.branches.iter().map(|(_, target)| target.as_str()))
+                .find_map(open_redirect)
+        {
+            return Err(RuleError::invalid(message));
+        }
+        let status_code = status_code.ok_or_else(invalid_status)?;
+        if let Some(message) = changed_method(status_code, &options) {
+            return Err(RuleError::invalid(message));
+        }
+        if from.contains('?') && options.query.is_some_and(|q| q != QueryMode::Exact) {
+            return Err(RuleError::invalid(format!(
+                "Sources with a query string only support 'query=exact': '{from}'"
+            )));
+        }
+        if let Some(message) = options
+            .branches
+            .iter()
+            .find_map(|(_, target)| redirects_to_itself(from, target, normalize))
+        {
+            return Err(RuleError::self_loop(message));
+        }
+        Ok(Self {
+            from,
+            to,
+            status_code,
+            options,
+        })
+    }
+
+    /// Whether the rule is a regex rule.
+    pub fn is_regex(&self) -> bool {
+        self.from.starts_with(REGEX_PREFIX)
+    }
+
+    /// Returns the key a literal or wildcard rule is stored under in the sources of a bundle: its
+    /// source, normalized if `normalize` is set, prefixed with its host if it has one.
+    pub fn key(&self, normalize: bool) -> Cow<'a, str> {
+        let source = source_key(self.from, normalize);
+        match &self.options.host {
+            Some(host) => Cow::Owned(format!("{host}{source}")),
+            None => source,
+        }
+    }
+
+    /// Returns the rule's main target and its conditional targets, with their conditions.
+    pub fn targets(&self) -> impl Iterator<Item = (&str, Option<&Condition>)> {
+        std::iter::once((self.to, None)).chain(
+            self.options
+                .branches
+                .iter()
+                .map(|(condition, target)| (target.as_str(), Some(condition))),
+        )
+    }
+}
+
+/// How a rule treats the query string of incoming requests.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QueryMode {
+    /// The query string is part of the path that has to match the source.
+    Exact,
+    /// The query string is ignored for matching and dropped from the redirect.
+    Ignore,
+    /// The query string is ignored for matching and appended to the redirect target.
+    Forward,
+}
+
+impl QueryMode {
+    pub fn parse(input: &str) -> Option<Self> {
+        match input {
+            "exact" => Some(Self::Exact),
+            "ignore" => Some(Self::Ignore),
+            "forward" => Some(Self::Forward),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Exact => "exact",
+            Self::Ignore => "ignore",
+            Self::Forward => "forward",
+        }
+    }
+
+    /// The encoding of the mode in a rule record.
+    /// 0 means that the component's default mode is used.
+    pub fn encoded(mode: Option<Self>) -> u8 {
+        match mode {
+            None => 0,
+            Some(Self::Exact) => 1,
+            Some(Self::Ignore) => 2,
+            Some(Self::Forward) => 3,
+        }
+    }
+
+    /// The inverse of `encoded`.
+    pub fn decode(encoded: u8) -> Option<Self> {
+        match encoded {
+            1 => Some(Self::Exact),
+            2 => Some(Self::Ignore),
+            3 => Some(Self::Forward),
+            _ => None,
+        }
+    }
+}
+
+/// HTTP methods a rule can be restricted to, in the order of their bits in a rule record.
+pub const METHODS: [&str; 6] = ["GET", "HEAD", "POST", "PUT", "DELETE", "PATCH"];
+
+/// A set of HTTP methods, as a bitmask of indices into `METHODS`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MethodSet(u8);
+
+impl MethodSet {
+    /// Parses a comma-separated list of methods. `GET` implies `HEAD`.
+    pub fn parse(input: &str) -> Option<Self> {
+        let mut bits = 0;
+        for method in input.split(',') {
+            let index = METHODS
+                .iter()
+                .position(|m| m.eq_ignore_ascii_case(method))?;
+            bits |= 1 << index;
+        }
+        if bits & 1 != 0 {
+            bits |= 1 << 1;
+        }
+        Some(Self(bits))
+    }
+
+    /// The encoding of the set in a rule record.
+    /// 0 means that the rule applies to all methods.
+    pub fn encoded(methods: Option<Self>) -> u8 {
+        methods.map_or(0, |methods| methods.0)
+    }
+
+    /// The inverse of `encoded`.
+    pub fn decode(encoded: u8) -> Option<Self> {
+        (encoded != 0).then_some(Self(encoded))
+    }
+}
+
+impl Display for MethodSet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let methods = METHODS
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| self.0 & (1 << index) != 0)
+            .map(|(_, method)| *method)
+            .collect::<Vec<_>>();
+        write!(f, "{}", methods.join(","))
+    }
+}
+
+/// Optional per-rule settings, given as `key=value` parts after the target and status code.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RuleOptions {
+    /// Restricts the rule to requests for this host. Rules without a host apply to all hosts
+    /// that don't have a more specific rule.
+    pub host: Option<String>,
+    /// Overrides the component's default query string handling for this rule.
+    pub query: Option<QueryMode>,
+    /// Restricts the rule to requests with these methods. Requests with other methods are
+    /// answered with status code 405.
+    pub methods: Option<MethodSet>,
+    /// Headers to add to the redirect response, in the order they were given.
+    pub headers: Vec<(String, String)>,
+    /// The time the rule becomes active, in seconds since the epoch.
+    pub valid_from: Option<u64>,
+    /// The time the rule expires, in seconds since the epoch.
+    pub valid_until: Option<u64>,
+    /// Targets taken instead of the rule's main target if their condition holds, in the order
+    /// they're evaluated in.
+    pub branches: Vec<(Condition, String)>,
+}
+
+impl RuleOptions {
+    pub fn parse(parts: &[&str]) -> Result<Self, String> {
+        let mut options = Self::default();
+        for part in parts {
+            let Some((key, value)) = part.split_once('=') else {
+                return Err(format!(
+                    "Invalid rule option '{part}', expected 'key=value'"
+                ));
+            };
+            match key {
+                "host" | "query" | "methods" | "valid_from" | "valid_until" if options.has(key) => {
+                    return Err(format!("Duplicate rule option '{key}'"));
+                }
+                "host" => {
+                    let host = value.to_ascii_lowercase();
+                    if !is_valid_host(&host) {
+                        return Err(format!("Invalid host '{value}'"));
+                    }
+                    options.host = Some(host);
+                }
+                "query" => {
+                    options.query = Some(QueryMode::parse(value).ok_or_else(|| {
+                        format!("Invalid query mode '{value}', expected exact, ignore, or forward")
+                    })?);
+                }
+                "methods" => {
+                    options.methods = Some(MethodSet::parse(value).ok_or_else(|| {
+                        format!(
+                            "Invalid methods '{value}', expected a comma-separated list of {}",
+                            METHODS.join(", ")
+                        )
+                    })?);
+                }
+                "header" => {
+                    let Some((name, value)) = value.split_once(':') else {
+                        return Err(format!(
+                            "Invalid header '{value}', expected 'header=<name>:<value>'"
+                        ));
+                    };
+                    if !is_valid_header_name(name) {
+                        return Err(format!("Invalid header name '{name}'"));
+                    }
+                    if RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+                        return Err(format!("Header '{name}' can't be overridden"));
+                    }
+                    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_graphic()) {
+                        return Err(format!("Invalid value for header '{name}'"));
+                    }
+                    options.headers.push((name.to_string(), value.to_string()));
+                }
+                "valid_from" | "valid_until" => {
+                    let time = schedule::parse_time(value).ok_or_else(|| {
+                        format!(
+                            "Invalid time '{value}' for '{key}', expected an RFC 3339 timestamp, \
+                             a date, or seconds since the epoch"
+                        )
+                    })?;
+                    // Bundles store times that aren't set as 0
+                    if time == 0 {
+                        return Err(format!(
+                            "Invalid time '{value}' for '{key}', which must be after the epoch"
+                        ));
+                    }
+                    if key == "valid_from" {
+                        options.valid_from = Some(time);
+                    } else {
+                        options.valid_until = Some(time);
+                    }
+                }
+                "when" => {
+                    let (condition, target) = Condition::parse_branch(value)?;
+                    if !is_valid_redirect_target(target) {
+                        return Err(format!(
+                            "Invalid format for target of condition '{condition}': '{target}'"
+                        ));
+                    }
+                    if options.branches.iter().any(|(c, _)| *c == condition) {
+                        return Err(format!("Duplicate condition '{condition}'"));
+                    }
+                    options.branches.push((condition, target.to_string()));
+                }
+                _ => return Err(format!("Unknown rule option '{key}'")),
+            }
+        }
+        if let (Some(from), Some(until)) = (options.valid_from, options.valid_until)
+            && from >= until
+        {
+            return Err("Rule must become valid before it expires".to_string());
+        }
+        Ok(options)
+    }
+
+    fn has(&self, key: &str) -> bool {
+        match key {
+            "host" => self.host.is_some(),
+            "query" => self.query.is_some(),
+            "methods" => self.methods.is_some(),
+            "valid_from" => self.valid_from.is_some(),
+            "valid_until" => self.valid_until.is_some(),
+            _ => false,
+        }
+    }
+
+    /// Returns the bundle record for a rule with these options and the given status code.
+    /// `target_index` returns the index of a conditional target in the bundle's targets.
+    pub fn record(&self, status_code: u16, target_index: impl Fn(&str) -> u32) -> RuleRecord {
+        RuleRecord {
+            status_code,
+            query_mode: QueryMode::encoded(self.query),
+            methods: MethodSet::encoded(self.methods),
+            headers: self.headers.clone(),
+            valid_from: self.valid_from,
+            valid_until: self.valid_until,
+            branches: self
+                .branches
+                .iter()
+                .map(|(condition, target)| (condition.clone(), target_index(target)))
+                .collect(),
+        }
+    }
+}
+
+impl RuleOptions {
+    /// The inverse of `record`, for a rule restricted to `host`. `target` returns the conditional
+    /// target with the given index in the bundle's targets.
+    pub fn from_record(
+        record: &RuleRecord,
+        host: Option<String>,
+        target: impl Fn(u32) -> String,
+    ) -> Self {
+        Self {
+            host,
+            query: QueryMode::decode(record.query_mode),
+            methods: MethodSet::decode(record.methods),
+            headers: record.headers.clone(),
+            valid_from: record.valid_from,
+            valid_until: record.valid_until,
+            branches: record
+                .branches
+                .iter()
+                .map(|(condition, index)| (condition.clone(), target(*index)))
+                .collect(),
+        }
+    }
+}
+
+impl Display for RuleOptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(host) = &self.host {
+            write!(f, " host={host}")?;
+        }
+        if let Some(query) = self.query {
+            write!(f, " query={}", query.as_str())?;
+        }
+        if let Some(methods) = self.methods {
+            write!(f, " methods={methods}")?;
+        }
+        for (name, value) in &self.headers {
+            write!(f, " header={name}:{value}")?;
+        }
+        if let Some(time) = self.valid_from {
+            write!(f, " valid_from={}", schedule::format_time(time))?;
+        }
+        if let Some(time) = self.valid_until {
+            write!(f, " valid_until={}", schedule::format_time(time))?;
+        }
+        for (condition, target) in &self.branches {
+            write!(f, " when={condition}:{target}")?;
+        }
+        Ok(())
+    }
+}
+
+static BASE: LazyLock<Url> = LazyLock::new(|| Url::parse("https://example.com").unwrap());
+
+/// Headers the component sets itself, which rules can't override.
+const RESERVED_HEADERS: &[&str] = &["content-length", "location"];
+
+/// Sources ending in `*` are wildcard rules matching every path that starts with the rest of the
+/// source. The matched remainder of the path replaces `$1` in the rule's target.
+pub fn is_wildcard_source(input: &str) -> bool {
+    input.ends_with('*')
+}
+
+/// Returns the key under which rules for `path` are stored, normalizing it if `normalize` is set.
+pub fn source_key(path: &str, normalize: bool) -> Cow<'_, str> {
+    if normalize {
+        Cow::Owned(normalize_source(path))
+    } else {
+        Cow::Borrowed(path)
+    }
+}
+
+/// Canonicalizes the path of a source, leaving any query string untouched: percent-encoded
+/// unreserved characters are decoded, the path is lowercased, duplicate slashes are collapsed,
+/// and trailing slashes are stripped. Wildcard sources keep their trailing slash, since it's part
+/// of the prefix they match.
+///
+/// The Wasm component applies the same normalization to incoming requests for bundles with
+/// normalized sources, see `normalize_request`.
+pub fn normalize_source(input: &str) -> String {
+    let mut normalized = normalize_path(input, |_| {});
+    strip_trailing_slash(&mut normalized);
+    normalized
+}
+
+/// Normalizes the path of a request like `normalize_source`, but keeps any trailing slash, which
+/// wildcard sources can match. Also returns the offset in `input` of each byte of the result,
+/// followed by the length of `input`, so that the part of `input` after a normalized prefix can be
+/// found.
+pub fn normalize_request(input: &str) -> (String, Vec<usize>) {
+    let mut offsets = Vec::with_capacity(input.len() + 1);
+    let normalized = normalize_path(input, |offset| offsets.push(offset));
+    offsets.push(input.len());
+    (normalized, offsets)
+}
+
+/// Removes the trailing slash of a normalized path, unless it's the root path, leaving any query
+/// string untouched.
+pub fn strip_trailing_slash(path: &mut String) {
+    let end = path.find('?').unwrap_or(path.len());
+    if end > 1 && path[..end].ends_with('/') {
+        path.remove(end - 1);
+    }
+}
+
+/// Normalizes `input` without stripping trailing slashes, calling `offset` with the offset in
+/// `input` of each byte of the result.
+fn normalize_path(input: &str, mut offset: impl FnMut(usize)) -> String {
+    let (path, query) = match input.find('?') {
+        Some(i) => input.split_at(i),
+        None => (input, ""),
+    };
+    let bytes = path.as_bytes();
+    let mut normalized = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let mut byte = bytes[i];
+        if byte == b'%' && i + 2 < bytes.len() {
+            let decoded = std::str::from_utf8(&bytes[i + 1..i + 3])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .filter(|b| b.is_ascii_alphanumeric() || b"-._~".contains(b));
+            if let Some(decoded) = decoded {
+                byte = decoded;
+                i += 2;
+            }
+        }
+        i += 1;
+        if byte == b'/' && normalized.last() == Some(&b'/') {
+            continue;
+        }
+        normalized.push(byte.to_ascii_lowercase());
+        offset(start);
+    }
+    let mut normalized = String::from_utf8(normalized).expect("only ASCII bytes are changed");
+    normalized.push_str(query);
+    (path.len()..input.len()).for_each(offset);
+    normalized
+}
+
+/// Splits a rule's key into its host, which is empty for host-agnostic rules, and its path.
+pub fn split_host(key: &str) -> (&str, &str) {
+    key.split_at(key.find('/').unwrap_or(key.len()))
+}
+
+pub fn is_valid_redirect_source(input: &str) -> bool {
+    input.starts_with("/") && BASE.join(input).is_ok()
+}
+
+pub fn is_valid_redirect_target(input: &str) -> bool {
+    assert!(
+        !input.contains(|c: char| c.is_whitespace()),
+        "Input should not contain newlines"
+    );
+    let violations = RefCell::new(Vec::new());
+    let cb = |v| violations.borrow_mut().push(v);
+    let parser = Url::options().syntax_violation_callback(Some(&cb));
+    // Browsers treat targets starting with `//` as absolute URLs on another host
+    if input.starts_with("//") {
+        return false;
+    }
+    if input.starts_with("/") {
+        return parser.base_url(Some(&BASE)).parse(input).is_ok()
+            && violations.borrow_mut().is_empty();
+    }
+
+    if !input.starts_with("http") {
+        return false;
+    }
+
+    Url::parse(input).is_ok() && violations.borrow_mut().is_empty()
+}
+
+fn is_valid_header_name(input: &str) -> bool {
+    !input.is_empty()
+        && input
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+fn is_valid_host(input: &str) -> bool {
+    Url::parse(&format!("https://{input}/"))
+        .is_ok_and(|url| url.host_str() == Some(input) && url.port().is_none())
+}
+
+/// Whether `from` is a wildcard source and redirecting to `to` always leads back into its prefix.
+fn redirects_into_prefix(from: &str, to: &str, normalize: bool) -> bool {
+    source_key(from, normalize)
+        .strip_suffix('*')
+        .is_some_and(|prefix| {
+            // Any suffix is substituted the same way, so checking one is enough. Requests keep
+            // their trailing slash when matching wildcard sources, so targets do as well.
+            let to = to.replace("$1", "x");
+            if normalize {
+                normalize_request(&to).0.starts_with(prefix)
+            } else {
+                to.starts_with(prefix)
+            }
+        })
+}
+
+/// Returns why a rule from `from` to the conditional target `to` always loops back to itself,
+/// if it does. Main targets are checked the same way while parsing a rule.
+fn redirects_to_itself(from: &str, to: &str, normalize: bool) -> Option<String> {
+    if from == to || source_key(from, normalize) == source_key(to, normalize) {
+        return Some(format!(
+            "Source and conditional target '{to}' cannot be the same"
+        ));
+    }
+    redirects_into_prefix(from, to, normalize)
+        .then(|| format!("Conditional target '{to}' redirects back into the source prefix"))
+}
+
+/// Returns why a rule's methods don't work with its status code, if they don't: clients may follow
+/// 301 and 302 redirects with `GET`, and with `strict-methods`, the component only redirects `GET`
+/// and `HEAD` requests for them, so a rule limited to other methods would never redirect.
+fn changed_method(status_code: u16, options: &RuleOptions) -> Option<String> {
+    let methods = options.methods?;
+    // `GET` implies `HEAD`, so checking `HEAD` covers both
+    let allows_head = MethodSet::encoded(Some(methods)) & (1 << 1) != 0;
+    (matches!(status_code, 301 | 302) && !allows_head).then(|| {
+        format!(
+            "Rules with status {status_code} must allow GET, since clients may follow them with \
+             GET; use 307 or 308 for 'methods={methods}'"
+        )
+    })
+}
+
+/// Returns why a wildcard or regex rule's target can redirect to any host, if it can: a target
+/// starting with `/$1` becomes `//` followed by a host if the captured part starts with a slash.
+fn open_redirect(to: &str) -> Option<String> {
+    let placeholder = to.strip_prefix("/$")?;
+    placeholder
+        .starts_with(|c: char| c.is_ascii_digit() && c != '0')
+        .then(|| {
+            format!(
+                "Target '{to}' can redirect to another host, since it starts with a captured part"
+            )
+        })
+}
+
+/// Checks a regex rule's pattern and targets.
+fn check_regex_rule(pattern: &str, to: &str, options: &RuleOptions) -> Result<(), RuleError> {
+    if !is_valid_redirect_target(to) {
+        return Err(RuleError::invalid(format!(
+            "Invalid format for target: '{to}'"
+        )));
+    }
+    if options.query.is_some() {
+        return Err(RuleError::invalid(
+            "Regex rules match the query string as part of the pattern, and don't support the \
+             'query' option",
+        ));
+    }
+    let groups = regex_rules::validate(pattern).map_err(RuleError::invalid)?;
+    let regex = regex_rules::compile(&[pattern]).map_err(RuleError::invalid)?;
+    let targets = std::iter::once(to).chain(options.branches.iter().map(|(_, t)| t.as_str()));
+    for to in targets {
+        if let Some(message) = open_redirect(to) {
+            return Err(RuleError::invalid(message));
+        }
+        let placeholder = regex_rules::max_placeholder(to);
+        if placeholder > groups {
+            return Err(RuleError::invalid(format!(
+                "Target '{to}' refers to ${placeholder}, but the regex only has {groups} groups"
+            )));
+        }
+        // Any captured groups are substituted the same way, so checking one target is enough
+        let target = (1..=9).fold(to.to_string(), |target, group| {
+            target.replace(&format!("${group}"), "x")
+        });
+        if !regex_rules::matching_patterns(&regex, &target).is_empty() {
+            return Err(RuleError::self_loop(format!(
+                "Regex target '{to}' redirects back into the source pattern"
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let rule = Rule::parse("/a /b 301 query=forward host=Example.com", 302, false).unwrap();
+        assert_eq!((rule.from, rule.to, rule.status_code), ("/a", "/b", 301));
+        assert_eq!(rule.options.query, Some(QueryMode::Forward));
+        assert_eq!(rule.key(false), "example.com/a");
+        assert!(!rule.is_regex());
+
+        let rule = Rule::parse("/About/* /about-us/$1", 302, true).unwrap();
+        assert_eq!(rule.status_code, 302);
+        assert_eq!(rule.key(true), "/about/*");
+
+        let rule = Rule::parse(r"~/p\.php\?id=(\d+) /p/$1 308", 302, false).unwrap();
+        assert!(rule.is_regex());
+        assert_eq!(rule.status_code, 308);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |line: &str, normalize: bool| Rule::parse(line, 302, normalize).unwrap_err();
+        let invalid = |message: &str| RuleError::invalid(message);
+        let self_loop = |message: &str| RuleError::self_loop(message);
+
+        assert_eq!(error("/a", false), invalid("Missing target for redirect"));
+        assert_eq!(
+            error("/a /b 200", false),
+            invalid("Invalid status code: '200'")
+        );
+        assert_eq!(
+            error("/a /A", true),
+            self_loop("Source and target cannot be the same")
+        );
+        assert_eq!(
+            error("a b", false),
+            invalid("Invalid format for source and target: 'a' -> 'b'")
+        );
+        assert_eq!(
+            error("/a/*/b /c", false),
+            invalid("Wildcards are only supported at the end of a source: '/a/*/b'")
+        );
+        assert_eq!(
+            error("/a/* /a/b/$1", false),
+            self_loop("Wildcard target redirects back into the source prefix")
+        );
+        assert_eq!(
+            error("/a /b when=lang:de:/a", false),
+            self_loop("Source and conditional target '/a' cannot be the same")
+        );
+        assert_eq!(
+            error("~/a/(.*) /a/$1", false),
+            self_loop("Regex target '/a/$1' redirects back into the source pattern")
+        );
+        assert_eq!(
+            error("/a/* /$1", false),
+            invalid(
+                "Target '/$1' can redirect to another host, since it starts with a captured part"
+            )
+        );
+        assert_eq!(
+            error("/a/* /b when=lang:de:/$1/de", false),
+            invalid(
+                "Target '/$1/de' can redirect to another host, since it starts with a captured part"
+            )
+        );
+        assert_eq!(
+            error(r"~/a/(.*) /$1", false),
+            invalid(
+                "Target '/$1' can redirect to another host, since it starts with a captured part"
+            )
+        );
+        assert!(Rule::parse("/a/* /b/$1", 302, false).is_ok());
+        assert_eq!(
+            error("/a /b 301 methods=POST", false),
+            invalid(
+                "Rules with status 301 must allow GET, since clients may follow them with GET; \
+                 use 307 or 308 for 'methods=POST'"
+            )
+        );
+        assert_eq!(
+            error(r"~/a\.php /b methods=PUT,DELETE", false),
+            invalid(
+                "Rules with status 302 must allow GET, since clients may follow them with GET; \
+                 use 307 or 308 for 'methods=PUT,DELETE'"
+            )
+        );
+        assert!(Rule::parse("/a /b 301 methods=GET,POST", 302, false).is_ok());
+        assert!(Rule::parse("/a /b 307 methods=POST", 302, false).is_ok());
+        assert_eq!(
+            error("/a /b valid_until=1970-01-01", false),
+            invalid("Invalid time '1970-01-01' for 'valid_until', which must be after the epoch")
+        );
+        assert_eq!(
+            error("/a /b query=sometimes", false).kind,
+            RuleErrorKind::Invalid
+        );
+    }
+
+    #[test]
+    fn test_is_valid_redirect_source() {
+        assert!(is_valid_redirect_source("/valid/path"));
+        assert!(is_valid_redirect_source("/"));
+        assert!(is_valid_redirect_source("/path?query=1"));
+        assert!(!is_valid_redirect_source("invalid/path")); // Must start with /
+        assert!(!is_valid_redirect_source("https://example.com/path")); // Must be relative
+        assert!(is_valid_redirect_source("/path%20with%20space")); // Encoded spaces are ok
+    }
+
+    #[test]
+    fn test_is_valid_redirect_target() {
+        assert!(is_valid_redirect_target("/valid/relative/path"));
+        assert!(is_valid_redirect_target("https://absolute.url/path"));
+        assert!(is_valid_redirect_target(
+            "http://absolute.url/path?query=1#fragment"
+        ));
+        assert!(!is_valid_redirect_target("invalid-relative-path")); // Relative must start with /
+        assert!(!is_valid_redirect_target("ftp://invalid.scheme")); // Only http/https schemes for absolute URLs
+        assert!(!is_valid_redirect_target("/<with>invalid|chars")); // Invalid chars
+        assert!(is_valid_redirect_target("/path%20with%20space")); // Encoded chars ok
+        assert!(!is_valid_redirect_target("//evil.example/x")); // Protocol-relative URLs aren't
+    }
+
+    #[test]
+    fn test_normalize_source() {
+        assert_eq!(normalize_source("/About/"), "/about");
+        assert_eq!(normalize_source("/a//b///c"), "/a/b/c");
+        assert_eq!(normalize_source("/"), "/");
+        assert_eq!(normalize_source("//"), "/");
+        assert_eq!(normalize_source("/%7Euser/%41%2F"), "/~user/a%2f");
+        assert_eq!(normalize_source("/Path/?Query=A/"), "/path?Query=A/");
+        assert_eq!(normalize_source("/Old-Blog//*"), "/old-blog/*");
+        assert_eq!(normalize_source("/trailing%"), "/trailing%");
+    }
+
+    #[test]
+    fn test_normalize_request() {
+        assert_eq!(
+            normalize_request("/About/"),
+            ("/about/".to_string(), vec![0, 1, 2, 3, 4, 5, 6, 7])
+        );
+        // Offsets skip collapsed slashes and point at the start of decoded characters
+        assert_eq!(
+            normalize_request("/a//%7Eb?Q"),
+            ("/a/~b?Q".to_string(), vec![0, 1, 2, 4, 7, 8, 9, 10])
+        );
+        let mut path = normalize_request("/Docs/?x=/").0;
+        strip_trailing_slash(&mut path);
+        assert_eq!(path, normalize_source("/Docs/?x=/"));
+    }
+}
@@ -0,0 +1,175 @@
+//! Activation and expiry times of scheduled rules.
+//!
+//! Rules can be limited to a time window with the `valid_from=` and `valid_until=` options, which
+//! take either an RFC 3339 timestamp like `2025-11-28T00:00:00Z` or `2025-11-28T09:00:00+01:00`, a
+//! date like `2025-11-28` for midnight UTC, or seconds since the Unix epoch. Times are stored as
+//! seconds since the epoch, and written back as RFC 3339 timestamps in UTC.
+
+/// Parses a point in time, returning it in seconds since the epoch.
+pub fn parse_time(input: &str) -> Option<u64> {
+    if !input.is_empty() && input.bytes().all(|b| b.is_ascii_digit()) {
+        return input.parse().ok();
+    }
+    let (date, time) = match input.split_once(['T', 't']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (input, None),
+    };
+    let days = parse_date(date)?;
+    let seconds = match time {
+        Some(time) => parse_time_of_day(time)?,
+        None => 0,
+    };
+    u64::try_from(days * 86400 + seconds).ok()
+}
+
+/// Formats seconds since the epoch as an RFC 3339 timestamp in UTC.
+pub fn format_time(time: u64) -> String {
+    let (days, seconds) = ((time / 86400) as i64, time % 86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Parses a `YYYY-MM-DD` date into days since the epoch.
+fn parse_date(date: &str) -> Option<i64> {
+    let [year, month, day] = fixed_width_fields(date, '-', [4, 2, 2])?;
+    let days_in_month = match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    if !(1..=days_in_month).contains(&day) {
+        return None;
+    }
+    Some(days_from_civil(year, month, day))
+}
+
+/// Parses an `HH:MM:SS` time with optional fractional seconds, which are dropped, and a `Z` or
+/// `±HH:MM` offset. Returns the number of seconds since midnight UTC, which may be negative or
+/// exceed a day because of the offset.
+fn parse_time_of_day(time: &str) -> Option<i64> {
+    let (time, offset) = if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        (time, 0)
+    } else {
+        let sign_at = time.rfind(['+', '-'])?;
+        let [hours, minutes] = fixed_width_fields(&time[sign_at + 1..], ':', [2, 2])?;
+        if hours > 23 || minutes > 59 {
+            return None;
+        }
+        let offset = hours * 3600 + minutes * 60;
+        match &time[sign_at..sign_at + 1] {
+            "+" => (&time[..sign_at], offset),
+            _ => (&time[..sign_at], -offset),
+        }
+    };
+    let time = match time.split_once('.') {
+        Some((time, fraction))
+            if !fraction.is_empty() && fraction.bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            time
+        }
+        Some(_) => return None,
+        None => time,
+    };
+    let [hours, minutes, seconds] = fixed_width_fields(time, ':', [2, 2, 2])?;
+    // Leap seconds are accepted, and end up in the first second of the next minute
+    if hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+    Some(hours * 3600 + minutes * 60 + seconds - offset)
+}
+
+/// Splits `input` at `separator` into exactly `N` numeric fields of the given widths.
+fn fixed_width_fields<const N: usize>(
+    input: &str,
+    separator: char,
+    widths: [usize; N],
+) -> Option<[i64; N]> {
+    let mut fields = input.split(separator);
+    let mut values = [0; N];
+    for (value, width) in values.iter_mut().zip(widths) {
+        let field = fields.next()?;
+        if field.len() != width || !field.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        *value = field.parse().ok()?;
+    }
+    fields.next().is_none().then_some(values)
+}
+
+/// Converts a date in the proleptic Gregorian calendar to days since the epoch, see
+/// <https://howardhinnant.github.io/date_algorithms.html#days_from_civil>.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_time("2025-11-28T00:00:00Z"), Some(1_764_288_000));
+        assert_eq!(parse_time("2025-11-28"), Some(1_764_288_000));
+        assert_eq!(parse_time("2025-11-28T01:00:00+01:00"), Some(1_764_288_000));
+        assert_eq!(
+            parse_time("2025-11-27T23:30:00.5-00:30"),
+            Some(1_764_288_000)
+        );
+        assert_eq!(parse_time("1764288000"), Some(1_764_288_000));
+        assert_eq!(parse_time("2024-02-29T12:00:00Z"), Some(1_709_208_000));
+
+        for invalid in [
+            "",
+            "2025-11-28T00:00:00",
+            "2025-11-28T24:00:00Z",
+            "2025-13-01",
+            "2025-02-29",
+            "2025-1-28",
+            "1969-12-31T23:59:59Z",
+            "tomorrow",
+        ] {
+            assert_eq!(parse_time(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_time(1_709_208_000), "2024-02-29T12:00:00Z");
+        for time in [1_764_288_000, 1_764_331_199, 4_102_444_800] {
+            assert_eq!(parse_time(&format_time(time)), Some(time));
+        }
+    }
+}
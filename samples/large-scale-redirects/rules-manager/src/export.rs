@@ -0,0 +1,687 @@
+//! Exporting of validated rules to the configuration formats of other servers and services.
+//!
+//! Rules are read back from a generated rules file or a bundle, and written as nginx `map` blocks,
+//! Apache `RewriteRule`s, a Netlify `_redirects` file, or JSON. JSON keeps everything about a rule.
+//! The other formats can't express conditional targets, method restrictions, response headers or
+//! schedules, so rules using them are skipped and listed, as are rules whose sources a format
+//! can't match, like regex rules for Netlify.
+//!
+//! Apache, nginx and Netlify all use the first matching rule, so rules are written in the order
+//! the Wasm component gives them precedence in: host-specific literal and wildcard rules, other
+//! literal and wildcard rules, and regex rules, with exact rules before wildcard rules with the
+//! longest prefixes.
+
+use crate::GENERATED_FILE_HEADER;
+use anyhow::{Context, Result, anyhow, bail};
+use clap::ValueEnum;
+use redirects_core::bundle::{self, DecodedRule};
+use redirects_core::regex_rules::REGEX_PREFIX;
+use redirects_core::rule::{QueryMode, Rule, is_wildcard_source};
+use redirects_core::schedule::format_time;
+use serde_json::{Map, Value, json};
+use std::cmp::Reverse;
+use std::fmt::Write;
+use std::fs::{read, read_to_string};
+use std::path::PathBuf;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ExportFormat {
+    /// nginx `map` blocks, with the `return` directives to use them in server blocks
+    Nginx,
+    /// Apache `RewriteRule`s, for server configs or `.htaccess` files
+    Apache,
+    /// A Netlify `_redirects` file
+    Netlify,
+    /// JSON, keeping every option of the rules
+    Json,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ExportArgs {
+    /// Path to the validated redirects file to export
+    #[arg(long, required_unless_present = "bundle", conflicts_with = "bundle")]
+    rules: Option<PathBuf>,
+
+    /// Path to the bundle to export
+    #[arg(long)]
+    bundle: Option<PathBuf>,
+
+    /// Format to export the rules to
+    #[arg(long, value_enum)]
+    format: ExportFormat,
+
+    /// Default status code the rules file was validated with. Bundles record their own.
+    #[arg(long, value_parser = clap::value_parser!(u16).range(301..400), default_value = "302")]
+    default_status_code: u16,
+
+    /// Query mode the Wasm component is built with, used for rules without a `query` option
+    #[arg(long, value_parser = parse_query_mode, default_value = "exact")]
+    query: QueryMode,
+
+    /// Path to write the exported rules to. Default is to print them.
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+fn parse_query_mode(input: &str) -> Result<QueryMode, String> {
+    QueryMode::parse(input).ok_or_else(|| format!("Invalid query mode '{input}'"))
+}
+
+pub(crate) fn export(args: &ExportArgs) -> Result<()> {
+    let (default_status_code, rules) = match (&args.rules, &args.bundle) {
+        (Some(path), _) => {
+            let contents = read_to_string(path)
+                .with_context(|| format!("Failed to read redirects file {}", path.display()))?;
+            let rules = read_rules(&contents, args.default_status_code)
+                .with_context(|| format!("Invalid redirects file {}", path.display()))?;
+            (args.default_status_code, rules)
+        }
+        (None, Some(path)) => {
+            let bytes =
+                read(path).with_context(|| format!("Failed to read bundle {}", path.display()))?;
+            let bundle = bundle::decode(&bytes)
+                .with_context(|| format!("Invalid bundle {}", path.display()))?;
+            (bundle.header.default_status_code, bundle.rules())
+        }
+        (None, None) => unreachable!("clap requires either a rules file or a bundle"),
+    };
+
+    let exported = match args.format {
+        ExportFormat::Nginx => export_nginx(&rules, args.query),
+        ExportFormat::Apache => export_apache(&rules, args.query),
+        ExportFormat::Netlify => export_netlify(&rules),
+        ExportFormat::Json => Exported {
+            output: export_json(&rules, default_status_code),
+            ..Exported::default()
+        },
+    };
+
+    match &args.output {
+        Some(path) => {
+            std::fs::write(path, &exported.output)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            eprintln!(
+                "Exported {} of {} rules to {}",
+                rules.len() - exported.skipped.len(),
+                rules.len(),
+                path.display()
+            );
+        }
+        None => print!("{}", exported.output),
+    }
+    if !exported.skipped.is_empty() {
+        eprintln!(
+            "Skipped {} rules that can't be expressed in the {:?} format:",
+            exported.skipped.len(),
+            args.format
+        );
+        for (rule, reason) in &exported.skipped {
+            eprintln!("  {} {}: {reason}", rule.from, rule.to);
+        }
+    }
+    Ok(())
+}
+
+/// Reads the rules of a generated rules file.
+fn read_rules(contents: &str, default_status_code: u16) -> Result<Vec<DecodedRule>> {
+    let mut lines = contents.lines().enumerate();
+    if lines.next().map(|(_, line)| line.trim()) != Some(GENERATED_FILE_HEADER) {
+        bail!("Redirects file must be generated by this tool");
+    }
+    let mut rules = Vec::new();
+    for (line_no, line) in lines {
+        let rule_part = line.split('#').next().unwrap_or("").trim();
+        if rule_part.is_empty() {
+            continue;
+        }
+        // Generated files are already validated, and their sources normalized if requested
+        let rule = Rule::parse(rule_part, default_status_code, false)
+            .map_err(|e| anyhow!("Line {line_no}: {e}"))?;
+        rules.push(DecodedRule {
+            from: rule.from.to_string(),
+            to: rule.to.to_string(),
+            status_code: rule.status_code,
+            options: rule.options,
+        });
+    }
+    Ok(rules)
+}
+
+/// An exported rules file, with the rules that couldn't be exported and why.
+#[derive(Default)]
+struct Exported<'a> {
+    output: String,
+    skipped: Vec<(&'a DecodedRule, String)>,
+}
+
+/// Returns the rules in the order the Wasm component gives them precedence in.
+fn by_precedence(rules: &[DecodedRule]) -> Vec<&DecodedRule> {
+    let mut rules: Vec<_> = rules.iter().enumerate().collect();
+    // Sorting is stable, so regex rules keep their order
+    rules.sort_by_key(|(index, rule)| {
+        let regex = is_regex(rule);
+        let wildcard = is_wildcard_source(&rule.from);
+        let prefix_len = if wildcard { rule.from.len() } else { 0 };
+        let index = if regex { *index } else { 0 };
+        (
+            regex,
+            rule.options.host.is_none(),
+            wildcard,
+            Reverse(prefix_len),
+            index,
+        )
+    });
+    rules.into_iter().map(|(_, rule)| rule).collect()
+}
+
+fn is_regex(rule: &DecodedRule) -> bool {
+    rule.from.starts_with(REGEX_PREFIX)
+}
+
+/// Returns why a rule can't be exported to formats other than JSON, if it uses options they
+/// can't express.
+fn unsupported_options(rule: &DecodedRule) -> Option<&'static str> {
+    let options = &rule.options;
+    if !options.branches.is_empty() {
+        Some("conditional targets aren't supported")
+    } else if options.methods.is_some() {
+        Some("method restrictions aren't supported")
+    } else if !options.headers.is_empty() {
+        Some("response headers aren't supported")
+    } else if options.valid_from.is_some() || options.valid_until.is_some() {
+        Some("schedules aren't supported")
+    } else {
+        None
+    }
+}
+
+/// Returns the pattern of a regex rule for matching request paths without their query string.
+fn path_pattern(rule: &DecodedRule) -> Result<&str, String> {
+    let pattern = &rule.from[REGEX_PREFIX.len_utf8()..];
+    if pattern.contains(r"\?") {
+        return Err("patterns matching query strings aren't supported".to_string());
+    }
+    Ok(pattern)
+}
+
+/// Quotes an nginx parameter.
+fn nginx_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', r"\\").replace('"', "\\\""))
+}
+
+/// Returns the map entry of a rule: the source to match `$uri` or `$host$uri` against, and the
+/// target with the query string appended if the rule forwards it.
+fn nginx_entry(rule: &DecodedRule, query: QueryMode) -> Result<(String, String), String> {
+    if let Some(reason) = unsupported_options(rule) {
+        return Err(reason.to_string());
+    }
+    let host = rule.options.host.as_deref().unwrap_or("");
+    let mut target = rule.to.to_string();
+    let source = if rule.from.starts_with(REGEX_PREFIX) {
+        format!("~^{}{}$", regex_syntax::escape(host), path_pattern(rule)?)
+    } else if rule.from.contains('?') {
+        return Err("sources with query strings aren't supported".to_string());
+    } else if let Some(prefix) = rule.from.strip_suffix('*') {
+        format!(
+            "~^{}{}(.*)$",
+            regex_syntax::escape(host),
+            regex_syntax::escape(prefix)
+        )
+    } else {
+        format!("{host}{}", rule.from)
+    };
+    if rule.options.query.unwrap_or(query) == QueryMode::Forward && !is_regex(rule) {
+        if target.contains('?') {
+            return Err("forwarding query strings to targets with one isn't supported".to_string());
+        }
+        target.push_str("$is_args$args");
+    }
+    Ok((source, target))
+}
+
+/// An nginx `map` block, setting a variable to the status code and target of the rule matching the
+/// key.
+struct NginxMap {
+    variable: &'static str,
+    key: &'static str,
+    /// Sources and values
+    entries: Vec<(String, String)>,
+}
+
+fn export_nginx(rules: &[DecodedRule], query: QueryMode) -> Exported<'_> {
+    let mut exported = Exported::default();
+    // Host-specific rules are matched against `$host$uri`, and each map is checked in turn
+    let mut maps = [
+        ("redirect_host", "$host$uri"),
+        ("redirect", "$uri"),
+        ("redirect_host_regex", "$host$uri"),
+        ("redirect_regex", "$uri"),
+    ]
+    .map(|(variable, key)| NginxMap {
+        variable,
+        key,
+        entries: Vec::new(),
+    })
+    .into_iter()
+    .collect::<Vec<_>>();
+    let mut status_codes = Vec::new();
+    for rule in by_precedence(rules) {
+        match nginx_entry(rule, query) {
+            Ok((source, target)) => {
+                let map = match (is_regex(rule), rule.options.host.is_some()) {
+                    (false, true) => 0,
+                    (false, false) => 1,
+                    (true, true) => 2,
+                    (true, false) => 3,
+                };
+                maps[map]
+                    .entries
+                    .push((source, format!("{}:{target}", rule.status_code)));
+                if !status_codes.contains(&rule.status_code) {
+                    status_codes.push(rule.status_code);
+                }
+            }
+            Err(reason) => exported.skipped.push((rule, reason)),
+        }
+    }
+    status_codes.sort();
+    maps.retain(|map| !map.entries.is_empty());
+
+    let output = &mut exported.output;
+    writeln!(
+        output,
+        "# Exported by rules-manager. Include this file in the http block, and add"
+    )
+    .unwrap();
+    writeln!(output, "# these lines to server blocks to redirect:").unwrap();
+    writeln!(output, "#").unwrap();
+    for map in &maps {
+        for status_code in &status_codes {
+            writeln!(
+                output,
+                "#     if (${} ~ \"^{status_code}:(.*)$\") {{ return {status_code} $1; }}",
+                map.variable
+            )
+            .unwrap();
+        }
+    }
+    for map in &maps {
+        writeln!(output, "\nmap {} ${} {{", map.key, map.variable).unwrap();
+        writeln!(output, "    default \"\";").unwrap();
+        for (source, value) in &map.entries {
+            writeln!(
+                output,
+                "    {} {};",
+                nginx_quote(source),
+                nginx_quote(value)
+            )
+            .unwrap();
+        }
+        writeln!(output, "}}").unwrap();
+    }
+    exported
+}
+
+/// Escapes characters mod_rewrite would otherwise interpret in substitutions.
+fn apache_substitution(target: &str) -> String {
+    target.replace('%', r"\%")
+}
+
+fn apache_rule(rule: &DecodedRule, query: QueryMode) -> Result<String, String> {
+    if let Some(reason) = unsupported_options(rule) {
+        return Err(reason.to_string());
+    }
+    let mut conditions = Vec::new();
+    if let Some(host) = &rule.options.host {
+        conditions.push(format!(
+            "RewriteCond %{{HTTP_HOST}} ^{}(:\\d+)?$ [NC]",
+            regex_syntax::escape(host)
+        ));
+    }
+    let mode = rule.options.query.unwrap_or(query);
+    let (pattern, query_flag) = if is_regex(rule) {
+        // Regex rules never forward query strings
+        let pattern = path_pattern(rule)?;
+        let pattern = match pattern.strip_prefix('/') {
+            Some(rest) => format!("^/?{rest}$"),
+            None => format!("^(?:{pattern})$"),
+        };
+        (pattern, Some("QSD"))
+    } else {
+        // Paths of `.htaccess` rules don't start with a slash
+        let (path, source_query) = match rule.from.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (rule.from.as_str(), None),
+        };
+        let path = path.strip_prefix('/').unwrap_or(path);
+        let pattern = match path.strip_suffix('*') {
+            Some(prefix) => format!("^/?{}(.*)$", regex_syntax::escape(prefix)),
+            None => format!("^/?{}$", regex_syntax::escape(path)),
+        };
+        let query_flag = match (source_query, mode) {
+            (Some(source_query), _) => {
+                conditions.push(format!(
+                    "RewriteCond %{{QUERY_STRING}} ^{}$",
+                    regex_syntax::escape(source_query)
+                ));
+                Some("QSD")
+            }
+            (None, QueryMode::Forward) => Some("QSA"),
+            (None, QueryMode::Ignore) => Some("QSD"),
+            // Wildcard rules match the query string as part of the suffix, which mod_rewrite
+            // appends to targets without one by default
+            (None, QueryMode::Exact) if rule.from.ends_with('*') => None,
+            (None, QueryMode::Exact) => {
+                conditions.push("RewriteCond %{QUERY_STRING} ^$".to_string());
+                None
+            }
+        };
+        (pattern, query_flag)
+    };
+    let mut flags = vec![
+        format!("R={}", rule.status_code),
+        "L".to_string(),
+        "NE".to_string(),
+    ];
+    flags.extend(query_flag.map(str::to_string));
+    conditions.push(format!(
+        "RewriteRule {pattern} {} [{}]",
+        apache_substitution(&rule.to),
+        flags.join(",")
+    ));
+    Ok(conditions.join("\n"))
+}
+
+fn export_apache(rules: &[DecodedRule], query: QueryMode) -> Exported<'_> {
+    let mut exported = Exported::default();
+    writeln!(exported.output, "# Exported by rules-manager").unwrap();
+    writeln!(exported.output, "RewriteEngine On").unwrap();
+    for rule in by_precedence(rules) {
+        match apache_rule(rule, query) {
+            Ok(lines) => writeln!(exported.output, "{lines}").unwrap(),
+            Err(reason) => exported.skipped.push((rule, reason)),
+        }
+    }
+    exported
+}
+
+fn netlify_rules(rule: &DecodedRule) -> Result<Vec<String>, String> {
+    if let Some(reason) = unsupported_options(rule) {
+        return Err(reason.to_string());
+    }
+    if is_regex(rule) {
+        return Err("regex sources aren't supported".to_string());
+    }
+    if rule.from.contains('?') {
+        return Err("sources with query strings aren't supported".to_string());
+    }
+    let (from, to) = match rule.from.strip_suffix('*') {
+        // Splats can only match whole path segments
+        Some(prefix) if !prefix.ends_with('/') => {
+            return Err("wildcard prefixes not ending in '/' aren't supported".to_string());
+        }
+        Some(_) => (rule.from.clone(), rule.to.replace("$1", ":splat")),
+        None => (rule.from.clone(), rule.to.clone()),
+    };
+    // Rules always apply, even to paths with content, so they're forced
+    Ok(match &rule.options.host {
+        Some(host) => ["https", "http"]
+            .iter()
+            .map(|scheme| format!("{scheme}://{host}{from} {to} {}!", rule.status_code))
+            .collect(),
+        None => vec![format!("{from} {to} {}!", rule.status_code)],
+    })
+}
+
+fn export_netlify(rules: &[DecodedRule]) -> Exported<'_> {
+    let mut exported = Exported::default();
+    writeln!(exported.output, "# Exported by rules-manager").unwrap();
+    for rule in by_precedence(rules) {
+        match netlify_rules(rule) {
+            Ok(lines) => {
+                for line in lines {
+                    writeln!(exported.output, "{line}").unwrap();
+                }
+            }
+            Err(reason) => exported.skipped.push((rule, reason)),
+        }
+    }
+    exported
+}
+
+fn export_json(rules: &[DecodedRule], default_status_code: u16) -> String {
+    let rules = rules
+        .iter()
+        .map(|rule| {
+            let options = &rule.options;
+            let mut object = Map::new();
+            object.insert("source".into(), json!(rule.from));
+            object.insert("target".into(), json!(rule.to));
+            object.insert("status".into(), json!(rule.status_code));
+            if let Some(host) = &options.host {
+                object.insert("host".into(), json!(host));
+            }
+            if let Some(query) = options.query {
+                object.insert("query".into(), json!(query.as_str()));
+            }
+            if let Some(methods) = options.methods {
+                let methods = methods.to_string();
+                object.insert(
+                    "methods".into(),
+                    json!(methods.split(',').collect::<Vec<_>>()),
+                );
+            }
+            if !options.headers.is_empty() {
+                let headers = options
+                    .headers
+                    .iter()
+                    .map(|(name, value)| json!({"name": name, "value": value}))
+                    .collect::<Vec<_>>();
+                object.insert("headers".into(), json!(headers));
+            }
+            if let Some(time) = options.valid_from {
+                object.insert("valid_from".into(), json!(format_time(time)));
+            }
+            if let Some(time) = options.valid_until {
+                object.insert("valid_until".into(), json!(format_time(time)));
+            }
+            if !options.branches.is_empty() {
+                let conditions = options
+                    .branches
+                    .iter()
+                    .map(|(condition, target)| {
+                        json!({"when": condition.to_string(), "target": target})
+                    })
+                    .collect::<Vec<_>>();
+                object.insert("conditions".into(), json!(conditions));
+            }
+            Value::Object(object)
+        })
+        .collect::<Vec<_>>();
+    let mut output = serde_json::to_string_pretty(&json!({
+        "default_status_code": default_status_code,
+        "rules": rules,
+    }))
+    .unwrap();
+    output.push('\n');
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(lines: &str) -> Vec<DecodedRule> {
+        read_rules(&format!("{GENERATED_FILE_HEADER}\n{lines}"), 302).unwrap()
+    }
+
+    #[test]
+    fn test_read_rules() {
+        let rules = rules("/a /b 301\n\n/c/* /d/$1 query=forward\n~/e/(\\d+) /f/$1");
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[0].status_code, 301);
+        assert_eq!(rules[1].status_code, 302);
+        assert_eq!(rules[1].options.query, Some(QueryMode::Forward));
+        assert!(read_rules("/a /b", 302).is_err());
+        assert!(read_rules(&format!("{GENERATED_FILE_HEADER}\n/a"), 302).is_err());
+    }
+
+    #[test]
+    fn test_by_precedence() {
+        let rules = rules(
+            "~/r/.* /r2\n/a/* /x\n/a/b/* /y\n/a/c /z\n/a/* /w host=example.com\n\
+             ~/h/.* /h2 host=example.com\n~/q/.* /q2",
+        );
+        let sources: Vec<_> = by_precedence(&rules)
+            .iter()
+            .map(|rule| (rule.from.as_str(), rule.options.host.is_some()))
+            .collect();
+        assert_eq!(
+            sources,
+            vec![
+                ("/a/*", true),
+                ("/a/c", false),
+                ("/a/b/*", false),
+                ("/a/*", false),
+                ("~/h/.*", true),
+                ("~/r/.*", false),
+                ("~/q/.*", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_export_nginx() {
+        let rules = rules(
+            "/a /b 301\n/c/* /d/$1 query=forward\n/e /f host=example.com\n~/g/(\\d+) /h/$1 308\n\
+             /i /j methods=GET\n/k?x=1 /l",
+        );
+        let exported = export_nginx(&rules, QueryMode::Exact);
+        let output = &exported.output;
+        assert!(
+            output.contains("map $uri $redirect {\n    default \"\";\n    \"/a\" \"301:/b\";\n")
+        );
+        assert!(output.contains("    \"~^/c/(.*)$\" \"302:/d/$1$is_args$args\";\n"));
+        assert!(output.contains("map $host$uri $redirect_host {"));
+        assert!(output.contains("    \"example.com/e\" \"302:/f\";\n"));
+        assert!(output.contains("map $uri $redirect_regex {"));
+        assert!(output.contains("    \"~^/g/(\\\\d+)$\" \"308:/h/$1\";\n"));
+        assert!(output.contains("#     if ($redirect ~ \"^301:(.*)$\") { return 301 $1; }\n"));
+        assert!(output.contains("#     if ($redirect_host ~ \"^308:(.*)$\") { return 308 $1; }\n"));
+        assert!(!output.contains("redirect_host_regex"));
+        let skipped: Vec<_> = exported
+            .skipped
+            .iter()
+            .map(|(rule, _)| rule.from.as_str())
+            .collect();
+        assert_eq!(skipped, vec!["/i", "/k?x=1"]);
+    }
+
+    #[test]
+    fn test_export_apache() {
+        let rules = rules(
+            "/a.html /b%20c 301\n/c/* /d/$1 query=forward\n/e /f host=example.com query=ignore\n\
+             ~/g/(\\d+) /h/$1 308\n/k?x=1 /l\n~/m\\?n=(\\d+) /o",
+        );
+        let exported = export_apache(&rules, QueryMode::Exact);
+        assert_eq!(
+            exported.output,
+            "# Exported by rules-manager\n\
+             RewriteEngine On\n\
+             RewriteCond %{HTTP_HOST} ^example\\.com(:\\d+)?$ [NC]\n\
+             RewriteRule ^/?e$ /f [R=302,L,NE,QSD]\n\
+             RewriteCond %{QUERY_STRING} ^$\n\
+             RewriteRule ^/?a\\.html$ /b\\%20c [R=301,L,NE]\n\
+             RewriteCond %{QUERY_STRING} ^x=1$\n\
+             RewriteRule ^/?k$ /l [R=302,L,NE,QSD]\n\
+             RewriteRule ^/?c/(.*)$ /d/$1 [R=302,L,NE,QSA]\n\
+             RewriteRule ^/?g/(\\d+)$ /h/$1 [R=308,L,NE,QSD]\n"
+        );
+        let skipped: Vec<_> = exported
+            .skipped
+            .iter()
+            .map(|(rule, _)| rule.from.as_str())
+            .collect();
+        assert_eq!(skipped, vec!["~/m\\?n=(\\d+)"]);
+    }
+
+    #[test]
+    fn test_export_netlify() {
+        let rules =
+            rules("/a /b 301\n/c/* /d/$1\n/e /f host=example.com\n/g* /h\n~/i/(\\d+) /j/$1");
+        let exported = export_netlify(&rules);
+        assert_eq!(
+            exported.output,
+            "# Exported by rules-manager\n\
+             https://example.com/e /f 302!\n\
+             http://example.com/e /f 302!\n\
+             /a /b 301!\n\
+             /c/* /d/:splat 302!\n"
+        );
+        let skipped: Vec<_> = exported
+            .skipped
+            .iter()
+            .map(|(rule, _)| rule.from.as_str())
+            .collect();
+        assert_eq!(skipped, vec!["/g*", "~/i/(\\d+)"]);
+    }
+
+    #[test]
+    fn test_export_json() {
+        let rules = rules(
+            "/a /b 301 host=example.com query=forward methods=GET header=X-A:1 \
+             valid_until=2030-01-01 when=lang:de:/de/b\n~/c/(\\d+) /d/$1",
+        );
+        let exported: Value = serde_json::from_str(&export_json(&rules, 302)).unwrap();
+        assert_eq!(
+            exported,
+            json!({
+                "default_status_code": 302,
+                "rules": [
+                    {
+                        "source": "/a",
+                        "target": "/b",
+                        "status": 301,
+                        "host": "example.com",
+                        "query": "forward",
+                        "methods": ["GET", "HEAD"],
+                        "headers": [{"name": "X-A", "value": "1"}],
+                        "valid_until": "2030-01-01T00:00:00Z",
+                        "conditions": [{"when": "lang:de", "target": "/de/b"}],
+                    },
+                    {"source": "~/c/(\\d+)", "target": "/d/$1", "status": 302},
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_export_bundle() {
+        let rules = rules("/a /b 301 query=forward\n/c/* /d/$1 host=example.com\n~/e/(\\d+) /f/$1");
+        let keys = rules
+            .iter()
+            .filter(|rule| !rule.rule().is_regex())
+            .map(|rule| (rule.rule().key(false).into_owned(), rule.rule()))
+            .collect::<Vec<_>>();
+        let regex_rules = rules
+            .iter()
+            .filter(|rule| rule.rule().is_regex())
+            .map(DecodedRule::rule)
+            .collect::<Vec<_>>();
+        let bytes = bundle::build(
+            302,
+            0,
+            0,
+            keys.iter().map(|(key, rule)| (key.as_str(), rule)),
+            &regex_rules,
+        )
+        .unwrap();
+        let decoded = bundle::decode(&bytes).unwrap().rules();
+        // Bundles list literal and wildcard rules by key, which doesn't change their precedence
+        assert_eq!(
+            export_netlify(&decoded).output,
+            export_netlify(&rules).output
+        );
+        assert_eq!(export_json(&decoded, 302), export_json(&rules, 302));
+    }
+}
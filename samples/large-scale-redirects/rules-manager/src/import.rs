@@ -0,0 +1,1093 @@
+//! Importing of redirects configured for other servers and services.
+//!
+//! Files added with `--add-rules` can be given in other formats with `--rules-format`, and are
+//! translated into rules in the native format before being validated like any other rules:
+//! - `apache`: `Redirect`, `RedirectPermanent`, `RedirectTemp`, and `RedirectMatch` directives
+//! - `nginx`: `rewrite` directives with the `permanent` or `redirect` flag, and `return`
+//!   directives with a redirect status code inside `location` blocks
+//! - `netlify`: Netlify `_redirects` files
+//! - `csv`: CSV files, with the columns holding sources, targets and status codes given by
+//!   `--csv-columns`
+//!
+//! Lines that don't configure redirects, like other directives, are skipped. Redirects that can't
+//! be expressed as rules, like internal rewrites or targets built from server variables, are
+//! reported as untranslatable lines.
+//!
+//! Regexes that only match a fixed path, or a fixed prefix with the rest captured, are translated
+//! into literal and wildcard rules, which are much cheaper to match than regex rules. Apache,
+//! nginx and Netlify all append the request's query string to the target by default. Regex rules
+//! can't do that, so requests with query strings are still redirected, but without them.
+
+use clap::ValueEnum;
+use redirects_core::rule::QueryMode;
+use std::fmt::{Display, Formatter};
+use url::Url;
+
+/// Appended to translated regex rules anchored at the end, since regex rules are matched against
+/// the query string as well.
+const ANY_QUERY: &str = r"(?:\?.*)?";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum RulesFormat {
+    /// The native format described in the README
+    Native,
+    /// Apache `Redirect` and `RedirectMatch` directives
+    Apache,
+    /// nginx `rewrite` and `return` directives
+    Nginx,
+    /// Netlify `_redirects` files
+    Netlify,
+    /// CSV files, see `--csv-columns`
+    Csv,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ImportArgs {
+    /// Format of the files added with `--add-rules`
+    #[arg(long, value_enum, default_value_t = RulesFormat::Native)]
+    pub(crate) rules_format: RulesFormat,
+
+    /// Columns of CSV files holding sources, targets, and optionally status codes, either as
+    /// 1-based indices or as names in a header row, e.g. `source=old,target=new,status=code`
+    #[arg(long, default_value = "source=1,target=2,status=3", value_parser = CsvColumns::parse)]
+    pub(crate) csv_columns: CsvColumns,
+}
+
+impl Default for ImportArgs {
+    fn default() -> Self {
+        Self {
+            rules_format: RulesFormat::Native,
+            csv_columns: CsvColumns {
+                source: Column::Index(0),
+                target: Column::Index(1),
+                status: Some(Column::Index(2)),
+            },
+        }
+    }
+}
+
+/// A column of a CSV file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Column {
+    /// 0-based index of the column
+    Index(usize),
+    /// Name of the column in the header row
+    Name(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CsvColumns {
+    source: Column,
+    target: Column,
+    /// Rows without a status code, or files without the column, use the default status code
+    status: Option<Column>,
+}
+
+impl CsvColumns {
+    fn parse(input: &str) -> Result<Self, String> {
+        let (mut source, mut target, mut status) = (None, None, None);
+        for mapping in input.split(',') {
+            let Some((field, column)) = mapping.split_once('=') else {
+                return Err(format!(
+                    "Invalid column mapping '{mapping}', expected field=column"
+                ));
+            };
+            let column = match column.trim().parse::<usize>() {
+                Ok(0) => return Err("Column indices start at 1".to_string()),
+                Ok(index) => Column::Index(index - 1),
+                Err(_) => Column::Name(column.trim().to_string()),
+            };
+            match field.trim() {
+                "source" => source = Some(column),
+                "target" => target = Some(column),
+                "status" => status = Some(column),
+                field => return Err(format!("Unknown field '{field}'")),
+            }
+        }
+        Ok(Self {
+            source: source.ok_or("Missing column for 'source'")?,
+            target: target.ok_or("Missing column for 'target'")?,
+            status,
+        })
+    }
+
+    fn has_header(&self) -> bool {
+        [Some(&self.source), Some(&self.target), self.status.as_ref()]
+            .into_iter()
+            .flatten()
+            .any(|column| matches!(column, Column::Name(_)))
+    }
+}
+
+/// The result of translating a file into rules.
+#[derive(Debug, Default)]
+pub(crate) struct Imported {
+    /// Rules in the native format, with the index of the line they were translated from. A line
+    /// can translate into more than one rule.
+    pub(crate) rules: Vec<(usize, String)>,
+    /// Indices of lines that can't be translated, with the reason
+    pub(crate) untranslatable: Vec<(usize, String)>,
+}
+
+impl Imported {
+    fn push(&mut self, line_no: usize, redirects: Result<Vec<Redirect>, String>) {
+        match redirects {
+            Ok(redirects) => self.rules.extend(
+                redirects
+                    .into_iter()
+                    .map(|redirect| (line_no, redirect.to_string())),
+            ),
+            Err(message) => self.untranslatable.push((line_no, message)),
+        }
+    }
+}
+
+/// Translates the contents of a rules file into native rules. Returns `None` for files in the
+/// native format, which don't need translating.
+pub(crate) fn import(args: &ImportArgs, contents: &str) -> Option<Imported> {
+    let mut imported = Imported::default();
+    match args.rules_format {
+        RulesFormat::Native => return None,
+        RulesFormat::Apache => import_apache(contents, &mut imported),
+        RulesFormat::Nginx => import_nginx(contents, &mut imported),
+        RulesFormat::Netlify => import_netlify(contents, &mut imported),
+        RulesFormat::Csv => import_csv(contents, &args.csv_columns, &mut imported),
+    }
+    Some(imported)
+}
+
+/// A translated rule.
+struct Redirect {
+    source: String,
+    target: String,
+    status_code: Option<u16>,
+    host: Option<String>,
+    /// Must be `None` for regex rules
+    query: Option<QueryMode>,
+}
+
+impl Display for Redirect {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.source, self.target)?;
+        if let Some(status_code) = self.status_code {
+            write!(f, " {status_code}")?;
+        }
+        if let Some(host) = &self.host {
+            write!(f, " host={host}")?;
+        }
+        if let Some(query) = self.query {
+            write!(f, " query={}", query.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits absolute source URLs into the path and query, and the host they are restricted to.
+/// Other sources are returned as they are.
+fn split_source(source: &str) -> Result<(String, Option<String>), String> {
+    if !source.starts_with("http://") && !source.starts_with("https://") {
+        return Ok((source.to_string(), None));
+    }
+    let url = Url::parse(source).map_err(|e| format!("Invalid source URL '{source}': {e}"))?;
+    if url.port().is_some() {
+        return Err(format!(
+            "Source URL '{source}' has a port, which isn't supported"
+        ));
+    }
+    let host = url.host_str().unwrap_or_default().to_string();
+    let path = match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_string(),
+    };
+    Ok((path, Some(host)))
+}
+
+/// Rejects targets using server variables, or `$0`, which refers to the whole match in Apache and
+/// nginx, but isn't supported by regex rules.
+fn check_variables(target: &str) -> Result<(), String> {
+    let mut rest = target;
+    while let Some(index) = rest.find('$') {
+        rest = &rest[index + 1..];
+        let variable: String = rest
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '{' | '}'))
+            .collect();
+        if variable == "0" || variable.starts_with(|c: char| !c.is_ascii_digit()) {
+            return Err(format!(
+                "Target '{target}' uses the variable '${variable}', which isn't supported"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the literal or wildcard source equivalent to a regex, if it only matches a fixed path,
+/// or a fixed prefix with the rest of the path captured by its only group.
+fn literal_source(pattern: &str) -> Option<String> {
+    let body = pattern.strip_prefix('^')?.strip_suffix('$')?;
+    let (body, wildcard) = match body.strip_suffix("(.*)") {
+        Some(prefix) => (prefix, true),
+        None => (body, false),
+    };
+    let mut source = String::new();
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                c if c.is_ascii_alphanumeric() => return None,
+                c => source.push(c),
+            },
+            '.' | '^' | '$' | '*' | '+' | '?' | '(' | ')' | '[' | ']' | '{' | '}' | '|' => {
+                return None;
+            }
+            c => source.push(c),
+        }
+    }
+    if !source.starts_with('/') || source.contains('*') {
+        return None;
+    }
+    if wildcard {
+        source.push('*');
+    }
+    Some(source)
+}
+
+/// Translates a redirect matching the request path against a regex, anywhere in the path unless
+/// the regex is anchored, like Apache's `RedirectMatch` and nginx's `rewrite` do.
+fn regex_redirect(
+    pattern: &str,
+    case_insensitive: bool,
+    target: &str,
+    status_code: u16,
+    query: QueryMode,
+) -> Redirect {
+    let uses_captures = target.contains('$');
+    if let Some(source) = literal_source(pattern)
+        .filter(|source| !case_insensitive && (source.ends_with('*') || !uses_captures))
+    {
+        return Redirect {
+            source,
+            target: target.to_string(),
+            status_code: Some(status_code),
+            host: None,
+            query: Some(query),
+        };
+    }
+
+    let (prefix, pattern) = match pattern.strip_prefix('^') {
+        Some(pattern) => ("", pattern),
+        None => (".*", pattern),
+    };
+    let (pattern, suffix) = match pattern.strip_suffix('$') {
+        Some(pattern) if !pattern.ends_with('\\') => (pattern, ANY_QUERY),
+        _ => (pattern, ".*"),
+    };
+    let flags = if case_insensitive { "(?i)" } else { "" };
+    Redirect {
+        source: format!("~{flags}{prefix}{pattern}{suffix}"),
+        target: target.to_string(),
+        status_code: Some(status_code),
+        host: None,
+        query: None,
+    }
+}
+
+/// Splits a line into whitespace separated arguments, unquoting double-quoted ones.
+fn split_arguments(line: &str) -> Result<Vec<String>, String> {
+    let mut arguments = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut argument = String::new();
+        if c == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => argument.extend(chars.next()),
+                    Some(c) => argument.push(c),
+                    None => return Err("Unterminated quoted argument".to_string()),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                argument.push(c);
+            }
+        }
+        arguments.push(argument);
+    }
+    Ok(arguments)
+}
+
+fn import_apache(contents: &str, imported: &mut Imported) {
+    for (line_no, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let arguments = match split_arguments(line) {
+            Ok(arguments) => arguments,
+            Err(message) => {
+                imported.untranslatable.push((line_no, message));
+                continue;
+            }
+        };
+        let Some((directive, arguments)) = arguments.split_first() else {
+            continue;
+        };
+        let redirects = match directive.to_ascii_lowercase().as_str() {
+            "redirect" => apache_redirect(arguments, None),
+            "redirectpermanent" => apache_redirect(arguments, Some(301)),
+            "redirecttemp" => apache_redirect(arguments, Some(302)),
+            "redirectmatch" => apache_redirect_match(arguments),
+            "rewriterule" => Err(
+                "RewriteRule isn't supported, use Redirect or RedirectMatch instead".to_string(),
+            ),
+            _ => continue,
+        };
+        imported.push(line_no, redirects);
+    }
+}
+
+/// Splits the optional status off the arguments of `Redirect` and `RedirectMatch`, which default
+/// to 302.
+fn apache_status(arguments: &[String]) -> Result<(u16, &[String]), String> {
+    let Some((status, rest)) = arguments.split_first() else {
+        return Err("Missing arguments".to_string());
+    };
+    let status_code = match status.to_ascii_lowercase().as_str() {
+        "permanent" => 301,
+        "temp" => 302,
+        "seeother" => 303,
+        "gone" => return Err("Status 'gone' isn't a redirect".to_string()),
+        status => match status.parse::<u16>() {
+            Ok(status_code @ 300..400) => status_code,
+            Ok(status_code) => return Err(format!("Status {status_code} isn't a redirect")),
+            Err(_) => return Ok((302, arguments)),
+        },
+    };
+    Ok((status_code, rest))
+}
+
+/// Apache doesn't append the request's query string to targets that have one. A trailing `?`
+/// drops it without adding one.
+fn apache_query(target: &str) -> (&str, QueryMode) {
+    if target.contains('?') {
+        (
+            target.strip_suffix('?').unwrap_or(target),
+            QueryMode::Ignore,
+        )
+    } else {
+        (target, QueryMode::Forward)
+    }
+}
+
+fn apache_redirect(
+    arguments: &[String],
+    status_code: Option<u16>,
+) -> Result<Vec<Redirect>, String> {
+    let (status_code, arguments) = match status_code {
+        Some(status_code) => (status_code, arguments),
+        None => apache_status(arguments)?,
+    };
+    let [path, target] = arguments else {
+        return Err("Expected a path and a target".to_string());
+    };
+    let (path, host) = split_source(path)?;
+    let (target, query) = apache_query(target);
+
+    // `Redirect` matches whole path segments, and appends the rest of the path to the target
+    let redirect = |source: String, target: String| Redirect {
+        source,
+        target,
+        status_code: Some(status_code),
+        host: host.clone(),
+        query: Some(query),
+    };
+    if path.ends_with('/') {
+        return Ok(vec![redirect(format!("{path}*"), format!("{target}$1"))]);
+    }
+    Ok(vec![
+        redirect(path.clone(), target.to_string()),
+        redirect(format!("{path}/*"), format!("{target}/$1")),
+    ])
+}
+
+fn apache_redirect_match(arguments: &[String]) -> Result<Vec<Redirect>, String> {
+    let (status_code, arguments) = apache_status(arguments)?;
+    let [pattern, target] = arguments else {
+        return Err("Expected a regex and a target".to_string());
+    };
+    let (target, query) = apache_query(target);
+    check_variables(target)?;
+    Ok(vec![regex_redirect(
+        pattern,
+        false,
+        target,
+        status_code,
+        query,
+    )])
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum NginxToken {
+    Word(String),
+    /// `{`
+    Open,
+    /// `}`
+    Close,
+    /// `;`
+    End,
+}
+
+/// Splits a line of an nginx config into tokens, skipping comments.
+fn nginx_tokens(line: &str) -> Result<Vec<NginxToken>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => break,
+            '{' => tokens.push(NginxToken::Open),
+            '}' => tokens.push(NginxToken::Close),
+            ';' => tokens.push(NginxToken::End),
+            c if c.is_whitespace() => {}
+            '"' | '\'' => {
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some(quote) if quote == c => break,
+                        Some('\\') => word.extend(chars.next()),
+                        Some(c) => word.push(c),
+                        None => return Err("Unterminated quoted argument".to_string()),
+                    }
+                }
+                tokens.push(NginxToken::Word(word));
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) =
+                    chars.next_if(|c| !c.is_whitespace() && !matches!(c, '{' | '}' | ';'))
+                {
+                    word.push(c);
+                }
+                tokens.push(NginxToken::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// A block of an nginx config, e.g. `server` or `location = /a`.
+enum NginxBlock {
+    Server { names: Vec<String> },
+    Location { modifier: String, path: String },
+    Other(String),
+}
+
+impl NginxBlock {
+    fn new(header: Vec<String>) -> Self {
+        let mut header = header.into_iter();
+        match header.next().as_deref() {
+            Some("server") => Self::Server { names: Vec::new() },
+            Some("location") => match (header.next(), header.next()) {
+                (Some(modifier), Some(path)) => Self::Location { modifier, path },
+                (Some(path), None) => Self::Location {
+                    modifier: String::new(),
+                    path,
+                },
+                _ => Self::Other("location".to_string()),
+            },
+            name => Self::Other(name.unwrap_or_default().to_string()),
+        }
+    }
+}
+
+fn import_nginx(contents: &str, imported: &mut Imported) {
+    let mut blocks = Vec::new();
+    let mut statement = Vec::new();
+    let mut statement_line = 0;
+    for (line_no, line) in contents.lines().enumerate() {
+        let tokens = match nginx_tokens(line) {
+            Ok(tokens) => tokens,
+            Err(message) => {
+                imported.untranslatable.push((line_no, message));
+                continue;
+            }
+        };
+        for token in tokens {
+            // Statements can span lines, and are reported on the line they start on
+            if statement.is_empty() {
+                statement_line = line_no;
+            }
+            match token {
+                NginxToken::Word(word) => statement.push(word),
+                NginxToken::Open => blocks.push(NginxBlock::new(std::mem::take(&mut statement))),
+                NginxToken::Close => {
+                    blocks.pop();
+                    statement.clear();
+                }
+                NginxToken::End => {
+                    let statement = std::mem::take(&mut statement);
+                    nginx_statement(&statement, &mut blocks, statement_line, imported);
+                }
+            }
+        }
+    }
+}
+
+fn nginx_statement(
+    statement: &[String],
+    blocks: &mut [NginxBlock],
+    line_no: usize,
+    imported: &mut Imported,
+) {
+    let Some((directive, arguments)) = statement.split_first() else {
+        return;
+    };
+    if directive == "server_name" {
+        if let Some(NginxBlock::Server { names }) = blocks.last_mut() {
+            names.extend(arguments.iter().cloned());
+        }
+        return;
+    }
+    let redirects = match directive.as_str() {
+        "rewrite" => nginx_rewrite(arguments),
+        "return" => match nginx_return(arguments, blocks) {
+            Some(redirects) => redirects,
+            None => return,
+        },
+        _ => return,
+    };
+    // Only redirects in server and location blocks apply unconditionally
+    let redirects = match blocks.iter().find_map(|block| match block {
+        NginxBlock::Other(name) if name != "http" => Some(name),
+        _ => None,
+    }) {
+        Some(name) => Err(format!("Redirects in '{name}' blocks aren't supported")),
+        None => redirects,
+    };
+    let redirects = redirects.and_then(|redirects| nginx_hosts(redirects, blocks));
+    imported.push(line_no, redirects);
+}
+
+fn nginx_rewrite(arguments: &[String]) -> Result<Vec<Redirect>, String> {
+    let (pattern, replacement, flag) = match arguments {
+        [pattern, replacement] => (pattern, replacement, None),
+        [pattern, replacement, flag] => (pattern, replacement, Some(flag.as_str())),
+        _ => return Err("Expected a regex, a replacement, and an optional flag".to_string()),
+    };
+    let absolute = ["http://", "https://", "$scheme://"]
+        .iter()
+        .any(|scheme| replacement.starts_with(scheme));
+    let status_code = match flag {
+        Some("permanent") => 301,
+        Some("redirect") => 302,
+        None if absolute => 302,
+        _ => return Err("Internal rewrites aren't redirects".to_string()),
+    };
+    // nginx appends the request's query string unless the replacement ends with `?`
+    let (target, query) = match replacement.strip_suffix('?') {
+        Some(target) => (target, QueryMode::Ignore),
+        None => (replacement.as_str(), QueryMode::Forward),
+    };
+    check_variables(target)?;
+    Ok(vec![regex_redirect(
+        pattern,
+        false,
+        target,
+        status_code,
+        query,
+    )])
+}
+
+/// Translates `return` directives with a redirect status code, which apply to the paths matched
+/// by the location they are in. Returns `None` for other responses.
+fn nginx_return(
+    arguments: &[String],
+    blocks: &[NginxBlock],
+) -> Option<Result<Vec<Redirect>, String>> {
+    let (status_code, target) = match arguments {
+        [code, target] => match code.parse::<u16>() {
+            Ok(status_code @ (301 | 302 | 303 | 307 | 308)) => (status_code, target),
+            _ => return None,
+        },
+        [target] if target.starts_with("http://") || target.starts_with("https://") => {
+            (302, target)
+        }
+        _ => return None,
+    };
+    if let Err(message) = check_variables(target) {
+        return Some(Err(message));
+    }
+    // `return` doesn't append the request's query string
+    let redirect = |source: String| Redirect {
+        source,
+        target: target.clone(),
+        status_code: Some(status_code),
+        host: None,
+        query: Some(QueryMode::Ignore),
+    };
+    let location = blocks.iter().rev().find_map(|block| match block {
+        NginxBlock::Location { modifier, path } => Some((modifier.as_str(), path)),
+        _ => None,
+    });
+    let redirect = match location {
+        Some(("=", path)) => redirect(path.clone()),
+        // Prefix locations match any path starting with them, even in the middle of a segment
+        Some(("" | "^~", path)) => redirect(format!("{path}*")),
+        Some((modifier @ ("~" | "~*"), pattern)) => regex_redirect(
+            pattern,
+            modifier == "~*",
+            target,
+            status_code,
+            QueryMode::Ignore,
+        ),
+        Some((modifier, _)) => {
+            return Some(Err(format!("Unknown location modifier '{modifier}'")));
+        }
+        None => {
+            return Some(Err(
+                "'return' outside of a location block applies to every request".to_string(),
+            ));
+        }
+    };
+    Some(Ok(vec![redirect]))
+}
+
+/// Restricts redirects to the names of the server block they are in, if it has any.
+fn nginx_hosts(redirects: Vec<Redirect>, blocks: &[NginxBlock]) -> Result<Vec<Redirect>, String> {
+    let Some(names) = blocks.iter().rev().find_map(|block| match block {
+        NginxBlock::Server { names } => Some(names),
+        _ => None,
+    }) else {
+        return Ok(redirects);
+    };
+    // `_` and empty names are conventionally used for catch-all servers
+    let names: Vec<_> = names
+        .iter()
+        .filter(|name| !name.is_empty() && *name != "_")
+        .collect();
+    if let Some(name) = names
+        .iter()
+        .find(|name| name.starts_with(['~', '.', '*']) || name.ends_with('*'))
+    {
+        return Err(format!(
+            "Server name '{name}' is a wildcard or regex, which isn't supported"
+        ));
+    }
+    if names.is_empty() {
+        return Ok(redirects);
+    }
+    Ok(redirects
+        .iter()
+        .flat_map(|redirect| {
+            names.iter().map(|name| Redirect {
+                source: redirect.source.clone(),
+                target: redirect.target.clone(),
+                status_code: redirect.status_code,
+                host: Some(name.to_ascii_lowercase()),
+                query: redirect.query,
+            })
+        })
+        .collect())
+}
+
+fn import_netlify(contents: &str, imported: &mut Imported) {
+    for (line_no, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let arguments: Vec<_> = line.split_whitespace().collect();
+        imported.push(line_no, netlify_redirect(&arguments));
+    }
+}
+
+fn netlify_redirect(arguments: &[&str]) -> Result<Vec<Redirect>, String> {
+    let Some((from, arguments)) = arguments.split_first() else {
+        return Err("Missing arguments".to_string());
+    };
+    // Query parameters to match come before the target, and conditions after the status
+    let is_parameter = |argument: &&str| {
+        argument.contains('=') && !argument.starts_with('/') && !argument.contains("://")
+    };
+    let Some(to_index) = arguments
+        .iter()
+        .position(|argument| !is_parameter(argument))
+    else {
+        return Err("Missing target".to_string());
+    };
+    if let Some(parameter) = arguments[..to_index].first() {
+        return Err(format!(
+            "Matching query parameters isn't supported: '{parameter}'"
+        ));
+    }
+    let to = arguments[to_index];
+    let mut rest = arguments[to_index + 1..].iter();
+    let status_code = match rest.clone().next() {
+        Some(status) if !is_parameter(status) => {
+            rest.next();
+            // Forced rules also apply to paths with content, which rules always do
+            match status.trim_end_matches('!').parse::<u16>() {
+                Ok(status_code @ 300..400) => status_code,
+                Ok(200) => return Err("Rewrites with status 200 aren't redirects".to_string()),
+                Ok(status_code) => return Err(format!("Status {status_code} isn't a redirect")),
+                Err(_) => return Err(format!("Invalid status '{status}'")),
+            }
+        }
+        _ => 301,
+    };
+    if let Some(condition) = rest.next() {
+        return Err(format!("Conditions aren't supported: '{condition}'"));
+    }
+
+    let (path, host) = split_source(from)?;
+    // Placeholders match a whole path segment, and a trailing `*` the rest of the path
+    let segments: Vec<_> = path.split('/').collect();
+    let mut placeholders = Vec::new();
+    let mut pattern = Vec::new();
+    for (index, segment) in segments.iter().enumerate() {
+        if *segment == "*" && index == segments.len() - 1 {
+            placeholders.push("splat");
+            pattern.push("(.*)".to_string());
+        } else if let Some(name) = segment.strip_prefix(':') {
+            placeholders.push(name);
+            pattern.push("([^/]+)".to_string());
+        } else {
+            pattern.push(regex_syntax::escape(segment));
+        }
+    }
+
+    // Longer names first, so that `:id` doesn't replace the start of `:identifier`
+    let mut target = to.to_string();
+    let mut names: Vec<_> = placeholders.iter().enumerate().collect();
+    names.sort_by_key(|(_, name)| std::cmp::Reverse(name.len()));
+    for (index, name) in names {
+        target = target.replace(&format!(":{name}"), &format!("${}", index + 1));
+    }
+
+    if placeholders.iter().all(|name| *name == "splat") {
+        return Ok(vec![Redirect {
+            source: path,
+            target,
+            status_code: Some(status_code),
+            host,
+            query: Some(QueryMode::Forward),
+        }]);
+    }
+    Ok(vec![Redirect {
+        source: format!("~{}{ANY_QUERY}", pattern.join("/")),
+        target,
+        status_code: Some(status_code),
+        host,
+        query: None,
+    }])
+}
+
+/// Splits a CSV row into its fields, unquoting quoted ones.
+fn csv_fields(row: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = row.chars().peekable();
+    loop {
+        match chars.next() {
+            Some('"') if quoted => {
+                if chars.next_if_eq(&'"').is_some() {
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            Some('"') if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            Some(',') if !quoted => fields.push(std::mem::take(&mut field)),
+            Some(c) => field.push(c),
+            None if quoted => return Err("Unterminated quoted field".to_string()),
+            None => {
+                fields.push(field);
+                return Ok(fields.into_iter().map(|f| f.trim().to_string()).collect());
+            }
+        }
+    }
+}
+
+fn import_csv(contents: &str, columns: &CsvColumns, imported: &mut Imported) {
+    let mut rows = contents
+        .trim_start_matches('\u{feff}')
+        .lines()
+        .enumerate()
+        .filter(|(_, row)| !row.trim().is_empty());
+
+    let mut header = Vec::new();
+    let mut header_line = 0;
+    if columns.has_header() {
+        let Some((line_no, row)) = rows.next() else {
+            return;
+        };
+        header_line = line_no;
+        match csv_fields(row) {
+            Ok(fields) => header = fields,
+            Err(message) => {
+                imported.untranslatable.push((line_no, message));
+                return;
+            }
+        }
+    }
+    let index = |column: &Column| match column {
+        Column::Index(index) => Some(*index),
+        Column::Name(name) => header.iter().position(|h| h.eq_ignore_ascii_case(name)),
+    };
+    let (Some(source), Some(target)) = (index(&columns.source), index(&columns.target)) else {
+        imported.untranslatable.push((
+            header_line,
+            "Header row is missing the source or target column".to_string(),
+        ));
+        return;
+    };
+    let status = columns.status.as_ref().and_then(index);
+
+    for (line_no, row) in rows {
+        let redirect = csv_fields(row).and_then(|fields| {
+            let field = |index: usize, name: &str| match fields.get(index) {
+                Some(field) if !field.is_empty() => Ok(field.as_str()),
+                _ => Err(format!("Missing {name}")),
+            };
+            let (from, to) = (field(source, "source")?, field(target, "target")?);
+            if from.contains(char::is_whitespace) || to.contains(char::is_whitespace) {
+                return Err("Sources and targets can't contain whitespace".to_string());
+            }
+            let status_code = match status.and_then(|index| fields.get(index)) {
+                Some(status) if !status.is_empty() => Some(
+                    status
+                        .parse::<u16>()
+                        .map_err(|_| format!("Invalid status '{status}'"))?,
+                ),
+                _ => None,
+            };
+            let (source, host) = split_source(from)?;
+            Ok(vec![Redirect {
+                source,
+                target: to.to_string(),
+                status_code,
+                host,
+                query: None,
+            }])
+        });
+        imported.push(line_no, redirect);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import_as(rules_format: RulesFormat, contents: &str) -> Imported {
+        let args = ImportArgs {
+            rules_format,
+            csv_columns: CsvColumns::parse("source=1,target=2,status=3").unwrap(),
+        };
+        import(&args, contents).unwrap()
+    }
+
+    fn rules(imported: &Imported) -> Vec<(usize, &str)> {
+        imported
+            .rules
+            .iter()
+            .map(|(line_no, rule)| (*line_no, rule.as_str()))
+            .collect()
+    }
+
+    fn untranslatable(imported: &Imported) -> Vec<usize> {
+        imported
+            .untranslatable
+            .iter()
+            .map(|(line_no, _)| *line_no)
+            .collect()
+    }
+
+    #[test]
+    fn test_apache() {
+        let imported = import_as(
+            RulesFormat::Apache,
+            r#"
+# Comment
+RewriteEngine On
+Redirect permanent /old https://example.com/new
+Redirect /blog/ /news/
+RedirectMatch 301 ^/posts/(\d+)$ /articles/$1
+RedirectMatch "^/docs/(.*)$" /documentation/$1?
+Redirect gone /removed
+RewriteRule ^/a$ /b [R=301]
+RedirectMatch 302 ^/x$ https://$host/y
+"#,
+        );
+        assert_eq!(
+            rules(&imported),
+            vec![
+                (3, "/old https://example.com/new 301 query=forward"),
+                (3, "/old/* https://example.com/new/$1 301 query=forward"),
+                (4, "/blog/* /news/$1 302 query=forward"),
+                (5, r"~/posts/(\d+)(?:\?.*)? /articles/$1 301"),
+                (6, "/docs/* /documentation/$1 302 query=ignore"),
+            ]
+        );
+        assert_eq!(untranslatable(&imported), vec![7, 8, 9]);
+    }
+
+    #[test]
+    fn test_nginx() {
+        let imported = import_as(
+            RulesFormat::Nginx,
+            r#"
+server {
+    listen 80;
+    server_name old.example.com www.old.example.com;
+    rewrite ^/a$ /b permanent;
+    rewrite ^/c/(.*)$ https://example.com/d/$1? redirect;
+    rewrite ^/internal$ /other last;
+    location = /exact { return 301 /target; }
+    location /prefix {
+        return 302 /landing;
+    }
+    location ~* ^/legacy/(\d+)$ {
+        return 308 /modern/$1;
+    }
+    location /all { return 301 https://new.example.com$request_uri; }
+    location /ok { return 200 "ok"; }
+    if ($http_user_agent ~ Bot) {
+        return 301 /bots;
+    }
+}
+server {
+    server_name _;
+    rewrite ^/(?<name>x)+ /y permanent;
+}
+"#,
+        );
+        assert_eq!(
+            rules(&imported),
+            vec![
+                (4, "/a /b 301 host=old.example.com query=forward"),
+                (4, "/a /b 301 host=www.old.example.com query=forward"),
+                (
+                    5,
+                    "/c/* https://example.com/d/$1 302 host=old.example.com query=ignore"
+                ),
+                (
+                    5,
+                    "/c/* https://example.com/d/$1 302 host=www.old.example.com query=ignore"
+                ),
+                (7, "/exact /target 301 host=old.example.com query=ignore"),
+                (
+                    7,
+                    "/exact /target 301 host=www.old.example.com query=ignore"
+                ),
+                (9, "/prefix* /landing 302 host=old.example.com query=ignore"),
+                (
+                    9,
+                    "/prefix* /landing 302 host=www.old.example.com query=ignore"
+                ),
+                (
+                    12,
+                    r"~(?i)/legacy/(\d+)(?:\?.*)? /modern/$1 308 host=old.example.com"
+                ),
+                (
+                    12,
+                    r"~(?i)/legacy/(\d+)(?:\?.*)? /modern/$1 308 host=www.old.example.com"
+                ),
+                (22, "~/(?<name>x)+.* /y 301"),
+            ]
+        );
+        assert_eq!(untranslatable(&imported), vec![6, 14, 17]);
+    }
+
+    #[test]
+    fn test_netlify() {
+        let imported = import_as(
+            RulesFormat::Netlify,
+            r#"
+# Comment
+/home              /
+/blog/*            /news/:splat         302
+/news/:year/:month /archive/:month-:year
+https://old.example.com/* https://example.com/:splat 301!
+/app/*             /index.html          200
+/store id=:id      /products/:id        301
+/de/*              /de/index.html       302  Language=de
+"#,
+        );
+        assert_eq!(
+            rules(&imported),
+            vec![
+                (2, "/home / 301 query=forward"),
+                (3, "/blog/* /news/$1 302 query=forward"),
+                (4, r"~/news/([^/]+)/([^/]+)(?:\?.*)? /archive/$2-$1 301"),
+                (
+                    5,
+                    "/* https://example.com/$1 301 host=old.example.com query=forward"
+                ),
+            ]
+        );
+        assert_eq!(untranslatable(&imported), vec![6, 7, 8]);
+    }
+
+    #[test]
+    fn test_csv() {
+        let imported = import_as(
+            RulesFormat::Csv,
+            "/a,/b,301\n\n\"/c\",\"/d,e\"\nhttps://example.com/f?x=1,/g,\n/h,,\n\"/i,/j\n",
+        );
+        assert_eq!(
+            rules(&imported),
+            vec![
+                (0, "/a /b 301"),
+                (2, "/c /d,e"),
+                (3, "/f?x=1 /g host=example.com"),
+            ]
+        );
+        assert_eq!(untranslatable(&imported), vec![4, 5]);
+
+        let args = ImportArgs {
+            rules_format: RulesFormat::Csv,
+            csv_columns: CsvColumns::parse("source=Old URL,target=new url").unwrap(),
+        };
+        let imported = import(&args, "\u{feff}id,old url,New URL\n1,/a,/b\n2,/c,/d").unwrap();
+        assert_eq!(rules(&imported), vec![(1, "/a /b"), (2, "/c /d")]);
+        assert!(imported.untranslatable.is_empty());
+
+        let imported = import(&args, "id,source,target\n1,/a,/b").unwrap();
+        assert!(imported.rules.is_empty());
+        assert_eq!(untranslatable(&imported), vec![0]);
+    }
+
+    #[test]
+    fn test_csv_columns() {
+        assert_eq!(
+            CsvColumns::parse("target=2,source=1"),
+            Ok(CsvColumns {
+                source: Column::Index(0),
+                target: Column::Index(1),
+                status: None,
+            })
+        );
+        assert!(CsvColumns::parse("source=1").is_err());
+        assert!(CsvColumns::parse("source=0,target=1").is_err());
+        assert!(CsvColumns::parse("source=1,target=2,code=3").is_err());
+    }
+
+    #[test]
+    fn test_literal_source() {
+        assert_eq!(literal_source(r"^/a\.html$").as_deref(), Some("/a.html"));
+        assert_eq!(literal_source("^/a/(.*)$").as_deref(), Some("/a/*"));
+        assert_eq!(literal_source("^/a.html$"), None);
+        assert_eq!(literal_source("^/a/(.*)"), None);
+        assert_eq!(literal_source(r"^/a/\d$"), None);
+        assert_eq!(literal_source("/a$"), None);
+    }
+}
@@ -0,0 +1,505 @@
+//! Inspection of encoded bundles: dumping them back to rules files, looking up requests, printing
+//! statistics, and comparing two bundles.
+//!
+//! Lookups go through the same lookup code as the Wasm component, `redirects_core::lookup`, so they
+//! match the rule and target it would answer a request with, including the status codes of legacy bundles
+//! and the handling of query strings, methods, conditional targets and schedules.
+
+use crate::GENERATED_FILE_HEADER;
+use anyhow::{Context, Result, anyhow, bail};
+use redirects_core::bundle::{self, Bundle, DecodedRule, FLAG_NORMALIZED_SOURCES};
+use redirects_core::native::{self, NativeRedirects, NativeRequest};
+use redirects_core::rule::{QueryMode, is_wildcard_source};
+use redirects_core::schedule::{format_time, parse_time};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::fs::read;
+use std::path::{Path, PathBuf};
+
+/// The most redirects followed when measuring chains, beyond which a chain counts as a loop.
+const MAX_CHAIN_LEN: usize = 32;
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct DumpArgs {
+    /// Path to the bundle to dump
+    bundle: PathBuf,
+
+    /// Path to write the rules file to. Default is to print it.
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct LookupArgs {
+    /// Path to the bundle to look the request up in
+    bundle: PathBuf,
+
+    /// Path of the request, with the query string if it has one
+    path: String,
+
+    /// Host of the request. Default is to only match rules for all hosts.
+    #[arg(long)]
+    host: Option<String>,
+
+    /// Method of the request
+    #[arg(long, default_value = "GET")]
+    method: String,
+
+    /// Request header as `name:value`, for rules with conditional targets. Can be repeated.
+    #[arg(long = "header", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+
+    /// Time of the request, as `YYYY-MM-DDTHH:MM:SSZ`, for rules with schedules. Default is now.
+    #[arg(long, value_parser = parse_at)]
+    at: Option<u64>,
+
+    /// Query mode the Wasm component is built with, used for rules without a `query` option
+    #[arg(long, value_parser = parse_query_mode, default_value = "exact")]
+    query: QueryMode,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct StatsArgs {
+    /// Path to the bundle to print statistics for
+    bundle: PathBuf,
+
+    /// Query mode the Wasm component is built with, used for rules without a `query` option
+    #[arg(long, value_parser = parse_query_mode, default_value = "exact")]
+    query: QueryMode,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct DiffArgs {
+    /// Path to the old bundle
+    old: PathBuf,
+
+    /// Path to the new bundle
+    new: PathBuf,
+}
+
+fn parse_header(input: &str) -> Result<(String, String), String> {
+    let (name, value) = input
+        .split_once(':')
+        .ok_or_else(|| format!("Invalid header '{input}', expected name:value"))?;
+    Ok((name.trim().to_string(), value.trim().to_string()))
+}
+
+fn parse_at(input: &str) -> Result<u64, String> {
+    parse_time(input)
+        .ok_or_else(|| format!("Invalid time '{input}', expected YYYY-MM-DDTHH:MM:SSZ"))
+}
+
+fn parse_query_mode(input: &str) -> Result<QueryMode, String> {
+    QueryMode::parse(input).ok_or_else(|| format!("Invalid query mode '{input}'"))
+}
+
+fn read_bundle(path: &Path) -> Result<Vec<u8>> {
+    let bytes = read(path).with_context(|| format!("Failed to read bundle {}", path.display()))?;
+    bundle::decode(&bytes).with_context(|| format!("Invalid bundle {}", path.display()))?;
+    Ok(bytes)
+}
+
+fn native_redirects(bytes: Vec<u8>, query: QueryMode) -> Result<NativeRedirects> {
+    NativeRedirects::decode_with_query_mode(bytes, query).map_err(|e| anyhow!(e))
+}
+
+pub(crate) fn dump(args: &DumpArgs) -> Result<()> {
+    let bytes = read_bundle(&args.bundle)?;
+    let bundle = bundle::decode(&bytes)?;
+    let rules = bundle.rules();
+    let contents = dump_rules(&rules, bundle.header.default_status_code);
+    match &args.output {
+        Some(path) => {
+            std::fs::write(path, contents)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            eprintln!("Wrote {} rules to {}", rules.len(), path.display());
+        }
+        None => print!("{contents}"),
+    }
+    Ok(())
+}
+
+/// Writes `rules` the way validated rules files are written, so that dumping a bundle gives back
+/// the rules file it was built from.
+fn dump_rules(rules: &[DecodedRule], default_status_code: u16) -> String {
+    let (regex_rules, rules): (Vec<_>, Vec<_>) =
+        rules.iter().partition(|rule| rule.rule().is_regex());
+    let mut lines: Vec<_> = rules
+        .iter()
+        .map(|rule| rule_line(rule, Some(default_status_code)))
+        .collect();
+    lines.sort();
+    // Regex rules come last, in the order they take precedence in
+    lines.extend(
+        regex_rules
+            .iter()
+            .map(|rule| rule_line(rule, Some(default_status_code))),
+    );
+    let mut contents = format!("{GENERATED_FILE_HEADER}\n");
+    for line in lines {
+        contents.push_str(&line);
+        contents.push('\n');
+    }
+    contents
+}
+
+/// Formats a rule as a line of a rules file, leaving out its status code if it's the default.
+fn rule_line(rule: &DecodedRule, default_status_code: Option<u16>) -> String {
+    if Some(rule.status_code) == default_status_code {
+        format!("{} {}{}", rule.from, rule.to, rule.options)
+    } else {
+        format!(
+            "{} {} {}{}",
+            rule.from, rule.to, rule.status_code, rule.options
+        )
+    }
+}
+
+pub(crate) fn lookup(args: &LookupArgs) -> Result<()> {
+    let redirects = native_redirects(read_bundle(&args.bundle)?, args.query)?;
+    let request = NativeRequest {
+        host: args.host.as_deref(),
+        path: &args.path,
+        method: &args.method,
+        headers: &args.headers,
+        now: args.at.unwrap_or_else(native::now),
+    };
+    let response = redirects
+        .inspect(&request)
+        .ok_or_else(|| anyhow!("No rule matches {}", args.path))?;
+    if response.wildcard {
+        println!("Rule: {} (wildcard)", response.rule);
+    } else {
+        println!("Rule: {}", response.rule);
+    }
+    println!("Status: {}", response.status_code);
+    if let Some(target) = &response.target {
+        println!("Location: {}", String::from_utf8_lossy(target));
+    }
+    for (name, value) in &response.headers {
+        println!("{name}: {}", String::from_utf8_lossy(value));
+    }
+    if let Some(until) = response.valid_until {
+        println!("Expires: {}", format_time(until));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Stats {
+    rules: usize,
+    wildcard_rules: usize,
+    regex_rules: usize,
+    host_rules: usize,
+    unique_targets: usize,
+    /// Number of rules per status code
+    status_codes: BTreeMap<u16, usize>,
+    encoded_size: usize,
+    /// Size of the bundle's rules as a rules file
+    text_size: usize,
+    chains: Chains,
+}
+
+/// Redirects whose target is redirected again. Regex rules aren't followed.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Chains {
+    /// Number of rules redirecting to the source of another rule
+    chained_rules: usize,
+    /// The most redirects a request is sent through, and the rule it starts with
+    longest: Option<(usize, String)>,
+    /// Number of rules whose redirects never end
+    looping_rules: usize,
+}
+
+pub(crate) fn stats(args: &StatsArgs) -> Result<()> {
+    let bytes = read_bundle(&args.bundle)?;
+    let bundle = bundle::decode(&bytes)?;
+    let redirects = native_redirects(bytes.clone(), args.query)?;
+    let stats = collect_stats(&bundle, &redirects);
+    let header = &bundle.header;
+
+    println!("Format version: {}", bundle.format_version);
+    println!("Created: {}", format_time(header.timestamp));
+    println!("Default status code: {}", header.default_status_code);
+    println!(
+        "Normalized sources: {}",
+        header.flags & FLAG_NORMALIZED_SOURCES != 0
+    );
+    println!(
+        "Rules: {} ({} wildcard, {} regex, {} host-specific)",
+        stats.rules, stats.wildcard_rules, stats.regex_rules, stats.host_rules
+    );
+    for (status_code, count) in &stats.status_codes {
+        println!("  {status_code}: {count}");
+    }
+    println!("Unique targets: {}", stats.unique_targets);
+    println!(
+        "Size: {} bytes, {} bytes as a rules file, compression ratio {:.2}",
+        stats.encoded_size,
+        stats.text_size,
+        stats.text_size as f64 / stats.encoded_size.max(1) as f64
+    );
+    println!("Chained rules: {}", stats.chains.chained_rules);
+    if let Some((len, rule)) = &stats.chains.longest {
+        println!("Longest chain: {len} redirects, starting with {rule}");
+    }
+    println!("Rules in loops: {}", stats.chains.looping_rules);
+    Ok(())
+}
+
+fn collect_stats<D: AsRef<[u8]>>(bundle: &Bundle<D>, redirects: &NativeRedirects) -> Stats {
+    let rules = bundle.rules();
+    let mut stats = Stats {
+        rules: rules.len(),
+        unique_targets: bundle.targets.len(),
+        encoded_size: redirects.encoded_size(),
+        text_size: dump_rules(&rules, bundle.header.default_status_code).len(),
+        ..Stats::default()
+    };
+    for rule in &rules {
+        let parsed = rule.rule();
+        if parsed.is_regex() {
+            stats.regex_rules += 1;
+        } else if is_wildcard_source(&rule.from) {
+            stats.wildcard_rules += 1;
+        }
+        if rule.options.host.is_some() {
+            stats.host_rules += 1;
+        }
+        *stats.status_codes.entry(rule.status_code).or_default() += 1;
+        if parsed.is_regex() {
+            continue;
+        }
+        match chain_len(redirects, rule) {
+            Some(len) if len > 1 => {
+                stats.chains.chained_rules += 1;
+                if stats
+                    .chains
+                    .longest
+                    .as_ref()
+                    .is_none_or(|(max, _)| len > *max)
+                {
+                    stats.chains.longest = Some((len, rule_line(rule, None)));
+                }
+            }
+            Some(_) => {}
+            None => stats.chains.looping_rules += 1,
+        }
+    }
+    stats
+}
+
+/// Returns the number of redirects a request for the source of `rule` goes through, following
+/// relative targets on the same host, or `None` if they never end. Wildcard rules are followed
+/// from their prefix.
+fn chain_len(redirects: &NativeRedirects, rule: &DecodedRule) -> Option<usize> {
+    let host = rule.options.host.as_deref();
+    let mut path = rule.from.trim_end_matches('*').to_string();
+    let mut seen = HashSet::new();
+    let mut len = 0;
+    while seen.insert(path.clone()) && len < MAX_CHAIN_LEN {
+        let request = NativeRequest {
+            host,
+            path: &path,
+            method: "GET",
+            headers: &[],
+            now: native::now(),
+        };
+        let Some(target) = redirects
+            .inspect(&request)
+            .and_then(|response| response.target)
+        else {
+            return Some(len);
+        };
+        len += 1;
+        let target = String::from_utf8_lossy(&target).into_owned();
+        if !target.starts_with('/') {
+            return Some(len);
+        }
+        path = target;
+    }
+    None
+}
+
+pub(crate) fn diff(args: &DiffArgs) -> Result<()> {
+    let old_bytes = read_bundle(&args.old)?;
+    let new_bytes = read_bundle(&args.new)?;
+    let (old, new) = (bundle::decode(&old_bytes)?, bundle::decode(&new_bytes)?);
+    if old.header.flags & FLAG_NORMALIZED_SOURCES != new.header.flags & FLAG_NORMALIZED_SOURCES {
+        bail!("Bundles with and without normalized sources can't be compared");
+    }
+    if old.header.default_status_code != new.header.default_status_code {
+        println!(
+            "Default status code: {} -> {}",
+            old.header.default_status_code, new.header.default_status_code
+        );
+    }
+    let changes = diff_rules(&old.rules(), &new.rules());
+    for change in &changes {
+        println!("{change}");
+    }
+    println!("{}", change_counts(&changes));
+    Ok(())
+}
+
+/// A difference between two sets of rules, with the rules formatted as lines of a rules file.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Change {
+    Added(String),
+    Removed(String),
+    Modified(String, String),
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Added(line) => write!(f, "+ {line}"),
+            Change::Removed(line) => write!(f, "- {line}"),
+            Change::Modified(old, new) => write!(f, "- {old}\n+ {new}"),
+        }
+    }
+}
+
+/// Returns the changes from the rules in `old` to the ones in `new`, both keyed by what identifies
+/// a rule, in the order of their keys, with added rules last.
+pub(crate) fn diff_lines<K: Ord>(
+    old: BTreeMap<K, String>,
+    mut new: BTreeMap<K, String>,
+) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for (key, old_line) in old {
+        match new.remove(&key) {
+            Some(new_line) if new_line == old_line => {}
+            Some(new_line) => changes.push(Change::Modified(old_line, new_line)),
+            None => changes.push(Change::Removed(old_line)),
+        }
+    }
+    changes.extend(new.into_values().map(Change::Added));
+    changes
+}
+
+/// Summarizes `changes` as the number of added, modified and removed rules.
+pub(crate) fn change_counts(changes: &[Change]) -> String {
+    let count = |f: fn(&Change) -> bool| changes.iter().filter(|c| f(c)).count();
+    format!(
+        "{} added, {} modified, {} removed",
+        count(|c| matches!(c, Change::Added(_))),
+        count(|c| matches!(c, Change::Modified(..))),
+        count(|c| matches!(c, Change::Removed(_)))
+    )
+}
+
+/// Compares rules by host and source, listing rules with status codes even if they're the
+/// default, since bundles may have different defaults.
+fn diff_rules(old: &[DecodedRule], new: &[DecodedRule]) -> Vec<Change> {
+    let by_source = |rules: &[DecodedRule]| {
+        rules
+            .iter()
+            .map(|rule| {
+                let host = rule.options.host.clone().unwrap_or_default();
+                ((host, rule.from.clone()), rule_line(rule, None))
+            })
+            .collect::<BTreeMap<_, _>>()
+    };
+    diff_lines(by_source(old), by_source(new))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redirects_core::rule::Rule;
+
+    /// Builds a bundle from the lines of a rules file.
+    fn build(lines: &str, default_status_code: u16) -> Vec<u8> {
+        let rules = lines
+            .lines()
+            .map(|line| Rule::parse(line, default_status_code, false).unwrap())
+            .collect::<Vec<_>>();
+        let (regex_rules, rules): (Vec<_>, Vec<_>) =
+            rules.into_iter().partition(|rule| rule.is_regex());
+        let keys = rules
+            .iter()
+            .map(|rule| (rule.key(false).into_owned(), rule))
+            .collect::<Vec<_>>();
+        bundle::build(
+            default_status_code,
+            0,
+            1_700_000_000,
+            keys.iter().map(|(key, rule)| (key.as_str(), *rule)),
+            &regex_rules,
+        )
+        .unwrap()
+    }
+
+    fn rules(bytes: &[u8]) -> Vec<DecodedRule> {
+        bundle::decode(bytes).unwrap().rules()
+    }
+
+    #[test]
+    fn test_dump_rules() {
+        let lines = "/a /b 301 query=forward\n/c/* /d/$1 host=example.com\n/b /e\n~/f/(\\d+) /g/$1";
+        let dumped = dump_rules(&rules(&build(lines, 302)), 302);
+        assert_eq!(
+            dumped,
+            format!(
+                "{GENERATED_FILE_HEADER}\n/a /b 301 query=forward\n/b /e\n/c/* /d/$1 host=example.com\n~/f/(\\d+) /g/$1\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_lookup_and_stats() {
+        let bytes = build(
+            "/a /b 301\n/b /c\n/c /d\n/x/* /y/$1 host=example.com\n/l1 /l2\n/l2 /l1 301\n~/r/(\\d+) /s/$1",
+            302,
+        );
+        let redirects = native_redirects(bytes.clone(), QueryMode::Exact).unwrap();
+        let request = |host, path| NativeRequest {
+            host,
+            path,
+            method: "GET",
+            headers: &[],
+            now: 1_700_000_000,
+        };
+        let response = redirects
+            .inspect(&request(Some("Example.com:8080"), "/x/z"))
+            .unwrap();
+        assert_eq!(response.rule, "example.com/x/");
+        assert!(response.wildcard);
+        assert_eq!(response.status_code, 302);
+        assert_eq!(response.target.as_deref(), Some(&b"/y/z"[..]));
+        let response = redirects.inspect(&request(None, "/r/42")).unwrap();
+        assert_eq!(response.target.as_deref(), Some(&b"/s/42"[..]));
+        assert!(redirects.inspect(&request(None, "/x/z")).is_none());
+
+        let stats = collect_stats(&bundle::decode(&bytes).unwrap(), &redirects);
+        assert_eq!(stats.rules, 7);
+        assert_eq!(stats.wildcard_rules, 1);
+        assert_eq!(stats.regex_rules, 1);
+        assert_eq!(stats.host_rules, 1);
+        assert_eq!(stats.status_codes, BTreeMap::from([(301, 2), (302, 5)]));
+        assert_eq!(
+            stats.chains,
+            Chains {
+                chained_rules: 2,
+                longest: Some((3, "/a /b 301".to_string())),
+                looping_rules: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_diff_rules() {
+        let old = rules(&build("/a /b\n/c /d\n/e /f host=example.com", 302));
+        let new = rules(&build("/a /b\n/c /d 301\n/e /f\n~/g /h", 302));
+        assert_eq!(
+            diff_rules(&old, &new),
+            vec![
+                Change::Modified("/c /d 302".to_string(), "/c /d 301".to_string()),
+                Change::Removed("/e /f 302 host=example.com".to_string()),
+                Change::Added("/e /f 302".to_string()),
+                Change::Added("~/g /h 302".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,382 @@
+//! Checking that the targets of rules exist.
+//!
+//! Relative targets are either requested from a base URL given with `--check-targets-url`, e.g. a
+//! local server serving a snapshot of the origin, or looked up in a sitemap or a list of URLs
+//! given with `--check-targets-list`. Targets that are redirected again by other rules are left to
+//! loop detection and chain shortening, and targets built from the request, like those of most
+//! wildcard and regex rules, can't be checked.
+
+use crate::rule_index::RuleIndex;
+use crate::{
+    FailedCheck, FailedCheckReason, MapEntry, RedirectsMap, ValidationBehavior, ValidationBehaviors,
+};
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use ureq::Agent;
+use url::Url;
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct LivenessArgs {
+    /// Base URL to request relative targets from, to check that they exist. Targets are requested
+    /// with `HEAD`, and exist if the response is a success or a redirect.
+    #[arg(long, conflicts_with = "check_targets_list")]
+    pub(crate) check_targets_url: Option<Url>,
+
+    /// Sitemap, or file listing URLs or paths one per line, that relative targets must be in
+    #[arg(long)]
+    pub(crate) check_targets_list: Option<PathBuf>,
+
+    /// Maximum number of concurrent requests to `--check-targets-url`
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u16).range(1..))]
+    pub(crate) check_targets_concurrency: u16,
+
+    /// Timeout of requests to `--check-targets-url`, in seconds
+    #[arg(long, default_value_t = 10)]
+    pub(crate) check_targets_timeout: u64,
+}
+
+impl Default for LivenessArgs {
+    fn default() -> Self {
+        Self {
+            check_targets_url: None,
+            check_targets_list: None,
+            check_targets_concurrency: 8,
+            check_targets_timeout: 10,
+        }
+    }
+}
+
+/// Returns a failed check for each rule with a relative target that doesn't exist, ordered by
+/// file and line.
+pub(crate) fn check_targets<'a>(
+    args: &LivenessArgs,
+    redirects: &RedirectsMap<'a>,
+    checks: &ValidationBehaviors,
+) -> Result<Vec<FailedCheck<'a>>> {
+    let Some(checker) = TargetChecker::new(args, checks)? else {
+        return Ok(Vec::new());
+    };
+    let targets = checked_targets(redirects);
+    let paths = targets.keys().copied().collect::<Vec<_>>();
+    let missing = checker.check(&paths);
+
+    let mut failed_checks = Vec::new();
+    for (entries, reason) in targets.into_values().zip(missing) {
+        let Some(message) = reason else {
+            continue;
+        };
+        failed_checks.extend(entries.into_iter().map(|entry| FailedCheck {
+            source: entry.source,
+            line_no: entry.line_no,
+            line: entry.source.contents.lines().nth(entry.line_no).unwrap(),
+            reason: FailedCheckReason {
+                check: "missing-targets",
+                message: message.clone(),
+                severity: checks.missing_targets,
+            },
+        }));
+    }
+    failed_checks.sort_by_key(|failed| (failed.source.path, failed.line_no));
+    Ok(failed_checks)
+}
+
+/// Checks whether targets exist, by requesting them from `--check-targets-url` or looking them up
+/// in `--check-targets-list`.
+pub(crate) struct TargetChecker<'a> {
+    args: &'a LivenessArgs,
+    /// `--check-targets-list` and the paths listed in it, if it's given
+    list: Option<(&'a Path, HashSet<String>)>,
+}
+
+impl<'a> TargetChecker<'a> {
+    /// Returns a checker for the targets, or `None` if they aren't checked.
+    pub(crate) fn new(
+        args: &'a LivenessArgs,
+        checks: &ValidationBehaviors,
+    ) -> Result<Option<Self>> {
+        let (url, list) = (&args.check_targets_url, &args.check_targets_list);
+        if checks.missing_targets == ValidationBehavior::Ignore || url.is_none() && list.is_none() {
+            return Ok(None);
+        }
+        let list = match (url, list) {
+            (None, Some(path)) => {
+                let contents = read_to_string(path)
+                    .with_context(|| format!("Failed to read target list {}", path.display()))?;
+                Some((path.as_path(), listed_paths(&contents)))
+            }
+            _ => None,
+        };
+        Ok(Some(Self { args, list }))
+    }
+
+    /// Returns why each of `targets` doesn't exist, if it doesn't.
+    pub(crate) fn check(&self, targets: &[&str]) -> Vec<Option<String>> {
+        println!("Checking {} targets", targets.len());
+        match (&self.args.check_targets_url, &self.list) {
+            (Some(base), _) => request_all(self.args, base, targets),
+            (None, Some((path, listed))) => targets
+                .iter()
+                .map(|target| {
+                    (!listed.contains(without_query(target)))
+                        .then(|| format!("Target {target} isn't listed in {}", path.display()))
+                })
+                .collect(),
+            (None, None) => unreachable!(),
+        }
+    }
+}
+
+/// Whether `target`, a target of a rule for requests to `host`, can be checked: relative targets
+/// can, unless they're built from the request, or redirected again by another rule.
+pub(crate) fn is_checked(index: &impl RuleIndex, host: &str, target: &str) -> bool {
+    target.starts_with('/') && !target.contains('$') && index.follow(host, target).is_none()
+}
+
+/// Returns the relative targets that can be checked, with the rules redirecting to them.
+fn checked_targets<'r, 'a>(
+    redirects: &'r RedirectsMap<'a>,
+) -> BTreeMap<&'r str, Vec<&'r MapEntry<'a>>> {
+    let index = redirects.index();
+    let mut targets: BTreeMap<&str, Vec<&MapEntry>> = BTreeMap::new();
+    for (_, entry) in redirects.all_rules() {
+        let host = entry.options.host.as_deref().unwrap_or_default();
+        for (target, _) in entry.targets() {
+            if is_checked(&index, host, target) {
+                targets.entry(target).or_default().push(entry);
+            }
+        }
+    }
+    targets
+}
+
+/// Requests each target from `base` with at most `--check-targets-concurrency` requests at a
+/// time, returning why each target doesn't exist, if it doesn't.
+fn request_all(args: &LivenessArgs, base: &Url, targets: &[&str]) -> Vec<Option<String>> {
+    let agent: Agent = Agent::config_builder()
+        .http_status_as_error(false)
+        .max_redirects(0)
+        .timeout_global(Some(Duration::from_secs(args.check_targets_timeout)))
+        .build()
+        .into();
+    let base = base.as_str().trim_end_matches('/');
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![None; targets.len()]);
+    thread::scope(|scope| {
+        for _ in 0..usize::from(args.check_targets_concurrency).min(targets.len()) {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(target) = targets.get(index) else {
+                        break;
+                    };
+                    let missing = request(&agent, &format!("{base}{target}"));
+                    results.lock().unwrap()[index] = missing;
+                }
+            });
+        }
+    });
+    results.into_inner().unwrap()
+}
+
+/// Requests `url`, returning why it doesn't exist, if it doesn't.
+fn request(agent: &Agent, url: &str) -> Option<String> {
+    let mut response = agent.head(url).call();
+    // Not every server supports HEAD requests
+    if matches!(&response, Ok(response) if matches!(response.status().as_u16(), 405 | 501)) {
+        response = agent.get(url).call();
+    }
+    match response {
+        Ok(response) if response.status().is_success() || response.status().is_redirection() => {
+            None
+        }
+        Ok(response) => Some(format!("{url} responded with {}", response.status())),
+        Err(error) => Some(format!("Failed to request {url}: {error}")),
+    }
+}
+
+/// Returns the paths listed in a sitemap, or in a file listing URLs or paths one per line.
+fn listed_paths(contents: &str) -> HashSet<String> {
+    let entries: Vec<String> = if contents.contains("<urlset") || contents.contains("<loc>") {
+        contents
+            .split("<loc>")
+            .skip(1)
+            .filter_map(|loc| Some(unescape_xml(loc.split_once("</loc>")?.0.trim())))
+            .collect()
+    } else {
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect()
+    };
+    entries
+        .iter()
+        .filter_map(|entry| {
+            if entry.starts_with('/') {
+                return Some(without_query(entry).to_string());
+            }
+            Some(Url::parse(entry).ok()?.path().to_string())
+        })
+        .collect()
+}
+
+fn without_query(target: &str) -> &str {
+    target.split(['?', '#']).next().unwrap_or_default()
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RedirectsSource;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use tempfile::tempdir;
+
+    fn redirects<'a>(source: &'a RedirectsSource<'a>) -> RedirectsMap<'a> {
+        let mut redirects = RedirectsMap::new(302);
+        redirects.add_rules(source, &ValidationBehaviors::default());
+        assert!(redirects.parse_errors.is_empty());
+        redirects
+    }
+
+    fn rules() -> RedirectsSource<'static> {
+        RedirectsSource {
+            path: Path::new("rules"),
+            contents: "/a /exists\n/b /missing?x=1\n/c /a\n/d/* /e/$1\n/f https://example.com/g\n\
+                       /h /missing"
+                .to_string(),
+            imported: None,
+        }
+    }
+
+    #[test]
+    fn test_listed_paths() {
+        let sitemap = r#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <url><loc>https://example.com/a?b=1&amp;c=2</loc></url>
+  <url>
+    <loc> https://example.com/d/ </loc>
+  </url>
+</urlset>"#;
+        let paths = listed_paths(sitemap);
+        assert_eq!(paths.len(), 2);
+        assert!(paths.contains("/a") && paths.contains("/d/"));
+
+        let paths = listed_paths("# Pages\n/a\nhttps://example.com/b?c=d\n\n/e#top\n");
+        assert_eq!(paths.len(), 3);
+        assert!(paths.contains("/a") && paths.contains("/b") && paths.contains("/e"));
+    }
+
+    #[test]
+    fn test_check_targets_list() -> Result<()> {
+        let dir = tempdir()?;
+        let list = dir.path().join("urls.txt");
+        std::fs::write(&list, "https://example.com/exists\n")?;
+        let args = LivenessArgs {
+            check_targets_list: Some(list),
+            ..LivenessArgs::default()
+        };
+        let source = rules();
+        let redirects = redirects(&source);
+
+        // Targets of other rules, substituted targets and absolute targets aren't checked
+        let failed = check_targets(&args, &redirects, &ValidationBehaviors::default())?;
+        let lines = failed
+            .iter()
+            .map(|failed| (failed.line_no, failed.reason.severity))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [(1, ValidationBehavior::Warn), (5, ValidationBehavior::Warn)]
+        );
+        assert_eq!(failed[0].reason.check, "missing-targets");
+        assert!(
+            failed[0]
+                .reason
+                .message
+                .contains("/missing?x=1 isn't listed")
+        );
+
+        let checks = ValidationBehaviors {
+            missing_targets: ValidationBehavior::Ignore,
+            ..Default::default()
+        };
+        assert!(check_targets(&args, &redirects, &checks)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_targets_url() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let base = Url::parse(&format!("http://{}/snapshot/", listener.local_addr()?))?;
+        // Answers three requests, only finding /snapshot/exists
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for stream in listener.incoming().take(3) {
+                let mut stream = stream.unwrap();
+                let mut request_line = String::new();
+                let mut reader = BufReader::new(&stream);
+                reader.read_line(&mut request_line).unwrap();
+                let mut header = String::new();
+                while reader.read_line(&mut header).unwrap() > 2 {
+                    header.clear();
+                }
+                let status = if request_line.starts_with("HEAD /snapshot/exists ") {
+                    "200 OK"
+                } else {
+                    "404 Not Found"
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+                requests.push(request_line.trim().to_string());
+            }
+            requests
+        });
+
+        let args = LivenessArgs {
+            check_targets_url: Some(base),
+            check_targets_concurrency: 1,
+            ..LivenessArgs::default()
+        };
+        let source = rules();
+        let redirects = redirects(&source);
+        let failed = check_targets(&args, &redirects, &ValidationBehaviors::default())?;
+        assert_eq!(failed.len(), 2);
+        assert!(
+            failed[0]
+                .reason
+                .message
+                .contains("responded with 404 Not Found")
+        );
+
+        let mut requests = server.join().unwrap();
+        requests.sort();
+        assert_eq!(
+            requests,
+            [
+                "HEAD /snapshot/exists HTTP/1.1",
+                "HEAD /snapshot/missing HTTP/1.1",
+                "HEAD /snapshot/missing?x=1 HTTP/1.1"
+            ]
+        );
+        Ok(())
+    }
+}
@@ -2,28 +2,74 @@
 //!
 //! The way this works is:
 //! - we first load an existing redirect file that is known-valid
+//! - we then remove the rules listed in removal files
 //! - we then load additional redirect files provided by the user
 //!   - for each of them, we
 //!     - first check if each rule is valid by itself
 //!     - then add them to the list of rules, checking for duplicates
 //!   - we then check if the resulting list contains any loops, and abort with a descriptive error if so
-//!   - we then write the resulting list to a file
+//!   - we then replace chains of redirects with a single redirect to their end, remembering the
+//!     rules each chain went through
+//!   - we then write the resulting list to a file, and the rules added, modified and removed
+//!     compared to the existing file to a change summary
 //!   - we additionally generate optimized data structures for both rule sources and destinations
-//!     and write those to files as well
-
-use anyhow::{anyhow, Context, Result};
-use clap::{arg, Parser, ValueEnum};
-use std::cell::RefCell;
+//!     and write those to a bundle file, together with the default status code
+//! - rules can have conditional targets, taken instead of their main target depending on the
+//!   request's language, headers, or cookies, and loops are checked through every one of them
+//! - regex rules are kept separately from literal and wildcard rules, and checked for overlaps
+//!   with them
+//! - relative targets can be checked for existence, by requesting them from a base URL or looking
+//!   them up in a sitemap
+//! - every failed check, loop and shortened chain can also be written to a JSON, SARIF, or JUnit
+//!   report, with exit codes configurable per severity
+//! - with `--streaming`, rules are sorted on disk instead of being loaded into memory, and loops
+//!   and chains are followed between rules kept in files, for rule sets too large for memory
+//! - new redirect files can also be Apache or nginx configs, Netlify `_redirects` files, or CSV
+//!   files, which are translated into rules first
+//! - the `publish` subcommand writes the bundle to a Spin key-value store, from which running
+//!   applications reload it
+//! - the `export` subcommand writes validated rules as nginx, Apache, or Netlify configuration, or
+//!   JSON
+//! - the `dump`, `lookup`, `stats` and `diff` subcommands inspect existing bundles, looking up
+//!   requests with the same lookup code as the Wasm component
+//! - the `report` subcommand lists rules that haven't been used, based on the analytics recorded by
+//!   running applications
+
+mod export;
+mod import;
+mod inspect;
+mod liveness;
+mod publish;
+mod report;
+mod rule_index;
+mod streaming;
+mod validation_report;
+
+use anyhow::{Context, Result, anyhow};
+use clap::{Parser, Subcommand, ValueEnum};
+use redirects_core::bundle;
+use redirects_core::regex_rules::REGEX_PREFIX;
+use redirects_core::rule::{
+    self, Rule, RuleErrorKind, RuleOptions, is_wildcard_source, split_host,
+};
+use rule_index::{Hosts, LoopStep, RegexOverlaps, RuleIndex};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
-use std::fs::{read_to_string, File};
-use std::io::{BufWriter, Write};
+use std::fs::{File, read_to_string};
+use std::io::Write;
+use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
-use std::sync::LazyLock;
+use std::time::{SystemTime, UNIX_EPOCH};
 use url::Url;
 
-const GENERATED_FILE_HEADER: &'static str =
+const GENERATED_FILE_HEADER: &str =
     "# Validated redirects, DO NOT EDIT. EDITING WILL CAUSE INCORRECT REDIRECTS!";
 
+/// The number of rules recorded for each shortened chain. Every rule starts a chain, so recording
+/// all of them would take quadratic time on long chains.
+const MAX_CHAIN_STEPS: usize = 32;
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 enum ValidationBehavior {
     Ignore,
@@ -31,6 +77,127 @@ enum ValidationBehavior {
     Error,
 }
 
+/// How to handle rules redefining the source of an earlier rule with a different target or
+/// options.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum DuplicateSources {
+    /// Abort with an error
+    Error,
+    /// Warn and keep the earlier rule
+    Warn,
+    /// Replace the earlier rule
+    LastWins,
+}
+
+/// How to shorten chains of redirects with different status codes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum ChainStatusCodes {
+    /// Don't shorten them
+    Refuse,
+    /// Use the status code of the first redirect
+    First,
+    /// Use a permanent status code only if every redirect is permanent
+    Weakest,
+    /// Use a permanent status code if any redirect is permanent
+    Strictest,
+}
+
+impl ChainStatusCodes {
+    /// Returns the status code of a single redirect replacing a chain starting with a redirect
+    /// with the status code `first` and going through redirects with the status codes `codes`.
+    /// Unless the first status code is kept, the request method is only preserved if every
+    /// redirect preserves it, and changed to `GET` if any redirect is a 303. Chains through a
+    /// single status code keep it, which includes every chain through a status code that can't be
+    /// combined with others, since `chain_next` only continues those with the same status code.
+    fn status_code(self, first: u16, codes: StatusCodes) -> u16 {
+        if StatusCodes::of(first).is_none_or(|first| first == codes) {
+            return first;
+        }
+        let permanent = match self {
+            Self::Refuse | Self::First => return first,
+            Self::Weakest => codes.only(&[301, 308]),
+            Self::Strictest => codes.any(&[301, 308]),
+        };
+        let preserves_method = codes.only(&[307, 308]);
+        match (permanent, preserves_method) {
+            (false, _) if codes.any(&[303]) => 303,
+            (true, true) => 308,
+            (true, false) => 301,
+            (false, true) => 307,
+            (false, false) => 302,
+        }
+    }
+}
+
+/// Which schemes absolute targets may use.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum TargetSchemes {
+    /// `http` and `https`
+    Any,
+    /// Only `https`
+    Https,
+}
+
+/// Parses an entry of `--allowed-target-hosts`: a host, or `*.` followed by a domain to allow any
+/// of its subdomains. Hosts are returned in the form `Url` uses, e.g. lowercased.
+fn parse_allowed_host(input: &str) -> Result<String, String> {
+    let (wildcard, host) = match input.trim().strip_prefix("*.") {
+        Some(domain) => ("*.", domain),
+        None => ("", input.trim()),
+    };
+    match url::Host::parse(host) {
+        Ok(parsed) if !host.contains('*') => Ok(format!("{wildcard}{parsed}")),
+        _ => Err(format!(
+            "Invalid host '{input}', expected a host or *. followed by a domain"
+        )),
+    }
+}
+
+/// Returns whether `host` is one of the `allowed` hosts, or a subdomain of an allowed domain.
+fn is_allowed_host(allowed: &[String], host: &str) -> bool {
+    allowed
+        .iter()
+        .any(|allowed| match allowed.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+            None => host == allowed,
+        })
+}
+
+/// A set of the status codes redirects with different status codes can be combined from.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+struct StatusCodes(u8);
+
+impl StatusCodes {
+    const COMBINABLE: [u16; 5] = [301, 302, 303, 307, 308];
+
+    /// Returns the set containing just `code`, if it can be combined with others.
+    fn of(code: u16) -> Option<Self> {
+        let index = Self::COMBINABLE.iter().position(|&c| c == code)?;
+        Some(Self(1 << index))
+    }
+
+    fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    fn set(codes: &[u16]) -> Self {
+        codes
+            .iter()
+            .filter_map(|&code| Self::of(code))
+            .fold(Self::default(), Self::union)
+    }
+
+    fn any(self, codes: &[u16]) -> bool {
+        self.0 & Self::set(codes).0 != 0
+    }
+
+    fn only(self, codes: &[u16]) -> bool {
+        self.0 & !Self::set(codes).0 == 0
+    }
+}
+
 #[derive(Parser, Debug)]
 struct ValidationBehaviors {
     /// Behavior for self-referential loops. Default is to warn and discard the rule.
@@ -44,6 +211,59 @@ struct ValidationBehaviors {
     /// Behavior for invalid rules. Default is to abort with an error.
     #[arg(long, value_enum, hide_default_value = true, default_value_t = ValidationBehavior::Error)]
     invalid_lines: ValidationBehavior,
+
+    /// Behavior for rules whose sources become identical after normalization. Default is to abort
+    /// with an error.
+    #[arg(long, value_enum, hide_default_value = true, default_value_t = ValidationBehavior::Error)]
+    normalization_conflicts: ValidationBehavior,
+
+    /// Behavior for regex rules matching the sources of literal rules, which take precedence.
+    /// Default is to warn. The regex rules are kept, since they still apply to other paths.
+    #[arg(long, value_enum, hide_default_value = true, default_value_t = ValidationBehavior::Warn)]
+    regex_overlaps: ValidationBehavior,
+
+    /// Behavior for lines of imported files configuring redirects that can't be translated into
+    /// rules. Default is to abort with an error.
+    #[arg(long, value_enum, hide_default_value = true, default_value_t = ValidationBehavior::Error)]
+    untranslatable_lines: ValidationBehavior,
+
+    /// Behavior for rules redefining the source of an earlier rule. Default is to replace the
+    /// earlier rule, which is listed as modified in the change summary.
+    #[arg(long, value_enum, hide_default_value = true, default_value_t = DuplicateSources::LastWins)]
+    duplicate_sources: DuplicateSources,
+
+    /// Behavior for rules to remove that don't exist. Default is to warn.
+    #[arg(long, value_enum, hide_default_value = true, default_value_t = ValidationBehavior::Warn)]
+    missing_removals: ValidationBehavior,
+
+    /// How to shorten chains of redirects with different status codes. Default is to not shorten
+    /// them.
+    #[arg(long, value_enum, hide_default_value = true, default_value_t = ChainStatusCodes::Refuse)]
+    chain_status_codes: ChainStatusCodes,
+
+    /// Hosts absolute targets may point to, comma-separated. `*.example.com` allows any subdomain
+    /// of example.com. Default is to allow any host.
+    #[arg(long, value_delimiter = ',', value_parser = parse_allowed_host)]
+    allowed_target_hosts: Vec<String>,
+
+    /// Schemes absolute targets may use. Default is to allow both `http` and `https`.
+    #[arg(long, value_enum, hide_default_value = true, default_value_t = TargetSchemes::Any)]
+    target_schemes: TargetSchemes,
+
+    /// Behavior for absolute targets on hosts that aren't in `--allowed-target-hosts`, or with a
+    /// scheme `--target-schemes` doesn't allow. Default is to abort with an error.
+    #[arg(long, value_enum, hide_default_value = true, default_value_t = ValidationBehavior::Error)]
+    disallowed_targets: ValidationBehavior,
+
+    /// Behavior for relative targets that don't exist, when checked with `--check-targets-url` or
+    /// `--check-targets-list`. Default is to warn.
+    #[arg(long, value_enum, hide_default_value = true, default_value_t = ValidationBehavior::Warn)]
+    missing_targets: ValidationBehavior,
+
+    /// Behavior for rules redirecting to an absolute URL on a host with host-specific rules that
+    /// redirect it again. Such chains aren't shortened. Default is to ignore them.
+    #[arg(long, value_enum, hide_default_value = true, default_value_t = ValidationBehavior::Ignore)]
+    owned_host_chains: ValidationBehavior,
 }
 
 #[derive(clap::Args)]
@@ -56,6 +276,11 @@ struct RuleFiles {
     /// Path(s) to new redirect files to add
     #[arg(long, num_args = 0..)]
     add_rules: Vec<PathBuf>,
+
+    /// Path(s) to files listing the sources of rules to remove, one per line, optionally with a
+    /// `host` option. Lines of rules files can be used as is.
+    #[arg(long, num_args = 0..)]
+    remove_rules: Vec<PathBuf>,
 }
 
 #[derive(clap::Args)]
@@ -68,13 +293,13 @@ struct Output {
     #[arg(long, default_value = "new_redirects.txt")]
     rules_output_file: String,
 
-    /// Path to store the encoded sources in
-    #[arg(long, default_value = "sources.fst")]
-    encoded_sources: String,
+    /// Path to store the bundle of encoded sources and targets in
+    #[arg(long, default_value = "redirects.bundle")]
+    bundle: String,
 
-    /// Path to store the encoded targets in
-    #[arg(long, default_value = "targets.fcsd")]
-    encoded_targets: String,
+    /// Path to the file listing the rules added, modified and removed
+    #[arg(long, default_value = "changes.txt")]
+    changes_output_file: String,
 }
 
 impl Default for ValidationBehaviors {
@@ -83,13 +308,56 @@ impl Default for ValidationBehaviors {
             self_loops: ValidationBehavior::Warn,
             loops: ValidationBehavior::Error,
             invalid_lines: ValidationBehavior::Error,
+            normalization_conflicts: ValidationBehavior::Error,
+            regex_overlaps: ValidationBehavior::Warn,
+            untranslatable_lines: ValidationBehavior::Error,
+            duplicate_sources: DuplicateSources::LastWins,
+            missing_removals: ValidationBehavior::Warn,
+            chain_status_codes: ChainStatusCodes::Refuse,
+            allowed_target_hosts: Vec::new(),
+            target_schemes: TargetSchemes::Any,
+            disallowed_targets: ValidationBehavior::Error,
+            missing_targets: ValidationBehavior::Warn,
+            owned_host_chains: ValidationBehavior::Ignore,
         }
     }
 }
 
 /// A tool for updating and validating redirect rules
 #[derive(Parser)]
-#[command(version, about)]
+#[command(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Publish encoded redirects to a Spin key-value store
+    Publish(publish::PublishArgs),
+    /// Report unused rules, based on recorded analytics
+    Report(report::ReportArgs),
+    /// Export validated rules to the configuration formats of other servers
+    Export(export::ExportArgs),
+    /// Dump a bundle as a validated rules file
+    Dump(inspect::DumpArgs),
+    /// Look up a request in a bundle the way the Wasm component does
+    Lookup(inspect::LookupArgs),
+    /// Print statistics about a bundle
+    Stats(inspect::StatsArgs),
+    /// List the rules added, removed and changed between two bundles
+    Diff(inspect::DiffArgs),
+}
+
+#[derive(Parser)]
 struct Args {
     #[command(flatten)]
     rule_files: RuleFiles,
@@ -105,22 +373,83 @@ struct Args {
     #[arg(long)]
     include_existing: bool,
 
+    /// Canonicalize sources by lowercasing them, collapsing duplicate slashes, stripping trailing
+    /// slashes, and decoding percent-encoded unreserved characters. This is recorded in the bundle,
+    /// so the Wasm component applies the same normalization to incoming requests.
+    #[arg(long)]
+    normalize_sources: bool,
+
+    #[command(flatten)]
+    import: import::ImportArgs,
+
     #[command(flatten)]
     behaviors: ValidationBehaviors,
+
+    #[command(flatten)]
+    liveness: liveness::LivenessArgs,
+
+    #[command(flatten)]
+    streaming: streaming::StreamingArgs,
+
+    #[command(flatten)]
+    report: validation_report::ValidationReportArgs,
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
-    run(&args)
+    let cli = Cli::parse();
+    match &cli.command {
+        Some(Command::Publish(args)) => publish::publish(args),
+        Some(Command::Report(args)) => report::report(args),
+        Some(Command::Export(args)) => export::export(args),
+        Some(Command::Dump(args)) => inspect::dump(args),
+        Some(Command::Lookup(args)) => inspect::lookup(args),
+        Some(Command::Stats(args)) => inspect::stats(args),
+        Some(Command::Diff(args)) => inspect::diff(args),
+        None => {
+            let mut report = validation_report::ValidationReport::default();
+            let result = run(&cli.args, &mut report);
+            match cli.args.report.exit_code(&result, &report) {
+                0 => result,
+                exit_code => {
+                    if let Err(error) = result {
+                        eprintln!("Error: {error:?}");
+                    }
+                    std::process::exit(exit_code.into());
+                }
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
 struct RedirectsSource<'a> {
     pub path: &'a Path,
     pub contents: String,
+    /// The rules translated from `contents`, if the file isn't in the native format
+    pub imported: Option<import::Imported>,
+}
+
+/// Updates the rules, and writes a report of the validation run if requested, even if it fails.
+fn run(args: &Args, report: &mut validation_report::ValidationReport) -> Result<()> {
+    let result = update(args, report);
+    if let Err(error) = &result {
+        report.error = Some(format!("{error:#}"));
+    }
+    if let (Some(format), Some(file)) = (args.report.report_format, args.report.file()) {
+        let output_directory = Path::new(&args.output.output_dir);
+        ensure_dir(&output_directory)?;
+        let report_file_path = output_directory.join(file);
+        std::fs::write(&report_file_path, report.render(format))
+            .with_context(|| format!("Failed to write report {}", report_file_path.display()))?;
+        println!("Saved validation report to {}", report_file_path.display());
+    }
+    result
 }
 
-fn run(args: &Args) -> Result<()> {
+fn update(args: &Args, report: &mut validation_report::ValidationReport) -> Result<()> {
+    if args.streaming.streaming {
+        return streaming::update(args, report);
+    }
     let existing_redirects = args
         .rule_files
         .existing_rules
@@ -134,6 +463,7 @@ fn run(args: &Args) -> Result<()> {
                         path.to_string_lossy()
                     )
                 })?,
+                imported: None,
             })
         })
         .collect::<Result<Vec<_>>>()?;
@@ -142,15 +472,36 @@ fn run(args: &Args) -> Result<()> {
         .rule_files
         .add_rules
         .iter()
+        .map(|path| {
+            let contents = read_to_string(path).with_context(|| {
+                format!(
+                    "Failed to read new redirects file {}",
+                    path.to_string_lossy()
+                )
+            })?;
+            let imported = import::import(&args.import, &contents);
+            Ok(RedirectsSource {
+                path,
+                contents,
+                imported,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let removed_redirects = args
+        .rule_files
+        .remove_rules
+        .iter()
         .map(|path| {
             Ok(RedirectsSource {
                 path,
                 contents: read_to_string(path).with_context(|| {
                     format!(
-                        "Failed to read new redirects file {}",
+                        "Failed to read file of redirects to remove {}",
                         path.to_string_lossy()
                     )
                 })?,
+                imported: None,
             })
         })
         .collect::<Result<Vec<_>>>()?;
@@ -158,11 +509,30 @@ fn run(args: &Args) -> Result<()> {
     let redirects = RedirectsMap::build(
         &existing_redirects,
         &new_redirects,
+        &removed_redirects,
         args.default_status_code,
+        args.normalize_sources,
         &args.behaviors,
+        report,
     )
     .with_context(|| "Failed to update redirects".to_string())?;
 
+    let missing_targets = liveness::check_targets(&args.liveness, &redirects, &args.behaviors)?;
+    report.add_failed_checks(&missing_targets);
+    let errors_found = print_failed_checks(
+        &missing_targets,
+        ValidationBehavior::Error,
+        "Errors in file: ",
+    );
+    print_failed_checks(
+        &missing_targets,
+        ValidationBehavior::Warn,
+        "Warning, missing targets in file: ",
+    );
+    if errors_found {
+        return Err(anyhow!("Missing redirect targets, aborting"));
+    }
+
     // Write the resulting list to a file
     let excluded_rules: Option<Vec<&RedirectsSource>> = if args.include_existing {
         None
@@ -181,47 +551,47 @@ fn run(args: &Args) -> Result<()> {
         println!("Saved updated redirects to {}", output_file_path.display());
     }
 
-    let mut entries = redirects
-        .map
-        .iter()
-        .map(|(key, val)| {
-            if val.status_code == args.default_status_code {
-                (*key, val.to.to_string())
-            } else {
-                (*key, format!("{} {}", val.to, val.status_code))
-            }
-        })
-        .collect::<Vec<_>>();
-    entries.sort_by_key(|entry| entry.0);
-
-    let mut targets = entries.iter().map(|(_, to)| to).collect::<Vec<_>>();
-    targets.sort();
-    targets.dedup();
+    if !args.rule_files.add_rules.is_empty() || !args.rule_files.remove_rules.is_empty() {
+        println!("Changes: {}", inspect::change_counts(&redirects.changes));
+        ensure_dir(&output_directory)?;
+        let changes_file_path = output_directory.join(&args.output.changes_output_file);
+        let mut file = File::create(&changes_file_path)?;
+        for change in &redirects.changes {
+            writeln!(file, "{change}")?;
+        }
+        println!("Saved change summary to {}", changes_file_path.display());
+    }
 
-    // Encode redirect sources using fst and store them in a file
+    // Encode the rules into a bundle, together with the settings the component needs to use them
+    let flags = if args.normalize_sources {
+        bundle::FLAG_NORMALIZED_SOURCES
+    } else {
+        0
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let encoded = bundle::build(
+        args.default_status_code,
+        flags,
+        timestamp,
+        redirects
+            .map
+            .iter()
+            .map(|(key, val)| (key.as_ref(), &val.rule)),
+        redirects.regex_rules.iter().map(|(_, val)| &val.rule),
+    )
+    .map_err(|e| anyhow!(e))
+    .context("Failed to encode redirects")?;
     ensure_dir(&output_directory)?;
-    let sources_file_path = output_directory.join(&args.output.encoded_sources);
-    let wtr = BufWriter::new(File::create(&sources_file_path)?);
-    let mut build = fst::MapBuilder::new(wtr)?;
-    for (from, to) in entries.iter() {
-        // Find the index of the target in the sorted list and store it as the value
-        let index = targets.binary_search(&to).unwrap();
-        build.insert(from, index as u64)?;
-    }
-    build.finish()?;
+    let bundle_file_path = output_directory.join(&args.output.bundle);
+    std::fs::write(&bundle_file_path, encoded)
+        .with_context(|| format!("Failed to write bundle {}", bundle_file_path.display()))?;
     println!(
-        "Saved encoded redirect sources to {}",
-        sources_file_path.display()
-    );
-
-    // Encode redirect targets using fcsd and store them in a file
-    let targets_file_path = output_directory.join(&args.output.encoded_targets);
-    let target_set = fcsd::Set::with_bucket_size(targets.as_slice(), 128)?;
-    let set_file = File::create(&targets_file_path)?;
-    target_set.serialize_into(BufWriter::new(set_file))?;
-    println!(
-        "Saved encoded redirect targets to {}",
-        targets_file_path.display()
+        "Saved bundle of {} encoded redirects and {} regex rules to {}",
+        redirects.map.len(),
+        redirects.regex_rules.len(),
+        bundle_file_path.display()
     );
 
     Ok(())
@@ -239,14 +609,36 @@ fn ensure_dir(dir: &&Path) -> Result<()> {
     Ok(())
 }
 
+/// A rule, together with where it was defined.
 #[derive(Debug, Clone)]
 struct MapEntry<'a> {
-    to: &'a str,
+    rule: Rule<'a>,
     source: &'a RedirectsSource<'a>,
-    status_code: u16,
     line_no: usize,
 }
 
+impl<'a> Deref for MapEntry<'a> {
+    type Target = Rule<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rule
+    }
+}
+
+impl<'a> DerefMut for MapEntry<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.rule
+    }
+}
+
+impl<'a> MapEntry<'a> {
+    /// Whether the rule matches requests regardless of their query string, see
+    /// `rule_index::ignores_query`.
+    fn ignores_query(&self) -> bool {
+        rule_index::ignores_query(&self.options)
+    }
+}
+
 impl<'a> PartialEq for MapEntry<'a> {
     fn eq(&self, other: &Self) -> bool {
         self.to == other.to
@@ -255,9 +647,14 @@ impl<'a> PartialEq for MapEntry<'a> {
 
 #[derive(Debug)]
 struct RedirectsMap<'a> {
-    map: std::collections::HashMap<&'a str, MapEntry<'a>>,
+    map: std::collections::HashMap<Cow<'a, str>, MapEntry<'a>>,
+    /// Regex rules with their keys, in the order they take precedence in
+    regex_rules: Vec<(String, MapEntry<'a>)>,
     default_status_code: u16,
+    normalize_sources: bool,
     parse_errors: Vec<FailedCheck<'a>>,
+    /// The changes to the existing rules, set by `build`
+    changes: Vec<inspect::Change>,
 }
 
 #[derive(Debug)]
@@ -270,26 +667,41 @@ struct FailedCheck<'a> {
 
 #[derive(Debug)]
 struct FailedCheckReason {
+    /// The name of the check, which is also the name of the option setting its behavior
+    check: &'static str,
     message: String,
     severity: ValidationBehavior,
 }
 
-#[derive(Debug)]
-enum ParseResult<'a> {
-    Ok((&'a str, &'a str, u16)),
-    Err(String, ValidationBehavior),
-}
-
 impl<'a> RedirectsMap<'a> {
     fn new(default_status_code: u16) -> RedirectsMap<'a> {
         Self {
             map: std::collections::HashMap::new(),
+            regex_rules: Vec::new(),
             default_status_code,
+            normalize_sources: false,
             parse_errors: Vec::new(),
+            changes: Vec::new(),
         }
     }
 
+    /// Returns the keys and entries of all rules, including regex rules.
+    fn all_rules(&self) -> impl Iterator<Item = (&str, &MapEntry<'a>)> {
+        self.map
+            .iter()
+            .map(|(key, entry)| (key.as_ref(), entry))
+            .chain(
+                self.regex_rules
+                    .iter()
+                    .map(|(key, entry)| (key.as_str(), entry)),
+            )
+    }
+
     fn add_rules(&mut self, source: &'a RedirectsSource, checks: &ValidationBehaviors) {
+        if let Some(imported) = &source.imported {
+            self.add_imported_rules(source, imported, checks);
+            return;
+        }
         for (line_no, line) in source.contents.lines().enumerate() {
             // Strip inline comments
             let rule_part = line.split('#').next().unwrap_or("").trim();
@@ -302,6 +714,50 @@ impl<'a> RedirectsMap<'a> {
         }
     }
 
+    /// Adds the rules translated from a file in another format. Errors are reported against the
+    /// lines they were translated from.
+    fn add_imported_rules(
+        &mut self,
+        source: &'a RedirectsSource,
+        imported: &'a import::Imported,
+        checks: &ValidationBehaviors,
+    ) {
+        let lines: Vec<_> = source.contents.lines().collect();
+        let mut untranslatable = imported.untranslatable.iter().peekable();
+        for (line_no, rule) in &imported.rules {
+            while let Some((line_no, message)) =
+                untranslatable.next_if(|(untranslatable_line, _)| untranslatable_line < line_no)
+            {
+                self.add_untranslatable_line(source, &lines, *line_no, message, checks);
+            }
+            self.parse_line(rule, lines[*line_no], checks, source, *line_no);
+        }
+        for (line_no, message) in untranslatable {
+            self.add_untranslatable_line(source, &lines, *line_no, message, checks);
+        }
+    }
+
+    fn add_untranslatable_line(
+        &mut self,
+        source: &'a RedirectsSource,
+        lines: &[&'a str],
+        line_no: usize,
+        message: &str,
+        checks: &ValidationBehaviors,
+    ) {
+        let reason = FailedCheckReason {
+            check: "untranslatable-lines",
+            message: format!("Untranslatable: {message}"),
+            severity: checks.untranslatable_lines,
+        };
+        self.parse_errors.push(FailedCheck {
+            source,
+            line_no,
+            line: lines.get(line_no).copied().unwrap_or_default(),
+            reason,
+        });
+    }
+
     fn parse_line(
         &mut self,
         rule_part: &'a str,
@@ -310,74 +766,18 @@ impl<'a> RedirectsMap<'a> {
         source: &'a RedirectsSource,
         line_no: usize,
     ) {
-        let parts: Vec<&str> = rule_part.split_whitespace().collect();
-        let parts = match parts.len() {
-            0 => ParseResult::Err("Empty line".to_string(), checks.invalid_lines),
-            1 => ParseResult::Err(
-                "Missing target for redirect".to_string(),
-                checks.invalid_lines,
-            ),
-            2 | 3 => {
-                let from = parts[0];
-                let to = parts[1];
-                let status_code = if parts.len() == 3 {
-                    parts[2]
-                        .parse::<u16>()
-                        .ok()
-                        .filter(|&code| (301..=399).contains(&code))
-                } else {
-                    Some(self.default_status_code)
+        let rule = match Rule::parse(rule_part, self.default_status_code, self.normalize_sources) {
+            Ok(rule) => rule,
+            Err(error) => {
+                let (check, severity) = match error.kind {
+                    RuleErrorKind::Invalid => ("invalid-lines", checks.invalid_lines),
+                    RuleErrorKind::SelfLoop => ("self-loops", checks.self_loops),
+                };
+                let reason = FailedCheckReason {
+                    check,
+                    message: error.message,
+                    severity,
                 };
-
-                if from == to {
-                    ParseResult::Err(
-                        "Source and target cannot be the same".to_string(),
-                        checks.self_loops,
-                    )
-                } else if !is_valid_redirect_source(from) && !is_valid_redirect_target(to) {
-                    ParseResult::Err(
-                        format!("Invalid format for source and target: '{from}' -> '{to}'"),
-                        checks.invalid_lines,
-                    )
-                } else if !is_valid_redirect_source(from) {
-                    ParseResult::Err(
-                        format!("Invalid format for source: '{from}'"),
-                        checks.invalid_lines,
-                    )
-                } else if !is_valid_redirect_target(to) {
-                    ParseResult::Err(
-                        format!("Invalid format for target: '{to}'"),
-                        checks.invalid_lines,
-                    )
-                } else if status_code.is_none() {
-                    ParseResult::Err(
-                        format!("Invalid status code: '{}'", parts[2]),
-                        checks.invalid_lines,
-                    )
-                } else {
-                    ParseResult::Ok((from, to, status_code.unwrap()))
-                }
-            }
-            n => ParseResult::Err(
-                format!("Line must contain 2 or 3 whitespace-separated parts, but found {n}"),
-                checks.invalid_lines,
-            ),
-        };
-
-        match parts {
-            ParseResult::Ok((from, to, status_code)) => {
-                self.map.insert(
-                    from,
-                    MapEntry {
-                        to,
-                        status_code,
-                        source,
-                        line_no,
-                    },
-                );
-            }
-            ParseResult::Err(message, severity) => {
-                let reason = FailedCheckReason { message, severity };
                 let failed = FailedCheck {
                     source,
                     line_no,
@@ -385,88 +785,363 @@ impl<'a> RedirectsMap<'a> {
                     reason,
                 };
                 self.parse_errors.push(failed);
+                return;
             }
+        };
+        let from = rule.from;
+        let entry = MapEntry {
+            rule,
+            source,
+            line_no,
+        };
+
+        if entry.is_regex() {
+            let key = format!("{}{from}", entry.options.host.as_deref().unwrap_or(""));
+            // Redefined rules keep their precedence
+            match self.regex_rules.iter().position(|(k, _)| *k == key) {
+                Some(index) => {
+                    let existing = &self.regex_rules[index].1;
+                    if let Some(reason) = duplicate_source(existing, &entry, checks) {
+                        self.parse_errors.push(FailedCheck {
+                            source,
+                            line_no,
+                            line: original_line,
+                            reason,
+                        });
+                    } else {
+                        self.regex_rules[index].1 = entry;
+                    }
+                }
+                None => self.regex_rules.push((key, entry)),
+            }
+            return;
+        }
+
+        let key = entry.key(self.normalize_sources);
+        if let Some(existing) = self.map.get(&key)
+            && existing.from != from
+        {
+            let reason = normalization_conflict(
+                &entry,
+                existing,
+                existing.source.path,
+                existing.line_no,
+                checks,
+            );
+            self.parse_errors.push(FailedCheck {
+                source,
+                line_no,
+                line: original_line,
+                reason,
+            });
+            return;
         }
+        if let Some(existing) = self.map.get(&key)
+            && let Some(reason) = duplicate_source(existing, &entry, checks)
+        {
+            self.parse_errors.push(FailedCheck {
+                source,
+                line_no,
+                line: original_line,
+                reason,
+            });
+            return;
+        }
+        self.map.insert(key, entry);
     }
 
-    fn check_for_loops(&self) -> Result<()> {
-        let mut loops = Vec::new();
-        for (start_node, target) in self.map.iter() {
-            let mut visited = vec![LoopCheckEntry::new(*start_node, target)];
-            let mut from = target.to;
+    /// Removes the rules listed in `source`, reporting lines that don't match any rule.
+    fn remove_rules(&mut self, source: &'a RedirectsSource, checks: &ValidationBehaviors) {
+        for (line_no, line) in source.contents.lines().enumerate() {
+            let rule_part = line.split('#').next().unwrap_or("").trim();
+            let mut parts = rule_part.split_whitespace();
+            let Some(from) = parts.next() else {
+                continue;
+            };
+            let host_parts = parts
+                .filter(|part| part.starts_with("host="))
+                .collect::<Vec<_>>();
+            let (check, message, severity) = match RuleOptions::parse(&host_parts) {
+                Ok(options) if self.remove_rule(from, options.host.as_deref()) => continue,
+                Ok(_) => (
+                    "missing-removals",
+                    format!("No rule with source '{from}' to remove"),
+                    checks.missing_removals,
+                ),
+                Err(message) => ("invalid-lines", message, checks.invalid_lines),
+            };
+            self.parse_errors.push(FailedCheck {
+                source,
+                line_no,
+                line,
+                reason: FailedCheckReason {
+                    check,
+                    message,
+                    severity,
+                },
+            });
+        }
+    }
 
-            while let Some(target) = self.map.get(from) {
-                let entry = LoopCheckEntry::new(from, target);
-                if visited.contains(&entry) {
-                    loops.push(visited);
-                    break;
-                }
+    /// Removes the rule with the source `from` for `host`, returning whether there was one.
+    fn remove_rule(&mut self, from: &str, host: Option<&str>) -> bool {
+        let host = host.unwrap_or("");
+        if from.starts_with(REGEX_PREFIX) {
+            let key = format!("{host}{from}");
+            let count = self.regex_rules.len();
+            self.regex_rules.retain(|(k, _)| *k != key);
+            return self.regex_rules.len() < count;
+        }
+        let key = format!("{host}{}", self.source_key(from));
+        self.map.remove(key.as_str()).is_some()
+    }
 
-                visited.push(entry);
-                from = target.to;
-            }
+    /// Returns every rule as a line of a rules file including its status code, keyed like in
+    /// `map` and `regex_rules`.
+    fn rule_lines(&self) -> BTreeMap<String, String> {
+        self.all_rules()
+            .map(|(key, entry)| {
+                let line = format!(
+                    "{} {} {}{}",
+                    entry.from, entry.to, entry.status_code, entry.options
+                );
+                (key.to_string(), line)
+            })
+            .collect()
+    }
+
+    /// Returns the key under which rules for `path` are stored, normalizing it if enabled.
+    fn source_key<'p>(&self, path: &'p str) -> Cow<'p, str> {
+        rule::source_key(path, self.normalize_sources)
+    }
+
+    /// Returns an index of the rules in `map`, for looking them up like the component does.
+    fn index(&self) -> MapIndex<'_, 'a> {
+        let mut rules = self
+            .map
+            .iter()
+            .map(|(key, entry)| (key.as_ref(), entry))
+            .collect::<Vec<_>>();
+        rules.sort_unstable_by_key(|&(key, _)| key);
+        let mut hosts = Hosts::default();
+        let host_ids = rules
+            .iter()
+            .map(|(key, _)| match split_host(key).0 {
+                "" => 0,
+                host => hosts.insert(host),
+            })
+            .collect();
+        let (keys, entries): (Vec<_>, Vec<_>) = rules.into_iter().unzip();
+        MapIndex {
+            has_wildcards: keys.iter().any(|key| is_wildcard_source(key)),
+            keys,
+            entries,
+            host_ids,
+            hosts,
+            normalize_sources: self.normalize_sources,
         }
-        if !loops.is_empty() {
-            let loops: Vec<String> = loops
-                .iter()
-                .map(|loop_nodes| {
-                    format!(
-                        "Loop:\n   {}",
-                        loop_nodes
-                            .iter()
-                            .map(|entry| entry.to_string())
-                            .collect::<Vec<_>>()
-                            .join("\n-> ")
-                    )
-                })
-                .collect();
-            return Err(anyhow!("Loops detected:\n{}", loops.join("\n")));
+    }
+
+    /// Records regex rules matching the sources of literal rules. Literal rules are looked up
+    /// first, so these regex rules never apply to the matching requests.
+    fn check_regex_overlaps(&mut self, checks: &ValidationBehaviors) -> Result<()> {
+        if self.regex_rules.is_empty() {
+            return Ok(());
+        }
+        let index = self.index();
+        let mut overlaps =
+            RegexOverlaps::new(self.regex_rules.iter().map(|(_, entry)| &entry.rule))?;
+        for (rule, key) in index.keys.iter().enumerate() {
+            overlaps.add(rule as u32, key);
         }
 
+        let mut failed_checks = Vec::new();
+        for ((_, entry), overlap) in self.regex_rules.iter().zip(overlaps.finish()) {
+            let Some((count, first)) = overlap else {
+                continue;
+            };
+            let first = index.entries[first as usize];
+            failed_checks.push(FailedCheck {
+                source: entry.source,
+                line_no: entry.line_no,
+                line: entry.source.contents.lines().nth(entry.line_no).unwrap(),
+                reason: regex_overlap(count, first, first.source.path, first.line_no, checks),
+            });
+        }
+        self.parse_errors.extend(failed_checks);
         Ok(())
     }
 
-    fn shorten_chains(&mut self) -> Result<()> {
-        let chain_starts: Vec<&str> = self.map.keys().map(|key| *key).collect();
-        let mut chain_depths = vec![];
-
-        for start in chain_starts {
-            let mut current = self.map.get(start).unwrap();
-            let mut depth = 1;
+    /// Checks that absolute targets point to allowed hosts with an allowed scheme, so that rules
+    /// can't turn the site into an open redirector.
+    fn check_target_hosts(&mut self, checks: &ValidationBehaviors) {
+        let all_hosts = checks.allowed_target_hosts.is_empty();
+        let all_schemes = checks.target_schemes == TargetSchemes::Any;
+        if checks.disallowed_targets == ValidationBehavior::Ignore || all_hosts && all_schemes {
+            return;
+        }
+        let mut entries = self.all_rules().map(|(_, entry)| entry).collect::<Vec<_>>();
+        entries.sort_by_key(|entry| (entry.source.path, entry.line_no));
+
+        let mut failed_checks = Vec::new();
+        for entry in entries {
+            for (target, _) in entry.targets() {
+                let Some(message) = disallowed_target(target, checks) else {
+                    continue;
+                };
+                failed_checks.push(FailedCheck {
+                    source: entry.source,
+                    line_no: entry.line_no,
+                    line: entry.source.contents.lines().nth(entry.line_no).unwrap(),
+                    reason: FailedCheckReason {
+                        check: "disallowed-targets",
+                        message,
+                        severity: checks.disallowed_targets,
+                    },
+                });
+            }
+        }
+        self.parse_errors.extend(failed_checks);
+    }
 
-            while let Some(target) = self.map.get(current.to).map(|e| e.clone()) {
-                if target.status_code != current.status_code {
-                    break;
+    /// Checks for rules redirecting to an absolute URL on a host with host-specific rules, where
+    /// another rule redirects the request again. Chains through them aren't shortened, while a
+    /// relative target would let them be.
+    fn check_owned_host_chains(&mut self, checks: &ValidationBehaviors) {
+        if checks.owned_host_chains == ValidationBehavior::Ignore {
+            return;
+        }
+        let index = self.index();
+        let mut failed_checks = Vec::new();
+        for (key, entry) in index.keys.iter().zip(&index.entries) {
+            let (host, _) = split_host(key);
+            for (target, _) in entry.targets() {
+                if target.starts_with('/') {
+                    continue;
                 }
-                depth += 1;
-                self.map.insert(start, target);
-                current = self.map.get(start).unwrap();
+                let Some(found) = index.follow(host, target) else {
+                    continue;
+                };
+                let found = index.entries[found.rule as usize];
+                failed_checks.push(FailedCheck {
+                    source: entry.source,
+                    line_no: entry.line_no,
+                    line: entry.source.contents.lines().nth(entry.line_no).unwrap(),
+                    reason: owned_host_chain(
+                        target,
+                        found,
+                        found.source.path,
+                        found.line_no,
+                        checks,
+                    ),
+                });
             }
+        }
+        self.parse_errors.extend(failed_checks);
+    }
+
+    /// Returns each loop once, as the redirects between the requests in it, see
+    /// `rule_index::find_loops`.
+    fn find_loops(&self) -> Result<Vec<Vec<LoopCheckEntry<'_>>>> {
+        let index = self.index();
+        let loops = rule_index::find_loops(&index)?;
+        Ok(loops
+            .into_iter()
+            .map(|steps| {
+                steps
+                    .into_iter()
+                    .map(|step| LoopCheckEntry {
+                        to: index.entries[step.rule as usize],
+                        step,
+                    })
+                    .collect()
+            })
+            .collect())
+    }
 
-            if depth > 1 {
-                chain_depths.push(depth);
+    /// Replaces each chain of redirects through exact rules with a single redirect to the end of
+    /// the chain, returning the chains with the rules they went through, see
+    /// `rule_index::chain_ends`.
+    fn flatten_chains(
+        &mut self,
+        checks: &ValidationBehaviors,
+    ) -> Result<Vec<validation_report::ShortenedChain>> {
+        let status_codes = checks.chain_status_codes;
+        let (replacements, chains) = {
+            let index = self.index();
+            let ends = rule_index::chain_ends(&index, status_codes);
+            let mut replacements = Vec::new();
+            let mut chains = Vec::new();
+            for rule in 0..index.rule_count() as u32 {
+                let end = ends.own(rule);
+                if end.depth == 1 {
+                    continue;
+                }
+                let first = index.entries[rule as usize];
+                let steps = rule_index::chain_steps(&index, status_codes, rule, end)
+                    .into_iter()
+                    .map(|step| {
+                        let entry = index.entries[step as usize];
+                        validation_report::ChainStep {
+                            file: entry.source.path.display().to_string(),
+                            line: entry.line_no + 1,
+                            from: entry.from.to_string(),
+                            to: entry.to.to_string(),
+                            status_code: entry.status_code,
+                        }
+                    })
+                    .collect();
+                // The flattened rule still applies to the same source and host
+                let mut flattened = index.entries[end.end as usize].clone();
+                flattened.from = first.from;
+                flattened.options.host = first.options.host.clone();
+                flattened.status_code =
+                    status_codes.status_code(first.status_code, end.status_codes);
+                chains.push(validation_report::ShortenedChain {
+                    file: first.source.path.display().to_string(),
+                    line: first.line_no + 1,
+                    from: first.from.to_string(),
+                    to: flattened.to.to_string(),
+                    status_code: flattened.status_code,
+                    depth: end.depth as usize,
+                    steps,
+                });
+                let (key, _) = self.map.get_key_value(index.keys[rule as usize]).unwrap();
+                replacements.push((key.clone(), flattened));
             }
-        }
-        if !chain_depths.is_empty() {
-            let average = chain_depths.iter().sum::<usize>() as f64 / chain_depths.len() as f64;
+            (replacements, chains)
+        };
+        self.map.extend(replacements);
+
+        if !chains.is_empty() {
+            let average =
+                chains.iter().map(|chain| chain.depth).sum::<usize>() as f64 / chains.len() as f64;
             println!(
                 "Shortened {} chains with an average depth of {:.2}",
-                chain_depths.len(),
+                chains.len(),
                 average
             );
         }
 
-        Ok(())
+        Ok(chains)
     }
 
     /// Process redirects from input streams and return the combined redirect map
     fn build(
         existing_redirects: &'a Vec<RedirectsSource>,
         new_redirects: &'a Vec<RedirectsSource>,
+        removed_redirects: &'a Vec<RedirectsSource>,
         default_status_code: u16,
+        normalize_sources: bool,
         checks: &ValidationBehaviors,
+        report: &mut validation_report::ValidationReport,
     ) -> Result<Self> {
         let mut redirects = Self::new(default_status_code);
+        redirects.normalize_sources = normalize_sources;
+        report.chain_status_codes = checks
+            .chain_status_codes
+            .to_possible_value()
+            .map(|value| value.get_name().to_string());
 
         for existing_redirects in existing_redirects {
             let header = existing_redirects.contents.lines().next().unwrap();
@@ -475,16 +1150,25 @@ impl<'a> RedirectsMap<'a> {
                     "Existing redirects file must be generated by this tool"
                 ));
             }
-            redirects.add_rules(&existing_redirects, checks);
+            redirects.add_rules(existing_redirects, checks);
         }
 
         if !redirects.parse_errors.is_empty() {
             return Err(anyhow!("No parse errors expected in existing redirects"));
         }
+        let existing_lines = redirects.rule_lines();
 
+        // Rules are removed first, so that removing a rule and adding it again replaces it
+        for source in removed_redirects {
+            redirects.remove_rules(source, checks);
+        }
         for source in new_redirects {
-            redirects.add_rules(&source, checks);
+            redirects.add_rules(source, checks);
         }
+        redirects.check_regex_overlaps(checks)?;
+        redirects.check_owned_host_chains(checks);
+        redirects.check_target_hosts(checks);
+        report.add_failed_checks(&redirects.parse_errors);
 
         let errors_found = redirects.print_errors(ValidationBehavior::Error, "Errors in file: ");
         redirects.print_errors(ValidationBehavior::Warn, "Warning, ignored lines in file: ");
@@ -494,41 +1178,32 @@ impl<'a> RedirectsMap<'a> {
             .iter()
             .filter(|e| e.reason.severity != ValidationBehavior::Error)
             .count();
-        if !ignored_lines > 0 {
+        if ignored_lines > 0 {
             println!("Skipped {ignored_lines} invalid lines");
         }
 
         if checks.loops != ValidationBehavior::Ignore {
-            redirects.check_for_loops()?;
+            let loops = redirects.find_loops()?;
+            report.add_loops(&loops, checks.loops);
+            if !loops.is_empty() {
+                return Err(loops_error(&loops));
+            }
         }
 
-        redirects.shorten_chains()?;
+        report.chains = redirects.flatten_chains(checks)?;
+        report.rules = redirects.map.len();
+        report.regex_rules = redirects.regex_rules.len();
 
         if errors_found {
             return Err(anyhow!("Errors found in redirect rules, aborting"));
         }
+        redirects.changes = inspect::diff_lines(existing_lines, redirects.rule_lines());
+        report.set_changes(&redirects.changes);
         Ok(redirects)
     }
 
     fn print_errors(&self, severity: ValidationBehavior, header: &str) -> bool {
-        let errors: Vec<_> = self
-            .parse_errors
-            .iter()
-            .filter(|e| e.reason.severity == severity)
-            .collect();
-        let errors_found = !errors.is_empty();
-        let mut current_path = Path::new("");
-        for error in &errors {
-            if error.source.path != current_path {
-                println!("{header}{}", error.source.path.display());
-                current_path = error.source.path;
-            }
-            println!(
-                "  Line {}: {} (Line source: \"{}\")",
-                error.line_no, error.reason.message, error.line
-            );
-        }
-        errors_found
+        print_failed_checks(&self.parse_errors, severity, header)
     }
 
     fn write_to_file(
@@ -539,15 +1214,27 @@ impl<'a> RedirectsMap<'a> {
         let mut sorted_redirects: Vec<_> = self
             .map
             .iter()
-            .map(|(from, entry)| {
+            .map(|(key, entry)| {
+                let from = split_host(key).1;
                 if entry.status_code == self.default_status_code {
-                    format!("{from} {}", entry.to)
+                    format!("{from} {}{}", entry.to, entry.options)
                 } else {
-                    format!("{from} {} {}", entry.to, entry.status_code)
+                    format!("{from} {} {}{}", entry.to, entry.status_code, entry.options)
                 }
             })
             .collect();
         sorted_redirects.sort();
+        // Regex rules come last, in the order they take precedence in
+        sorted_redirects.extend(self.regex_rules.iter().map(|(_, entry)| {
+            if entry.status_code == self.default_status_code {
+                format!("{} {}{}", entry.from, entry.to, entry.options)
+            } else {
+                format!(
+                    "{} {} {}{}",
+                    entry.from, entry.to, entry.status_code, entry.options
+                )
+            }
+        }));
 
         if let Some(excluded_rules) = excluded_rules {
             let mut excluded_lines: std::collections::HashSet<&str> =
@@ -566,10 +1253,7 @@ impl<'a> RedirectsMap<'a> {
             }
 
             // Filter out lines that appear in excluded_rules
-            sorted_redirects = sorted_redirects
-                .into_iter()
-                .filter(|line| !excluded_lines.contains(line.as_str()))
-                .collect();
+            sorted_redirects.retain(|line| !excluded_lines.contains(line.as_str()));
         }
 
         if sorted_redirects.is_empty() {
@@ -585,66 +1269,305 @@ impl<'a> RedirectsMap<'a> {
     }
 }
 
-const BASE: LazyLock<Url> = LazyLock::new(|| Url::parse("https://example.com").unwrap());
-
-fn is_valid_redirect_source(input: &str) -> bool {
-    input.starts_with("/") && BASE.join(input).is_ok()
-}
-
-fn is_valid_redirect_target(input: &str) -> bool {
-    assert!(
-        !input.contains(|c: char| c.is_whitespace()),
-        "Input should not contain newlines"
-    );
-    let violations = RefCell::new(Vec::new());
-    let cb = |v| violations.borrow_mut().push(v);
-    let parser = Url::options().syntax_violation_callback(Some(&cb));
-    if input.starts_with("/") {
-        return parser.base_url(Some(&BASE)).parse(input).is_ok()
-            && violations.borrow_mut().is_empty();
+/// Returns the request a client makes when redirected to `target` from a request to `host`, in
+/// the same format as rule keys. Returns `None` for absolute targets on hosts without rules.
+fn follow_redirect<'t>(hosts: &Hosts, host: &str, target: Cow<'t, str>) -> Option<Cow<'t, str>> {
+    if target.starts_with('/') {
+        if host.is_empty() {
+            return Some(target);
+        }
+        return Some(Cow::Owned(format!("{host}{target}")));
     }
+    let url = Url::parse(&target).ok()?;
+    let target_host = url.host_str().filter(|h| hosts.contains(h))?;
+    Some(Cow::Owned(match url.query() {
+        Some(query) => format!("{target_host}{}?{query}", url.path()),
+        None => format!("{target_host}{}", url.path()),
+    }))
+}
 
-    if !input.starts_with("http") {
-        return false;
+/// Prints the failed checks with the given severity, grouped by file, and returns whether there
+/// were any.
+fn print_failed_checks(
+    failed_checks: &[FailedCheck],
+    severity: ValidationBehavior,
+    header: &str,
+) -> bool {
+    let errors: Vec<_> = failed_checks
+        .iter()
+        .filter(|e| e.reason.severity == severity)
+        .collect();
+    let errors_found = !errors.is_empty();
+    let mut current_path = Path::new("");
+    for error in &errors {
+        if error.source.path != current_path {
+            println!("{header}{}", error.source.path.display());
+            current_path = error.source.path;
+        }
+        println!(
+            "  Line {}: {} (Line source: \"{}\")",
+            error.line_no, error.reason.message, error.line
+        );
     }
+    errors_found
+}
 
-    Url::parse(input).is_ok() && violations.borrow_mut().is_empty()
+fn loops_error(loops: &[Vec<LoopCheckEntry>]) -> anyhow::Error {
+    let loops: Vec<String> = loops
+        .iter()
+        .map(|loop_nodes| {
+            format!(
+                "Loop:\n   {}",
+                loop_nodes
+                    .iter()
+                    .map(|entry| entry.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n-> ")
+            )
+        })
+        .collect();
+    anyhow!("Loops detected:\n{}", loops.join("\n"))
 }
 
-struct LoopCheckEntry<'a> {
-    from: &'a str,
-    to: &'a MapEntry<'a>,
+/// Returns why `entry` can't replace `existing`, which has the same source, or `None` if it can.
+fn duplicate_source(
+    existing: &MapEntry,
+    entry: &MapEntry,
+    checks: &ValidationBehaviors,
+) -> Option<FailedCheckReason> {
+    duplicate_rule(
+        existing,
+        existing.source.path,
+        existing.line_no,
+        entry,
+        checks,
+    )
 }
 
-impl<'a> Display for LoopCheckEntry<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}#{}: {} -> {}",
-            self.to.source.path.display(),
-            self.to.line_no,
-            self.from,
-            self.to.to
-        )
+/// Like `duplicate_source`, for rules that aren't kept in a `RedirectsMap`, with the file and line
+/// `existing` was defined at.
+fn duplicate_rule(
+    existing: &Rule,
+    path: &Path,
+    line_no: usize,
+    rule: &Rule,
+    checks: &ValidationBehaviors,
+) -> Option<FailedCheckReason> {
+    let severity = match checks.duplicate_sources {
+        _ if existing == rule => return None,
+        DuplicateSources::LastWins => return None,
+        DuplicateSources::Warn => ValidationBehavior::Warn,
+        DuplicateSources::Error => ValidationBehavior::Error,
+    };
+    let message = format!(
+        "Source '{}' is already used by a different rule ({}#{line_no})",
+        rule.from,
+        path.display(),
+    );
+    Some(FailedCheckReason {
+        check: "duplicate-sources",
+        message,
+        severity,
+    })
+}
+
+/// Returns why `rule` can't be added, since its source only matches the different source of
+/// `existing`, defined at the given file and line, after normalization.
+fn normalization_conflict(
+    rule: &Rule,
+    existing: &Rule,
+    path: &Path,
+    line_no: usize,
+    checks: &ValidationBehaviors,
+) -> FailedCheckReason {
+    let message = format!(
+        "Source '{}' conflicts with '{}' ({}#{line_no}) after normalization",
+        rule.from,
+        existing.from,
+        path.display(),
+    );
+    FailedCheckReason {
+        check: "normalization-conflicts",
+        message,
+        severity: checks.normalization_conflicts,
+    }
+}
+
+/// Returns why `target` isn't allowed by `--allowed-target-hosts` and `--target-schemes`, if it
+/// isn't.
+fn disallowed_target(target: &str, checks: &ValidationBehaviors) -> Option<String> {
+    let url = Url::parse(target).ok()?;
+    let host = url.host_str().unwrap_or_default();
+    if checks.target_schemes != TargetSchemes::Any && url.scheme() != "https" {
+        Some(format!("Target {target} doesn't use https"))
+    } else if !checks.allowed_target_hosts.is_empty()
+        && !is_allowed_host(&checks.allowed_target_hosts, host)
+    {
+        Some(format!("Target host {host} isn't an allowed target host"))
+    } else {
+        None
+    }
+}
+
+/// Returns why `rule`, whose source matches `count` literal rules, never applies to the requests
+/// they match, naming `first`, the first of them, defined at the given file and line.
+fn regex_overlap(
+    count: usize,
+    first: &Rule,
+    path: &Path,
+    line_no: usize,
+    checks: &ValidationBehaviors,
+) -> FailedCheckReason {
+    let message = format!(
+        "Regex rule matches {count} literal rules, which take precedence, e.g. '{}' ({}#{line_no})",
+        first.from,
+        path.display(),
+    );
+    FailedCheckReason {
+        check: "regex-overlaps",
+        message,
+        severity: checks.regex_overlaps,
+    }
+}
+
+/// Returns why a redirect to the absolute `target` on a host with rules can't be shortened, since
+/// `found`, defined at the given file and line, redirects it again.
+fn owned_host_chain(
+    target: &str,
+    found: &Rule,
+    path: &Path,
+    line_no: usize,
+    checks: &ValidationBehaviors,
+) -> FailedCheckReason {
+    let message = format!(
+        "Redirects to {target} on an owned host, which is redirected again by '{}' ({}#{line_no})",
+        found.from,
+        path.display(),
+    );
+    FailedCheckReason {
+        check: "owned-host-chains",
+        message,
+        severity: checks.owned_host_chains,
+    }
+}
+
+/// Appends `query` to the query string of `target`, keeping any fragment at the end.
+fn append_query(target: &str, query: &str) -> String {
+    if query.is_empty() {
+        return target.to_string();
+    }
+    let (base, fragment) = target.split_at(target.find('#').unwrap_or(target.len()));
+    let separator = match base.find('?') {
+        None => "?",
+        Some(_) if base.ends_with('?') || base.ends_with('&') => "",
+        Some(_) => "&",
+    };
+    format!("{base}{separator}{query}{fragment}")
+}
+
+/// The rules of a `RedirectsMap` in the order of their keys, as returned by `RedirectsMap::index`.
+struct MapIndex<'s, 'a> {
+    keys: Vec<&'s str>,
+    entries: Vec<&'s MapEntry<'a>>,
+    host_ids: Vec<u32>,
+    hosts: Hosts,
+    normalize_sources: bool,
+    has_wildcards: bool,
+}
+
+impl RuleIndex for MapIndex<'_, '_> {
+    fn rule_count(&self) -> usize {
+        self.keys.len()
+    }
+
+    fn get(&self, key: &str) -> Option<u32> {
+        self.keys.binary_search(&key).ok().map(|rule| rule as u32)
+    }
+
+    fn normalizes_sources(&self) -> bool {
+        self.normalize_sources
+    }
+
+    fn has_wildcards(&self) -> bool {
+        self.has_wildcards
+    }
+
+    fn hosts(&self) -> &Hosts {
+        &self.hosts
+    }
+
+    fn host_id(&self, rule: u32) -> u32 {
+        self.host_ids[rule as usize]
+    }
+
+    fn is_wildcard(&self, rule: u32) -> bool {
+        is_wildcard_source(self.keys[rule as usize])
+    }
+
+    fn ignores_query(&self, rule: u32) -> bool {
+        self.entries[rule as usize].ignores_query()
+    }
+
+    fn status_code(&self, rule: u32) -> u16 {
+        self.entries[rule as usize].status_code
+    }
+
+    fn with_rule<T>(&self, rule: u32, f: impl FnOnce(&str, &Rule) -> T) -> Result<T> {
+        Ok(f(self.keys[rule as usize], self.entries[rule as usize]))
+    }
+
+    fn chain_next(&self, host: u32, rule: u32, status_codes: ChainStatusCodes) -> Option<u32> {
+        let current = self.entries[rule as usize];
+        let next_rule = self.chain_successor(self.hosts.name(host), current.to)?;
+        let next = self.entries[next_rule as usize];
+        let compatible = rule_index::status_codes_compatible(
+            status_codes,
+            current.status_code,
+            next.status_code,
+        ) && next.options.query == current.options.query
+            && next.options.methods == current.options.methods
+            && next.options.headers == current.options.headers
+            && next.options.valid_from == current.options.valid_from
+            && next.options.valid_until == current.options.valid_until
+            && next.options.branches == current.options.branches;
+        compatible.then_some(next_rule)
     }
 }
 
-impl<'a> LoopCheckEntry<'a> {
-    fn new(from: &'a str, to: &'a MapEntry<'a>) -> Self {
-        Self { from, to }
+/// A redirect on a loop, with the rule redirecting.
+struct LoopCheckEntry<'a> {
+    step: LoopStep,
+    to: &'a MapEntry<'a>,
+}
+
+impl<'a> Display for LoopCheckEntry<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let file = self.to.source.path.display();
+        f.write_str(&self.step.describe(file, self.to.line_no))
     }
 }
 
 impl<'a> PartialEq for LoopCheckEntry<'a> {
     fn eq(&self, other: &Self) -> bool {
-        self.from == other.from
+        self.step.request == other.step.request
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use redirects_core::conditions::Condition;
+    use redirects_core::rule::QueryMode;
     use std::collections::HashSet;
+    use validation_report::{ValidationReport, ValidationReportArgs};
+
+    /// Returns the main target of the rule handling `request`, as redirected to.
+    fn lookup_target(redirects: &RedirectsMap, request: &str) -> Option<String> {
+        let index = redirects.index();
+        let found = index.lookup(request)?;
+        let entry = index.entries[found.rule as usize];
+        Some(found.resolve(entry, entry.to).into_owned())
+    }
     use tempfile::tempdir;
 
     #[test]
@@ -654,6 +1577,7 @@ mod tests {
         let rules = RedirectsSource {
             path: Path::new("test"),
             contents: "/path-a /path-b\n/path-b /path-c\n/path-c /path-a".to_string(),
+            imported: None,
         };
         redirects.add_rules(&rules, &ValidationBehaviors::default());
         assert!(
@@ -662,16 +1586,64 @@ mod tests {
         );
 
         // Now check for loops
-        let result = redirects.check_for_loops();
-        assert!(result.is_err(), "Loop should be detected");
+        let loops = redirects.find_loops().unwrap();
+        assert!(!loops.is_empty(), "Loop should be detected");
 
         // Verify that the loop is detected and the error message contains the description of the loop.
-        let err_msg = result.unwrap_err().to_string();
+        let err_msg = loops_error(&loops).to_string();
         assert!(err_msg.contains("/path-a"), "Error should mention path-a");
         assert!(err_msg.contains("/path-b"), "Error should mention path-b");
         assert!(err_msg.contains("/path-c"), "Error should mention path-c");
     }
 
+    #[test]
+    fn test_loop_reported_once() {
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("test"),
+            contents: "/start /a\n/a /b\n/b /c\n/c /a\n/other /b\n/x /y\n/y /x".to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        assert!(redirects.parse_errors.is_empty());
+
+        // Each loop is reported once, whichever rules lead into it
+        let loops = redirects.find_loops().unwrap();
+        assert_eq!(loops.len(), 2);
+        let lines = |entries: &Vec<LoopCheckEntry>| {
+            let mut lines = entries
+                .iter()
+                .map(|entry| entry.to.line_no)
+                .collect::<Vec<_>>();
+            lines.sort();
+            lines
+        };
+        assert_eq!(lines(&loops[0]), [1, 2, 3]);
+        assert_eq!(lines(&loops[1]), [5, 6]);
+    }
+
+    #[test]
+    fn test_long_chain() {
+        let rules = RedirectsSource {
+            path: Path::new("test"),
+            contents: (0..20_000)
+                .map(|i| format!("/r{i} /r{}\n", i + 1))
+                .collect(),
+            imported: None,
+        };
+        let mut redirects = RedirectsMap::new(302);
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        assert!(redirects.find_loops().unwrap().is_empty());
+        let chains = redirects
+            .flatten_chains(&ValidationBehaviors::default())
+            .unwrap();
+        assert_eq!(chains.len(), 19_999);
+        assert_eq!(chains[0].from, "/r0");
+        assert_eq!(chains[0].depth, 20_000);
+        assert_eq!(chains[0].steps.len(), MAX_CHAIN_STEPS);
+        assert_eq!(redirects.map.get("/r500").unwrap().to, "/r20000");
+    }
+
     #[test]
     fn test_loop_with_existing_and_new_rules() {
         // First set up the "existing" redirects
@@ -679,6 +1651,7 @@ mod tests {
         let rules = RedirectsSource {
             path: Path::new("existing"),
             contents: "/existing-1 /existing-2\n/existing-2 /existing-3".to_string(),
+            imported: None,
         };
         redirects.add_rules(&rules, &ValidationBehaviors::default());
         assert!(
@@ -690,18 +1663,19 @@ mod tests {
         let rules = RedirectsSource {
             path: Path::new("new"),
             contents: "/existing-3 /existing-1".to_string(),
+            imported: None,
         };
         redirects.add_rules(&rules, &ValidationBehaviors::default());
 
         // Now verify the loop is detected
-        let result = redirects.check_for_loops();
+        let loops = redirects.find_loops().unwrap();
         assert!(
-            result.is_err(),
+            !loops.is_empty(),
             "Loop should be detected after adding new rule"
         );
 
         // Verify error message contains the full loop path
-        let err_msg = result.unwrap_err().to_string();
+        let err_msg = loops_error(&loops).to_string();
         assert!(
             err_msg.contains("/existing-1"),
             "Error should mention existing-1"
@@ -716,6 +1690,321 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_cli() {
+        let cli = Cli::try_parse_from(["rules-manager", "--add-rules", "a.txt"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.args.import.rules_format, import::RulesFormat::Native);
+
+        let cli = Cli::try_parse_from([
+            "rules-manager",
+            "--add-rules",
+            ".htaccess",
+            "--rules-format",
+            "apache",
+            "--untranslatable-lines",
+            "warn",
+        ])
+        .unwrap();
+        assert_eq!(cli.args.import.rules_format, import::RulesFormat::Apache);
+        assert_eq!(
+            cli.args.behaviors.untranslatable_lines,
+            ValidationBehavior::Warn
+        );
+
+        let cli = Cli::try_parse_from([
+            "rules-manager",
+            "export",
+            "--bundle",
+            "redirects.bundle",
+            "--format",
+            "nginx",
+        ])
+        .unwrap();
+        assert!(matches!(cli.command, Some(Command::Export(_))));
+        assert!(Cli::try_parse_from(["rules-manager", "export", "--format", "json"]).is_err());
+
+        let cli = Cli::try_parse_from([
+            "rules-manager",
+            "lookup",
+            "redirects.bundle",
+            "/a?b=c",
+            "--header",
+            "Accept-Language: de",
+            "--at",
+            "2024-01-01T00:00:00Z",
+        ])
+        .unwrap();
+        assert!(matches!(cli.command, Some(Command::Lookup(_))));
+        assert!(
+            Cli::try_parse_from([
+                "rules-manager",
+                "lookup",
+                "redirects.bundle",
+                "/a",
+                "--header",
+                "a"
+            ])
+            .is_err()
+        );
+        assert!(Cli::try_parse_from(["rules-manager", "diff", "old.bundle"]).is_err());
+        assert!(
+            Cli::try_parse_from([
+                "rules-manager",
+                "--add-rules",
+                "a.txt",
+                "--error-exit-code",
+                "0"
+            ])
+            .is_err()
+        );
+
+        let cli = Cli::try_parse_from([
+            "rules-manager",
+            "--existing-rules",
+            "redirects.txt",
+            "--remove-rules",
+            "removed.txt",
+            "--duplicate-sources",
+            "last-wins",
+            "--report-format",
+            "sarif",
+            "--warning-exit-code",
+            "2",
+            "--chain-status-codes",
+            "weakest",
+            "--owned-host-chains",
+            "warn",
+        ])
+        .unwrap();
+        assert_eq!(cli.args.report.file(), Some("validation-report.sarif"));
+        assert_eq!(cli.args.rule_files.remove_rules, [Path::new("removed.txt")]);
+        assert_eq!(
+            cli.args.behaviors.duplicate_sources,
+            DuplicateSources::LastWins
+        );
+        assert_eq!(
+            cli.args.behaviors.chain_status_codes,
+            ChainStatusCodes::Weakest
+        );
+        assert_eq!(
+            cli.args.behaviors.owned_host_chains,
+            ValidationBehavior::Warn
+        );
+
+        let cli = Cli::try_parse_from([
+            "rules-manager",
+            "--add-rules",
+            "a.txt",
+            "--check-targets-url",
+            "http://localhost:8080",
+            "--check-targets-concurrency",
+            "32",
+            "--missing-targets",
+            "error",
+        ])
+        .unwrap();
+        assert_eq!(cli.args.liveness.check_targets_concurrency, 32);
+        assert_eq!(
+            cli.args.behaviors.missing_targets,
+            ValidationBehavior::Error
+        );
+        for conflicting in [
+            [
+                "--check-targets-url",
+                "http://localhost:8080",
+                "--check-targets-list",
+                "urls.txt",
+            ],
+            [
+                "--check-targets-url",
+                "http://localhost:8080",
+                "--check-targets-concurrency",
+                "0",
+            ],
+        ] {
+            let args = ["rules-manager", "--add-rules", "a.txt"]
+                .into_iter()
+                .chain(conflicting);
+            assert!(Cli::try_parse_from(args).is_err());
+        }
+
+        let cli = Cli::try_parse_from([
+            "rules-manager",
+            "--add-rules",
+            "a.txt",
+            "--allowed-target-hosts",
+            "example.com,*.example.org",
+            "--target-schemes",
+            "https",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.args.behaviors.allowed_target_hosts,
+            ["example.com", "*.example.org"]
+        );
+        assert_eq!(cli.args.behaviors.target_schemes, TargetSchemes::Https);
+    }
+
+    #[test]
+    fn test_remove_rules() {
+        let existing = vec![RedirectsSource {
+            path: Path::new("existing"),
+            contents: format!(
+                "{GENERATED_FILE_HEADER}\n/a /b\n/c /d host=example.com\n/e/* /f/$1\n~/g/\\d+ /h"
+            ),
+            imported: None,
+        }];
+        let added = vec![RedirectsSource {
+            path: Path::new("added"),
+            contents: "/a /x 301".to_string(),
+            imported: None,
+        }];
+        let removed = vec![RedirectsSource {
+            path: Path::new("removed"),
+            contents:
+                "/a\n/c /d host=Example.com # copied from a rules file\n/e/*\n~/g/\\d+\n/missing"
+                    .to_string(),
+            imported: None,
+        }];
+        let redirects = RedirectsMap::build(
+            &existing,
+            &added,
+            &removed,
+            302,
+            false,
+            &ValidationBehaviors::default(),
+            &mut ValidationReport::default(),
+        )
+        .unwrap();
+        assert_eq!(redirects.map.keys().collect::<Vec<_>>(), vec!["/a"]);
+        assert_eq!(redirects.map["/a"].to, "/x");
+        assert!(redirects.regex_rules.is_empty());
+        assert_eq!(redirects.parse_errors.len(), 1);
+        assert_eq!(redirects.parse_errors[0].line_no, 4);
+        assert_eq!(
+            redirects.parse_errors[0].reason.severity,
+            ValidationBehavior::Warn
+        );
+        assert_eq!(
+            redirects.changes,
+            vec![
+                inspect::Change::Modified("/a /b 302".to_string(), "/a /x 301".to_string()),
+                inspect::Change::Removed("/e/* /f/$1 302".to_string()),
+                inspect::Change::Removed("/c /d 302 host=example.com".to_string()),
+                inspect::Change::Removed("~/g/\\d+ /h 302".to_string()),
+            ]
+        );
+
+        let removed = vec![RedirectsSource {
+            path: Path::new("removed"),
+            contents: "/missing".to_string(),
+            imported: None,
+        }];
+        let checks = ValidationBehaviors {
+            missing_removals: ValidationBehavior::Error,
+            ..Default::default()
+        };
+        assert!(
+            RedirectsMap::build(
+                &existing,
+                &Vec::new(),
+                &removed,
+                302,
+                false,
+                &checks,
+                &mut ValidationReport::default()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_duplicate_sources() {
+        let rules = RedirectsSource {
+            path: Path::new("rules"),
+            contents: "/a /b\n/c /d\n/a /b\n/a /e\n~/f /g\n~/f /h".to_string(),
+            imported: None,
+        };
+        let add = |duplicate_sources| {
+            let mut redirects = RedirectsMap::new(302);
+            let checks = ValidationBehaviors {
+                duplicate_sources,
+                ..Default::default()
+            };
+            redirects.add_rules(&rules, &checks);
+            let errors = redirects
+                .parse_errors
+                .iter()
+                .map(|error| (error.line_no, error.reason.severity))
+                .collect::<Vec<_>>();
+            (
+                redirects.map["/a"].to,
+                redirects.regex_rules[0].1.to,
+                errors,
+            )
+        };
+        assert_eq!(add(DuplicateSources::LastWins), ("/e", "/h", vec![]));
+        assert_eq!(
+            add(DuplicateSources::Warn),
+            (
+                "/b",
+                "/g",
+                vec![(3, ValidationBehavior::Warn), (5, ValidationBehavior::Warn)]
+            )
+        );
+        assert_eq!(
+            add(DuplicateSources::Error).2,
+            vec![
+                (3, ValidationBehavior::Error),
+                (5, ValidationBehavior::Error)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_imported_rules() {
+        let contents = "RewriteEngine On\n\
+                        Redirect 301 /old /new\n\
+                        RewriteRule ^/a$ /b [R=301]\n\
+                        RedirectMatch 301 ^/same$ /same\n\
+                        RedirectMatch 301 ^/p/(\\d+)$ /products/$1";
+        let args = import::ImportArgs {
+            rules_format: import::RulesFormat::Apache,
+            ..import::ImportArgs::default()
+        };
+        let rules = RedirectsSource {
+            path: Path::new("redirects.conf"),
+            contents: contents.to_string(),
+            imported: import::import(&args, contents),
+        };
+        let mut redirects = RedirectsMap::new(302);
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+
+        assert_eq!(redirects.map["/old"].to, "/new");
+        assert_eq!(redirects.map["/old"].status_code, 301);
+        assert_eq!(redirects.map["/old/*"].to, "/new/$1");
+        assert_eq!(redirects.regex_rules.len(), 1);
+
+        // Errors refer to the lines of the original file
+        let errors: Vec<_> = redirects
+            .parse_errors
+            .iter()
+            .map(|e| (e.line_no, e.line, e.reason.severity))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (2, "RewriteRule ^/a$ /b [R=301]", ValidationBehavior::Error),
+                (
+                    3,
+                    "RedirectMatch 301 ^/same$ /same",
+                    ValidationBehavior::Warn
+                ),
+            ]
+        );
+    }
+
     #[test]
     fn test_self_referential_loop() {
         // Create a redirect map with a self-referential loop
@@ -723,6 +2012,7 @@ mod tests {
         let rules = RedirectsSource {
             path: Path::new("new"),
             contents: "/self-loop /self-loop".to_string(),
+            imported: None,
         };
         redirects.add_rules(&rules, &ValidationBehaviors::default());
         assert!(
@@ -743,6 +2033,7 @@ mod tests {
         let rules = RedirectsSource {
             path: Path::new("test"),
             contents: "/start /middle\n/middle /end".to_string(),
+            imported: None,
         };
         redirects.add_rules(&rules, &ValidationBehaviors::default());
 
@@ -759,20 +2050,26 @@ mod tests {
         let existing_content = vec![RedirectsSource {
             path: Path::new("existing"),
             contents: format!("{GENERATED_FILE_HEADER}\n/path-x /path-y\n/path-y /path-z"),
+            imported: None,
         }];
         let new_content = RedirectsSource {
             path: Path::new("new"),
             contents: "/path-z /path-x".to_string(), // This creates a loop with existing redirects
+            imported: None,
         };
 
         let new_sources = vec![new_content];
 
         // Attempt to update redirects
+        let removed_sources = Vec::new();
         let result = RedirectsMap::build(
             &existing_content,
             &new_sources,
+            &removed_sources,
             302,
+            false,
             &ValidationBehaviors::default(),
+            &mut ValidationReport::default(),
         );
 
         // Verify the operation fails due to loop detection
@@ -792,24 +2089,31 @@ mod tests {
         let existing_content = vec![RedirectsSource {
             path: Path::new("existing"),
             contents: format!("{GENERATED_FILE_HEADER}\n/start /middle"),
+            imported: None,
         }];
         let new_content1 = RedirectsSource {
             path: Path::new("new1"),
             contents: "/middle /next".to_string(),
+            imported: None,
         };
         let new_content2 = RedirectsSource {
             path: Path::new("new2"),
             contents: "/next /start".to_string(),
+            imported: None,
         };
 
         let new_readers = vec![new_content1, new_content2];
 
         // Attempt to update redirects
+        let removed_sources = Vec::new();
         let result = RedirectsMap::build(
             &existing_content,
             &new_readers,
+            &removed_sources,
             302,
+            false,
             &ValidationBehaviors::default(),
+            &mut ValidationReport::default(),
         );
 
         // Verify the operation fails due to loop detection
@@ -829,20 +2133,26 @@ mod tests {
         let existing_content = vec![RedirectsSource {
             path: Path::new("existing"),
             contents: format!("{GENERATED_FILE_HEADER}\n/old /new\n/old/page /new/page"),
+            imported: None,
         }];
         let new_content = RedirectsSource {
             path: Path::new("existing"),
             contents: "/another /destination\n/yet-another /final".to_string(), // This creates a loop with existing redirects
+            imported: None,
         };
 
         let new_sources = vec![new_content];
 
         // Attempt to update redirects
+        let removed_sources = Vec::new();
         let result = RedirectsMap::build(
             &existing_content,
             &new_sources,
+            &removed_sources,
             302,
+            false,
             &ValidationBehaviors::default(),
+            &mut ValidationReport::default(),
         );
 
         // Verify the operation succeeds
@@ -860,6 +2170,7 @@ mod tests {
         let rules = RedirectsSource {
             path: Path::new("invalid"),
             contents: "/valid /target\ninvalid line\n/another /valid".to_string(),
+            imported: None,
         };
         let behaviors = ValidationBehaviors {
             invalid_lines: ValidationBehavior::Error,
@@ -886,6 +2197,7 @@ mod tests {
         let rules = RedirectsSource {
             path: Path::new("invalid"),
             contents: "/valid /target\ninvalid line\n/another /valid".to_string(),
+            imported: None,
         };
         let behaviors = ValidationBehaviors {
             invalid_lines: ValidationBehavior::Warn,
@@ -906,6 +2218,7 @@ mod tests {
         let rules = RedirectsSource {
             path: Path::new("invalid"),
             contents: "/valid /target\ninvalid line\n/another /valid".to_string(),
+            imported: None,
         };
         let behaviors = ValidationBehaviors {
             invalid_lines: ValidationBehavior::Ignore,
@@ -929,6 +2242,7 @@ mod tests {
         let rules = RedirectsSource {
             path: Path::new("self_loop"),
             contents: "/a /a".to_string(),
+            imported: None,
         };
         let behaviors = ValidationBehaviors {
             self_loops: ValidationBehavior::Error,
@@ -943,40 +2257,20 @@ mod tests {
         );
     }
 
-    #[test]
-    fn test_is_valid_redirect_source() {
-        assert!(is_valid_redirect_source("/valid/path"));
-        assert!(is_valid_redirect_source("/"));
-        assert!(is_valid_redirect_source("/path?query=1"));
-        assert!(!is_valid_redirect_source("invalid/path")); // Must start with /
-        assert!(!is_valid_redirect_source("https://example.com/path")); // Must be relative
-        assert!(is_valid_redirect_source("/path%20with%20space")); // Encoded spaces are ok
-    }
-
-    #[test]
-    fn test_is_valid_redirect_target() {
-        assert!(is_valid_redirect_target("/valid/relative/path"));
-        assert!(is_valid_redirect_target("https://absolute.url/path"));
-        assert!(is_valid_redirect_target(
-            "http://absolute.url/path?query=1#fragment"
-        ));
-        assert!(!is_valid_redirect_target("invalid-relative-path")); // Relative must start with /
-        assert!(!is_valid_redirect_target("ftp://invalid.scheme")); // Only http/https schemes for absolute URLs
-        assert!(!is_valid_redirect_target("/<with>invalid|chars")); // Invalid chars
-        assert!(is_valid_redirect_target("/path%20with%20space")); // Encoded chars ok
-    }
-
     #[test]
     fn test_chain_shortening() {
         let mut redirects = RedirectsMap::new(302);
         let rules = RedirectsSource {
             path: Path::new("chains"),
             contents: "/a /b\n/b /c\n/c /d\n/x /y\n/y /z".to_string(),
+            imported: None,
         };
         redirects.add_rules(&rules, &ValidationBehaviors::default());
         assert!(redirects.parse_errors.is_empty());
 
-        redirects.shorten_chains().unwrap();
+        redirects
+            .flatten_chains(&ValidationBehaviors::default())
+            .unwrap();
 
         assert_eq!(redirects.map.get("/a").unwrap().to, "/d");
         assert_eq!(redirects.map.get("/b").unwrap().to, "/d"); // Intermediate steps also point to final
@@ -985,6 +2279,46 @@ mod tests {
         assert_eq!(redirects.map.get("/y").unwrap().to, "/z");
     }
 
+    #[test]
+    fn test_chain_flattening_into_loop() {
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("chains"),
+            contents: "/start /a\n/a /b\n/b /c\n/c /b".to_string(),
+            imported: None,
+        };
+        let checks = ValidationBehaviors {
+            loops: ValidationBehavior::Ignore,
+            ..Default::default()
+        };
+        redirects.add_rules(&rules, &checks);
+        assert_eq!(redirects.find_loops().unwrap().len(), 1);
+
+        // Chains end where they run into the loop, and the loop itself is left alone
+        let chains = redirects
+            .flatten_chains(&ValidationBehaviors::default())
+            .unwrap();
+        assert_eq!(chains.len(), 2);
+        assert_eq!(redirects.map.get("/start").unwrap().to, "/c");
+        assert_eq!(redirects.map.get("/a").unwrap().to, "/c");
+        assert_eq!(redirects.map.get("/b").unwrap().to, "/c");
+        assert_eq!(redirects.map.get("/c").unwrap().to, "/b");
+
+        let steps = &chains
+            .iter()
+            .find(|chain| chain.from == "/start")
+            .unwrap()
+            .steps;
+        let steps = steps
+            .iter()
+            .map(|step| (step.line, step.from.as_str(), step.to.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            steps,
+            [(1, "/start", "/a"), (2, "/a", "/b"), (3, "/b", "/c")]
+        );
+    }
+
     #[test]
     fn test_write_to_file_include_existing() -> Result<()> {
         let dir = tempdir()?;
@@ -1001,19 +2335,25 @@ mod tests {
             rule_files: RuleFiles {
                 existing_rules: vec![existing_path.clone()],
                 add_rules: vec![new_path.clone()],
+                remove_rules: Vec::new(),
             },
             default_status_code: 302,
             output: Output {
                 output_dir: dir.path().to_path_buf(),
                 rules_output_file: "output.txt".to_string(),
-                encoded_sources: "sources.fst".to_string(),
-                encoded_targets: "targets.fcsd".to_string(),
+                bundle: "redirects.bundle".to_string(),
+                changes_output_file: "changes.txt".to_string(),
             },
             include_existing: true,
+            normalize_sources: false,
+            import: import::ImportArgs::default(),
+            liveness: liveness::LivenessArgs::default(),
+            streaming: streaming::StreamingArgs::default(),
             behaviors: ValidationBehaviors::default(),
+            report: ValidationReportArgs::default(),
         };
 
-        run(&args)?;
+        run(&args, &mut ValidationReport::default())?;
 
         let output_content = read_to_string(&output_path)?;
         let lines: HashSet<&str> = output_content.lines().collect();
@@ -1043,19 +2383,25 @@ mod tests {
             rule_files: RuleFiles {
                 existing_rules: vec![existing_path.clone()],
                 add_rules: vec![new_path.clone()],
+                remove_rules: Vec::new(),
             },
             default_status_code: 302,
             output: Output {
                 output_dir: dir.path().to_path_buf(),
                 rules_output_file: "output.txt".to_string(),
-                encoded_sources: "sources.fst".to_string(),
-                encoded_targets: "targets.fcsd".to_string(),
+                bundle: "redirects.bundle".to_string(),
+                changes_output_file: "changes.txt".to_string(),
             },
             include_existing: false, // Default, but explicit here
+            normalize_sources: false,
+            import: import::ImportArgs::default(),
+            liveness: liveness::LivenessArgs::default(),
+            streaming: streaming::StreamingArgs::default(),
             behaviors: ValidationBehaviors::default(),
+            report: ValidationReportArgs::default(),
         };
 
-        run(&args)?;
+        run(&args, &mut ValidationReport::default())?;
 
         let output_content = read_to_string(&output_path)?;
         let mut lines = output_content.lines();
@@ -1084,20 +2430,26 @@ mod tests {
             rule_files: RuleFiles {
                 existing_rules: vec![existing_path.clone()],
                 add_rules: vec![new_path.clone()],
+                remove_rules: Vec::new(),
             },
             default_status_code: 302,
             output: Output {
                 output_dir: dir.path().to_path_buf(),
                 rules_output_file: "output.txt".to_string(),
-                encoded_sources: "sources.fst".to_string(),
-                encoded_targets: "targets.fcsd".to_string(),
+                bundle: "redirects.bundle".to_string(),
+                changes_output_file: "changes.txt".to_string(),
             },
             include_existing: false,
+            normalize_sources: false,
+            import: import::ImportArgs::default(),
+            liveness: liveness::LivenessArgs::default(),
+            streaming: streaming::StreamingArgs::default(),
             behaviors: ValidationBehaviors::default(),
+            report: ValidationReportArgs::default(),
         };
 
         // Run should not succeed, because providing rules to add signaled that an update should happen.
-        let run_result = run(&args);
+        let run_result = run(&args, &mut ValidationReport::default());
         assert!(run_result.is_err()); // The overall run succeeds (FST/FCSD generated)
 
         // Check that the output file was NOT created or is empty because write_to_file errored internally
@@ -1112,6 +2464,7 @@ mod tests {
         let rules = RedirectsSource {
             path: Path::new("comments"),
             contents: "/valid /target # This is a comment\n/another /valid  # Comment with spaces\n# Just a comment line\n/no-comment /here".to_string(),
+            imported: None,
         };
         redirects.add_rules(&rules, &ValidationBehaviors::default());
         assert!(
@@ -1133,6 +2486,7 @@ mod tests {
         let rules = RedirectsSource {
             path: Path::new("invalid_comment"),
             contents: "/invalid # comment".to_string(),
+            imported: None,
         };
         let behaviors = ValidationBehaviors {
             invalid_lines: ValidationBehavior::Warn, // Warn to check the error message
@@ -1164,6 +2518,7 @@ mod tests {
         let rules = RedirectsSource {
             path: Path::new("hash_path"),
             contents: "/path#frag /target".to_string(),
+            imported: None,
         };
         redirects.add_rules(&rules, &ValidationBehaviors::default());
         assert!(redirects.map.is_empty(), "Invalid rule should not be added");
@@ -1186,6 +2541,7 @@ mod tests {
         let rules = RedirectsSource {
             path: Path::new("test"),
             contents: "/source /target 301\n/source2 /target2".to_string(),
+            imported: None,
         };
         redirects.add_rules(&rules, &ValidationBehaviors::default());
         assert_eq!(redirects.map.get("/source").unwrap().status_code, 301);
@@ -1199,6 +2555,7 @@ mod tests {
             path: Path::new("test"),
             contents: "/source /target abc\n/source2 /target2 200\n/source3 /target3 600"
                 .to_string(),
+            imported: None,
         };
         redirects.add_rules(&rules, &ValidationBehaviors::default());
 
@@ -1221,6 +2578,7 @@ mod tests {
         let rules = RedirectsSource {
             path: Path::new("test"),
             contents: "/source1 /target1 301\n/source2 /target2 302\n/source3 /target3 307\n/source4 /target4 308".to_string(),
+            imported: None,
         };
         redirects.add_rules(&rules, &ValidationBehaviors::default());
 
@@ -1232,6 +2590,75 @@ mod tests {
         assert_eq!(redirects.parse_errors.len(), 0);
     }
 
+    #[test]
+    fn test_validation_report() -> Result<()> {
+        let dir = tempdir()?;
+        let new_path = dir.path().join("new.txt");
+        std::fs::write(&new_path, "/a /b\n/b /c\ninvalid\n/x /y\n/y /x")?;
+
+        let mut args = Args {
+            rule_files: RuleFiles {
+                existing_rules: Vec::new(),
+                add_rules: vec![new_path.clone()],
+                remove_rules: Vec::new(),
+            },
+            default_status_code: 302,
+            output: Output {
+                output_dir: dir.path().to_path_buf(),
+                rules_output_file: "output.txt".to_string(),
+                bundle: "redirects.bundle".to_string(),
+                changes_output_file: "changes.txt".to_string(),
+            },
+            include_existing: false,
+            normalize_sources: false,
+            import: import::ImportArgs::default(),
+            liveness: liveness::LivenessArgs::default(),
+            streaming: streaming::StreamingArgs::default(),
+            behaviors: ValidationBehaviors {
+                invalid_lines: ValidationBehavior::Warn,
+                ..Default::default()
+            },
+            report: ValidationReportArgs {
+                report_format: Some(validation_report::ReportFormat::Json),
+                ..ValidationReportArgs::default()
+            },
+        };
+
+        // The loop fails the run, but the report is written anyway
+        let mut report = ValidationReport::default();
+        assert!(run(&args, &mut report).is_err());
+        assert_eq!(
+            args.report.exit_code(&Err::<(), _>(anyhow!("")), &report),
+            1
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&read_to_string(dir.path().join("validation-report.json"))?)?;
+        assert_eq!(json["summary"]["failed"], true);
+        assert_eq!(json["summary"]["warnings"], 1);
+        assert_eq!(json["summary"]["loops"], 1);
+        assert_eq!(json["summary"]["chain_status_codes"], "refuse");
+        assert_eq!(json["findings"][0]["check"], "invalid-lines");
+        assert_eq!(json["findings"][0]["line"], 3);
+        assert_eq!(json["findings"][1]["check"], "loops");
+        assert_eq!(json["findings"][1]["severity"], "error");
+        assert_eq!(json["findings"][1]["related"][0]["line"], 4);
+        assert_eq!(json["findings"][1]["related"][1]["line"], 5);
+
+        std::fs::write(&new_path, "/a /b\n/b /c\ninvalid")?;
+        args.report.warning_exit_code = 3;
+        let mut report = ValidationReport::default();
+        let result = run(&args, &mut report);
+        assert!(result.is_ok());
+        assert_eq!(args.report.exit_code(&result, &report), 3);
+        assert_eq!(report.chains.len(), 1);
+        assert_eq!(report.chains[0].line, 1);
+        assert_eq!(report.chains[0].to, "/c");
+        let steps = &report.chains[0].steps;
+        assert_eq!(steps.len(), 2);
+        assert_eq!((steps[1].line, steps[1].from.as_str()), (2, "/b"));
+        Ok(())
+    }
+
     #[test]
     fn test_status_codes_in_file_output() -> Result<()> {
         let dir = tempdir()?;
@@ -1241,6 +2668,7 @@ mod tests {
         let rules = RedirectsSource {
             path: Path::new("test"),
             contents: "/source1 /target1 301\n/source2 /target2".to_string(),
+            imported: None,
         };
         redirects.add_rules(&rules, &ValidationBehaviors::default());
 
@@ -1261,11 +2689,14 @@ mod tests {
         let rules = RedirectsSource {
             path: Path::new("chains"),
             contents: "/a /b 301\n/b /c 301\n/c /d 301".to_string(),
+            imported: None,
         };
         redirects.add_rules(&rules, &ValidationBehaviors::default());
         assert!(redirects.parse_errors.is_empty());
 
-        redirects.shorten_chains().unwrap();
+        redirects
+            .flatten_chains(&ValidationBehaviors::default())
+            .unwrap();
 
         // Verify the shortened chains use the status code from the final target
         assert_eq!(redirects.map.get("/a").unwrap().to, "/d");
@@ -1281,6 +2712,7 @@ mod tests {
         let rules = RedirectsSource {
             path: Path::new("mixed"),
             contents: "/page1 /page2\n/page2 /page3 301\n/page3 /page4\n/page4 /page5".to_string(),
+            imported: None,
         };
         redirects.add_rules(&rules, &ValidationBehaviors::default());
 
@@ -1289,7 +2721,9 @@ mod tests {
         assert_eq!(redirects.map.get("/page3").unwrap().status_code, 302); // Default
         assert_eq!(redirects.map.get("/page4").unwrap().status_code, 302); // Explicit
 
-        redirects.shorten_chains().unwrap();
+        redirects
+            .flatten_chains(&ValidationBehaviors::default())
+            .unwrap();
 
         // First rule isn't eliminated because it has a different status code
         assert_eq!(redirects.map.get("/page1").unwrap().to, "/page2");
@@ -1305,43 +2739,1184 @@ mod tests {
     }
 
     #[test]
-    fn test_custom_status_code_serialization() -> Result<()> {
-        let dir = tempdir()?;
-        let existing_path = dir.path().join("existing.txt");
-        let new_path = dir.path().join("new.txt");
-        let output_path = dir.path().join("output.txt");
+    fn test_chain_status_code_policies() {
+        let rules = RedirectsSource {
+            path: Path::new("mixed"),
+            contents: "/a /b 301\n/b /c 302\n/c /d 301\n/x /y 308\n/y /z 307\n/p /q 303\n/q /r 301"
+                .to_string(),
+            imported: None,
+        };
+        let flattened = |chain_status_codes| {
+            let checks = ValidationBehaviors {
+                chain_status_codes,
+                ..Default::default()
+            };
+            let mut redirects = RedirectsMap::new(302);
+            redirects.add_rules(&rules, &checks);
+            let chains = redirects.flatten_chains(&checks).unwrap();
+            let status_codes = ["/a", "/x", "/p"].map(|key| {
+                let entry = redirects.map.get(key).unwrap();
+                (entry.to, entry.status_code)
+            });
+            (chains.len(), status_codes)
+        };
 
-        let existing_content = format!("{GENERATED_FILE_HEADER}\n/old /intermediate 301");
-        let new_content = "/intermediate /new 308\n/another /rule 307";
-        std::fs::write(&existing_path, &existing_content)?;
-        std::fs::write(&new_path, new_content)?;
+        let (chains, _) = flattened(ChainStatusCodes::Refuse);
+        assert_eq!(chains, 0);
+        let (chains, status_codes) = flattened(ChainStatusCodes::First);
+        assert_eq!(chains, 4);
+        assert_eq!(status_codes, [("/d", 301), ("/z", 308), ("/r", 303)]);
+        let (_, status_codes) = flattened(ChainStatusCodes::Weakest);
+        assert_eq!(status_codes, [("/d", 302), ("/z", 307), ("/r", 303)]);
+        let (_, status_codes) = flattened(ChainStatusCodes::Strictest);
+        assert_eq!(status_codes, [("/d", 301), ("/z", 308), ("/r", 301)]);
+
+        // Rules with other status codes aren't combined with any
+        assert_eq!(
+            ChainStatusCodes::Weakest.status_code(301, StatusCodes::set(&[301, 308])),
+            301
+        );
+        assert_eq!(StatusCodes::of(304), None);
+    }
 
-        let args = Args {
-            rule_files: RuleFiles {
-                existing_rules: vec![existing_path.clone()],
-                add_rules: vec![new_path.clone()],
-            },
-            default_status_code: 302,
-            output: Output {
-                output_dir: dir.path().to_path_buf(),
-                rules_output_file: "output.txt".to_string(),
-                encoded_sources: "sources.fst".to_string(),
-                encoded_targets: "targets.fcsd".to_string(),
-            },
-            include_existing: true,
-            behaviors: ValidationBehaviors::default(),
+    #[test]
+    fn test_chain_status_code_policies_with_other_status_codes() {
+        let rules = RedirectsSource {
+            path: Path::new("other"),
+            contents: "/a /b 305\n/b /c 305\n/x /y 305\n/y /z 301".to_string(),
+            imported: None,
         };
+        for chain_status_codes in ChainStatusCodes::value_variants() {
+            let checks = ValidationBehaviors {
+                chain_status_codes: *chain_status_codes,
+                ..Default::default()
+            };
+            let mut redirects = RedirectsMap::new(302);
+            redirects.add_rules(&rules, &checks);
+            let chains = redirects.flatten_chains(&checks).unwrap();
+            // Chains through a single status code keep it, whatever the policy
+            assert_eq!(chains.len(), 1, "{chain_status_codes:?}");
+            let entry = redirects.map.get("/a").unwrap();
+            assert_eq!((entry.to, entry.status_code), ("/c", 305));
+            assert_eq!(redirects.map.get("/x").unwrap().to, "/y");
+        }
+    }
 
-        run(&args)?;
-
-        let output_content = read_to_string(&output_path)?;
-        let lines: HashSet<&str> = output_content.lines().collect();
+    #[test]
+    fn test_target_hosts() {
+        let rules = RedirectsSource {
+            path: Path::new("targets"),
+            contents: "/a https://example.com/a\n/b https://shop.Example.com/b\n\
+                       /c https://evil.com/c\n/d http://example.com/d\n/e /local\n\
+                       /f https://notexample.com/f\n/g /g2 when=lang:de:https://evil.com/g"
+                .to_string(),
+            imported: None,
+        };
+        let failed = |checks: &ValidationBehaviors| {
+            let mut redirects = RedirectsMap::new(302);
+            redirects.add_rules(&rules, checks);
+            redirects.check_target_hosts(checks);
+            redirects
+                .parse_errors
+                .iter()
+                .map(|failed| {
+                    assert_eq!(failed.reason.check, "disallowed-targets");
+                    failed.line_no
+                })
+                .collect::<Vec<_>>()
+        };
 
-        // No shortening because of different status codes, so all rules should be present
-        assert!(lines.contains("/old /intermediate 301"));
+        assert!(failed(&ValidationBehaviors::default()).is_empty());
+        let mut checks = ValidationBehaviors {
+            allowed_target_hosts: vec!["example.com".to_string(), "*.example.com".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(failed(&checks), [2, 5, 6]);
+        checks.target_schemes = TargetSchemes::Https;
+        assert_eq!(failed(&checks), [2, 3, 5, 6]);
+        checks.allowed_target_hosts.clear();
+        assert_eq!(failed(&checks), [3]);
+        checks.disallowed_targets = ValidationBehavior::Ignore;
+        assert!(failed(&checks).is_empty());
+
+        // Targets that could leave the site without naming a host are refused while parsing
+        let open = RedirectsSource {
+            path: Path::new("open"),
+            contents: "/a //evil.example/x\n/b/* /$1\n/c/* /c2/$1".to_string(),
+            imported: None,
+        };
+        let mut redirects = RedirectsMap::new(302);
+        redirects.add_rules(&open, &ValidationBehaviors::default());
+        let invalid = redirects
+            .parse_errors
+            .iter()
+            .map(|failed| (failed.reason.check, failed.line_no))
+            .collect::<Vec<_>>();
+        assert_eq!(invalid, [("invalid-lines", 0), ("invalid-lines", 1)]);
+
+        assert_eq!(
+            parse_allowed_host("*.Example.COM"),
+            Ok("*.example.com".to_string())
+        );
+        assert!(parse_allowed_host("*.*.example.com").is_err());
+        assert!(parse_allowed_host("exa mple.com").is_err());
+        assert!(is_allowed_host(
+            &["*.example.com".to_string()],
+            "a.b.example.com"
+        ));
+        assert!(!is_allowed_host(
+            &["*.example.com".to_string()],
+            "example.com"
+        ));
+    }
+
+    #[test]
+    fn test_owned_host_chains() {
+        let rules = RedirectsSource {
+            path: Path::new("hosts"),
+            contents: "/a https://shop.example.com/b\n/b /c host=shop.example.com\n\
+                       /x https://other.example.com/b\n/y https://shop.example.com/none"
+                .to_string(),
+            imported: None,
+        };
+        let checks = ValidationBehaviors {
+            owned_host_chains: ValidationBehavior::Warn,
+            ..Default::default()
+        };
+        let mut redirects = RedirectsMap::new(302);
+        redirects.add_rules(&rules, &checks);
+        redirects.check_owned_host_chains(&checks);
+        assert_eq!(redirects.parse_errors.len(), 1);
+        let failed = &redirects.parse_errors[0];
+        assert_eq!(failed.reason.check, "owned-host-chains");
+        assert_eq!(failed.line_no, 0);
+        assert!(failed.reason.message.contains("'/b' (hosts#1)"));
+
+        let mut redirects = RedirectsMap::new(302);
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        redirects.check_owned_host_chains(&ValidationBehaviors::default());
+        assert!(redirects.parse_errors.is_empty());
+    }
+
+    #[test]
+    fn test_custom_status_code_serialization() -> Result<()> {
+        let dir = tempdir()?;
+        let existing_path = dir.path().join("existing.txt");
+        let new_path = dir.path().join("new.txt");
+        let output_path = dir.path().join("output.txt");
+
+        let existing_content = format!("{GENERATED_FILE_HEADER}\n/old /intermediate 301");
+        let new_content = "/intermediate /new 308\n/another /rule 307";
+        std::fs::write(&existing_path, &existing_content)?;
+        std::fs::write(&new_path, new_content)?;
+
+        let args = Args {
+            rule_files: RuleFiles {
+                existing_rules: vec![existing_path.clone()],
+                add_rules: vec![new_path.clone()],
+                remove_rules: Vec::new(),
+            },
+            default_status_code: 302,
+            output: Output {
+                output_dir: dir.path().to_path_buf(),
+                rules_output_file: "output.txt".to_string(),
+                bundle: "redirects.bundle".to_string(),
+                changes_output_file: "changes.txt".to_string(),
+            },
+            include_existing: true,
+            normalize_sources: false,
+            import: import::ImportArgs::default(),
+            liveness: liveness::LivenessArgs::default(),
+            streaming: streaming::StreamingArgs::default(),
+            behaviors: ValidationBehaviors::default(),
+            report: ValidationReportArgs::default(),
+        };
+
+        run(&args, &mut ValidationReport::default())?;
+
+        let output_content = read_to_string(&output_path)?;
+        let lines: HashSet<&str> = output_content.lines().collect();
+
+        // No shortening because of different status codes, so all rules should be present
+        assert!(lines.contains("/old /intermediate 301"));
         assert!(lines.contains("/intermediate /new 308"));
         assert!(lines.contains("/another /rule 307"));
 
         Ok(())
     }
+
+    #[test]
+    fn test_wildcard_rules() {
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("wildcards"),
+            contents: "/old-blog/* /blog/$1 301\n/old-blog/special /special\n/docs/* https://docs.example.com/$1".to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        assert!(redirects.parse_errors.is_empty());
+        assert_eq!(redirects.map.get("/old-blog/*").unwrap().to, "/blog/$1");
+        assert_eq!(redirects.map.get("/old-blog/*").unwrap().status_code, 301);
+
+        let target = lookup_target(&redirects, "/old-blog/2024/post").unwrap();
+        assert_eq!(target, "/blog/2024/post");
+        let target = lookup_target(&redirects, "/old-blog/special").unwrap();
+        assert_eq!(target, "/special"); // Exact matches take priority
+        let target = lookup_target(&redirects, "/docs/").unwrap();
+        assert_eq!(target, "https://docs.example.com/");
+        assert!(lookup_target(&redirects, "/old-blog").is_none());
+    }
+
+    #[test]
+    fn test_wildcard_longest_prefix_wins() {
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("wildcards"),
+            contents: "/a/* /x/$1\n/a/b/* /y/$1".to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+
+        assert_eq!(lookup_target(&redirects, "/a/b/c").unwrap(), "/y/c");
+        assert_eq!(lookup_target(&redirects, "/a/c").unwrap(), "/x/c");
+    }
+
+    #[test]
+    fn test_invalid_wildcard_rules() {
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("wildcards"),
+            contents: "/a/*/b /c\n/old/* /old/new/$1\n/* /index.html".to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        assert!(redirects.map.is_empty());
+        assert_eq!(redirects.parse_errors.len(), 3);
+        assert_eq!(
+            redirects.parse_errors[0].reason.severity,
+            ValidationBehavior::Error
+        );
+        assert!(
+            redirects.parse_errors[0]
+                .reason
+                .message
+                .contains("only supported at the end")
+        );
+        assert!(
+            redirects.parse_errors[1]
+                .reason
+                .message
+                .contains("source prefix")
+        );
+        assert!(
+            redirects.parse_errors[2]
+                .reason
+                .message
+                .contains("source prefix")
+        );
+    }
+
+    #[test]
+    fn test_loop_through_wildcard_rules() {
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("wildcards"),
+            contents: "/a/* /b/$1\n/b/* /a/$1".to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        assert!(redirects.parse_errors.is_empty());
+
+        let err_msg = loops_error(&redirects.find_loops().unwrap()).to_string();
+        assert!(err_msg.contains("/a/$1"), "Error should mention /a/$1");
+        assert!(err_msg.contains("/b/$1"), "Error should mention /b/$1");
+
+        // A growing loop never revisits the same path, but still never terminates
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("wildcards"),
+            contents: "/a/* /b/x/$1\n/b/* /a/$1\n/start /a/page".to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        assert!(!redirects.find_loops().unwrap().is_empty());
+
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("wildcards"),
+            contents: "/a/* /b/$1\n/b/x/* /c/$1\n/start /a/x/page".to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        assert!(redirects.find_loops().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_query_mode_options() {
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("query"),
+            contents: "/a /b query=forward\n/c /d 301 query=ignore\n/e /f query=exact\n/g /h"
+                .to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        assert!(redirects.parse_errors.is_empty());
+        assert_eq!(
+            redirects.map.get("/a").unwrap().options.query,
+            Some(QueryMode::Forward)
+        );
+        assert_eq!(
+            redirects.map.get("/c").unwrap().options.query,
+            Some(QueryMode::Ignore)
+        );
+        assert_eq!(redirects.map.get("/c").unwrap().status_code, 301);
+        assert_eq!(
+            redirects.map.get("/e").unwrap().options.query,
+            Some(QueryMode::Exact)
+        );
+        assert_eq!(redirects.map.get("/g").unwrap().options.query, None);
+    }
+
+    #[test]
+    fn test_invalid_query_mode_options() {
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("query"),
+            contents: "/a /b query=sometimes\n/c /d query=ignore query=forward\n/e /f colour=blue\n/g /h 301 extra\n/i?x=1 /j query=forward".to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        assert!(redirects.map.is_empty());
+        let messages: Vec<_> = redirects
+            .parse_errors
+            .iter()
+            .map(|e| &e.reason.message)
+            .collect();
+        assert!(messages[0].contains("Invalid query mode"));
+        assert!(messages[1].contains("Duplicate rule option"));
+        assert!(messages[2].contains("Unknown rule option"));
+        assert!(messages[3].contains("expected 'key=value'"));
+        assert!(messages[4].contains("only support 'query=exact'"));
+    }
+
+    #[test]
+    fn test_query_mode_lookup() {
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("query"),
+            contents:
+                "/a /b?ref=a query=forward\n/c /d query=exact\n/c?x=1 /e\n/w/* /v/$1 query=forward"
+                    .to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+
+        assert_eq!(
+            lookup_target(&redirects, "/a?utm=x").unwrap(),
+            "/b?ref=a&utm=x"
+        );
+        assert!(lookup_target(&redirects, "/c?y=2").is_none());
+        assert_eq!(lookup_target(&redirects, "/c?x=1").unwrap(), "/e");
+        assert_eq!(
+            lookup_target(&redirects, "/w/page?utm=x").unwrap(),
+            "/v/page?utm=x"
+        );
+    }
+
+    #[test]
+    fn test_query_mode_serialization() -> Result<()> {
+        let dir = tempdir()?;
+        let output_path = dir.path().join("output.txt");
+
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("test"),
+            contents: "/source1 /target1 301 query=forward\n/source2 /target2 query=ignore"
+                .to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        redirects.write_to_file(&output_path, None)?;
+
+        let output_content = read_to_string(&output_path)?;
+        let lines: Vec<&str> = output_content.lines().collect();
+        assert!(lines.contains(&"/source1 /target1 301 query=forward"));
+        assert!(lines.contains(&"/source2 /target2 query=ignore"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_query() {
+        assert_eq!(append_query("/b", "x=1"), "/b?x=1");
+        assert_eq!(append_query("/b?y=2", "x=1"), "/b?y=2&x=1");
+        assert_eq!(append_query("/b?", "x=1"), "/b?x=1");
+        assert_eq!(append_query("/b#frag", "x=1"), "/b?x=1#frag");
+        assert_eq!(append_query("/b", ""), "/b");
+    }
+
+    #[test]
+    fn test_normalized_sources() {
+        let mut redirects = RedirectsMap::new(302);
+        redirects.normalize_sources = true;
+        let rules = RedirectsSource {
+            path: Path::new("normalized"),
+            contents: "/About/ /about-us\n/Blog//* /blog-archive/$1\n/Team /team".to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        assert!(redirects.map.contains_key("/about"));
+        assert!(redirects.map.contains_key("/blog/*"));
+
+        // Redirecting to a variant of the source itself is a self-loop
+        assert_eq!(redirects.parse_errors.len(), 1);
+        assert!(
+            redirects.parse_errors[0]
+                .reason
+                .message
+                .contains("cannot be the same")
+        );
+
+        assert_eq!(lookup_target(&redirects, "/ABOUT").unwrap(), "/about-us");
+        // Wildcard rules take the suffix from the requested path, and match their prefix itself
+        assert_eq!(
+            lookup_target(&redirects, "/blog/Post").unwrap(),
+            "/blog-archive/Post"
+        );
+        assert_eq!(
+            lookup_target(&redirects, "/BLOG/").unwrap(),
+            "/blog-archive/"
+        );
+    }
+
+    #[test]
+    fn test_normalization_conflicts() {
+        let mut redirects = RedirectsMap::new(302);
+        redirects.normalize_sources = true;
+        let rules = RedirectsSource {
+            path: Path::new("normalized"),
+            contents: "/about /about-us\n/About/ /other\n/about /replaced".to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+
+        // Exact duplicates still replace earlier rules, but variants are reported
+        assert_eq!(redirects.map.get("/about").unwrap().to, "/replaced");
+        assert_eq!(redirects.parse_errors.len(), 1);
+        assert_eq!(redirects.parse_errors[0].line_no, 1);
+        assert_eq!(
+            redirects.parse_errors[0].reason.severity,
+            ValidationBehavior::Error
+        );
+        assert!(
+            redirects.parse_errors[0]
+                .reason
+                .message
+                .contains("normalization")
+        );
+
+        // Without normalization, both variants are separate rules
+        let mut redirects = RedirectsMap::new(302);
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        assert_eq!(redirects.map.len(), 2);
+        assert!(redirects.parse_errors.is_empty());
+    }
+
+    #[test]
+    fn test_normalized_wildcard_loop() {
+        let mut redirects = RedirectsMap::new(302);
+        redirects.normalize_sources = true;
+        let rules = RedirectsSource {
+            path: Path::new("normalized"),
+            contents: "/Blog/* /blog/$1\n/old/* /old/".to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        // The target `/old/` matches the wildcard rule itself
+        assert_eq!(redirects.parse_errors.len(), 2);
+        assert_eq!(redirects.parse_errors[0].line_no, 0);
+        assert_eq!(redirects.parse_errors[1].line_no, 1);
+        assert!(redirects.map.is_empty());
+    }
+
+    #[test]
+    fn test_host_scoped_rules() {
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("hosts"),
+            contents: "/sale /offers host=Shop.Example.com\n/sale /promotions\n/blog/* /news/$1 host=www.example.com".to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        assert!(redirects.parse_errors.is_empty());
+        assert_eq!(redirects.map.len(), 3);
+        assert_eq!(
+            redirects.map.get("shop.example.com/sale").unwrap().to,
+            "/offers"
+        );
+        assert_eq!(redirects.map.get("/sale").unwrap().to, "/promotions");
+
+        assert_eq!(
+            lookup_target(&redirects, "shop.example.com/sale").unwrap(),
+            "/offers"
+        );
+        // Hosts without specific rules fall back to host-agnostic ones
+        assert_eq!(
+            lookup_target(&redirects, "www.example.com/sale").unwrap(),
+            "/promotions"
+        );
+        assert_eq!(
+            lookup_target(&redirects, "www.example.com/blog/a").unwrap(),
+            "/news/a"
+        );
+        assert!(lookup_target(&redirects, "shop.example.com/blog/a").is_none());
+    }
+
+    #[test]
+    fn test_invalid_hosts() {
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("hosts"),
+            contents:
+                "/a /b host=example.com:8080\n/a /b host=example.com/path\n/a /b host=a host=b"
+                    .to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        assert!(redirects.map.is_empty());
+        assert_eq!(redirects.parse_errors.len(), 3);
+        assert!(
+            redirects.parse_errors[0]
+                .reason
+                .message
+                .contains("Invalid host")
+        );
+        assert!(
+            redirects.parse_errors[1]
+                .reason
+                .message
+                .contains("Invalid host")
+        );
+        assert!(
+            redirects.parse_errors[2]
+                .reason
+                .message
+                .contains("Duplicate")
+        );
+    }
+
+    #[test]
+    fn test_loop_across_hosts() {
+        // Absolute targets on configured hosts continue the chain on that host
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("hosts"),
+            contents: "/a https://shop.example.com/b host=www.example.com\n/b https://www.example.com/a host=shop.example.com".to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        let err_msg = loops_error(&redirects.find_loops().unwrap()).to_string();
+        assert!(err_msg.contains("shop.example.com/b"));
+        assert!(err_msg.contains("www.example.com/a"));
+
+        // Host-specific rules don't loop with the same paths on other hosts
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("hosts"),
+            contents: "/a /b host=www.example.com\n/b /a host=shop.example.com".to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        assert!(redirects.find_loops().unwrap().is_empty());
+
+        // But they do loop with host-agnostic rules
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("hosts"),
+            contents: "/a /b host=www.example.com\n/b /a".to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        assert!(!redirects.find_loops().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_host_aware_chain_shortening() -> Result<()> {
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("hosts"),
+            contents:
+                "/a /b host=www.example.com\n/b /c\n/x /y\n/y /z host=shop.example.com\n/y /w"
+                    .to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        redirects.flatten_chains(&ValidationBehaviors::default())?;
+
+        // Host-specific rules can be shortened through host-agnostic ones
+        assert_eq!(redirects.map.get("www.example.com/a").unwrap().to, "/c");
+        // Host-agnostic rules can't be shortened if another host has a specific rule
+        assert_eq!(redirects.map.get("/x").unwrap().to, "/y");
+
+        let dir = tempdir()?;
+        let output_path = dir.path().join("output.txt");
+        redirects.write_to_file(&output_path, None)?;
+        let output_content = read_to_string(&output_path)?;
+        let lines: Vec<&str> = output_content.lines().collect();
+        assert!(lines.contains(&"/a /c host=www.example.com"));
+        assert!(lines.contains(&"/y /z host=shop.example.com"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_method_options() -> Result<()> {
+        let dir = tempdir()?;
+        let output_path = dir.path().join("output.txt");
+
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("methods"),
+            contents: "/a /b methods=get\n/c /d 308 methods=POST,put\n/e /f\n\
+                       /g /h methods=GETT\n/i /j methods=GET methods=POST"
+                .to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        let messages: Vec<_> = redirects
+            .parse_errors
+            .iter()
+            .map(|e| &e.reason.message)
+            .collect();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].contains("Invalid methods 'GETT'"));
+        assert!(messages[1].contains("Duplicate rule option"));
+
+        // GET implies HEAD
+        let get = redirects.map.get("/a").unwrap().options.methods.unwrap();
+        assert_eq!(get.to_string(), "GET,HEAD");
+        assert_eq!(rule::MethodSet::encoded(Some(get)), 0b11);
+        let post_put = redirects.map.get("/c").unwrap().options.methods.unwrap();
+        assert_eq!(post_put.to_string(), "POST,PUT");
+        assert_eq!(
+            redirects
+                .map
+                .get("/c")
+                .unwrap()
+                .options
+                .record(308, |_| 0)
+                .methods,
+            0b1100
+        );
+        assert_eq!(redirects.map.get("/e").unwrap().options.methods, None);
+
+        redirects.write_to_file(&output_path, None)?;
+        let output_content = read_to_string(&output_path)?;
+        let lines: Vec<&str> = output_content.lines().collect();
+        assert!(lines.contains(&"/a /b methods=GET,HEAD"));
+        assert!(lines.contains(&"/c /d 308 methods=POST,PUT"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_chain_shortening_with_methods() {
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("methods"),
+            contents: "/a /b methods=GET\n/b /c methods=GET\n/c /d methods=GET,POST".to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        redirects
+            .flatten_chains(&ValidationBehaviors::default())
+            .unwrap();
+
+        // Chains are only shortened through rules with the same methods
+        assert_eq!(redirects.map.get("/a").unwrap().to, "/c");
+        assert_eq!(redirects.map.get("/b").unwrap().to, "/c");
+        assert_eq!(redirects.map.get("/c").unwrap().to, "/d");
+    }
+
+    #[test]
+    fn test_header_options() -> Result<()> {
+        let dir = tempdir()?;
+        let output_path = dir.path().join("output.txt");
+
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("headers"),
+            contents: "/a /b header=X-Robots-Tag:noindex header=Link:</b>;rel=canonical\n\
+                       /c /d header=Location:/e\n/f /g header=X-Empty:\n/h /i header=NoColon\n\
+                       /j /k header=Bad@Name:x"
+                .to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        let messages: Vec<_> = redirects
+            .parse_errors
+            .iter()
+            .map(|e| e.reason.message.as_str())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Header 'Location' can't be overridden",
+                "Invalid value for header 'X-Empty'",
+                "Invalid header 'NoColon', expected 'header=<name>:<value>'",
+                "Invalid header name 'Bad@Name'",
+            ]
+        );
+        assert_eq!(
+            redirects.map.get("/a").unwrap().options.headers,
+            vec![
+                ("X-Robots-Tag".to_string(), "noindex".to_string()),
+                ("Link".to_string(), "</b>;rel=canonical".to_string()),
+            ]
+        );
+
+        redirects.write_to_file(&output_path, None)?;
+        let output_content = read_to_string(&output_path)?;
+        assert!(
+            output_content
+                .lines()
+                .any(|line| line
+                    == "/a /b header=X-Robots-Tag:noindex header=Link:</b>;rel=canonical")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_schedule_options() -> Result<()> {
+        let dir = tempdir()?;
+        let output_path = dir.path().join("output.txt");
+
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("schedules"),
+            contents: "/sale /campaign valid_from=2025-11-28T09:00:00+01:00 \
+                       valid_until=2025-12-01\n\
+                       /campaign /landing valid_from=2025-11-28T07:00:00Z valid_until=1764547200\n\
+                       /a /b valid_from=tomorrow\n/c /d valid_from=2025-12-01 valid_until=2025-11-28\n\
+                       /e /f valid_until=2025-12-01 valid_until=2025-12-02"
+                .to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        let messages: Vec<_> = redirects
+            .parse_errors
+            .iter()
+            .map(|e| e.reason.message.as_str())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Invalid time 'tomorrow' for 'valid_from', expected an RFC 3339 timestamp, a \
+                 date, or seconds since the epoch",
+                "Rule must become valid before it expires",
+                "Duplicate rule option 'valid_until'",
+            ]
+        );
+        let options = &redirects.map.get("/sale").unwrap().options;
+        assert_eq!(options.valid_from, Some(1_764_316_800));
+        assert_eq!(options.valid_until, Some(1_764_547_200));
+        let record = options.record(302, |_| 0);
+        assert_eq!(
+            (record.valid_from, record.valid_until),
+            (Some(1_764_316_800), Some(1_764_547_200))
+        );
+
+        // Chains are only shortened through rules with the same schedule
+        redirects.flatten_chains(&ValidationBehaviors::default())?;
+        assert_eq!(redirects.map.get("/sale").unwrap().to, "/campaign");
+
+        redirects.write_to_file(&output_path, None)?;
+        let output_content = read_to_string(&output_path)?;
+        assert!(output_content.lines().any(|line| line
+            == "/sale /campaign valid_from=2025-11-28T08:00:00Z valid_until=2025-12-01T00:00:00Z"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rule_records_in_bundle() -> Result<()> {
+        let dir = tempdir()?;
+        let new_path = dir.path().join("new.txt");
+        std::fs::write(
+            &new_path,
+            "/a /target\n/b /target 301\n/c /other 301\n/d /other query=forward methods=GET\n\
+             /e /other header=Cache-Control:no-cache",
+        )?;
+
+        let args = Args {
+            rule_files: RuleFiles {
+                existing_rules: vec![],
+                add_rules: vec![new_path],
+                remove_rules: Vec::new(),
+            },
+            default_status_code: 302,
+            output: Output {
+                output_dir: dir.path().to_path_buf(),
+                rules_output_file: "output.txt".to_string(),
+                bundle: "redirects.bundle".to_string(),
+                changes_output_file: "changes.txt".to_string(),
+            },
+            include_existing: false,
+            normalize_sources: false,
+            import: import::ImportArgs::default(),
+            liveness: liveness::LivenessArgs::default(),
+            streaming: streaming::StreamingArgs::default(),
+            behaviors: ValidationBehaviors::default(),
+            report: ValidationReportArgs::default(),
+        };
+        run(&args, &mut ValidationReport::default())?;
+
+        let bytes = std::fs::read(dir.path().join("redirects.bundle"))?;
+        let bundle = bundle::decode(&bytes)?;
+        // Status codes are stored in the records, not in the targets
+        assert_eq!(bundle.targets.len(), 2);
+        assert_eq!(bundle.records.len(), 4);
+        let rule = |source: &str| {
+            let value = bundle.sources.get(source).unwrap();
+            let target = bundle
+                .targets
+                .decoder()
+                .run((value & ((1 << bundle::RECORD_SHIFT) - 1)) as usize);
+            let record = &bundle.records[(value >> bundle::RECORD_SHIFT) as usize];
+            (String::from_utf8(target).unwrap(), record.clone())
+        };
+
+        let (target, record) = rule("/a");
+        assert_eq!(target, "/target");
+        assert_eq!(record.status_code, 302);
+        let (target, record) = rule("/b");
+        assert_eq!(target, "/target");
+        assert_eq!(record.status_code, 301);
+        // Rules with the same settings share a record
+        assert_eq!(record, rule("/c").1);
+        let (_, record) = rule("/d");
+        assert_eq!((record.query_mode, record.methods), (3, 0b11));
+        let (_, record) = rule("/e");
+        assert_eq!(
+            record.headers,
+            vec![("Cache-Control".to_string(), "no-cache".to_string())]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_regex_rules() {
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("regex"),
+            contents: "~/product\\.php\\?id=(\\d+) /products/$1 301\n\
+                       ~/(a|b)/(\\d+) /new/$2 host=example.com\n\
+                       ~/(unclosed /target\n\
+                       ~/c/(\\d+) /d/$2\n\
+                       ~/x{1000} /target\n\
+                       ~/e/.* /e/index.html\n\
+                       ~/f /g query=forward\n\
+                       ~/product\\.php\\?id=(\\d+) /items/$1 301"
+                .to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        let keys = redirects
+            .regex_rules
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec![r"~/product\.php\?id=(\d+)", r"example.com~/(a|b)/(\d+)",]
+        );
+        // Redefined rules keep their precedence
+        assert_eq!(redirects.regex_rules[0].1.to, "/items/$1");
+
+        let messages = redirects
+            .parse_errors
+            .iter()
+            .map(|e| (e.line_no, e.reason.message.as_str(), e.reason.severity))
+            .collect::<Vec<_>>();
+        assert_eq!(messages.len(), 5);
+        assert!(messages[0].1.starts_with("Invalid regex"));
+        assert!(messages[1].1.contains("only has 1 groups"));
+        assert!(messages[2].1.contains("repeats 1000 times"));
+        // The target matches the pattern itself
+        assert_eq!(messages[3].0, 5);
+        assert_eq!(messages[3].2, ValidationBehaviors::default().self_loops);
+        assert!(messages[4].1.contains("query"));
+    }
+
+    #[test]
+    fn test_regex_overlaps() {
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("overlaps"),
+            contents: "/old/1 /new/1\n/old/2 /new/2\n/old/3 /new/3 host=shop.example.com\n\
+                       ~/old/(\\d+) /new/$1\n~/older/(\\d+) /new/$1\n\
+                       ~/old/(\\d+) /other/$1 host=example.com"
+                .to_string(),
+            imported: None,
+        };
+        let checks = ValidationBehaviors::default();
+        redirects.add_rules(&rules, &checks);
+        assert!(redirects.parse_errors.is_empty());
+        redirects.check_regex_overlaps(&checks).unwrap();
+
+        let messages = redirects
+            .parse_errors
+            .iter()
+            .map(|e| (e.line_no, e.reason.message.as_str(), e.reason.severity))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                (
+                    3,
+                    "Regex rule matches 3 literal rules, which take precedence, e.g. '/old/1' \
+                     (overlaps#0)",
+                    ValidationBehavior::Warn
+                ),
+                // Literal rules without a host apply to every host
+                (
+                    5,
+                    "Regex rule matches 2 literal rules, which take precedence, e.g. '/old/1' \
+                     (overlaps#0)",
+                    ValidationBehavior::Warn
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_regex_rules_in_bundle() -> Result<()> {
+        let dir = tempdir()?;
+        let new_path = dir.path().join("new.txt");
+        std::fs::write(
+            &new_path,
+            "/a /target\n~/p/(\\d+) /products/$1 301\n~/q/(.*) /target host=example.com",
+        )?;
+
+        let args = Args {
+            rule_files: RuleFiles {
+                existing_rules: vec![],
+                add_rules: vec![new_path],
+                remove_rules: Vec::new(),
+            },
+            default_status_code: 302,
+            output: Output {
+                output_dir: dir.path().to_path_buf(),
+                rules_output_file: "output.txt".to_string(),
+                bundle: "redirects.bundle".to_string(),
+                changes_output_file: "changes.txt".to_string(),
+            },
+            include_existing: false,
+            normalize_sources: false,
+            import: import::ImportArgs::default(),
+            liveness: liveness::LivenessArgs::default(),
+            streaming: streaming::StreamingArgs::default(),
+            behaviors: ValidationBehaviors::default(),
+            report: ValidationReportArgs::default(),
+        };
+        run(&args, &mut ValidationReport::default())?;
+
+        let output = std::fs::read_to_string(dir.path().join("output.txt"))?;
+        assert_eq!(
+            output.lines().skip(1).collect::<Vec<_>>(),
+            vec![
+                "/a /target",
+                r"~/p/(\d+) /products/$1 301",
+                r"~/q/(.*) /target host=example.com",
+            ]
+        );
+
+        let bytes = std::fs::read(dir.path().join("redirects.bundle"))?;
+        let bundle = bundle::decode(&bytes)?;
+        assert_eq!(bundle.header.rule_count, 1);
+        assert_eq!(bundle.targets.len(), 2);
+        let rules = bundle
+            .regex_rules
+            .iter()
+            .map(|rule| {
+                let target = bundle
+                    .targets
+                    .decoder()
+                    .run((rule.value & ((1 << bundle::RECORD_SHIFT) - 1)) as usize);
+                let record = &bundle.records[(rule.value >> bundle::RECORD_SHIFT) as usize];
+                (
+                    rule.host.as_deref(),
+                    rule.pattern.as_str(),
+                    String::from_utf8(target).unwrap(),
+                    record.status_code,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rules,
+            vec![
+                (None, r"/p/(\d+)", "/products/$1".to_string(), 301),
+                (Some("example.com"), "/q/(.*)", "/target".to_string(), 302),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_conditional_targets() -> Result<()> {
+        let dir = tempdir()?;
+        let output_path = dir.path().join("output.txt");
+
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("conditions"),
+            contents: "/shop /en/shop when=lang:de:/de/shop when=cookie:region=ch:https://example.ch/shop\n\
+                       /a /b when=geo:CH:/c\n/d /e when=lang:fr:/f when=lang:FR:/g\n\
+                       /h /i when=lang:de:h\n/j /k when=header:X-Test=1:/j\n\
+                       /old/* /new/$1 when=lang:de:/old/de/$1\n\
+                       ~/p/(\\d+) /products/$1 when=lang:de:/de/produkte/$2"
+                .to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        let messages: Vec<_> = redirects
+            .parse_errors
+            .iter()
+            .map(|e| e.reason.message.as_str())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Invalid condition 'geo:CH:/c', expected 'lang:<tag>:<target>', \
+                 'header:<name>=<value>:<target>', or 'cookie:<name>=<value>:<target>'",
+                "Duplicate condition 'lang:fr'",
+                "Invalid format for target of condition 'lang:de': 'h'",
+                "Source and conditional target '/j' cannot be the same",
+                "Conditional target '/old/de/$1' redirects back into the source prefix",
+                "Target '/de/produkte/$2' refers to $2, but the regex only has 1 groups",
+            ]
+        );
+        let options = &redirects.map.get("/shop").unwrap().options;
+        assert_eq!(
+            options.branches,
+            vec![
+                (
+                    Condition::Language("de".to_string()),
+                    "/de/shop".to_string()
+                ),
+                (
+                    Condition::Cookie {
+                        name: "region".to_string(),
+                        value: "ch".to_string()
+                    },
+                    "https://example.ch/shop".to_string()
+                ),
+            ]
+        );
+
+        redirects.write_to_file(&output_path, None)?;
+        let output_content = read_to_string(&output_path)?;
+        assert!(output_content.lines().any(|line| {
+            line
+            == "/shop /en/shop when=lang:de:/de/shop when=cookie:region=ch:https://example.ch/shop"
+        }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_loop_through_conditional_target() {
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("conditions"),
+            contents: "/a /b when=lang:de:/de/a\n/b /c\n/de/a /de/b\n/de/b /a".to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        assert!(redirects.parse_errors.is_empty());
+
+        let err_msg = loops_error(&redirects.find_loops().unwrap()).to_string();
+        assert!(
+            err_msg.contains("/a -> /de/a (when lang:de)"),
+            "Error should mention the conditional target: {err_msg}"
+        );
+
+        // Branches out of a chain are followed as well, and both lead out of it here
+        let mut redirects = RedirectsMap::new(302);
+        let rules = RedirectsSource {
+            path: Path::new("conditions"),
+            contents: "/a /b when=lang:de:/de/a when=header:X-Beta=1:/b\n/b /c\n/de/a /de/b"
+                .to_string(),
+            imported: None,
+        };
+        redirects.add_rules(&rules, &ValidationBehaviors::default());
+        assert!(redirects.find_loops().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_conditional_targets_in_bundle() -> Result<()> {
+        let dir = tempdir()?;
+        let new_path = dir.path().join("new.txt");
+        std::fs::write(
+            &new_path,
+            "/a /en/a when=lang:de:/de/a when=header:CF-IPCountry=CH:/ch/a\n/b /de/a",
+        )?;
+
+        let args = Args {
+            rule_files: RuleFiles {
+                existing_rules: vec![],
+                add_rules: vec![new_path],
+                remove_rules: Vec::new(),
+            },
+            default_status_code: 302,
+            output: Output {
+                output_dir: dir.path().to_path_buf(),
+                rules_output_file: "output.txt".to_string(),
+                bundle: "redirects.bundle".to_string(),
+                changes_output_file: "changes.txt".to_string(),
+            },
+            include_existing: false,
+            normalize_sources: false,
+            import: import::ImportArgs::default(),
+            liveness: liveness::LivenessArgs::default(),
+            streaming: streaming::StreamingArgs::default(),
+            behaviors: ValidationBehaviors::default(),
+            report: ValidationReportArgs::default(),
+        };
+        run(&args, &mut ValidationReport::default())?;
+
+        let bytes = std::fs::read(dir.path().join("redirects.bundle"))?;
+        let bundle = bundle::decode(&bytes)?;
+        // Conditional targets share the targets section with main targets
+        assert_eq!(bundle.targets.len(), 3);
+        let value = bundle.sources.get("/a").unwrap();
+        let record = &bundle.records[(value >> bundle::RECORD_SHIFT) as usize];
+        let branches = record
+            .branches
+            .iter()
+            .map(|(condition, target)| {
+                let target = bundle.targets.decoder().run(*target as usize);
+                (condition.to_string(), String::from_utf8(target).unwrap())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            branches,
+            vec![
+                ("lang:de".to_string(), "/de/a".to_string()),
+                ("header:CF-IPCountry=CH".to_string(), "/ch/a".to_string()),
+            ]
+        );
+        let value = bundle.sources.get("/b").unwrap();
+        assert!(
+            bundle.records[(value >> bundle::RECORD_SHIFT) as usize]
+                .branches
+                .is_empty()
+        );
+
+        Ok(())
+    }
 }
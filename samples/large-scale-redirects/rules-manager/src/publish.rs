@@ -0,0 +1,238 @@
+//! Publishing of redirect bundles to a Spin key-value store.
+//!
+//! Spin's default key-value store is backed by a SQLite database, which we write to directly. The
+//! Wasm component only checks the checksum of bundles it reads from the store, so bundles are fully
+//! validated before they're published. The version identifies the published bundle, and is written
+//! in the same transaction as the bundle itself.
+
+use anyhow::{Context, Result};
+use redirects_core::bundle;
+use rusqlite::{Connection, params};
+use std::fs::read;
+use std::path::PathBuf;
+
+/// Keys the bundle and its version are stored under. The bundle key must match `src/tables.rs`.
+const VERSION_KEY: &str = "redirects/version";
+const BUNDLE_KEY: &str = "redirects/bundle";
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct PublishArgs {
+    /// Path to the bundle to publish
+    #[arg(long, default_value = "redirects.bundle")]
+    bundle: PathBuf,
+
+    /// Path to the SQLite database backing the Spin key-value store
+    #[arg(long, default_value = ".spin/sqlite_key_value.db")]
+    database: PathBuf,
+
+    /// Label of the key-value store to publish to
+    #[arg(long, default_value = "default")]
+    store: String,
+
+    /// Version to publish the bundle as. Defaults to a hash of the bundle.
+    #[arg(long)]
+    data_version: Option<String>,
+}
+
+pub(crate) fn publish(args: &PublishArgs) -> Result<()> {
+    let bytes = read(&args.bundle)
+        .with_context(|| format!("Failed to read bundle {}", args.bundle.display()))?;
+    // Catch corrupt bundles before they break a running application, which only checks the
+    // checksum
+    let bundle = bundle::decode(&bytes)
+        .with_context(|| format!("Invalid bundle {}", args.bundle.display()))?;
+    println!(
+        "Publishing {} redirects and {} regex rules to {} distinct targets with {} distinct \
+         settings, built at {} (seconds since the epoch), with default status code {} and format \
+         version {}",
+        bundle.sources.len(),
+        bundle.regex_rules.len(),
+        bundle.targets.len(),
+        bundle.records.len(),
+        bundle.header.timestamp,
+        bundle.header.default_status_code,
+        bundle.format_version
+    );
+
+    let version = match &args.data_version {
+        Some(version) => version.clone(),
+        None => format!("{:016x}", fnv1a(&bytes)),
+    };
+
+    if let Some(parent) = args.database.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+    let mut connection = Connection::open(&args.database).with_context(|| {
+        format!(
+            "Failed to open key-value database {}",
+            args.database.display()
+        )
+    })?;
+    write_bundle(&mut connection, &args.store, &bytes, &version)
+        .with_context(|| "Failed to publish redirects".to_string())?;
+    println!(
+        "Published redirects version {version} to store '{}' in {}",
+        args.store,
+        args.database.display()
+    );
+    Ok(())
+}
+
+fn write_bundle(
+    connection: &mut Connection,
+    store: &str,
+    bundle: &[u8],
+    version: &str,
+) -> Result<()> {
+    let transaction = connection.transaction()?;
+    // Same schema as Spin's SQLite key-value implementation, which creates it on first use
+    transaction.execute(
+        "CREATE TABLE IF NOT EXISTS spin_key_value (
+            store TEXT NOT NULL,
+            key   TEXT NOT NULL,
+            value BLOB NOT NULL,
+            PRIMARY KEY (store, key)
+        )",
+        [],
+    )?;
+    {
+        let mut statement = transaction.prepare(
+            "INSERT INTO spin_key_value (store, key, value) VALUES ($1, $2, $3)
+             ON CONFLICT(store, key) DO UPDATE SET value=$3",
+        )?;
+        statement.execute(params![store, BUNDLE_KEY, bundle])?;
+        statement.execute(params![store, VERSION_KEY, version.as_bytes()])?;
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+/// 64-bit FNV-1a hash of `bytes`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn write_test_bundle(dir: &Path, target: &str) -> PathBuf {
+        let path = dir.join("redirects.bundle");
+        let mut build = fst::MapBuilder::memory();
+        build.insert("/a", 0).unwrap();
+        let sources = build.into_inner().unwrap();
+        let mut targets = Vec::new();
+        fcsd::Set::new([target])
+            .unwrap()
+            .serialize_into(&mut targets)
+            .unwrap();
+        let header = bundle::Header {
+            default_status_code: 302,
+            flags: 0,
+            rule_count: 1,
+            timestamp: 0,
+        };
+        let records = [bundle::RuleRecord {
+            status_code: 302,
+            query_mode: 0,
+            methods: 0,
+            headers: vec![],
+            valid_from: None,
+            valid_until: None,
+            branches: vec![],
+        }];
+        write(
+            &path,
+            bundle::encode(&header, &sources, &targets, &records, &[]).unwrap(),
+        )
+        .unwrap();
+        path
+    }
+
+    fn stored_value(database: &Path, store: &str, key: &str) -> Option<Vec<u8>> {
+        let connection = Connection::open(database).unwrap();
+        connection
+            .query_row(
+                "SELECT value FROM spin_key_value WHERE store = $1 AND key = $2",
+                params![store, key],
+                |row| row.get(0),
+            )
+            .ok()
+    }
+
+    #[test]
+    fn test_publish_bundle() {
+        let dir = tempdir().unwrap();
+        let bundle = write_test_bundle(dir.path(), "/b");
+        let database = dir.path().join(".spin").join("sqlite_key_value.db");
+        let args = PublishArgs {
+            bundle: bundle.clone(),
+            database: database.clone(),
+            store: "redirects".to_string(),
+            data_version: None,
+        };
+        publish(&args).unwrap();
+
+        assert_eq!(
+            stored_value(&database, "redirects", BUNDLE_KEY).unwrap(),
+            read(&bundle).unwrap()
+        );
+        let first_version = stored_value(&database, "redirects", VERSION_KEY).unwrap();
+        assert!(stored_value(&database, "default", VERSION_KEY).is_none());
+
+        // Publishing a changed bundle replaces it and bumps the version
+        write_test_bundle(dir.path(), "/c");
+        publish(&args).unwrap();
+        assert_eq!(
+            stored_value(&database, "redirects", BUNDLE_KEY).unwrap(),
+            read(&bundle).unwrap()
+        );
+        let second_version = stored_value(&database, "redirects", VERSION_KEY).unwrap();
+        assert_ne!(first_version, second_version);
+    }
+
+    #[test]
+    fn test_publish_explicit_version() {
+        let dir = tempdir().unwrap();
+        let bundle = write_test_bundle(dir.path(), "/b");
+        let database = dir.path().join("kv.db");
+        publish(&PublishArgs {
+            bundle,
+            database: database.clone(),
+            store: "default".to_string(),
+            data_version: Some("2025-05-01".to_string()),
+        })
+        .unwrap();
+        assert_eq!(
+            stored_value(&database, "default", VERSION_KEY).unwrap(),
+            b"2025-05-01"
+        );
+    }
+
+    #[test]
+    fn test_publish_rejects_invalid_bundle() {
+        let dir = tempdir().unwrap();
+        let bundle = write_test_bundle(dir.path(), "/b");
+        let mut bytes = read(&bundle).unwrap();
+        bytes.truncate(bytes.len() - 1);
+        write(&bundle, bytes).unwrap();
+        let database = dir.path().join("kv.db");
+        let result = publish(&PublishArgs {
+            bundle,
+            database: database.clone(),
+            store: "default".to_string(),
+            data_version: None,
+        });
+        assert!(result.is_err());
+        assert!(!database.exists());
+    }
+}
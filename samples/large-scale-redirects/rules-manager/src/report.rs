@@ -0,0 +1,313 @@
+//! Reporting on rule usage, based on the hits and misses recorded by the Wasm component.
+//!
+//! The component records hits per rule, identified by the rule's key in the sources FST, and misses
+//! per request path. The counts are either stored in a Spin key-value store, or emitted as JSON log
+//! lines. This joins them back to the rules files the bundle was built from.
+
+use crate::{RedirectsMap, RedirectsSource, ValidationBehaviors};
+use anyhow::{Context, Result};
+use rusqlite::{Connection, OpenFlags, params};
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+/// Prefixes of the key-value store keys counters are stored under. These must match
+/// `src/analytics.rs`.
+const HIT_KEY_PREFIX: &str = "analytics/hit/";
+const MISS_KEY_PREFIX: &str = "analytics/miss/";
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ReportArgs {
+    /// Path(s) to the validated redirects file(s) the bundle was built from
+    #[arg(long, num_args = 1.., required = true)]
+    rules: Vec<PathBuf>,
+
+    /// Path to the SQLite database backing the Spin key-value store analytics were recorded in
+    #[arg(
+        long,
+        required_unless_present = "log_files",
+        conflicts_with = "log_files"
+    )]
+    database: Option<PathBuf>,
+
+    /// Label of the key-value store analytics were recorded in
+    #[arg(long, default_value = "default")]
+    store: String,
+
+    /// Path(s) to log files containing analytics log lines
+    #[arg(long, num_args = 1..)]
+    log_files: Vec<PathBuf>,
+
+    /// Number of most frequent misses to list
+    #[arg(long, default_value_t = 20)]
+    top_misses: usize,
+}
+
+/// Recorded hits per rule key, and misses per request path.
+#[derive(Debug, Default)]
+struct Counts {
+    hits: HashMap<String, u64>,
+    misses: HashMap<String, u64>,
+}
+
+impl Counts {
+    fn add(&mut self, event: &str, key: &str, count: u64) {
+        let counts = match event {
+            "hit" => &mut self.hits,
+            "miss" => &mut self.misses,
+            _ => return,
+        };
+        *counts.entry(key.to_string()).or_default() += count;
+    }
+}
+
+pub(crate) fn report(args: &ReportArgs) -> Result<()> {
+    let rules = args
+        .rules
+        .iter()
+        .map(|path| {
+            Ok(RedirectsSource {
+                path,
+                contents: read_to_string(path)
+                    .with_context(|| format!("Failed to read redirects file {}", path.display()))?,
+                imported: None,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let counts = match &args.database {
+        Some(database) => counts_from_database(database, &args.store)?,
+        None => counts_from_logs(&args.log_files)?,
+    };
+
+    let mut redirects = RedirectsMap::new(302);
+    for source in &rules {
+        redirects.add_rules(source, &ValidationBehaviors::default());
+    }
+    let dead_rules = dead_rules(&redirects, &counts);
+    let unknown_hits = counts
+        .hits
+        .keys()
+        .filter(|key| {
+            !redirects.map.contains_key(key.as_str())
+                && !redirects
+                    .regex_rules
+                    .iter()
+                    .any(|(regex_key, _)| regex_key == *key)
+        })
+        .count();
+
+    let rule_count = redirects.map.len() + redirects.regex_rules.len();
+    println!(
+        "{} of {rule_count} rules were hit, {} were not",
+        rule_count - dead_rules.len(),
+        dead_rules.len()
+    );
+    if unknown_hits > 0 {
+        println!(
+            "{unknown_hits} rules with recorded hits are not in the rules files, \
+             which might be outdated"
+        );
+    }
+    if !dead_rules.is_empty() {
+        println!("\nRules without any recorded hits:");
+        for (path, line_no, line) in &dead_rules {
+            println!("  {}#{line_no}: {line}", path.display());
+        }
+    }
+
+    let top_misses = top_misses(&counts, args.top_misses);
+    if !top_misses.is_empty() {
+        println!("\nMost frequent misses:");
+        for (path, count) in top_misses {
+            println!("  {count:>10} {path}");
+        }
+    }
+    Ok(())
+}
+
+/// Returns the location and text of all rules without any recorded hits, ordered by location.
+fn dead_rules<'a>(
+    redirects: &RedirectsMap<'a>,
+    counts: &Counts,
+) -> Vec<(&'a Path, usize, &'a str)> {
+    let mut dead_rules = redirects
+        .all_rules()
+        .filter(|(key, _)| !counts.hits.contains_key(*key))
+        .map(|(_, entry)| {
+            let line = entry.source.contents.lines().nth(entry.line_no).unwrap();
+            (entry.source.path, entry.line_no, line)
+        })
+        .collect::<Vec<_>>();
+    dead_rules.sort();
+    dead_rules
+}
+
+/// Returns up to `limit` request paths with the most misses, most frequent first.
+fn top_misses(counts: &Counts, limit: usize) -> Vec<(&str, u64)> {
+    let mut misses = counts
+        .misses
+        .iter()
+        .map(|(path, count)| (path.as_str(), *count))
+        .collect::<Vec<_>>();
+    misses.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    misses.truncate(limit);
+    misses
+}
+
+fn counts_from_database(database: &Path, store: &str) -> Result<Counts> {
+    let connection = Connection::open_with_flags(database, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Failed to open key-value database {}", database.display()))?;
+    let mut statement = connection.prepare(
+        "SELECT key, value FROM spin_key_value WHERE store = $1 AND key LIKE 'analytics/%'",
+    )?;
+    let rows = statement.query_map(params![store], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+    })?;
+
+    let mut counts = Counts::default();
+    for row in rows {
+        let (key, value) = row?;
+        let Some(count) = std::str::from_utf8(&value)
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        if let Some(rule) = key.strip_prefix(HIT_KEY_PREFIX) {
+            counts.add("hit", rule, count);
+        } else if let Some(path) = key.strip_prefix(MISS_KEY_PREFIX) {
+            counts.add("miss", path, count);
+        }
+    }
+    Ok(counts)
+}
+
+fn counts_from_logs(log_files: &[PathBuf]) -> Result<Counts> {
+    let mut counts = Counts::default();
+    for path in log_files {
+        let contents = read_to_string(path)
+            .with_context(|| format!("Failed to read log file {}", path.display()))?;
+        add_log_lines(&mut counts, &contents);
+    }
+    Ok(counts)
+}
+
+/// Adds the counts from all analytics log lines in `contents`, skipping any other output.
+fn add_log_lines(counts: &mut Counts, contents: &str) {
+    for line in contents.lines() {
+        // Log collectors might prefix lines with timestamps and the like
+        let Some(start) = line.find('{') else {
+            continue;
+        };
+        let Ok(value) = serde_json::from_str::<serde_json::Value>(&line[start..]) else {
+            continue;
+        };
+        let event = value.get("redirects").and_then(|event| event.as_str());
+        let key = value.get("key").and_then(|key| key.as_str());
+        let count = value.get("count").and_then(|count| count.as_u64());
+        if let (Some(event), Some(key), Some(count)) = (event, key, count) {
+            counts.add(event, key, count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GENERATED_FILE_HEADER;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_dead_rules_from_logs() {
+        let source = RedirectsSource {
+            path: Path::new("rules.txt"),
+            contents: format!(
+                "{GENERATED_FILE_HEADER}\n/used /a\n/unused /b 301\n/blog/* /news/$1\n\
+                 /sale /c host=shop.example.com\n/old-sale /d host=shop.example.com\n\
+                 ~/p/(\\d+) /products/$1\n~/q/(\\d+) /questions/$1 host=shop.example.com"
+            ),
+            imported: None,
+        };
+        let mut redirects = RedirectsMap::new(302);
+        redirects.add_rules(&source, &ValidationBehaviors::default());
+
+        let mut counts = Counts::default();
+        add_log_lines(
+            &mut counts,
+            r#"Recording analytics for one in 10 requests to Log
+{"redirects":"hit","key":"/used","count":10}
+2025-05-01T12:00:00Z {"redirects":"hit","key":"/blog/*","count":10}
+{"redirects":"hit","key":"shop.example.com/sale","count":10}
+{"redirects":"hit","key":"~/p/(\\d+)","count":10}
+{"redirects":"hit","key":"/used","count":10}
+{"redirects":"miss","key":"/missing","count":10}
+{"redirects":"miss","key":"/gone","count":10}
+{"redirects":"miss","key":"/missing","count":10}
+{"redirects":"hit","key":"/broken""#,
+        );
+        assert_eq!(counts.hits.get("/used"), Some(&20));
+
+        let dead_rules = dead_rules(&redirects, &counts);
+        assert_eq!(
+            dead_rules,
+            vec![
+                (Path::new("rules.txt"), 2, "/unused /b 301"),
+                (
+                    Path::new("rules.txt"),
+                    5,
+                    "/old-sale /d host=shop.example.com"
+                ),
+                (
+                    Path::new("rules.txt"),
+                    7,
+                    r"~/q/(\d+) /questions/$1 host=shop.example.com"
+                ),
+            ]
+        );
+        assert_eq!(
+            top_misses(&counts, 20),
+            vec![("/missing", 20), ("/gone", 10)]
+        );
+        assert_eq!(top_misses(&counts, 1), vec![("/missing", 20)]);
+    }
+
+    #[test]
+    fn test_counts_from_database() {
+        let dir = tempdir().unwrap();
+        let database = dir.path().join("kv.db");
+        let connection = Connection::open(&database).unwrap();
+        connection
+            .execute(
+                "CREATE TABLE spin_key_value (
+                    store TEXT NOT NULL,
+                    key   TEXT NOT NULL,
+                    value BLOB NOT NULL,
+                    PRIMARY KEY (store, key)
+                )",
+                [],
+            )
+            .unwrap();
+        for (store, key, value) in [
+            ("default", "analytics/hit//used", "5"),
+            ("default", "analytics/hit//blog/*", "7"),
+            ("default", "analytics/miss//missing", "3"),
+            ("default", "redirects/version", "abc"),
+            ("other", "analytics/hit//unused", "1"),
+        ] {
+            connection
+                .execute(
+                    "INSERT INTO spin_key_value (store, key, value) VALUES ($1, $2, $3)",
+                    params![store, key, value.as_bytes()],
+                )
+                .unwrap();
+        }
+
+        let counts = counts_from_database(&database, "default").unwrap();
+        assert_eq!(
+            counts.hits,
+            HashMap::from([("/used".to_string(), 5), ("/blog/*".to_string(), 7)])
+        );
+        assert_eq!(counts.misses, HashMap::from([("/missing".to_string(), 3)]));
+    }
+}
@@ -0,0 +1,741 @@
+//! Looking up rules by the requests they match, and the checks built on that, for both the rules
+//! a `RedirectsMap` keeps in memory and the rules `--streaming` keeps in files.
+//!
+//! Both identify rules by their position in the order of their keys, and implement `RuleIndex` to
+//! look up a rule's key, host and status code, or the rule itself. Everything else, from finding
+//! the rule handling a request to finding loops, overlapping regex rules and the ends of chains, is
+//! only written once, on top of the trait, so that both find the same problems.
+
+use crate::{ChainStatusCodes, MAX_CHAIN_STEPS, StatusCodes, append_query, follow_redirect};
+use anyhow::{Result, anyhow};
+use redirects_core::regex_rules::{self, REGEX_PREFIX};
+use redirects_core::rule::{
+    self, QueryMode, Rule, RuleOptions, is_wildcard_source, normalize_request, split_host,
+    strip_trailing_slash,
+};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+
+/// Marks rules without a successor in a chain, and nodes that weren't visited yet.
+pub(crate) const NONE: u32 = u32::MAX;
+
+/// The hosts with host-specific rules, with IDs starting at 1. Requests without a host, or for a
+/// host without rules, have the ID 0.
+#[derive(Debug, Default)]
+pub(crate) struct Hosts {
+    ids: HashMap<String, u32>,
+    names: Vec<String>,
+}
+
+impl Hosts {
+    /// Returns the ID of `host`, adding it if it's new.
+    pub(crate) fn insert(&mut self, host: &str) -> u32 {
+        if let Some(&id) = self.ids.get(host) {
+            return id;
+        }
+        self.names.push(host.to_string());
+        let id = self.names.len() as u32;
+        self.ids.insert(host.to_string(), id);
+        id
+    }
+
+    pub(crate) fn id(&self, host: &str) -> u32 {
+        self.ids.get(host).copied().unwrap_or(0)
+    }
+
+    /// Returns the name of the host with the ID `id`, which is empty for 0.
+    pub(crate) fn name(&self, id: u32) -> &str {
+        id.checked_sub(1)
+            .map_or("", |index| self.names[index as usize].as_str())
+    }
+
+    pub(crate) fn contains(&self, host: &str) -> bool {
+        self.ids.contains_key(host)
+    }
+
+    pub(crate) fn names(&self) -> impl Iterator<Item = &str> {
+        self.names.iter().map(String::as_str)
+    }
+}
+
+/// Whether rules with `options` match requests regardless of their query string. Since the
+/// component's default query mode isn't known here, rules without an explicit mode are assumed to
+/// do so, which detects every loop that could occur with any default.
+pub(crate) fn ignores_query(options: &RuleOptions) -> bool {
+    options.query != Some(QueryMode::Exact)
+}
+
+/// Rules, identified by their position in the order of their keys.
+pub(crate) trait RuleIndex {
+    fn rule_count(&self) -> usize;
+
+    /// Returns the rule stored under `key`.
+    fn get(&self, key: &str) -> Option<u32>;
+
+    fn normalizes_sources(&self) -> bool;
+
+    fn has_wildcards(&self) -> bool;
+
+    fn hosts(&self) -> &Hosts;
+
+    /// Returns the ID of the host of `rule` in `hosts`, which is 0 for host-agnostic rules.
+    fn host_id(&self, rule: u32) -> u32;
+
+    fn is_wildcard(&self, rule: u32) -> bool;
+
+    /// Whether `rule` matches requests regardless of their query string, see `ignores_query`.
+    fn ignores_query(&self, rule: u32) -> bool;
+
+    fn status_code(&self, rule: u32) -> u16;
+
+    /// Calls `f` with the key of `rule` and the rule itself.
+    fn with_rule<T>(&self, rule: u32, f: impl FnOnce(&str, &Rule) -> T) -> Result<T>;
+
+    /// Returns the rule continuing the chain from `rule` for requests to the host with the ID
+    /// `host`, if it has a compatible status code and the same options apart from its host. Chains
+    /// are only followed through exact rules, see `chain_successors`.
+    fn chain_next(&self, host: u32, rule: u32, status_codes: ChainStatusCodes) -> Option<u32>;
+
+    /// Finds the rule that handles `request` the same way the component does. `request` is a
+    /// path, optionally prefixed with the requested host. Host-specific rules take priority over
+    /// host-agnostic ones.
+    ///
+    /// Returns the matching rule, from which its targets for this specific request can be derived.
+    fn lookup(&self, request: &str) -> Option<Match> {
+        let (host, path) = split_host(request);
+        if !host.is_empty()
+            && let Some(found) = self.lookup_key(request)
+        {
+            return Some(found);
+        }
+        self.lookup_key(path)
+    }
+
+    /// Finds the rule stored under a key matching `path`: an exact match takes priority, then a
+    /// match ignoring the query string, and finally the wildcard rule with the longest matching
+    /// prefix.
+    fn lookup_key(&self, path: &str) -> Option<Match> {
+        // Normalized paths keep their trailing slash for matching wildcard sources, which end in
+        // one, and the offsets to take the suffix from the path as requested
+        let normalize = self.normalizes_sources();
+        let (prefixed, offsets) = if normalize {
+            let (normalized, offsets) = normalize_request(path);
+            (Cow::Owned(normalized), Some(offsets))
+        } else {
+            (Cow::Borrowed(path), None)
+        };
+        let mut exact = prefixed.clone();
+        if normalize {
+            strip_trailing_slash(exact.to_mut());
+        }
+        if let Some(rule) = self.get(&exact) {
+            return Some(Match::new(rule, None, None));
+        }
+        if let Some((path_only, query)) = exact.split_once('?')
+            && let Some(rule) = self.get(path_only).filter(|&rule| self.ignores_query(rule))
+        {
+            return Some(Match::new(rule, None, Some(query)));
+        }
+        if !self.has_wildcards() {
+            return None;
+        }
+        let mut key = String::with_capacity(prefixed.len() + 1);
+        let (rule, i) = (0..=prefixed.len())
+            .rev()
+            .filter(|&i| prefixed.is_char_boundary(i))
+            .find_map(|i| {
+                key.clear();
+                key.push_str(&prefixed[..i]);
+                key.push('*');
+                Some((self.get(&key)?, i))
+            })?;
+        let rest = match &offsets {
+            Some(offsets) => &path[offsets[i]..],
+            None => &prefixed[i..],
+        };
+        Some(match rest.split_once('?') {
+            // The query string isn't part of the captured suffix unless the prefix extends into it
+            Some((suffix, query)) if !prefixed[..i].contains('?') && self.ignores_query(rule) => {
+                Match::new(rule, Some(suffix), Some(query))
+            }
+            _ => Match::new(rule, Some(rest), None),
+        })
+    }
+
+    /// Returns the rule handling the request a client makes when redirected to `target` from a
+    /// request to `host`, if any, see `follow_redirect`.
+    fn follow(&self, host: &str, target: &str) -> Option<Match> {
+        let next = follow_redirect(self.hosts(), host, Cow::Borrowed(target))?;
+        self.lookup(&next)
+    }
+
+    /// Returns the exact rule handling every redirect to the relative `target` from a request to
+    /// `host`, which is empty for requests to hosts without rules.
+    fn chain_successor(&self, host: &str, target: &str) -> Option<u32> {
+        match self.chain_successors(host, target) {
+            // Requests for some hosts are handled by a host-specific rule instead
+            (_, host_next) if host.is_empty() && !host_next.is_empty() => None,
+            (next, _) => next,
+        }
+    }
+
+    /// Returns the exact rule handling redirects to `target` from a request to `host`, and if
+    /// `host` is empty, the rules handling them for the hosts with a host-specific rule for the
+    /// target instead, by host ID. A target that looks like a wildcard source is just a path, and
+    /// redirects to other hosts aren't shortened, since the target would have to become an
+    /// absolute URL, too.
+    fn chain_successors(&self, host: &str, target: &str) -> (Option<u32>, Vec<(u32, u32)>) {
+        if is_wildcard_source(target) || !target.starts_with('/') {
+            return (None, Vec::new());
+        }
+        let path = rule::source_key(target, self.normalizes_sources());
+        if !host.is_empty() {
+            let next = self
+                .get(&format!("{host}{path}"))
+                .or_else(|| self.get(&path));
+            return (next, Vec::new());
+        }
+        let hosts = self.hosts();
+        let host_next = hosts
+            .names()
+            .filter_map(|host| Some((hosts.id(host), self.get(&format!("{host}{path}"))?)))
+            .collect();
+        (self.get(&path), host_next)
+    }
+}
+
+/// Whether a chain can continue from a rule with the status code `current` to one with `next`.
+pub(crate) fn status_codes_compatible(
+    status_codes: ChainStatusCodes,
+    current: u16,
+    next: u16,
+) -> bool {
+    next == current
+        || status_codes != ChainStatusCodes::Refuse
+            && StatusCodes::of(current).is_some()
+            && StatusCodes::of(next).is_some()
+}
+
+/// A rule matching a specific request.
+pub(crate) struct Match {
+    pub(crate) rule: u32,
+    /// The part of the path matching a wildcard rule's `*`, which replaces `$1` in its targets
+    pub(crate) suffix: Option<String>,
+    /// The query string of the request, if the rule ignored it for matching
+    pub(crate) query: Option<String>,
+}
+
+impl Match {
+    fn new(rule: u32, suffix: Option<&str>, query: Option<&str>) -> Self {
+        Self {
+            rule,
+            suffix: suffix.map(str::to_string),
+            query: query.map(str::to_string),
+        }
+    }
+
+    /// Returns `target`, one of the targets of `rule`, the matching rule, as redirected to for
+    /// this request.
+    pub(crate) fn resolve<'t>(&self, rule: &Rule, target: &'t str) -> Cow<'t, str> {
+        let target = match &self.suffix {
+            Some(suffix) => Cow::Owned(target.replace("$1", suffix)),
+            None => Cow::Borrowed(target),
+        };
+        match (&self.query, rule.options.query) {
+            (Some(query), Some(QueryMode::Forward)) => Cow::Owned(append_query(&target, query)),
+            _ => target,
+        }
+    }
+}
+
+/// Returns the indices of the regex rules matching a path.
+type MatchingPatterns = Box<dyn Fn(&str) -> Vec<usize>>;
+
+/// Counts the literal rules each regex rule matches the source of, which take precedence, since
+/// literal rules are looked up first.
+pub(crate) struct RegexOverlaps {
+    matching: Option<MatchingPatterns>,
+    regex_hosts: Vec<Option<String>>,
+    /// The number of overlapping literal rules per regex rule, and the first of them
+    overlaps: Vec<Option<(usize, u32)>>,
+}
+
+impl RegexOverlaps {
+    pub(crate) fn new<'r>(regex_rules: impl IntoIterator<Item = &'r Rule<'r>>) -> Result<Self> {
+        let (patterns, regex_hosts): (Vec<_>, Vec<_>) = regex_rules
+            .into_iter()
+            .map(|rule| {
+                let pattern = &rule.from[REGEX_PREFIX.len_utf8()..];
+                (pattern, rule.options.host.clone())
+            })
+            .unzip();
+        let matching: Option<MatchingPatterns> = match patterns.is_empty() {
+            true => None,
+            false => {
+                let regex = regex_rules::compile(&patterns).map_err(|e| anyhow!(e))?;
+                Some(Box::new(move |path| {
+                    regex_rules::matching_patterns(&regex, path)
+                }))
+            }
+        };
+        Ok(Self {
+            matching,
+            overlaps: vec![None; regex_hosts.len()],
+            regex_hosts,
+        })
+    }
+
+    /// Counts `rule`, stored under `key`, for the regex rules matching its source. Rules have to
+    /// be added in the order of their keys.
+    pub(crate) fn add(&mut self, rule: u32, key: &str) {
+        let Some(matching) = &self.matching else {
+            return;
+        };
+        if is_wildcard_source(key) {
+            return;
+        }
+        let (host, path) = split_host(key);
+        for index in matching(path) {
+            // Literal rules without a host apply to requests for every host
+            let regex_host = self.regex_hosts[index].as_deref();
+            if !host.is_empty() && regex_host.is_some_and(|regex_host| regex_host != host) {
+                continue;
+            }
+            match &mut self.overlaps[index] {
+                Some((count, _)) => *count += 1,
+                overlap => *overlap = Some((1, rule)),
+            }
+        }
+    }
+
+    /// Returns the number of literal rules each regex rule matches and the first of them, if it
+    /// matches any.
+    pub(crate) fn finish(self) -> Vec<Option<(usize, u32)>> {
+        self.overlaps
+    }
+}
+
+/// A redirect between requests on a loop, as found by `find_loops`.
+pub(crate) struct LoopStep {
+    /// The request redirected, a path optionally prefixed with its host
+    pub(crate) request: String,
+    /// The rule redirecting it
+    pub(crate) rule: u32,
+    /// The target redirected to, which is one of the rule's conditional targets if `condition` is
+    /// set
+    pub(crate) target: String,
+    pub(crate) condition: Option<String>,
+}
+
+impl LoopStep {
+    /// Describes the step, with the file and line its rule was defined at.
+    pub(crate) fn describe(&self, file: impl Display, line_no: usize) -> String {
+        let mut description = format!("{file}#{line_no}: {} -> {}", self.request, self.target);
+        if let Some(condition) = &self.condition {
+            description.push_str(&format!(" (when {condition})"));
+        }
+        description
+    }
+}
+
+/// Returns each loop once, as the redirects between the requests in it. Redirects form a graph
+/// of requests, explored from the source of every rule, and its strongly connected components
+/// are the loops. They're found with Tarjan's algorithm, which follows each redirect once.
+pub(crate) fn find_loops(index: &impl RuleIndex) -> Result<Vec<Vec<LoopStep>>> {
+    // Wildcard rules can produce ever-growing paths instead of revisiting the same one, so
+    // chains longer than the number of rules are treated as loops, too.
+    let max_hops = index.rule_count() + 1;
+    let mut graph = RequestGraph::new(index.rule_count());
+    let mut loops = Vec::new();
+
+    for rule in 0..index.rule_count() as u32 {
+        // Wildcard rules are checked using their prefix as a representative path
+        let start = graph.id(
+            index,
+            Node {
+                rule,
+                host: index.host_id(rule),
+                suffix: index.is_wildcard(rule).then(String::new),
+                query: None,
+            },
+        );
+        if graph.index[start] != NONE {
+            continue;
+        }
+        // The requests being followed, with the index of the next redirect to follow
+        let mut call_stack = vec![(start, 0)];
+        while let Some(&(v, next_edge)) = call_stack.last() {
+            if graph.index[v] == NONE {
+                graph.visit(v);
+                let edges = graph.redirects(index, v)?;
+                graph.edges.insert(v, edges);
+            }
+
+            if let Some(w) = graph.edges[&v].get(next_edge).map(|edge| edge.to) {
+                call_stack.last_mut().unwrap().1 += 1;
+                match graph.index[w] {
+                    NONE if call_stack.len() > max_hops => {
+                        loops.push(graph.path_steps(index, &call_stack)?);
+                        // Don't follow the growing paths again from other requests
+                        graph.visit(w);
+                        graph.pop_component(w);
+                    }
+                    NONE => call_stack.push((w, 0)),
+                    w_index if graph.on_stack[w] => {
+                        graph.lowlink[v] = graph.lowlink[v].min(w_index);
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+
+            call_stack.pop();
+            if let Some(&(parent, _)) = call_stack.last() {
+                graph.lowlink[parent] = graph.lowlink[parent].min(graph.lowlink[v]);
+            }
+            if graph.lowlink[v] == graph.index[v] {
+                let component = graph.pop_component(v);
+                if component.len() > 1 || graph.edges[&v].iter().any(|edge| edge.to == v) {
+                    loops.push(graph.component_steps(index, &component)?);
+                }
+                // Redirects from requests whose component is known are never followed again
+                for node in component {
+                    graph.edges.remove(&node);
+                }
+            }
+        }
+    }
+    Ok(loops)
+}
+
+/// The requests a rule redirects the same way, as explored by `find_loops`: requests to the host
+/// with the ID `host` handled by `rule`, with the same part of the path matching a wildcard rule's
+/// `*`, and the same query string if the rule ignores it for matching.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Node {
+    rule: u32,
+    host: u32,
+    suffix: Option<String>,
+    query: Option<String>,
+}
+
+struct Edge {
+    to: usize,
+    target: String,
+    condition: Option<String>,
+}
+
+/// The requests reachable from the sources of rules, and the redirects between them, as explored
+/// by `find_loops`. The requests for a rule's own source and host are the node with the rule's
+/// ID, so that only requests other than those are kept, and Tarjan's algorithm keeps a few numbers
+/// per node.
+struct RequestGraph {
+    rule_count: usize,
+    /// The nodes after the rules' own ones
+    others: Vec<Node>,
+    other_ids: HashMap<Node, usize>,
+    /// The order each node was visited in, `NONE` if it wasn't yet
+    index: Vec<u32>,
+    /// The lowest index of a node reachable from each node that's still on the stack
+    lowlink: Vec<u32>,
+    on_stack: Vec<bool>,
+    /// The visited nodes whose strongly connected component isn't known yet
+    stack: Vec<usize>,
+    next_index: u32,
+    /// The redirects from the nodes on the stack to requests handled by a rule
+    edges: HashMap<usize, Vec<Edge>>,
+}
+
+impl RequestGraph {
+    fn new(rule_count: usize) -> Self {
+        Self {
+            rule_count,
+            others: Vec::new(),
+            other_ids: HashMap::new(),
+            index: vec![NONE; rule_count],
+            lowlink: vec![0; rule_count],
+            on_stack: vec![false; rule_count],
+            stack: Vec::new(),
+            next_index: 0,
+            edges: HashMap::new(),
+        }
+    }
+
+    /// Returns the ID of `node`, adding it if it's new.
+    fn id(&mut self, index: &impl RuleIndex, node: Node) -> usize {
+        let own = node.suffix.is_none() && node.query.is_none();
+        if own && index.host_id(node.rule) == node.host {
+            return node.rule as usize;
+        }
+        if let Some(&id) = self.other_ids.get(&node) {
+            return id;
+        }
+        let id = self.rule_count + self.others.len();
+        self.others.push(node.clone());
+        self.other_ids.insert(node, id);
+        self.index.push(NONE);
+        self.lowlink.push(0);
+        self.on_stack.push(false);
+        id
+    }
+
+    fn node(&self, index: &impl RuleIndex, id: usize) -> Node {
+        match id.checked_sub(self.rule_count) {
+            Some(other) => self.others[other].clone(),
+            None => Node {
+                rule: id as u32,
+                host: index.host_id(id as u32),
+                suffix: None,
+                query: None,
+            },
+        }
+    }
+
+    fn visit(&mut self, id: usize) {
+        self.index[id] = self.next_index;
+        self.lowlink[id] = self.next_index;
+        self.on_stack[id] = true;
+        self.next_index += 1;
+        self.stack.push(id);
+    }
+
+    /// Removes the strongly connected component with the root `id` from the stack.
+    fn pop_component(&mut self, id: usize) -> Vec<usize> {
+        let start = self.stack.iter().rposition(|&node| node == id).unwrap();
+        let component = self.stack.split_off(start);
+        for &node in &component {
+            self.on_stack[node] = false;
+        }
+        component
+    }
+
+    /// Returns the redirects from the requests of the node `id` to requests handled by a rule.
+    fn redirects(&mut self, index: &impl RuleIndex, id: usize) -> Result<Vec<Edge>> {
+        let node = self.node(index, id);
+        let hosts = index.hosts();
+        let found = Match {
+            rule: node.rule,
+            suffix: node.suffix,
+            query: node.query,
+        };
+        let redirects = index.with_rule(node.rule, |_, rule| {
+            rule.targets()
+                .filter_map(|(target, condition)| {
+                    let to = found.resolve(rule, target);
+                    let next = follow_redirect(hosts, hosts.name(node.host), to)?;
+                    let next_found = index.lookup(&next)?;
+                    let next_host = hosts.id(split_host(&next).0);
+                    let condition = condition.map(ToString::to_string);
+                    Some((next_found, next_host, target.to_string(), condition))
+                })
+                .collect::<Vec<_>>()
+        })?;
+        Ok(redirects
+            .into_iter()
+            .map(|(next, host, target, condition)| {
+                let node = Node {
+                    rule: next.rule,
+                    host,
+                    suffix: next.suffix,
+                    query: next.query,
+                };
+                Edge {
+                    to: self.id(index, node),
+                    target,
+                    condition,
+                }
+            })
+            .collect())
+    }
+
+    /// Returns a request of the node `id`, whose rule has the key `key`.
+    fn request(&self, index: &impl RuleIndex, id: usize, key: &str) -> String {
+        let node = self.node(index, id);
+        let mut request = String::new();
+        if index.host_id(node.rule) != node.host {
+            request.push_str(index.hosts().name(node.host));
+        }
+        match &node.suffix {
+            Some(suffix) => {
+                request.push_str(key.strip_suffix('*').unwrap_or(key));
+                request.push_str(suffix);
+            }
+            None => request.push_str(key),
+        }
+        if let Some(query) = &node.query {
+            request.push('?');
+            request.push_str(query);
+        }
+        request
+    }
+
+    fn step(&self, index: &impl RuleIndex, id: usize, edge: &Edge) -> Result<LoopStep> {
+        let rule = self.node(index, id).rule;
+        Ok(LoopStep {
+            request: index.with_rule(rule, |key, _| self.request(index, id, key))?,
+            rule,
+            target: edge.target.clone(),
+            condition: edge.condition.clone(),
+        })
+    }
+
+    /// Returns the redirects between the requests of a component, in the order they were found.
+    fn component_steps(
+        &self,
+        index: &impl RuleIndex,
+        component: &[usize],
+    ) -> Result<Vec<LoopStep>> {
+        let members = component.iter().copied().collect::<HashSet<_>>();
+        let mut steps = Vec::new();
+        for &id in component {
+            for edge in self.edges[&id]
+                .iter()
+                .filter(|edge| members.contains(&edge.to))
+            {
+                steps.push(self.step(index, id, edge)?);
+            }
+        }
+        Ok(steps)
+    }
+
+    /// Returns the redirects followed to get to the top of `call_stack`.
+    fn path_steps(
+        &self,
+        index: &impl RuleIndex,
+        call_stack: &[(usize, usize)],
+    ) -> Result<Vec<LoopStep>> {
+        call_stack
+            .iter()
+            .map(|&(id, next_edge)| self.step(index, id, &self.edges[&id][next_edge - 1]))
+            .collect()
+    }
+}
+
+/// Where the chain of redirects from a rule ends, as found by `chain_ends`.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct ChainEnd {
+    /// The last rule in the chain
+    pub(crate) end: u32,
+    /// The number of rules in the chain, 0 if it's not known yet
+    pub(crate) depth: u32,
+    pub(crate) status_codes: StatusCodes,
+}
+
+impl ChainEnd {
+    const UNKNOWN: Self = Self {
+        end: NONE,
+        depth: 0,
+        status_codes: StatusCodes(0),
+    };
+
+    fn new(rule: u32, status_code: u16) -> Self {
+        Self {
+            end: rule,
+            depth: 1,
+            status_codes: StatusCodes::of(status_code).unwrap_or_default(),
+        }
+    }
+}
+
+/// The ends of the chains from each rule, for requests to its own host, and for requests to
+/// other hosts for host-agnostic rules reached from host-specific ones.
+pub(crate) struct ChainEnds {
+    own: Vec<ChainEnd>,
+    other_hosts: HashMap<(u32, u32), ChainEnd>,
+}
+
+impl ChainEnds {
+    /// Returns the end of the chain from `rule` for requests to its own host.
+    pub(crate) fn own(&self, rule: u32) -> ChainEnd {
+        self.own[rule as usize]
+    }
+
+    fn get(&self, index: &impl RuleIndex, host: u32, rule: u32) -> Option<ChainEnd> {
+        if index.host_id(rule) == host {
+            Some(self.own[rule as usize]).filter(|end| end.depth > 0)
+        } else {
+            self.other_hosts.get(&(host, rule)).copied()
+        }
+    }
+
+    fn set(&mut self, index: &impl RuleIndex, host: u32, rule: u32, end: ChainEnd) {
+        if index.host_id(rule) == host {
+            self.own[rule as usize] = end;
+        } else {
+            self.other_hosts.insert((host, rule), end);
+        }
+    }
+}
+
+/// Finds where the chain of redirects through exact rules from each rule ends. The end of every
+/// chain is remembered for each rule on it, so each rule's successor is only looked up once, and
+/// chains running into a loop end before it.
+pub(crate) fn chain_ends(index: &impl RuleIndex, status_codes: ChainStatusCodes) -> ChainEnds {
+    let mut ends = ChainEnds {
+        own: vec![ChainEnd::UNKNOWN; index.rule_count()],
+        other_hosts: HashMap::new(),
+    };
+    let mut path = Vec::new();
+    let mut on_path = HashMap::new();
+    for start in 0..index.rule_count() as u32 {
+        // The host requests are for decides whether host-specific rules continue the chain
+        let host = index.host_id(start);
+        path.clear();
+        on_path.clear();
+        let mut rule = start;
+        // The end of the chain from the rule after the path, if there is one
+        let mut tail = loop {
+            if let Some(end) = ends.get(index, host, rule) {
+                break Some(end);
+            }
+            if let Some(&cycle_start) = on_path.get(&rule) {
+                // Rules on the loop aren't flattened, and chains into it end at its start
+                for &looping in &path[cycle_start..] {
+                    let end = ChainEnd::new(looping, index.status_code(looping));
+                    ends.set(index, host, looping, end);
+                }
+                path.truncate(cycle_start);
+                break ends.get(index, host, rule);
+            }
+            on_path.insert(rule, path.len());
+            path.push(rule);
+            match index.chain_next(host, rule, status_codes) {
+                Some(next) => rule = next,
+                None => break None,
+            }
+        };
+        for &rule in path.iter().rev() {
+            let mut end = ChainEnd::new(rule, index.status_code(rule));
+            if let Some(next_end) = tail {
+                end = ChainEnd {
+                    end: next_end.end,
+                    depth: next_end.depth + 1,
+                    status_codes: end.status_codes.union(next_end.status_codes),
+                };
+            }
+            tail = Some(end);
+            ends.set(index, host, rule, end);
+        }
+    }
+    ends
+}
+
+/// Returns the rules on the chain from `rule`, which ends at `end`, up to `MAX_CHAIN_STEPS` of
+/// them.
+pub(crate) fn chain_steps(
+    index: &impl RuleIndex,
+    status_codes: ChainStatusCodes,
+    rule: u32,
+    end: ChainEnd,
+) -> Vec<u32> {
+    let host = index.host_id(rule);
+    let recorded = (end.depth as usize).min(MAX_CHAIN_STEPS);
+    let mut steps = Vec::with_capacity(recorded);
+    let mut next = Some(rule);
+    while let Some(current) = next.filter(|_| steps.len() < recorded) {
+        steps.push(current);
+        next = index.chain_next(host, current, status_codes);
+    }
+    steps
+}
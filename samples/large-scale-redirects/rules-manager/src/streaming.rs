@@ -0,0 +1,1984 @@
+//! Updating rules without loading them into memory, for rule sets with tens of millions of rules.
+//!
+//! With `--streaming`, rules files are read line by line, and rules are sorted by key on disk:
+//! they're buffered in chunks of `--sort-chunk-size` rules, each chunk is sorted and written to a
+//! temporary file, and the sorted files are merged. Merging applies removals and duplicate checks
+//! to the rules with the same key, and writes the resulting rules to a file in key order, indexing
+//! their keys with an FST built along the way. Loop detection and chain shortening only keep a few
+//! numbers per rule in memory, indexed by the rule's position in that file, and read rules back
+//! from it when they need more. Since rules end up in key order, the sources FST of the bundle is
+//! built directly into a file, too.
+//!
+//! Lookups, loop detection and chain shortening go through `rule_index`, like they do for the rules
+//! in memory, so they find the same problems. Regex rules are kept in memory, since there are only
+//! ever a few of them, and so are files in other formats than the native one while they're
+//! translated, and the distinct targets checked with `--check-targets-url` or
+//! `--check-targets-list`.
+
+use crate::import::{self, RulesFormat};
+use crate::inspect::Change;
+use crate::liveness::{self, TargetChecker};
+use crate::rule_index::{
+    self, ChainEnd, ChainEnds, Hosts, LoopStep, NONE, RegexOverlaps, RuleIndex,
+};
+use crate::validation_report::{ChainStep, Finding, ShortenedChain, ValidationReport};
+use crate::{
+    Args, ChainStatusCodes, FailedCheckReason, GENERATED_FILE_HEADER, TargetSchemes,
+    ValidationBehavior, disallowed_target, duplicate_rule, ensure_dir, normalization_conflict,
+    owned_host_chain, regex_overlap,
+};
+use anyhow::{Context, Result, anyhow};
+use clap::ValueEnum;
+use redirects_core::bundle::{self, Records, RegexRule};
+use redirects_core::regex_rules::REGEX_PREFIX;
+use redirects_core::rule::{
+    self, Rule, RuleErrorKind, RuleOptions, is_wildcard_source, split_host,
+};
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct StreamingArgs {
+    /// Read rules files line by line and sort rules on disk instead of loading them into memory,
+    /// for rule sets too large for that
+    #[arg(long)]
+    pub(crate) streaming: bool,
+
+    /// Number of rules sorted in memory at a time with `--streaming`
+    #[arg(long, default_value_t = 1_000_000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) sort_chunk_size: u64,
+
+    /// Directory to store the temporary files of `--streaming` in. Default is the system's
+    /// temporary directory.
+    #[arg(long)]
+    pub(crate) temp_dir: Option<PathBuf>,
+}
+
+impl Default for StreamingArgs {
+    fn default() -> Self {
+        Self {
+            streaming: false,
+            sort_chunk_size: 1_000_000,
+            temp_dir: None,
+        }
+    }
+}
+
+/// Updates the rules like `update` does, without loading them into memory.
+pub(crate) fn update(args: &Args, report: &mut ValidationReport) -> Result<()> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("rules-manager");
+    let temp_dir = match &args.streaming.temp_dir {
+        Some(dir) => builder.tempdir_in(dir),
+        None => builder.tempdir(),
+    }
+    .context("Failed to create temporary directory")?;
+
+    let context = || "Failed to update redirects".to_string();
+    let mut update = Update::new(args, temp_dir.path());
+    let rules = update.read_files(report).with_context(context)?;
+    let mut index =
+        MergedIndex::new(args, &rules, &temp_dir.path().join("keys.fst")).with_context(context)?;
+    let ends = update.validate(&mut index, report).with_context(context)?;
+    update.write(&index, &ends, report)
+}
+
+/// What a line of a rules file does to the rule with its key.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    Existing,
+    Remove,
+    Add,
+}
+
+impl Kind {
+    fn code(self) -> char {
+        match self {
+            Kind::Existing => 'e',
+            Kind::Remove => 'r',
+            Kind::Add => 'a',
+        }
+    }
+
+    fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "e" => Some(Kind::Existing),
+            "r" => Some(Kind::Remove),
+            "a" => Some(Kind::Add),
+            _ => None,
+        }
+    }
+}
+
+/// A line of a rules file, as sorted by the key of its rule and the order lines were read in.
+struct Entry {
+    key: String,
+    kind: Kind,
+    file: usize,
+    line_no: usize,
+    /// The rule translated from the line, or empty if the line is in the native format
+    rule: String,
+    /// The line, with tabs replaced by spaces
+    line: String,
+}
+
+impl Entry {
+    fn parse(sorted: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid sorted rule '{sorted}'");
+        let mut fields = sorted.splitn(7, '\t');
+        let mut field = || fields.next().ok_or_else(invalid);
+        let key = field()?.to_string();
+        let _seq = field()?;
+        let kind = Kind::from_code(field()?).ok_or_else(invalid)?;
+        let file = field()?.parse().map_err(|_| invalid())?;
+        let line_no = field()?.parse().map_err(|_| invalid())?;
+        let rule = field()?.to_string();
+        let line = field()?.to_string();
+        Ok(Self {
+            key,
+            kind,
+            file,
+            line_no,
+            rule,
+            line,
+        })
+    }
+
+    fn rule_part(&self) -> &str {
+        rule_part(&self.rule, &self.line)
+    }
+}
+
+/// A line of the merged rules file: a rule, together with the rule it replaces in the existing
+/// rules, or an existing rule that was removed.
+struct MergedRule {
+    key: String,
+    file: usize,
+    line_no: usize,
+    /// The existing rule with the same key as a line of a rules file including its status code,
+    /// or empty if there was none
+    existing: String,
+    /// The rule translated from the line, or empty if the line is in the native format
+    rule: String,
+    /// The line the rule was defined in, or empty if it was removed
+    line: String,
+}
+
+impl MergedRule {
+    fn parse(merged: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid merged rule '{merged}'");
+        let mut fields = merged.splitn(6, '\t');
+        let mut field = || fields.next().ok_or_else(invalid);
+        let key = field()?.to_string();
+        let file = field()?.parse().map_err(|_| invalid())?;
+        let line_no = field()?.parse().map_err(|_| invalid())?;
+        let existing = field()?.to_string();
+        let rule = field()?.to_string();
+        let line = field()?.to_string();
+        Ok(Self {
+            key,
+            file,
+            line_no,
+            existing,
+            rule,
+            line,
+        })
+    }
+
+    fn is_removed(&self) -> bool {
+        self.line.is_empty()
+    }
+
+    fn rule_part(&self) -> &str {
+        rule_part(&self.rule, &self.line)
+    }
+}
+
+/// A regex rule, which are kept in memory.
+struct RegexEntry {
+    key: String,
+    file: usize,
+    line_no: usize,
+    /// The rule translated from the line, or empty if the line is in the native format
+    rule: String,
+    line: String,
+}
+
+impl RegexEntry {
+    fn rule_part(&self) -> &str {
+        rule_part(&self.rule, &self.line)
+    }
+}
+
+/// The merged rules file, and what loop detection and chain shortening need to know about each
+/// rule in it, indexed by the rule's position among the rules that weren't removed.
+#[derive(Default)]
+struct MergedRules {
+    path: PathBuf,
+    /// The position of each rule in the merged file
+    offsets: Vec<u64>,
+    status_codes: Vec<u16>,
+    /// The options of each rule without its host, which have to be the same for rules in a chain,
+    /// as an index into `shapes`
+    shapes: Vec<u32>,
+    /// Distinct options, and whether rules with them match requests regardless of their query
+    /// string
+    shapes_ignoring_query: Vec<bool>,
+    /// The ID of the host of each rule in `hosts`, or 0 for host-agnostic rules
+    host_ids: Vec<u32>,
+    hosts: Hosts,
+    wildcards: Vec<bool>,
+    has_wildcards: bool,
+}
+
+impl MergedRules {
+    fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Returns the lines of the merged file in order.
+    fn lines(&self) -> Result<impl Iterator<Item = Result<MergedRule>>> {
+        let file = File::open(&self.path)?;
+        Ok(BufReader::new(file)
+            .lines()
+            .map(|line| MergedRule::parse(&line?)))
+    }
+}
+
+/// Reads rules from the merged file by their position.
+struct RuleReader<'m> {
+    reader: BufReader<File>,
+    offsets: &'m [u64],
+    /// The position of `reader` in the file
+    position: u64,
+}
+
+impl<'m> RuleReader<'m> {
+    fn new(rules: &'m MergedRules) -> Result<Self> {
+        Ok(Self {
+            reader: BufReader::new(File::open(&rules.path)?),
+            offsets: &rules.offsets,
+            position: 0,
+        })
+    }
+
+    fn read(&mut self, ordinal: u32) -> Result<MergedRule> {
+        // Seeking relative to the current position keeps the buffer if the rule is in it, which it
+        // often is, since rules are mostly read in order
+        let offset = self.offsets[ordinal as usize];
+        self.reader
+            .seek_relative(offset as i64 - self.position as i64)?;
+        let mut line = String::new();
+        self.position = offset + self.reader.read_line(&mut line)? as u64;
+        MergedRule::parse(line.trim_end_matches('\n'))
+    }
+}
+
+/// The rules continuing the chain from each rule, as found by `Update::index_rules`, which keeps
+/// `MergedIndex::chain_next` from reading rules.
+#[derive(Default)]
+struct RuleGraph {
+    /// The rule continuing the chain from each rule, for requests to its own host
+    next: Vec<u32>,
+    /// For host-agnostic rules, the rules continuing the chain for requests to hosts with a
+    /// host-specific rule for the target, by host ID
+    host_next: HashMap<u32, Vec<(u32, u32)>>,
+}
+
+/// A streaming update, with the rules files read so far and the checks they failed.
+struct Update<'a> {
+    args: &'a Args,
+    dir: &'a Path,
+    files: Vec<&'a Path>,
+    /// Failed checks, with the index of the file they're about
+    findings: Vec<(usize, Finding)>,
+    entries: LineSorter,
+    /// The number of entries sorted so far, which orders entries with the same key
+    next_seq: u64,
+    regex_rules: Vec<RegexEntry>,
+    /// The regex rules after reading the existing rules, as lines with status codes by key
+    existing_regex_lines: BTreeMap<String, String>,
+    /// The lines of the existing rules files, which are left out of the rules output file unless
+    /// `--include-existing` is set. The few regex rules are kept apart, since they come last.
+    existing_lines: LineSorter,
+    existing_regex: HashSet<String>,
+    /// Whether rules in the existing rules files conflict with each other
+    existing_errors: bool,
+}
+
+impl<'a> Update<'a> {
+    fn new(args: &'a Args, dir: &'a Path) -> Self {
+        let chunk_size = args.streaming.sort_chunk_size as usize;
+        Self {
+            args,
+            dir,
+            files: Vec::new(),
+            findings: Vec::new(),
+            entries: LineSorter::new(dir, "rules", chunk_size),
+            next_seq: 0,
+            regex_rules: Vec::new(),
+            existing_regex_lines: BTreeMap::new(),
+            existing_lines: LineSorter::new(dir, "existing", chunk_size),
+            existing_regex: HashSet::new(),
+            existing_errors: false,
+        }
+    }
+
+    /// Reads and merges the rules like `RedirectsMap::build` does.
+    fn read_files(&mut self, report: &mut ValidationReport) -> Result<MergedRules> {
+        report.chain_status_codes = self
+            .args
+            .behaviors
+            .chain_status_codes
+            .to_possible_value()
+            .map(|value| value.get_name().to_string());
+
+        let rule_files = &self.args.rule_files;
+        for path in &rule_files.existing_rules {
+            self.read_file(path, Kind::Existing)?;
+        }
+        if !self.findings.is_empty() {
+            return Err(anyhow!("No parse errors expected in existing redirects"));
+        }
+        self.existing_regex_lines = self.regex_lines();
+        // Rules are removed first, so that removing a rule and adding it again replaces it
+        for path in &rule_files.remove_rules {
+            self.read_file(path, Kind::Remove)?;
+        }
+        for path in &rule_files.add_rules {
+            self.read_file(path, Kind::Add)?;
+        }
+
+        self.merge()
+    }
+
+    /// Checks the merged rules like `RedirectsMap::build` does, and finds the end of the chain
+    /// from each rule.
+    fn validate(
+        &mut self,
+        index: &mut MergedIndex,
+        report: &mut ValidationReport,
+    ) -> Result<ChainEnds> {
+        let checks = &self.args.behaviors;
+        index.graph = self.index_rules(index)?;
+        self.findings
+            .sort_by_key(|(file, finding)| (*file, finding.line));
+        let findings = std::mem::take(&mut self.findings)
+            .into_iter()
+            .map(|(_, finding)| finding)
+            .collect::<Vec<_>>();
+        report.findings.extend(findings.iter().cloned());
+
+        let errors_found = print_findings(&findings, ValidationBehavior::Error, "Errors in file: ");
+        print_findings(
+            &findings,
+            ValidationBehavior::Warn,
+            "Warning, ignored lines in file: ",
+        );
+        let ignored_lines = findings
+            .iter()
+            .filter(|finding| finding.severity != ValidationBehavior::Error)
+            .count();
+        if ignored_lines > 0 {
+            println!("Skipped {ignored_lines} invalid lines");
+        }
+
+        if checks.loops != ValidationBehavior::Ignore {
+            let loops = self.find_loops(index)?;
+            if !loops.is_empty() {
+                let descriptions = loops
+                    .iter()
+                    .map(|(steps, _)| format!("Loop:\n   {}", steps.join("\n-> ")))
+                    .collect::<Vec<_>>();
+                report
+                    .findings
+                    .extend(loops.into_iter().map(|(_, finding)| finding));
+                return Err(anyhow!("Loops detected:\n{}", descriptions.join("\n")));
+            }
+        }
+
+        report.rules = index.rule_count();
+        report.regex_rules = self.regex_rules.len();
+        if errors_found {
+            return Err(anyhow!("Errors found in redirect rules, aborting"));
+        }
+        Ok(rule_index::chain_ends(index, checks.chain_status_codes))
+    }
+
+    fn read_file(&mut self, path: &'a Path, kind: Kind) -> Result<()> {
+        let description = match kind {
+            Kind::Existing => "existing redirects file",
+            Kind::Remove => "file of redirects to remove",
+            Kind::Add => "new redirects file",
+        };
+        let context = || format!("Failed to read {description} {}", path.to_string_lossy());
+        let file = self.files.len();
+        self.files.push(path);
+        if kind == Kind::Add && self.args.import.rules_format != RulesFormat::Native {
+            // Files in other formats are translated as a whole, so they're read into memory
+            let contents = std::fs::read_to_string(path).with_context(context)?;
+            return self.read_imported(file, &contents);
+        }
+        let reader = BufReader::new(File::open(path).with_context(context)?);
+        for (line_no, line) in reader.lines().enumerate() {
+            let line = line.with_context(context)?.replace('\t', " ");
+            if kind == Kind::Existing {
+                if line_no == 0 {
+                    if line.trim() != GENERATED_FILE_HEADER {
+                        return Err(anyhow!(
+                            "Existing redirects file must be generated by this tool"
+                        ));
+                    }
+                    continue;
+                }
+                if !self.args.include_existing {
+                    if line.starts_with(REGEX_PREFIX) {
+                        self.existing_regex.insert(line.clone());
+                    } else {
+                        self.existing_lines.push(line.clone())?;
+                    }
+                }
+            }
+
+            // Strip inline comments
+            let rule_part = line.split('#').next().unwrap_or("").trim();
+            if rule_part.is_empty() {
+                continue;
+            }
+            match kind {
+                Kind::Remove => self.read_removal(file, line_no, &line, rule_part)?,
+                _ => self.read_rule(file, line_no, &line, rule_part, kind)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the rules translated from a file in another format, like
+    /// `RedirectsMap::add_imported_rules` does. Errors are reported against the lines the rules
+    /// were translated from.
+    fn read_imported(&mut self, file: usize, contents: &str) -> Result<()> {
+        let Some(imported) = import::import(&self.args.import, contents) else {
+            return Ok(());
+        };
+        let lines = contents
+            .lines()
+            .map(|line| line.replace('\t', " "))
+            .collect::<Vec<_>>();
+        let line = |line_no: usize| lines.get(line_no).map_or("", String::as_str);
+        let mut untranslatable = imported.untranslatable.iter().peekable();
+        for (line_no, rule) in &imported.rules {
+            while let Some((line_no, message)) =
+                untranslatable.next_if(|(untranslatable_line, _)| untranslatable_line < line_no)
+            {
+                self.untranslatable(file, *line_no, line(*line_no), message);
+            }
+            let rule = rule.replace('\t', " ");
+            self.read_rule(file, *line_no, line(*line_no), &rule, Kind::Add)?;
+        }
+        for (line_no, message) in untranslatable {
+            self.untranslatable(file, *line_no, line(*line_no), message);
+        }
+        Ok(())
+    }
+
+    fn untranslatable(&mut self, file: usize, line_no: usize, line: &str, message: &str) {
+        let reason = FailedCheckReason {
+            check: "untranslatable-lines",
+            message: format!("Untranslatable: {message}"),
+            severity: self.args.behaviors.untranslatable_lines,
+        };
+        self.finding(file, line_no, line, reason);
+    }
+
+    /// Sorts the rule defined in a line, or records why it's invalid. Regex rules are added right
+    /// away, like `RedirectsMap::parse_line` does.
+    fn read_rule(
+        &mut self,
+        file: usize,
+        line_no: usize,
+        line: &str,
+        rule_part: &str,
+        kind: Kind,
+    ) -> Result<()> {
+        let checks = &self.args.behaviors;
+        let rule = match Rule::parse(
+            rule_part,
+            self.args.default_status_code,
+            self.args.normalize_sources,
+        ) {
+            Ok(rule) => rule,
+            Err(error) => {
+                let (check, severity) = match error.kind {
+                    RuleErrorKind::Invalid => ("invalid-lines", checks.invalid_lines),
+                    RuleErrorKind::SelfLoop => ("self-loops", checks.self_loops),
+                };
+                let reason = FailedCheckReason {
+                    check,
+                    message: error.message,
+                    severity,
+                };
+                self.finding(file, line_no, line, reason);
+                return Ok(());
+            }
+        };
+        // Rules translated from another format are kept, since they can't be parsed from the line
+        let translated = if rule_part == rule_part_of(line) {
+            ""
+        } else {
+            rule_part
+        };
+        if !rule.is_regex() {
+            let key = rule.key(self.args.normalize_sources);
+            return self.push_entry(&key, kind, file, line_no, translated, line);
+        }
+
+        let key = format!(
+            "{}{}",
+            rule.options.host.as_deref().unwrap_or(""),
+            rule.from
+        );
+        let entry = RegexEntry {
+            key,
+            file,
+            line_no,
+            rule: translated.to_string(),
+            line: line.to_string(),
+        };
+        // Redefined rules keep their precedence
+        let Some(index) = self.regex_rules.iter().position(|e| e.key == entry.key) else {
+            self.regex_rules.push(entry);
+            return Ok(());
+        };
+        let existing = &self.regex_rules[index];
+        let reason = duplicate_rule(
+            &self.parse(existing.rule_part()),
+            self.files[existing.file],
+            existing.line_no,
+            &rule,
+            checks,
+        );
+        match reason {
+            Some(reason) => self.finding(file, line_no, line, reason),
+            None => self.regex_rules[index] = entry,
+        }
+        Ok(())
+    }
+
+    /// Sorts the removal of the rule listed in a line, or records why it's invalid. Regex rules
+    /// are removed right away.
+    fn read_removal(
+        &mut self,
+        file: usize,
+        line_no: usize,
+        line: &str,
+        rule_part: &str,
+    ) -> Result<()> {
+        let checks = &self.args.behaviors;
+        let mut parts = rule_part.split_whitespace();
+        let Some(from) = parts.next() else {
+            return Ok(());
+        };
+        let host_parts = parts
+            .filter(|part| part.starts_with("host="))
+            .collect::<Vec<_>>();
+        let options = match RuleOptions::parse(&host_parts) {
+            Ok(options) => options,
+            Err(message) => {
+                let reason = FailedCheckReason {
+                    check: "invalid-lines",
+                    message,
+                    severity: checks.invalid_lines,
+                };
+                self.finding(file, line_no, line, reason);
+                return Ok(());
+            }
+        };
+        let host = options.host.as_deref().unwrap_or("");
+        if !from.starts_with(REGEX_PREFIX) {
+            let key = format!(
+                "{host}{}",
+                rule::source_key(from, self.args.normalize_sources)
+            );
+            return self.push_entry(&key, Kind::Remove, file, line_no, "", line);
+        }
+        let key = format!("{host}{from}");
+        let count = self.regex_rules.len();
+        self.regex_rules.retain(|entry| entry.key != key);
+        if self.regex_rules.len() == count {
+            self.finding(
+                file,
+                line_no,
+                line,
+                missing_removal(from, checks.missing_removals),
+            );
+        }
+        Ok(())
+    }
+
+    fn push_entry(
+        &mut self,
+        key: &str,
+        kind: Kind,
+        file: usize,
+        line_no: usize,
+        rule: &str,
+        line: &str,
+    ) -> Result<()> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        // Sequence numbers are padded, so that lines with the same key sort in the order they
+        // were read in
+        self.entries.push(format!(
+            "{key}\t{seq:020}\t{}\t{file}\t{line_no}\t{rule}\t{line}",
+            kind.code()
+        ))?;
+        Ok(())
+    }
+
+    /// Merges the sorted rules into a file with one line per key, checking rules with the same key
+    /// against each other, and indexes the keys of the resulting rules in an FST.
+    fn merge(&mut self) -> Result<MergedRules> {
+        let entries = std::mem::take(&mut self.entries);
+        let mut entries = entries
+            .finish()?
+            .map(|line| Entry::parse(&line?))
+            .peekable();
+        let mut rules = MergedRules {
+            path: self.dir.join("merged"),
+            ..MergedRules::default()
+        };
+        let mut merged = BufWriter::new(File::create(&rules.path)?);
+        let mut index =
+            fst::MapBuilder::new(BufWriter::new(File::create(self.dir.join("keys.fst"))?))?;
+        let mut shapes = HashMap::new();
+        let mut offset = 0;
+        let mut group = Vec::new();
+        while let Some(first) = entries.next().transpose()? {
+            group.clear();
+            group.push(first);
+            while let Some(Ok(entry)) = entries.peek()
+                && entry.key == group[0].key
+            {
+                group.push(entries.next().unwrap()?);
+            }
+            let (current, existing) = self.merge_group(&group);
+
+            let line = match current {
+                Some(entry) => format!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    entry.key, entry.file, entry.line_no, existing, entry.rule, entry.line
+                ),
+                None if !existing.is_empty() => {
+                    format!("{}\t0\t0\t{existing}\t\t", group[0].key)
+                }
+                None => continue,
+            };
+            writeln!(merged, "{line}")?;
+            let line_offset = offset;
+            offset += line.len() as u64 + 1;
+            let Some(entry) = current else {
+                continue;
+            };
+
+            let ordinal = rules.len();
+            if ordinal >= NONE as usize {
+                return Err(anyhow!("Too many rules, at most {} are supported", NONE));
+            }
+            index.insert(&entry.key, ordinal as u64)?;
+            let rule = self.parse(entry.rule_part());
+            let mut options = rule.options.clone();
+            options.host = None;
+            let shape_count = shapes.len() as u32;
+            let shape = *shapes.entry(options.to_string()).or_insert_with(|| {
+                rules
+                    .shapes_ignoring_query
+                    .push(rule_index::ignores_query(&options));
+                shape_count
+            });
+            let host_id = match &rule.options.host {
+                Some(host) => rules.hosts.insert(host),
+                None => 0,
+            };
+            let is_wildcard = is_wildcard_source(&entry.key);
+            rules.offsets.push(line_offset);
+            rules.status_codes.push(rule.status_code);
+            rules.shapes.push(shape);
+            rules.host_ids.push(host_id);
+            rules.wildcards.push(is_wildcard);
+            rules.has_wildcards |= is_wildcard;
+        }
+        merged.flush()?;
+        index.finish()?;
+        if self.existing_errors {
+            return Err(anyhow!("No parse errors expected in existing redirects"));
+        }
+        Ok(rules)
+    }
+
+    /// Applies the lines with the same key in the order they were read in, returning the
+    /// resulting rule, if any, and the existing rule as a line including its status code, or an
+    /// empty string if there was none.
+    fn merge_group<'e>(&mut self, group: &'e [Entry]) -> (Option<&'e Entry>, String) {
+        let checks = &self.args.behaviors;
+        let mut current: Option<&Entry> = None;
+        let mut existing = None;
+        for entry in group {
+            if entry.kind != Kind::Existing && existing.is_none() {
+                existing = Some(
+                    current
+                        .map(|entry| self.rule_line(entry))
+                        .unwrap_or_default(),
+                );
+            }
+            if entry.kind == Kind::Remove {
+                if current.take().is_none() {
+                    let from = entry.line.split_whitespace().next().unwrap_or_default();
+                    let reason = missing_removal(from, checks.missing_removals);
+                    self.finding(entry.file, entry.line_no, &entry.line, reason);
+                }
+                continue;
+            }
+            let reason = current.and_then(|current| {
+                let rule = self.parse(entry.rule_part());
+                let existing = self.parse(current.rule_part());
+                let path = self.files[current.file];
+                if existing.from != rule.from {
+                    return Some(normalization_conflict(
+                        &rule,
+                        &existing,
+                        path,
+                        current.line_no,
+                        checks,
+                    ));
+                }
+                duplicate_rule(&existing, path, current.line_no, &rule, checks)
+            });
+            match reason {
+                Some(reason) => {
+                    self.existing_errors |= entry.kind == Kind::Existing;
+                    self.finding(entry.file, entry.line_no, &entry.line, reason);
+                }
+                None => current = Some(entry),
+            }
+        }
+        let existing = existing.unwrap_or_else(|| {
+            current
+                .map(|entry| self.rule_line(entry))
+                .unwrap_or_default()
+        });
+        (current, existing)
+    }
+
+    fn finding(&mut self, file: usize, line_no: usize, line: &str, reason: FailedCheckReason) {
+        let finding = Finding {
+            check: reason.check,
+            severity: reason.severity,
+            file: self.files[file].display().to_string(),
+            line: line_no + 1,
+            source: line.to_string(),
+            message: reason.message,
+            related: Vec::new(),
+        };
+        self.findings.push((file, finding));
+    }
+
+    fn parse<'l>(&self, rule_part: &'l str) -> Rule<'l> {
+        parse(self.args, rule_part)
+    }
+
+    fn rule_line(&self, entry: &Entry) -> String {
+        status_line(&self.parse(entry.rule_part()))
+    }
+
+    /// Returns the regex rules as lines including their status codes, by key.
+    fn regex_lines(&self) -> BTreeMap<String, String> {
+        self.regex_rules
+            .iter()
+            .map(|entry| {
+                let line = status_line(&self.parse(entry.rule_part()));
+                (entry.key.clone(), line)
+            })
+            .collect()
+    }
+
+    /// Returns the changes to the regex rules, by key.
+    fn regex_changes(&self) -> Vec<(String, Change)> {
+        let mut new = self.regex_lines();
+        let mut changes = Vec::new();
+        for (key, old) in &self.existing_regex_lines {
+            match new.remove(key) {
+                Some(line) if line == *old => {}
+                Some(line) => changes.push((key.clone(), Change::Modified(old.clone(), line))),
+                None => changes.push((key.clone(), Change::Removed(old.clone()))),
+            }
+        }
+        changes.extend(
+            new.into_iter()
+                .map(|(key, line)| (key, Change::Added(line))),
+        );
+        changes.sort_by(|(a, _), (b, _)| a.cmp(b));
+        changes
+    }
+
+    /// Finds the rule continuing the chain from each rule, and runs the checks that need other
+    /// rules than the one checked, like `RedirectsMap::check_regex_overlaps`,
+    /// `check_owned_host_chains` and `check_target_hosts`.
+    fn index_rules(&mut self, index: &MergedIndex) -> Result<RuleGraph> {
+        let checks = &self.args.behaviors;
+        let check_targets = checks.disallowed_targets != ValidationBehavior::Ignore
+            && (!checks.allowed_target_hosts.is_empty()
+                || checks.target_schemes != TargetSchemes::Any);
+        let regex_rules = self
+            .regex_rules
+            .iter()
+            .map(|entry| self.parse(entry.rule_part()))
+            .collect::<Vec<_>>();
+        let mut overlaps = RegexOverlaps::new(&regex_rules)?;
+
+        let mut graph = RuleGraph::default();
+        let mut ordinal = 0;
+        for merged in index.rules.lines()? {
+            let merged = merged?;
+            if merged.is_removed() {
+                continue;
+            }
+            let rule = self.parse(merged.rule_part());
+            let (host, _) = split_host(&merged.key);
+
+            let (next, host_next) = index.chain_successors(host, rule.to);
+            graph.next.push(next.unwrap_or(NONE));
+            if !host_next.is_empty() {
+                graph.host_next.insert(ordinal, host_next);
+            }
+
+            let mut reasons = Vec::new();
+            for (target, _) in rule.targets() {
+                if check_targets && let Some(message) = disallowed_target(target, checks) {
+                    reasons.push(FailedCheckReason {
+                        check: "disallowed-targets",
+                        message,
+                        severity: checks.disallowed_targets,
+                    });
+                }
+                if checks.owned_host_chains == ValidationBehavior::Ignore || target.starts_with('/')
+                {
+                    continue;
+                }
+                let Some(found) = index.follow(host, target) else {
+                    continue;
+                };
+                let found = index.read(found.rule)?;
+                reasons.push(owned_host_chain(
+                    target,
+                    &self.parse(found.rule_part()),
+                    self.files[found.file],
+                    found.line_no,
+                    checks,
+                ));
+            }
+            for reason in reasons {
+                self.finding(merged.file, merged.line_no, &merged.line, reason);
+            }
+
+            overlaps.add(ordinal, &merged.key);
+            ordinal += 1;
+        }
+
+        for (regex_rule, overlap) in overlaps.finish().into_iter().enumerate() {
+            let Some((count, first)) = overlap else {
+                continue;
+            };
+            let first = index.read(first)?;
+            let reason = regex_overlap(
+                count,
+                &self.parse(first.rule_part()),
+                self.files[first.file],
+                first.line_no,
+                checks,
+            );
+            let entry = &self.regex_rules[regex_rule];
+            let (file, line_no, line) = (entry.file, entry.line_no, entry.line.clone());
+            self.finding(file, line_no, &line, reason);
+        }
+        Ok(graph)
+    }
+
+    /// Returns each loop once, like `RedirectsMap::find_loops` does, as the redirects in it and a
+    /// finding located at its first rule, see `rule_index::find_loops`.
+    fn find_loops(&self, index: &MergedIndex) -> Result<Vec<(Vec<String>, Finding)>> {
+        rule_index::find_loops(index)?
+            .iter()
+            .map(|steps| self.describe_loop(steps, index))
+            .collect()
+    }
+
+    /// Returns the redirects of a loop, and a finding about it like
+    /// `ValidationReport::add_loops` makes.
+    fn describe_loop(
+        &self,
+        steps: &[LoopStep],
+        index: &MergedIndex,
+    ) -> Result<(Vec<String>, Finding)> {
+        let mut descriptions = Vec::new();
+        let mut related = Vec::new();
+        let mut first = None;
+        for step in steps {
+            let merged = index.read(step.rule)?;
+            let file = self.files[merged.file].display().to_string();
+            descriptions.push(step.describe(&file, merged.line_no));
+            let location = (file.clone(), merged.line_no + 1);
+            if !related.contains(&location) {
+                related.push(location);
+            }
+            first.get_or_insert((file, merged));
+        }
+        let (file, first) = first.expect("loops have at least one step");
+        let finding = Finding {
+            check: "loops",
+            severity: self.args.behaviors.loops,
+            file,
+            line: first.line_no + 1,
+            source: first.line,
+            message: format!("Loop: {}", descriptions.join(" -> ")),
+            related,
+        };
+        Ok((descriptions, finding))
+    }
+
+    /// Returns a chain starting at the rule `ordinal`, which `flattened` replaces.
+    fn shortened_chain(
+        &self,
+        index: &MergedIndex,
+        ordinal: u32,
+        flattened: &Rule,
+        end: ChainEnd,
+    ) -> Result<ShortenedChain> {
+        let status_codes = self.args.behaviors.chain_status_codes;
+        let mut steps = Vec::new();
+        for rule in rule_index::chain_steps(index, status_codes, ordinal, end) {
+            let merged = index.read(rule)?;
+            let rule = self.parse(merged.rule_part());
+            steps.push(ChainStep {
+                file: self.files[merged.file].display().to_string(),
+                line: merged.line_no + 1,
+                from: rule.from.to_string(),
+                to: rule.to.to_string(),
+                status_code: rule.status_code,
+            });
+        }
+        Ok(ShortenedChain {
+            file: steps[0].file.clone(),
+            line: steps[0].line,
+            from: flattened.from.to_string(),
+            to: flattened.to.to_string(),
+            status_code: flattened.status_code,
+            depth: end.depth as usize,
+            steps,
+        })
+    }
+
+    /// Shortens chains and writes the rules output file, the change summary and the bundle, like
+    /// `update` does after validating the rules.
+    fn write(
+        &mut self,
+        index: &MergedIndex,
+        ends: &ChainEnds,
+        report: &mut ValidationReport,
+    ) -> Result<()> {
+        let args = self.args;
+        let chunk_size = args.streaming.sort_chunk_size as usize;
+        let rule_files = &args.rule_files;
+        let mut output_lines = LineSorter::new(self.dir, "output", chunk_size);
+        let mut targets = LineSorter::new(self.dir, "targets", chunk_size);
+        // The rules to encode, with their keys, targets, status codes and options
+        let encoded_path = self.dir.join("encoded");
+        let mut encoded = BufWriter::new(File::create(&encoded_path)?);
+        let mut changes = Changes::new(self.dir, self.regex_changes())?;
+        let mut chains = Vec::new();
+        // The targets to check, with the file, line number and line of the rule redirecting to
+        // each
+        let checker = TargetChecker::new(&args.liveness, &args.behaviors)?;
+        let mut checked = LineSorter::new(self.dir, "checked", chunk_size);
+
+        let mut ordinal = 0;
+        for merged in index.rules.lines()? {
+            let merged = merged?;
+            if merged.is_removed() {
+                changes.add(&merged.key, &merged.existing, None)?;
+                continue;
+            }
+            let rule = self.parse(merged.rule_part());
+            let end = ends.own(ordinal);
+            let end_rule = match end.depth > 1 {
+                true => Some(index.read(end.end)?),
+                false => None,
+            };
+            let rule = match &end_rule {
+                Some(end_rule) => {
+                    // The flattened rule still applies to the same source and host
+                    let mut flattened = self.parse(end_rule.rule_part());
+                    flattened.from = rule.from;
+                    flattened.options.host = rule.options.host.clone();
+                    flattened.status_code = args
+                        .behaviors
+                        .chain_status_codes
+                        .status_code(rule.status_code, end.status_codes);
+                    chains.push(self.shortened_chain(index, ordinal, &flattened, end)?);
+                    flattened
+                }
+                None => rule,
+            };
+            if checker.is_some() {
+                // Like the rules in memory, flattened rules are located at the end of the chain
+                let source = end_rule.as_ref().unwrap_or(&merged);
+                let host = split_host(&merged.key).0;
+                for (target, _) in rule.targets() {
+                    if liveness::is_checked(index, host, target) {
+                        checked.push(format!(
+                            "{target}\t{}\t{}\t{}",
+                            source.file, source.line_no, source.line
+                        ))?;
+                    }
+                }
+            }
+
+            changes.add(&merged.key, &merged.existing, Some(&status_line(&rule)))?;
+            if !rule_files.add_rules.is_empty() {
+                let from = split_host(&merged.key).1;
+                output_lines.push(output_line(from, &rule, args.default_status_code))?;
+            }
+            writeln!(
+                encoded,
+                "{}\t{}\t{}\t{}",
+                merged.key, rule.to, rule.status_code, rule.options
+            )?;
+            for (target, _) in rule.targets() {
+                targets.push(target.to_string())?;
+            }
+            ordinal += 1;
+        }
+        encoded.flush()?;
+        let regex_entries = std::mem::take(&mut self.regex_rules);
+        let regex_rules = regex_entries
+            .iter()
+            .map(|entry| self.parse(entry.rule_part()))
+            .collect::<Vec<_>>();
+        for (entry, rule) in regex_entries.iter().zip(&regex_rules) {
+            let host = rule.options.host.as_deref().unwrap_or_default();
+            for (target, _) in rule.targets() {
+                targets.push(target.to_string())?;
+                if checker.is_some() && liveness::is_checked(index, host, target) {
+                    checked.push(format!(
+                        "{target}\t{}\t{}\t{}",
+                        entry.file, entry.line_no, entry.line
+                    ))?;
+                }
+            }
+        }
+
+        if !chains.is_empty() {
+            let average =
+                chains.iter().map(|chain| chain.depth).sum::<usize>() as f64 / chains.len() as f64;
+            println!(
+                "Shortened {} chains with an average depth of {:.2}",
+                chains.len(),
+                average
+            );
+        }
+        report.chains = chains;
+
+        if let Some(checker) = &checker {
+            let findings = self.check_targets(checker, checked)?;
+            report.findings.extend(findings.iter().cloned());
+            let errors_found =
+                print_findings(&findings, ValidationBehavior::Error, "Errors in file: ");
+            print_findings(
+                &findings,
+                ValidationBehavior::Warn,
+                "Warning, missing targets in file: ",
+            );
+            if errors_found {
+                return Err(anyhow!("Missing redirect targets, aborting"));
+            }
+        }
+
+        let output_directory = Path::new(&args.output.output_dir);
+        if !rule_files.add_rules.is_empty() {
+            ensure_dir(&output_directory)?;
+            let output_file_path = output_directory.join(&args.output.rules_output_file);
+            let regex_lines = regex_rules
+                .iter()
+                .map(|rule| output_line(rule.from, rule, args.default_status_code))
+                .collect();
+            self.write_rules_file(output_lines, regex_lines, &output_file_path)
+                .with_context(|| "Failed to write updated redirects".to_string())?;
+            println!("Saved updated redirects to {}", output_file_path.display());
+        }
+
+        let changes_file_path = output_directory.join(&args.output.changes_output_file);
+        let write_changes = !rule_files.add_rules.is_empty() || !rule_files.remove_rules.is_empty();
+        if write_changes {
+            ensure_dir(&output_directory)?;
+        }
+        let [added, modified, removed] =
+            changes.finish(write_changes.then_some(changes_file_path.as_path()))?;
+        report.changes = Some([added, modified, removed]);
+        if write_changes {
+            println!("Changes: {added} added, {modified} modified, {removed} removed");
+            println!("Saved change summary to {}", changes_file_path.display());
+        }
+
+        // Encode the rules into a bundle, with the sources FST built directly into a file
+        let target_set = encode_targets(targets)?;
+        let mut locator = target_set.locator();
+        let mut records = Records::default();
+        let sources_path = self.dir.join("sources.fst");
+        let mut sources = fst::MapBuilder::new(BufWriter::new(File::create(&sources_path)?))?;
+        for line in BufReader::new(File::open(&encoded_path)?).lines() {
+            let line = line?;
+            let mut fields = line.splitn(4, '\t');
+            let (Some(key), Some(to), Some(status_code), Some(options)) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(anyhow!("Invalid encoded rule '{line}'"));
+            };
+            let options = options.split_whitespace().collect::<Vec<_>>();
+            let rule = Rule {
+                from: key,
+                to,
+                status_code: status_code.parse()?,
+                options: RuleOptions::parse(&options).map_err(|e| anyhow!(e))?,
+            };
+            let value = rule_value(&mut records, &mut locator, &rule)?;
+            sources
+                .insert(key, value)
+                .with_context(|| format!("Failed to encode source '{key}'"))?;
+        }
+        sources.finish()?;
+        let regex_rules = regex_rules
+            .iter()
+            .map(|rule| {
+                Ok(RegexRule {
+                    host: rule.options.host.clone(),
+                    pattern: rule.from[REGEX_PREFIX.len_utf8()..].to_string(),
+                    value: rule_value(&mut records, &mut locator, rule)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut encoded_targets = Vec::new();
+        target_set.serialize_into(&mut encoded_targets)?;
+
+        let flags = if args.normalize_sources {
+            bundle::FLAG_NORMALIZED_SOURCES
+        } else {
+            0
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        let header = bundle::Header {
+            default_status_code: args.default_status_code,
+            flags,
+            rule_count: index.rule_count() as u64,
+            timestamp,
+        };
+        ensure_dir(&output_directory)?;
+        let bundle_file_path = output_directory.join(&args.output.bundle);
+        let context = || format!("Failed to write bundle {}", bundle_file_path.display());
+        let mut out = BufWriter::new(File::create(&bundle_file_path).with_context(context)?);
+        bundle::write(
+            &mut out,
+            &header,
+            BufReader::new(File::open(&sources_path)?),
+            &encoded_targets,
+            &records.into_vec(),
+            &regex_rules,
+        )
+        .and_then(|()| out.flush())
+        .with_context(context)?;
+        println!(
+            "Saved bundle of {} encoded redirects and {} regex rules to {}",
+            index.rule_count(),
+            regex_rules.len(),
+            bundle_file_path.display()
+        );
+        Ok(())
+    }
+
+    /// Checks that the targets collected by `write` exist, like `liveness::check_targets` does,
+    /// returning a finding for each rule with a missing target, ordered by file and line.
+    fn check_targets(
+        &mut self,
+        checker: &TargetChecker,
+        checked: LineSorter,
+    ) -> Result<Vec<Finding>> {
+        // Only the distinct targets are kept in memory. The sorted lines are written to a file,
+        // to find the rules redirecting to missing targets afterwards.
+        let path = self.dir.join("checked");
+        let mut sorted = BufWriter::new(File::create(&path)?);
+        let mut targets: Vec<String> = Vec::new();
+        for line in checked.finish()? {
+            let line = line?;
+            let target = line.split('\t').next().unwrap_or_default();
+            if targets.last().is_none_or(|last| last != target) {
+                targets.push(target.to_string());
+            }
+            writeln!(sorted, "{line}")?;
+        }
+        sorted.flush()?;
+        let missing = checker.check(&targets.iter().map(String::as_str).collect::<Vec<_>>());
+
+        let mut current = 0;
+        for line in BufReader::new(File::open(&path)?).lines() {
+            let line = line?;
+            let invalid = || anyhow!("Invalid checked target '{line}'");
+            let mut fields = line.splitn(4, '\t');
+            let (Some(target), Some(file), Some(line_no), Some(source)) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid());
+            };
+            while targets[current] != target {
+                current += 1;
+            }
+            let Some(message) = &missing[current] else {
+                continue;
+            };
+            let reason = FailedCheckReason {
+                check: "missing-targets",
+                message: message.clone(),
+                severity: self.args.behaviors.missing_targets,
+            };
+            let file = file.parse().map_err(|_| invalid())?;
+            let line_no = line_no.parse().map_err(|_| invalid())?;
+            self.finding(file, line_no, source, reason);
+        }
+        self.findings
+            .sort_by_key(|(file, finding)| (*file, finding.line));
+        Ok(std::mem::take(&mut self.findings)
+            .into_iter()
+            .map(|(_, finding)| finding)
+            .collect())
+    }
+
+    /// Writes the sorted output lines followed by the regex rules, leaving out lines of the
+    /// existing rules files unless `--include-existing` is set, like `RedirectsMap::write_to_file`
+    /// does. The file is only created if there are lines left.
+    fn write_rules_file(
+        &mut self,
+        output_lines: LineSorter,
+        regex_lines: Vec<String>,
+        path: &Path,
+    ) -> Result<()> {
+        let mut existing = std::mem::take(&mut self.existing_lines).finish()?;
+        let mut existing_line = existing.next().transpose()?;
+        let mut file = None;
+        for line in output_lines.finish()? {
+            let line = line?;
+            while existing_line
+                .as_ref()
+                .is_some_and(|existing| *existing < line)
+            {
+                existing_line = existing.next().transpose()?;
+            }
+            if existing_line.as_ref() != Some(&line) {
+                write_line(&mut file, path, &line)?;
+            }
+        }
+        for line in regex_lines {
+            if !self.existing_regex.contains(&line) {
+                write_line(&mut file, path, &line)?;
+            }
+        }
+        match file {
+            Some(mut file) => Ok(file.flush()?),
+            None => Err(anyhow!("No new rules found")),
+        }
+    }
+}
+
+/// Looks up rules in the FST index of their keys, and reads them back from the merged file, like
+/// `MapIndex` does for the rules in memory.
+struct MergedIndex<'a> {
+    args: &'a Args,
+    index: fst::Map<Vec<u8>>,
+    rules: &'a MergedRules,
+    reader: RefCell<RuleReader<'a>>,
+    /// The rules continuing chains, set once `Update::index_rules` found them
+    graph: RuleGraph,
+}
+
+impl<'a> MergedIndex<'a> {
+    fn new(args: &'a Args, rules: &'a MergedRules, index_path: &Path) -> Result<Self> {
+        Ok(Self {
+            args,
+            index: fst::Map::new(std::fs::read(index_path)?)?,
+            rules,
+            reader: RefCell::new(RuleReader::new(rules)?),
+            graph: RuleGraph::default(),
+        })
+    }
+
+    fn read(&self, rule: u32) -> Result<MergedRule> {
+        self.reader.borrow_mut().read(rule)
+    }
+}
+
+impl RuleIndex for MergedIndex<'_> {
+    fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    fn get(&self, key: &str) -> Option<u32> {
+        self.index.get(key).map(|ordinal| ordinal as u32)
+    }
+
+    fn normalizes_sources(&self) -> bool {
+        self.args.normalize_sources
+    }
+
+    fn has_wildcards(&self) -> bool {
+        self.rules.has_wildcards
+    }
+
+    fn hosts(&self) -> &Hosts {
+        &self.rules.hosts
+    }
+
+    fn host_id(&self, rule: u32) -> u32 {
+        self.rules.host_ids[rule as usize]
+    }
+
+    fn is_wildcard(&self, rule: u32) -> bool {
+        self.rules.wildcards[rule as usize]
+    }
+
+    fn ignores_query(&self, rule: u32) -> bool {
+        let shape = self.rules.shapes[rule as usize];
+        self.rules.shapes_ignoring_query[shape as usize]
+    }
+
+    fn status_code(&self, rule: u32) -> u16 {
+        self.rules.status_codes[rule as usize]
+    }
+
+    fn with_rule<T>(&self, rule: u32, f: impl FnOnce(&str, &Rule) -> T) -> Result<T> {
+        let merged = self.read(rule)?;
+        Ok(f(&merged.key, &parse(self.args, merged.rule_part())))
+    }
+
+    fn chain_next(&self, host: u32, rule: u32, status_codes: ChainStatusCodes) -> Option<u32> {
+        let (graph, current) = (&self.graph, rule as usize);
+        let next = match graph.host_next.get(&rule) {
+            // Requests for some hosts are handled by a host-specific rule instead
+            Some(_) if host == 0 => NONE,
+            Some(host_next) => host_next
+                .iter()
+                .find(|&&(id, _)| id == host)
+                .map_or(graph.next[current], |&(_, next)| next),
+            None => graph.next[current],
+        };
+        if next == NONE {
+            return None;
+        }
+        let rules = self.rules;
+        let compatible = rule_index::status_codes_compatible(
+            status_codes,
+            rules.status_codes[current],
+            rules.status_codes[next as usize],
+        ) && rules.shapes[current] == rules.shapes[next as usize];
+        compatible.then_some(next)
+    }
+}
+
+/// The changes to the existing rules, written in the order of their keys with added rules last,
+/// like `inspect::diff_lines` returns them. Changes to regex rules are merged in by key.
+struct Changes {
+    changed_path: PathBuf,
+    changed: BufWriter<File>,
+    added_path: PathBuf,
+    added: BufWriter<File>,
+    regex_changes: std::iter::Peekable<std::vec::IntoIter<(String, Change)>>,
+    /// The number of added, modified and removed rules
+    counts: [usize; 3],
+}
+
+impl Changes {
+    fn new(dir: &Path, regex_changes: Vec<(String, Change)>) -> Result<Self> {
+        let changed_path = dir.join("changed");
+        let added_path = dir.join("added");
+        Ok(Self {
+            changed: BufWriter::new(File::create(&changed_path)?),
+            changed_path,
+            added: BufWriter::new(File::create(&added_path)?),
+            added_path,
+            regex_changes: regex_changes.into_iter().peekable(),
+            counts: [0; 3],
+        })
+    }
+
+    /// Records the change from the existing rule with the key `key`, empty if there was none, to
+    /// `line`, `None` if the rule was removed.
+    fn add(&mut self, key: &str, existing: &str, line: Option<&str>) -> Result<()> {
+        while let Some((_, change)) = self
+            .regex_changes
+            .next_if(|(regex_key, _)| regex_key.as_str() < key)
+        {
+            self.write(change)?;
+        }
+        let change = match line {
+            Some(line) if existing.is_empty() => Change::Added(line.to_string()),
+            Some(line) if line != existing => {
+                Change::Modified(existing.to_string(), line.to_string())
+            }
+            None if !existing.is_empty() => Change::Removed(existing.to_string()),
+            _ => return Ok(()),
+        };
+        self.write(change)
+    }
+
+    fn write(&mut self, change: Change) -> Result<()> {
+        let (index, file) = match change {
+            Change::Added(_) => (0, &mut self.added),
+            Change::Modified(..) => (1, &mut self.changed),
+            Change::Removed(_) => (2, &mut self.changed),
+        };
+        self.counts[index] += 1;
+        writeln!(file, "{change}")?;
+        Ok(())
+    }
+
+    /// Writes the changes to `path`, if given, and returns the number of added, modified and
+    /// removed rules.
+    fn finish(mut self, path: Option<&Path>) -> Result<[usize; 3]> {
+        while let Some((_, change)) = self.regex_changes.next() {
+            self.write(change)?;
+        }
+        self.changed.flush()?;
+        self.added.flush()?;
+        if let Some(path) = path {
+            let mut file = BufWriter::new(File::create(path)?);
+            for part in [&self.changed_path, &self.added_path] {
+                io::copy(&mut File::open(part)?, &mut file)?;
+            }
+            file.flush()?;
+        }
+        Ok(self.counts)
+    }
+}
+
+/// Sorts lines that don't have to fit into memory. Lines are buffered, and each time
+/// `chunk_size` of them are, they're sorted and written to a temporary file. The sorted files are
+/// merged when reading the lines back.
+#[derive(Default)]
+struct LineSorter {
+    dir: PathBuf,
+    name: &'static str,
+    chunk_size: usize,
+    lines: Vec<String>,
+    runs: Vec<PathBuf>,
+}
+
+impl LineSorter {
+    fn new(dir: &Path, name: &'static str, chunk_size: usize) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            name,
+            chunk_size,
+            lines: Vec::new(),
+            runs: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, line: String) -> io::Result<()> {
+        self.lines.push(line);
+        if self.lines.len() >= self.chunk_size {
+            self.lines.sort_unstable();
+            let path = self.dir.join(format!("{}-{}", self.name, self.runs.len()));
+            let mut run = BufWriter::new(File::create(&path)?);
+            for line in self.lines.drain(..) {
+                writeln!(run, "{line}")?;
+            }
+            run.flush()?;
+            self.runs.push(path);
+        }
+        Ok(())
+    }
+
+    /// Returns the lines in order. Lines that weren't written to a file yet are merged from
+    /// memory.
+    fn finish(mut self) -> io::Result<SortedLines> {
+        self.lines.sort_unstable();
+        let mut runs: Vec<Box<dyn Iterator<Item = io::Result<String>>>> =
+            vec![Box::new(self.lines.into_iter().map(Ok))];
+        for path in &self.runs {
+            runs.push(Box::new(BufReader::new(File::open(path)?).lines()));
+        }
+        let mut heap = BinaryHeap::new();
+        for (index, run) in runs.iter_mut().enumerate() {
+            if let Some(line) = run.next() {
+                heap.push(Reverse((line?, index)));
+            }
+        }
+        Ok(SortedLines { runs, heap })
+    }
+}
+
+/// The lines of a `LineSorter` in order, merged from its sorted runs.
+struct SortedLines {
+    runs: Vec<Box<dyn Iterator<Item = io::Result<String>>>>,
+    /// The next line of each run that has any left, with the index of the run
+    heap: BinaryHeap<Reverse<(String, usize)>>,
+}
+
+impl Iterator for SortedLines {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((line, index)) = self.heap.pop()?;
+        match self.runs[index].next() {
+            Some(Ok(next)) => self.heap.push(Reverse((next, index))),
+            Some(Err(error)) => return Some(Err(error)),
+            None => {}
+        }
+        Some(Ok(line))
+    }
+}
+
+/// Encodes the sorted targets into a set, leaving out duplicates.
+fn encode_targets(targets: LineSorter) -> Result<fcsd::Set> {
+    const ERROR: &str = "Failed to encode targets";
+    let mut builder = fcsd::builder::Builder::new(128).context(ERROR)?;
+    let mut previous: Option<String> = None;
+    for target in targets.finish()? {
+        let target = target?;
+        if previous.as_ref() != Some(&target) {
+            builder.add(target.as_bytes()).context(ERROR)?;
+            previous = Some(target);
+        }
+    }
+    Ok(builder.finish())
+}
+
+/// Returns the value of `rule` in the sources FST, like `bundle::build` does.
+fn rule_value(
+    records: &mut Records,
+    locator: &mut fcsd::locator::Locator,
+    rule: &Rule,
+) -> Result<u64> {
+    let indices = rule
+        .targets()
+        .map(|(target, _)| {
+            let index = locator
+                .run(target)
+                .ok_or_else(|| anyhow!("Target {target} wasn't encoded"))?;
+            Ok((target, index as u32))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(records.value(rule, |target| {
+        indices
+            .iter()
+            .find(|(encoded, _)| *encoded == target)
+            .map_or(0, |&(_, index)| index)
+    }))
+}
+
+fn write_line(file: &mut Option<BufWriter<File>>, path: &Path, line: &str) -> io::Result<()> {
+    let file = match file {
+        Some(file) => file,
+        None => {
+            let mut created = BufWriter::new(File::create(path)?);
+            writeln!(created, "{GENERATED_FILE_HEADER}")?;
+            file.insert(created)
+        }
+    };
+    writeln!(file, "{line}")
+}
+
+/// Prints the findings with the given severity, grouped by file, and returns whether there were
+/// any, like `print_failed_checks` does.
+fn print_findings(findings: &[Finding], severity: ValidationBehavior, header: &str) -> bool {
+    let mut current_file = None;
+    for finding in findings.iter().filter(|f| f.severity == severity) {
+        if current_file != Some(&finding.file) {
+            println!("{header}{}", finding.file);
+            current_file = Some(&finding.file);
+        }
+        println!(
+            "  Line {}: {} (Line source: \"{}\")",
+            finding.line - 1,
+            finding.message,
+            finding.source
+        );
+    }
+    current_file.is_some()
+}
+
+/// Parses a rule that was already validated when it was read.
+fn parse<'l>(args: &Args, rule_part: &'l str) -> Rule<'l> {
+    Rule::parse(rule_part, args.default_status_code, args.normalize_sources)
+        .expect("rules are validated when they're read")
+}
+
+/// Returns the rule translated from a line if there is one, or the part of the line before any
+/// comment.
+fn rule_part<'l>(rule: &'l str, line: &'l str) -> &'l str {
+    if rule.is_empty() {
+        rule_part_of(line)
+    } else {
+        rule
+    }
+}
+
+/// Returns the part of a line before any comment.
+fn rule_part_of(line: &str) -> &str {
+    line.split('#').next().unwrap_or("").trim()
+}
+
+/// Returns a rule as a line of a rules file including its status code.
+fn status_line(rule: &Rule) -> String {
+    format!(
+        "{} {} {}{}",
+        rule.from, rule.to, rule.status_code, rule.options
+    )
+}
+
+/// Returns a rule as a line of the rules output file, without the default status code.
+fn output_line(from: &str, rule: &Rule, default_status_code: u16) -> String {
+    if rule.status_code == default_status_code {
+        format!("{from} {}{}", rule.to, rule.options)
+    } else {
+        format!("{from} {} {}{}", rule.to, rule.status_code, rule.options)
+    }
+}
+
+fn missing_removal(from: &str, severity: ValidationBehavior) -> FailedCheckReason {
+    FailedCheckReason {
+        check: "missing-removals",
+        message: format!("No rule with source '{from}' to remove"),
+        severity,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation_report::ValidationReportArgs;
+    use crate::{DuplicateSources, Output, RuleFiles, ValidationBehaviors, import, liveness};
+    use std::fs::read_to_string;
+    use tempfile::tempdir;
+
+    fn args(dir: &Path, rule_files: RuleFiles, behaviors: ValidationBehaviors) -> Args {
+        Args {
+            rule_files,
+            default_status_code: 302,
+            output: Output {
+                output_dir: dir.to_path_buf(),
+                rules_output_file: "output.txt".to_string(),
+                bundle: "redirects.bundle".to_string(),
+                changes_output_file: "changes.txt".to_string(),
+            },
+            include_existing: false,
+            normalize_sources: false,
+            import: import::ImportArgs::default(),
+            liveness: liveness::LivenessArgs::default(),
+            streaming: StreamingArgs {
+                streaming: true,
+                // Small enough to sort the rules in several chunks
+                sort_chunk_size: 2,
+                temp_dir: None,
+            },
+            behaviors,
+            report: ValidationReportArgs::default(),
+        }
+    }
+
+    fn findings(report: &ValidationReport) -> Vec<(&str, &str, usize, &str)> {
+        let mut findings = report
+            .findings
+            .iter()
+            .map(|f| (f.check, f.file.as_str(), f.line, f.message.as_str()))
+            .collect::<Vec<_>>();
+        findings.sort();
+        findings
+    }
+
+    /// Runs both pipelines on the same files and checks that they fail the same way, or produce
+    /// the same output. `configure` sets other arguments than the rules files and checks.
+    fn assert_same_as_in_memory(
+        rule_files: RuleFiles,
+        behaviors: ValidationBehaviors,
+        configure: impl FnOnce(&mut Args),
+    ) -> Result<()> {
+        let memory_dir = tempdir()?;
+        let mut memory_args = args(memory_dir.path(), rule_files, behaviors);
+        memory_args.streaming.streaming = false;
+        configure(&mut memory_args);
+        let mut memory_report = ValidationReport::default();
+        let memory_result =
+            crate::update(&memory_args, &mut memory_report).map_err(|e| format!("{e:#}"));
+
+        let streaming_dir = tempdir()?;
+        let streaming_args = Args {
+            output: Output {
+                output_dir: streaming_dir.path().to_path_buf(),
+                ..memory_args.output
+            },
+            streaming: StreamingArgs {
+                streaming: true,
+                ..memory_args.streaming
+            },
+            ..memory_args
+        };
+        let mut streaming_report = ValidationReport::default();
+        let streaming_result =
+            crate::update(&streaming_args, &mut streaming_report).map_err(|e| format!("{e:#}"));
+        assert_eq!(streaming_result, memory_result);
+        assert_eq!(findings(&streaming_report), findings(&memory_report));
+        if memory_result.is_err() {
+            return Ok(());
+        }
+
+        for file in ["output.txt", "changes.txt"] {
+            assert_eq!(
+                read_to_string(streaming_dir.path().join(file))?,
+                read_to_string(memory_dir.path().join(file))?,
+                "{file}"
+            );
+        }
+        // The bundles only differ in their build timestamps
+        let bundles = [streaming_dir.path(), memory_dir.path()].map(|dir| {
+            let mut bundle = std::fs::read(dir.join("redirects.bundle")).unwrap();
+            bundle[24..32].fill(0);
+            bundle
+        });
+        assert_eq!(bundles[0], bundles[1]);
+        assert_eq!(streaming_report.chains, memory_report.chains);
+        assert_eq!(streaming_report.rules, memory_report.rules);
+        assert_eq!(streaming_report.regex_rules, memory_report.regex_rules);
+        assert_eq!(streaming_report.changes, memory_report.changes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_same_as_in_memory() -> Result<()> {
+        let dir = tempdir()?;
+        let existing_path = dir.path().join("existing.txt");
+        std::fs::write(
+            &existing_path,
+            format!(
+                "{GENERATED_FILE_HEADER}\n/old /kept\n/changed /before\n/gone /x 301\n\
+                 ~/r/(\\d+) /regex/$1\n/shop /old-shop host=shop.example.com"
+            ),
+        )?;
+        let new_path = dir.path().join("new.txt");
+        std::fs::write(
+            &new_path,
+            "/changed /after\n/a /b 301\n/b /c 301\n/c /d 301\ninvalid\n/a /dup 301\n\
+             /sale /offers host=shop.example.com\n/offers /final host=shop.example.com\n\
+             /temp /b 302\n/news/x /y\n/e https://shop.example.com/sale\n/promo /offers\n\
+             /offers /all-offers 308\n/c /d 301\n/about /team host=www.example.com\n/about /us",
+        )?;
+        let remove_path = dir.path().join("remove.txt");
+        std::fs::write(&remove_path, "/gone\n/missing\n/shop host=shop.example.com")?;
+        let wildcards_path = dir.path().join("wildcards.txt");
+        std::fs::write(
+            &wildcards_path,
+            "/blog/* /news/$1\n/news/x /y\n/docs/* https://shop.example.com/docs/$1\n\
+             /docs/* /manual/$1 host=shop.example.com\n/docs/old /docs/new",
+        )?;
+        // Loops through wildcard rules are found from their prefix, including those where the
+        // path grows with every redirect
+        let wildcard_loops_path = dir.path().join("wildcard-loops.txt");
+        std::fs::write(
+            &wildcard_loops_path,
+            "/blog/* /news/$1\n/news/x /blog/x\n/grow/* /grow/more/$1",
+        )?;
+        // Relative redirects from host-agnostic rules stay on the host of the request
+        let loops_path = dir.path().join("loops.txt");
+        std::fs::write(
+            &loops_path,
+            "/a /b host=www.example.com\n/b /a\n/c /d host=www.example.com\n/d /e\n/e /d",
+        )?;
+
+        let cases = [
+            (
+                vec![existing_path],
+                vec![new_path],
+                vec![remove_path],
+                ValidationBehavior::Error,
+            ),
+            (
+                Vec::new(),
+                vec![wildcards_path],
+                Vec::new(),
+                ValidationBehavior::Error,
+            ),
+            (
+                Vec::new(),
+                vec![wildcard_loops_path],
+                Vec::new(),
+                ValidationBehavior::Error,
+            ),
+            (
+                Vec::new(),
+                vec![loops_path],
+                Vec::new(),
+                ValidationBehavior::Error,
+            ),
+        ];
+        for (existing_rules, add_rules, remove_rules, loops) in cases {
+            for &chain_status_codes in ChainStatusCodes::value_variants() {
+                for &duplicate_sources in DuplicateSources::value_variants() {
+                    assert_same_as_in_memory(
+                        RuleFiles {
+                            existing_rules: existing_rules.clone(),
+                            add_rules: add_rules.clone(),
+                            remove_rules: remove_rules.clone(),
+                        },
+                        ValidationBehaviors {
+                            loops,
+                            invalid_lines: ValidationBehavior::Warn,
+                            duplicate_sources,
+                            chain_status_codes,
+                            owned_host_chains: ValidationBehavior::Warn,
+                            ..Default::default()
+                        },
+                        |_| {},
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_imported_rules_and_targets_same_as_in_memory() -> Result<()> {
+        let dir = tempdir()?;
+        let apache_path = dir.path().join("redirects.conf");
+        std::fs::write(
+            &apache_path,
+            "# Moved pages\nRedirect 301 /a /b\nRedirect 301 /b /c\nRedirect /blog/ /news/\n\
+             RedirectMatch 301 ^/posts/(\\d+)$ /articles/$1\nRewriteRule ^/x$ /y [R=301]\n\
+             RewriteRule ^/z$ /y [P]\nRedirect 301 /missing /gone\nRedirect\t301 /tab /c",
+        )?;
+        let list_path = dir.path().join("sitemap.txt");
+        std::fs::write(&list_path, "/c\n/y\n/news/\n")?;
+
+        for missing_targets in [ValidationBehavior::Warn, ValidationBehavior::Error] {
+            assert_same_as_in_memory(
+                RuleFiles {
+                    existing_rules: Vec::new(),
+                    add_rules: vec![apache_path.clone()],
+                    remove_rules: Vec::new(),
+                },
+                ValidationBehaviors {
+                    missing_targets,
+                    untranslatable_lines: ValidationBehavior::Warn,
+                    ..Default::default()
+                },
+                |args| {
+                    args.import.rules_format = RulesFormat::Apache;
+                    args.liveness.check_targets_list = Some(list_path.clone());
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_chain_status_code_policies_with_other_status_codes() -> Result<()> {
+        let dir = tempdir()?;
+        let new_path = dir.path().join("new.txt");
+        std::fs::write(&new_path, "/a /b 305\n/b /c 305\n/x /y 305\n/y /z 301")?;
+
+        for &chain_status_codes in ChainStatusCodes::value_variants() {
+            let args = args(
+                dir.path(),
+                RuleFiles {
+                    existing_rules: Vec::new(),
+                    add_rules: vec![new_path.clone()],
+                    remove_rules: Vec::new(),
+                },
+                ValidationBehaviors {
+                    chain_status_codes,
+                    ..Default::default()
+                },
+            );
+            let mut report = ValidationReport::default();
+            update(&args, &mut report)?;
+            // Chains through a single status code keep it, whatever the policy
+            assert_eq!(report.chains.len(), 1, "{chain_status_codes:?}");
+            assert_eq!(report.chains[0].status_code, 305);
+            let output = read_to_string(dir.path().join("output.txt"))?;
+            assert!(output.contains("/a /c 305\n"), "{output}");
+            assert!(output.contains("/x /y 305\n"), "{output}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_loops() -> Result<()> {
+        let dir = tempdir()?;
+        let new_path = dir.path().join("new.txt");
+        std::fs::write(&new_path, "/a /b\n/b /c\n/x /y\n/y /z\n/z /x")?;
+
+        let args = args(
+            dir.path(),
+            RuleFiles {
+                existing_rules: Vec::new(),
+                add_rules: vec![new_path],
+                remove_rules: Vec::new(),
+            },
+            ValidationBehaviors::default(),
+        );
+        let mut report = ValidationReport::default();
+        let error = update(&args, &mut report).unwrap_err();
+        assert!(format!("{error:#}").contains("Loops detected"));
+        let loops = report
+            .findings
+            .iter()
+            .filter(|finding| finding.check == "loops")
+            .collect::<Vec<_>>();
+        assert_eq!(loops.len(), 1);
+        assert_eq!(loops[0].related.len(), 3);
+        assert!(!dir.path().join("redirects.bundle").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wildcard_loops() -> Result<()> {
+        let dir = tempdir()?;
+        let new_path = dir.path().join("new.txt");
+        std::fs::write(&new_path, "/blog/* /news/$1\n/news/x /blog/x\n/news/y /z")?;
+
+        let mut args = args(
+            dir.path(),
+            RuleFiles {
+                existing_rules: Vec::new(),
+                add_rules: vec![new_path],
+                remove_rules: Vec::new(),
+            },
+            ValidationBehaviors::default(),
+        );
+        let mut report = ValidationReport::default();
+        let error = update(&args, &mut report).unwrap_err();
+        assert!(
+            format!("{error:#}").contains("/blog/x -> /news/$1"),
+            "{error:#}"
+        );
+        assert_eq!(report.findings.len(), 1);
+        assert_eq!(report.findings[0].related.len(), 2);
+        assert!(!dir.path().join("redirects.bundle").exists());
+
+        args.behaviors.loops = ValidationBehavior::Ignore;
+        update(&args, &mut ValidationReport::default())?;
+        assert!(dir.path().join("redirects.bundle").exists());
+        Ok(())
+    }
+}
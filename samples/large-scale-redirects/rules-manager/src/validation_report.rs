@@ -0,0 +1,617 @@
+//! Machine-readable reports of a validation run, for CI jobs and review tools.
+//!
+//! A report lists every failed check, loop, and shortened chain with the file and line of the rule
+//! it's about, together with summary counts. It's written as JSON, as SARIF for code scanning
+//! tools that annotate pull requests, or as JUnit XML for test report tools. Line numbers in
+//! reports start at 1, as these tools expect.
+
+use crate::inspect::Change;
+use crate::{FailedCheck, LoopCheckEntry, ValidationBehavior};
+use anyhow::Result;
+use clap::ValueEnum;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ReportFormat {
+    /// JSON, with the findings, shortened chains and summary counts
+    Json,
+    /// SARIF 2.1.0, for code scanning tools
+    Sarif,
+    /// JUnit XML, with a test case per finding
+    Junit,
+}
+
+impl ReportFormat {
+    fn default_file(self) -> &'static str {
+        match self {
+            ReportFormat::Json => "validation-report.json",
+            ReportFormat::Sarif => "validation-report.sarif",
+            ReportFormat::Junit => "validation-report.xml",
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ValidationReportArgs {
+    /// Format of a report of the validation run to write, in addition to the printed output. The
+    /// report is written even if validation fails.
+    #[arg(long, value_enum)]
+    pub report_format: Option<ReportFormat>,
+
+    /// Path to write the report to, in the output directory. Default is `validation-report` with
+    /// the format's extension.
+    #[arg(long, requires = "report_format")]
+    pub report_file: Option<String>,
+
+    /// Exit code of runs with warnings
+    #[arg(long, default_value_t = 0)]
+    pub warning_exit_code: u8,
+
+    /// Exit code of runs that fail, e.g. because of errors
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..), default_value_t = 1)]
+    pub error_exit_code: u8,
+}
+
+impl Default for ValidationReportArgs {
+    fn default() -> Self {
+        Self {
+            report_format: None,
+            report_file: None,
+            warning_exit_code: 0,
+            error_exit_code: 1,
+        }
+    }
+}
+
+impl ValidationReportArgs {
+    /// Returns the path of the report file in the output directory, if a report is requested.
+    pub(crate) fn file(&self) -> Option<&str> {
+        let format = self.report_format?;
+        Some(self.report_file.as_deref().unwrap_or(format.default_file()))
+    }
+
+    /// Returns the exit code of a run that ended with `result`.
+    pub(crate) fn exit_code<T>(&self, result: &Result<T>, report: &ValidationReport) -> u8 {
+        if result.is_err() {
+            self.error_exit_code
+        } else if report.count(ValidationBehavior::Warn) > 0 {
+            self.warning_exit_code
+        } else {
+            0
+        }
+    }
+}
+
+/// A failed check or a loop, located at the rule it's about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Finding {
+    /// The name of the check, which is also the name of the option setting its behavior
+    pub check: &'static str,
+    pub severity: ValidationBehavior,
+    pub file: String,
+    /// 1-based
+    pub line: usize,
+    /// The line of the file
+    pub source: String,
+    pub message: String,
+    /// The files and 1-based lines of every rule involved, for findings about several rules
+    pub related: Vec<(String, usize)>,
+}
+
+/// A chain of redirects shortened into a single redirect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ShortenedChain {
+    pub file: String,
+    /// 1-based line of the rule starting the chain
+    pub line: usize,
+    pub from: String,
+    /// The target at the end of the chain
+    pub to: String,
+    /// The status code of the redirect replacing the chain
+    pub status_code: u16,
+    /// The number of redirects in the chain
+    pub depth: usize,
+    /// The rules the chain went through, starting with the one replaced. Only the first ones are
+    /// recorded for long chains.
+    pub steps: Vec<ChainStep>,
+}
+
+/// A rule in a chain of redirects, before the chain was shortened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ChainStep {
+    pub file: String,
+    /// 1-based
+    pub line: usize,
+    pub from: String,
+    pub to: String,
+    pub status_code: u16,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ValidationReport {
+    pub findings: Vec<Finding>,
+    pub chains: Vec<ShortenedChain>,
+    /// The policy chains with different status codes were shortened with
+    pub chain_status_codes: Option<String>,
+    pub rules: usize,
+    pub regex_rules: usize,
+    /// The number of added, modified and removed rules, if validation got that far
+    pub changes: Option<[usize; 3]>,
+    /// Why the run failed, if it did
+    pub error: Option<String>,
+}
+
+impl ValidationReport {
+    pub(crate) fn add_failed_checks(&mut self, failed_checks: &[FailedCheck]) {
+        self.findings
+            .extend(failed_checks.iter().map(|failed| Finding {
+                check: failed.reason.check,
+                severity: failed.reason.severity,
+                file: failed.source.path.display().to_string(),
+                line: failed.line_no + 1,
+                source: failed.line.to_string(),
+                message: failed.reason.message.clone(),
+                related: Vec::new(),
+            }));
+    }
+
+    /// Adds a finding for each loop, located at the first rule in it and related to all of them.
+    pub(crate) fn add_loops(
+        &mut self,
+        loops: &[Vec<LoopCheckEntry>],
+        severity: ValidationBehavior,
+    ) {
+        for loop_entries in loops {
+            let first = loop_entries[0].to;
+            let steps = loop_entries
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            let mut related = Vec::new();
+            for entry in loop_entries {
+                let location = (
+                    entry.to.source.path.display().to_string(),
+                    entry.to.line_no + 1,
+                );
+                if !related.contains(&location) {
+                    related.push(location);
+                }
+            }
+            self.findings.push(Finding {
+                check: "loops",
+                severity,
+                file: first.source.path.display().to_string(),
+                line: first.line_no + 1,
+                source: first
+                    .source
+                    .contents
+                    .lines()
+                    .nth(first.line_no)
+                    .unwrap_or_default()
+                    .to_string(),
+                message: format!("Loop: {}", steps.join(" -> ")),
+                related,
+            });
+        }
+    }
+
+    pub(crate) fn set_changes(&mut self, changes: &[Change]) {
+        let mut counts = [0; 3];
+        for change in changes {
+            let index = match change {
+                Change::Added(_) => 0,
+                Change::Modified(..) => 1,
+                Change::Removed(_) => 2,
+            };
+            counts[index] += 1;
+        }
+        self.changes = Some(counts);
+    }
+
+    pub(crate) fn count(&self, severity: ValidationBehavior) -> usize {
+        self.findings
+            .iter()
+            .filter(|finding| finding.severity == severity)
+            .count()
+    }
+
+    fn summary(&self) -> Value {
+        let mut summary = json!({
+            "rules": self.rules,
+            "regex_rules": self.regex_rules,
+            "errors": self.count(ValidationBehavior::Error),
+            "warnings": self.count(ValidationBehavior::Warn),
+            "ignored": self.count(ValidationBehavior::Ignore),
+            "loops": self.findings.iter().filter(|finding| finding.check == "loops").count(),
+            "shortened_chains": self.chains.len(),
+            "chain_status_codes": self.chain_status_codes,
+            "failed": self.error.is_some(),
+        });
+        if let Some([added, modified, removed]) = self.changes {
+            summary["added"] = json!(added);
+            summary["modified"] = json!(modified);
+            summary["removed"] = json!(removed);
+        }
+        if let Some(error) = &self.error {
+            summary["error"] = json!(error);
+        }
+        summary
+    }
+
+    pub(crate) fn render(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Json => {
+                let mut report = serde_json::to_string_pretty(&self.json()).unwrap();
+                report.push('\n');
+                report
+            }
+            ReportFormat::Sarif => {
+                let mut report = serde_json::to_string_pretty(&self.sarif()).unwrap();
+                report.push('\n');
+                report
+            }
+            ReportFormat::Junit => self.junit(),
+        }
+    }
+
+    fn json(&self) -> Value {
+        let findings = self
+            .findings
+            .iter()
+            .map(|finding| {
+                json!({
+                    "check": finding.check,
+                    "severity": severity_name(finding.severity),
+                    "file": finding.file,
+                    "line": finding.line,
+                    "source": finding.source,
+                    "message": finding.message,
+                    "related": finding.related.iter().map(|(file, line)| {
+                        json!({ "file": file, "line": line })
+                    }).collect::<Vec<_>>(),
+                })
+            })
+            .collect::<Vec<_>>();
+        let chains = self
+            .chains
+            .iter()
+            .map(|chain| {
+                json!({
+                    "file": chain.file,
+                    "line": chain.line,
+                    "from": chain.from,
+                    "to": chain.to,
+                    "status_code": chain.status_code,
+                    "depth": chain.depth,
+                    "steps": chain.steps.iter().map(|step| {
+                        json!({
+                            "file": step.file,
+                            "line": step.line,
+                            "from": step.from,
+                            "to": step.to,
+                            "status_code": step.status_code,
+                        })
+                    }).collect::<Vec<_>>(),
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "summary": self.summary(),
+            "findings": findings,
+            "chains": chains,
+        })
+    }
+
+    fn sarif(&self) -> Value {
+        let location = |file: &str, line: usize| {
+            json!({
+                "physicalLocation": {
+                    "artifactLocation": { "uri": file },
+                    "region": { "startLine": line },
+                }
+            })
+        };
+        let result = |rule_id: &str, level: &str, message: String, file: &str, line: usize| {
+            json!({
+                "ruleId": rule_id,
+                "level": level,
+                "message": { "text": message },
+                "locations": [location(file, line)],
+            })
+        };
+        let mut results = self
+            .findings
+            .iter()
+            .map(|finding| {
+                let level = match finding.severity {
+                    ValidationBehavior::Error => "error",
+                    ValidationBehavior::Warn => "warning",
+                    ValidationBehavior::Ignore => "none",
+                };
+                let message = format!("{} (Line source: \"{}\")", finding.message, finding.source);
+                let mut result = result(finding.check, level, message, &finding.file, finding.line);
+                if !finding.related.is_empty() {
+                    result["relatedLocations"] = finding
+                        .related
+                        .iter()
+                        .map(|(file, line)| location(file, *line))
+                        .collect();
+                }
+                result
+            })
+            .collect::<Vec<_>>();
+        results.extend(self.chains.iter().map(|chain| {
+            let message = format!(
+                "Shortened a chain of {} redirects from {} to {} with status code {}",
+                chain.depth, chain.from, chain.to, chain.status_code
+            );
+            let mut result = result("shortened-chains", "note", message, &chain.file, chain.line);
+            result["relatedLocations"] = chain
+                .steps
+                .iter()
+                .map(|step| location(&step.file, step.line))
+                .collect();
+            result
+        }));
+        let mut rule_ids = self
+            .findings
+            .iter()
+            .map(|finding| finding.check)
+            .collect::<Vec<_>>();
+        if !self.chains.is_empty() {
+            rule_ids.push("shortened-chains");
+        }
+        rule_ids.sort();
+        rule_ids.dedup();
+        let rules = rule_ids
+            .iter()
+            .map(|id| json!({ "id": id }))
+            .collect::<Vec<_>>();
+        let mut invocation = json!({ "executionSuccessful": self.error.is_none() });
+        if let Some(error) = &self.error {
+            invocation["toolExecutionNotifications"] = json!([{
+                "level": "error",
+                "message": { "text": error },
+            }]);
+        }
+        json!({
+            "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+            "version": "2.1.0",
+            "runs": [{
+                "tool": {
+                    "driver": {
+                        "name": "rules-manager",
+                        "version": env!("CARGO_PKG_VERSION"),
+                        "rules": rules,
+                    }
+                },
+                "invocations": [invocation],
+                "results": results,
+                "properties": self.summary(),
+            }],
+        })
+    }
+
+    /// Writes a test suite per file with a test case per finding, failed for errors and
+    /// warnings and skipped for ignored findings, and a test case for the run as a whole.
+    fn junit(&self) -> String {
+        let mut by_file: BTreeMap<&str, Vec<&Finding>> = BTreeMap::new();
+        for finding in &self.findings {
+            by_file.entry(&finding.file).or_default().push(finding);
+        }
+        let failures = |findings: &[&Finding]| {
+            findings
+                .iter()
+                .filter(|finding| finding.severity != ValidationBehavior::Ignore)
+                .count()
+        };
+        let total_failures = self.findings.len() - self.count(ValidationBehavior::Ignore)
+            + usize::from(self.error.is_some());
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        writeln!(
+            xml,
+            "<testsuites name=\"rules-manager\" tests=\"{}\" failures=\"{total_failures}\">",
+            self.findings.len() + 1
+        )
+        .unwrap();
+        writeln!(
+            xml,
+            "  <testsuite name=\"rules-manager\" tests=\"1\" failures=\"{}\">",
+            usize::from(self.error.is_some())
+        )
+        .unwrap();
+        match &self.error {
+            Some(error) => writeln!(
+                xml,
+                "    <testcase classname=\"rules-manager\" name=\"validation\">\n      \
+                 <failure type=\"error\" message=\"{}\"/>\n    </testcase>",
+                escape_xml(error)
+            ),
+            None => writeln!(
+                xml,
+                "    <testcase classname=\"rules-manager\" name=\"validation\"/>"
+            ),
+        }
+        .unwrap();
+        xml.push_str("  </testsuite>\n");
+        for (file, findings) in by_file {
+            let file = escape_xml(file);
+            writeln!(
+                xml,
+                "  <testsuite name=\"{file}\" tests=\"{}\" failures=\"{}\">",
+                findings.len(),
+                failures(&findings)
+            )
+            .unwrap();
+            for finding in findings {
+                writeln!(
+                    xml,
+                    "    <testcase classname=\"{file}\" name=\"{} line {}\" file=\"{file}\" line=\"{}\">",
+                    finding.check, finding.line, finding.line
+                )
+                .unwrap();
+                let message = escape_xml(&finding.message);
+                match finding.severity {
+                    ValidationBehavior::Ignore => {
+                        writeln!(xml, "      <skipped message=\"{message}\"/>").unwrap();
+                    }
+                    severity => writeln!(
+                        xml,
+                        "      <failure type=\"{}\" message=\"{message}\">{}</failure>",
+                        severity_name(severity),
+                        escape_xml(&finding.source)
+                    )
+                    .unwrap(),
+                }
+                xml.push_str("    </testcase>\n");
+            }
+            xml.push_str("  </testsuite>\n");
+        }
+        xml.push_str("</testsuites>\n");
+        xml
+    }
+}
+
+fn severity_name(severity: ValidationBehavior) -> &'static str {
+    match severity {
+        ValidationBehavior::Error => "error",
+        ValidationBehavior::Warn => "warning",
+        ValidationBehavior::Ignore => "ignored",
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\n' => escaped.push_str("&#10;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> ValidationReport {
+        ValidationReport {
+            findings: vec![
+                Finding {
+                    check: "invalid-lines",
+                    severity: ValidationBehavior::Error,
+                    file: "new.txt".to_string(),
+                    line: 2,
+                    source: "invalid <line>".to_string(),
+                    message: "Invalid format".to_string(),
+                    related: Vec::new(),
+                },
+                Finding {
+                    check: "self-loops",
+                    severity: ValidationBehavior::Warn,
+                    file: "new.txt".to_string(),
+                    line: 3,
+                    source: "/a /a".to_string(),
+                    message: "Self-referential redirect".to_string(),
+                    related: Vec::new(),
+                },
+            ],
+            chains: vec![ShortenedChain {
+                file: "existing.txt".to_string(),
+                line: 1,
+                from: "/b".to_string(),
+                to: "/e".to_string(),
+                status_code: 302,
+                depth: 3,
+                steps: ["/b", "/c", "/d", "/e"]
+                    .windows(2)
+                    .enumerate()
+                    .map(|(line, step)| ChainStep {
+                        file: "existing.txt".to_string(),
+                        line: line + 1,
+                        from: step[0].to_string(),
+                        to: step[1].to_string(),
+                        status_code: if line == 0 { 301 } else { 302 },
+                    })
+                    .collect(),
+            }],
+            chain_status_codes: Some("weakest".to_string()),
+            rules: 2,
+            regex_rules: 0,
+            changes: Some([1, 0, 0]),
+            error: Some("Errors found in redirect rules, aborting".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_json() {
+        let json: Value = serde_json::from_str(&report().render(ReportFormat::Json)).unwrap();
+        assert_eq!(json["summary"]["errors"], 1);
+        assert_eq!(json["summary"]["warnings"], 1);
+        assert_eq!(json["summary"]["added"], 1);
+        assert_eq!(json["summary"]["chain_status_codes"], "weakest");
+        assert_eq!(json["summary"]["failed"], true);
+        assert_eq!(json["findings"][1]["check"], "self-loops");
+        assert_eq!(json["findings"][1]["severity"], "warning");
+        assert_eq!(json["findings"][1]["line"], 3);
+        assert_eq!(json["chains"][0]["depth"], 3);
+        assert_eq!(json["chains"][0]["steps"][1]["from"], "/c");
+        assert_eq!(json["chains"][0]["steps"][1]["line"], 2);
+        assert_eq!(json["chains"][0]["steps"][0]["status_code"], 301);
+        assert_eq!(json["chains"][0]["status_code"], 302);
+    }
+
+    #[test]
+    fn test_sarif() {
+        let sarif: Value = serde_json::from_str(&report().render(ReportFormat::Sarif)).unwrap();
+        let run = &sarif["runs"][0];
+        assert_eq!(run["invocations"][0]["executionSuccessful"], false);
+        let results = run["results"].as_array().unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0]["ruleId"], "invalid-lines");
+        assert_eq!(results[0]["level"], "error");
+        assert_eq!(
+            results[0]["locations"][0]["physicalLocation"]["region"]["startLine"],
+            2
+        );
+        assert_eq!(results[2]["level"], "note");
+        assert_eq!(results[2]["relatedLocations"].as_array().unwrap().len(), 3);
+        let rules = run["tool"]["driver"]["rules"].as_array().unwrap();
+        assert_eq!(rules.len(), 3);
+    }
+
+    #[test]
+    fn test_junit() {
+        let xml = report().render(ReportFormat::Junit);
+        assert!(xml.contains("<testsuites name=\"rules-manager\" tests=\"3\" failures=\"3\">"));
+        assert!(xml.contains("<testsuite name=\"new.txt\" tests=\"2\" failures=\"2\">"));
+        assert!(xml.contains(
+            "<failure type=\"error\" message=\"Invalid format\">invalid &lt;line&gt;</failure>"
+        ));
+        assert!(xml.contains("name=\"self-loops line 3\" file=\"new.txt\" line=\"3\""));
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let args = ValidationReportArgs {
+            warning_exit_code: 2,
+            ..ValidationReportArgs::default()
+        };
+        let mut report = report();
+        assert_eq!(
+            args.exit_code(&Err::<(), _>(anyhow::anyhow!("failed")), &report),
+            1
+        );
+        assert_eq!(args.exit_code(&Ok(()), &report), 2);
+        report.findings.clear();
+        assert_eq!(args.exit_code(&Ok(()), &report), 0);
+    }
+}
@@ -0,0 +1,144 @@
+//! Optional recording of rule hits and misses, for finding rules that aren't used anymore.
+//!
+//! Hits are recorded per rule, identified by the rule's key in the sources FST, and misses per
+//! request path. To keep the cost per request low, only a configurable sample of requests is
+//! recorded, with each recorded request standing in for the whole sample.
+
+use crate::bindings::fermyon::spin::key_value::Store;
+use std::sync::OnceLock;
+
+/// Prefixes of the key-value store keys counters are stored under. These must match
+/// `rules-manager/src/report.rs`.
+const HIT_KEY_PREFIX: &str = "analytics/hit/";
+const MISS_KEY_PREFIX: &str = "analytics/miss/";
+
+/// Where recorded hits and misses are sent.
+#[derive(Debug)]
+pub(crate) enum Sink {
+    /// Counters in the key-value store with the given label
+    KeyValue(String),
+    /// Structured log lines on stdout
+    Log,
+}
+
+impl Sink {
+    pub(crate) fn parse(input: &str) -> Option<Self> {
+        match input {
+            "log" => Some(Self::Log),
+            _ => input
+                .strip_prefix("kv:")
+                .filter(|label| !label.is_empty())
+                .map(|label| Self::KeyValue(label.to_string())),
+        }
+    }
+}
+
+struct Analytics {
+    sink: Sink,
+    /// One in this many requests is recorded
+    sample_rate: u32,
+}
+
+static ANALYTICS: OnceLock<Analytics> = OnceLock::new();
+
+pub(crate) fn configure(sink: Sink, sample_rate: u32) {
+    println!("Recording analytics for one in {sample_rate} requests to {sink:?}");
+    let _ = ANALYTICS.set(Analytics { sink, sample_rate });
+}
+
+/// Records a hit for the rule with the given source key. Wildcard rules are recorded with their
+/// source prefix followed by `*`, just like they are stored in the FST.
+pub(crate) fn record_hit(source: &str, wildcard: bool) {
+    if let Some(analytics) = sampled() {
+        let key = if wildcard {
+            format!("{source}*")
+        } else {
+            source.to_string()
+        };
+        analytics.record("hit", HIT_KEY_PREFIX, &key);
+    }
+}
+
+/// Records a request that didn't match any rule.
+pub(crate) fn record_miss(path: &str) {
+    if let Some(analytics) = sampled() {
+        analytics.record("miss", MISS_KEY_PREFIX, path);
+    }
+}
+
+/// Returns the analytics configuration if the current request should be recorded.
+fn sampled() -> Option<&'static Analytics> {
+    let analytics = ANALYTICS.get()?;
+    let sample = wasi::random::insecure::get_insecure_random_u64;
+    if analytics.sample_rate > 1 && !sample().is_multiple_of(analytics.sample_rate as u64) {
+        return None;
+    }
+    Some(analytics)
+}
+
+impl Analytics {
+    fn record(&self, event: &str, key_prefix: &str, key: &str) {
+        match &self.sink {
+            Sink::Log => println!(
+                "{{\"redirects\":\"{event}\",\"key\":\"{}\",\"count\":{}}}",
+                escape_json(key),
+                self.sample_rate
+            ),
+            Sink::KeyValue(label) => {
+                if let Err(error) =
+                    increment(label, &format!("{key_prefix}{key}"), self.sample_rate)
+                {
+                    eprintln!("Failed to record redirect {event} for '{key}': {error}");
+                }
+            }
+        }
+    }
+}
+
+/// Adds `amount` to the counter stored under `key`.
+///
+/// Spin's key-value stores don't support atomic updates, so concurrent requests can overwrite
+/// each other's increments. Counts should be treated as estimates.
+fn increment(label: &str, key: &str, amount: u32) -> Result<(), String> {
+    let store = Store::open(label).map_err(|e| format!("{e:?}"))?;
+    let current = store
+        .get(key)
+        .map_err(|e| format!("{e:?}"))?
+        .and_then(|value| String::from_utf8(value).ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(0);
+    let updated = current.saturating_add(amount as u64).to_string();
+    store
+        .set(key, updated.as_bytes())
+        .map_err(|e| format!("{e:?}"))
+}
+
+/// Escapes `input` for use in a JSON string literal.
+fn escape_json(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sink() {
+        assert!(matches!(Sink::parse("log"), Some(Sink::Log)));
+        assert!(matches!(
+            Sink::parse("kv:analytics"),
+            Some(Sink::KeyValue(label)) if label == "analytics"
+        ));
+        assert!(Sink::parse("kv:").is_none());
+        assert!(Sink::parse("stdout").is_none());
+    }
+}
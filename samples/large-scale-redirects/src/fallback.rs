@@ -0,0 +1,378 @@
+//! Handling of requests that don't match any redirect rule.
+//!
+//! By default, such requests get an empty 404 response. Alternatively, they can get a custom 404
+//! body, be redirected to a catch-all target, or be proxied to an origin, which allows putting the
+//! component in front of an existing site.
+
+use crate::{respond, send_redirect};
+use redirects_core::rule::is_valid_redirect_target;
+use std::sync::OnceLock;
+use wasi::http::outgoing_handler;
+use wasi::http::types::{
+    Fields, IncomingBody, IncomingRequest, IncomingResponse, Method, OutgoingBody, OutgoingRequest,
+    OutgoingResponse, ResponseOutparam, Scheme,
+};
+use wasi::io::streams::{InputStream, OutputStream, StreamError};
+
+/// Hop-by-hop and other headers that must not be forwarded between the client and the origin.
+const UNFORWARDED_HEADERS: &[&str] = &[
+    "connection",
+    "host",
+    "http2-settings",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// What to do with requests that don't match any rule.
+#[derive(Debug)]
+pub(crate) enum Fallback {
+    /// Respond with an empty 404
+    NotFound,
+    /// Respond with a 404 with the given body
+    Body {
+        content_type: &'static str,
+        body: Vec<u8>,
+    },
+    /// Redirect to the given target, using the default status code. Requests for a relative
+    /// target itself get a 404, so that it doesn't redirect to itself.
+    Redirect(String),
+    /// Forward the request to an origin
+    Proxy(Origin),
+}
+
+#[derive(Debug)]
+pub(crate) struct Origin {
+    https: bool,
+    authority: String,
+    /// Path prefix to prepend to request paths, without a trailing slash
+    base_path: String,
+}
+
+impl Fallback {
+    /// Parses the value of the `miss` option, reading any custom body from disk.
+    pub(crate) fn parse(input: &str) -> Result<Self, String> {
+        match input.split_once(':') {
+            None if input == "not-found" => Ok(Fallback::NotFound),
+            Some(("body", path)) => {
+                let body = std::fs::read(path)
+                    .map_err(|e| format!("Unable to read 404 body from {path}: {e}"))?;
+                Ok(Fallback::Body {
+                    content_type: content_type(path),
+                    body,
+                })
+            }
+            Some(("redirect", target)) if !target.is_empty() => {
+                if target.contains(char::is_whitespace) || !is_valid_redirect_target(target) {
+                    return Err(format!("Invalid catch-all target '{target}'"));
+                }
+                Ok(Fallback::Redirect(target.to_string()))
+            }
+            Some(("proxy", origin)) => Origin::parse(origin)
+                .map(Fallback::Proxy)
+                .ok_or_else(|| format!("Invalid origin URL '{origin}'")),
+            _ => Err(format!("Invalid miss handling '{input}'")),
+        }
+    }
+}
+
+impl Origin {
+    fn parse(input: &str) -> Option<Self> {
+        let (https, rest) = match input.split_once("://")? {
+            ("https", rest) => (true, rest),
+            ("http", rest) => (false, rest),
+            _ => return None,
+        };
+        let (authority, base_path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        if authority.is_empty() || base_path.contains(['?', '#']) {
+            return None;
+        }
+        Some(Self {
+            https,
+            authority: authority.to_string(),
+            base_path: base_path.trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+fn content_type(path: &str) -> &'static str {
+    match path.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("json") => "application/json",
+        _ => "text/plain; charset=utf-8",
+    }
+}
+
+static FALLBACK: OnceLock<Fallback> = OnceLock::new();
+
+pub(crate) fn configure(fallback: Fallback) {
+    match &fallback {
+        Fallback::NotFound => {}
+        Fallback::Body { body, .. } => {
+            println!("Responding to misses with a {} byte body", body.len())
+        }
+        Fallback::Redirect(target) => println!("Redirecting misses to {target}"),
+        Fallback::Proxy(origin) => println!("Proxying misses to {}", origin.authority),
+    }
+    let _ = FALLBACK.set(fallback);
+}
+
+/// Responds to a request that didn't match any rule.
+pub(crate) fn handle_miss(
+    request: IncomingRequest,
+    response_out: ResponseOutparam,
+    default_status_code: u16,
+) {
+    match FALLBACK.get().unwrap_or(&Fallback::NotFound) {
+        Fallback::NotFound => respond(response_out, 404, Fields::new(), &[]),
+        Fallback::Body { content_type, body } => {
+            let headers = Fields::new();
+            headers
+                .set("Content-Type", &[content_type.as_bytes().to_vec()])
+                .unwrap();
+            respond(response_out, 404, headers, body);
+        }
+        Fallback::Redirect(target) if is_target(&request, target) => {
+            respond(response_out, 404, Fields::new(), &[])
+        }
+        Fallback::Redirect(target) => {
+            let body = !matches!(request.method(), Method::Head);
+            send_redirect(
+                response_out,
+                default_status_code,
+                target.as_bytes(),
+                &[],
+                None,
+                body,
+            );
+        }
+        Fallback::Proxy(origin) => match send_to_origin(&request, origin) {
+            Ok(response) => forward_response(response, response_out),
+            Err(error) => {
+                eprintln!("Failed to proxy request to {}: {error}", origin.authority);
+                respond(response_out, 502, Fields::new(), &[]);
+            }
+        },
+    }
+}
+
+/// Whether `request` is for the relative catch-all `target`, ignoring query strings.
+fn is_target(request: &IncomingRequest, target: &str) -> bool {
+    let path = request.path_with_query().unwrap_or_else(|| "/".to_string());
+    target.starts_with('/') && without_query(&path) == without_query(target)
+}
+
+fn without_query(path: &str) -> &str {
+    path.split(['?', '#']).next().unwrap_or(path)
+}
+
+/// Sends the request to the origin, streaming its body, and waits for the response.
+fn send_to_origin(request: &IncomingRequest, origin: &Origin) -> Result<IncomingResponse, String> {
+    let headers = forwarded_headers(&request.headers())?;
+    let outgoing = OutgoingRequest::new(headers);
+    let path = format!(
+        "{}{}",
+        origin.base_path,
+        request.path_with_query().unwrap_or_else(|| "/".to_string())
+    );
+    let scheme = if origin.https {
+        Scheme::Https
+    } else {
+        Scheme::Http
+    };
+    outgoing
+        .set_method(&request.method())
+        .and_then(|_| outgoing.set_path_with_query(Some(&path)))
+        .and_then(|_| outgoing.set_scheme(Some(&scheme)))
+        .and_then(|_| outgoing.set_authority(Some(&origin.authority)))
+        .map_err(|_| "invalid request".to_string())?;
+
+    let outgoing_body = outgoing.body().unwrap();
+    let future_response = outgoing_handler::handle(outgoing, None).map_err(|e| format!("{e:?}"))?;
+
+    let incoming_body = request.consume().unwrap();
+    copy_body(
+        &incoming_body.stream().unwrap(),
+        &outgoing_body.write().unwrap(),
+    )?;
+    IncomingBody::finish(incoming_body);
+    OutgoingBody::finish(outgoing_body, None).map_err(|e| format!("{e:?}"))?;
+
+    future_response.subscribe().block();
+    match future_response.get() {
+        Some(Ok(Ok(response))) => Ok(response),
+        Some(Ok(Err(error))) => Err(format!("{error:?}")),
+        _ => Err("no response received".to_string()),
+    }
+}
+
+/// Streams the origin's response back to the client.
+fn forward_response(response: IncomingResponse, response_out: ResponseOutparam) {
+    let headers = match forwarded_headers(&response.headers()) {
+        Ok(headers) => headers,
+        Err(error) => {
+            eprintln!("Failed to forward response: {error}");
+            respond(response_out, 502, Fields::new(), &[]);
+            return;
+        }
+    };
+    let outgoing = OutgoingResponse::new(headers);
+    let _ = outgoing.set_status_code(response.status());
+    let outgoing_body = outgoing.body().unwrap();
+    ResponseOutparam::set(response_out, Ok(outgoing));
+
+    let incoming_body = response.consume().unwrap();
+    let result = copy_body(
+        &incoming_body.stream().unwrap(),
+        &outgoing_body.write().unwrap(),
+    );
+    IncomingBody::finish(incoming_body);
+    match result {
+        Ok(()) => {
+            let _ = OutgoingBody::finish(outgoing_body, None);
+        }
+        // Dropping the body without finishing it signals the error to the client
+        Err(error) => eprintln!("Failed to forward response body: {error}"),
+    }
+}
+
+fn forwarded_headers(headers: &Fields) -> Result<Fields, String> {
+    let entries = headers
+        .entries()
+        .into_iter()
+        .filter(|(name, _)| !UNFORWARDED_HEADERS.contains(&name.to_ascii_lowercase().as_str()))
+        .collect::<Vec<_>>();
+    Fields::from_list(&entries).map_err(|e| format!("{e:?}"))
+}
+
+/// Copies everything from `input` to `output`.
+fn copy_body(input: &InputStream, output: &OutputStream) -> Result<(), String> {
+    loop {
+        match input.blocking_read(64 * 1024) {
+            Ok(chunk) => {
+                // Writes are limited to 4096 bytes at a time
+                for part in chunk.chunks(4096) {
+                    output
+                        .blocking_write_and_flush(part)
+                        .map_err(|e| format!("{e:?}"))?;
+                }
+            }
+            Err(StreamError::Closed) => return Ok(()),
+            Err(error) => return Err(format!("{error:?}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fallback() {
+        assert!(matches!(
+            Fallback::parse("not-found"),
+            Ok(Fallback::NotFound)
+        ));
+        assert!(matches!(
+            Fallback::parse("redirect:https://example.com/"),
+            Ok(Fallback::Redirect(target)) if target == "https://example.com/"
+        ));
+        assert!(matches!(
+            Fallback::parse("proxy:https://origin.example.com"),
+            Ok(Fallback::Proxy(_))
+        ));
+
+        let path = std::env::temp_dir().join(format!("redirects-404-{}.html", std::process::id()));
+        std::fs::write(&path, "<h1>Not found</h1>").unwrap();
+        let fallback = Fallback::parse(&format!("body:{}", path.display()));
+        std::fs::remove_file(&path).unwrap();
+        let Ok(Fallback::Body { content_type, body }) = fallback else {
+            panic!("Expected a body: {fallback:?}");
+        };
+        assert_eq!(content_type, "text/html; charset=utf-8");
+        assert_eq!(body, b"<h1>Not found</h1>");
+
+        assert_eq!(
+            Fallback::parse("proxy:ftp://origin.example.com").unwrap_err(),
+            "Invalid origin URL 'ftp://origin.example.com'"
+        );
+        assert!(Fallback::parse("body:/does/not/exist")
+            .unwrap_err()
+            .starts_with("Unable to read 404 body from /does/not/exist"));
+        for input in [
+            "redirect:/404 page",
+            "redirect:404",
+            "redirect://evil.example",
+        ] {
+            let target = input.strip_prefix("redirect:").unwrap();
+            assert_eq!(
+                Fallback::parse(input).unwrap_err(),
+                format!("Invalid catch-all target '{target}'")
+            );
+        }
+        for input in ["redirect:", "not-found:", "missing", "teapot:418"] {
+            assert_eq!(
+                Fallback::parse(input).unwrap_err(),
+                format!("Invalid miss handling '{input}'")
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_origin() {
+        let origin = |input| {
+            Origin::parse(input).map(|origin| (origin.https, origin.authority, origin.base_path))
+        };
+        let parsed = |https, authority: &str, base_path: &str| {
+            Some((https, authority.to_string(), base_path.to_string()))
+        };
+        assert_eq!(
+            origin("http://localhost:3000"),
+            parsed(false, "localhost:3000", "")
+        );
+        assert_eq!(
+            origin("https://example.com/"),
+            parsed(true, "example.com", "")
+        );
+        // Trailing slashes are stripped from the base path, since request paths start with one
+        assert_eq!(
+            origin("https://example.com/site//"),
+            parsed(true, "example.com", "/site")
+        );
+        assert_eq!(
+            origin("https://example.com/a/b"),
+            parsed(true, "example.com", "/a/b")
+        );
+
+        for input in [
+            "example.com",
+            "ftp://example.com",
+            "HTTPS://example.com",
+            "https://",
+            "https:///path",
+            "https://example.com/site?x=1",
+            "https://example.com/site#top",
+        ] {
+            assert_eq!(origin(input), None, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_without_query() {
+        assert_eq!(without_query("/404"), "/404");
+        assert_eq!(without_query("/404?from=/a"), "/404");
+        assert_eq!(without_query("/?x#top"), "/");
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(content_type("404.htm"), "text/html; charset=utf-8");
+        assert_eq!(content_type("404.json"), "application/json");
+        assert_eq!(content_type("404"), "text/plain; charset=utf-8");
+    }
+}
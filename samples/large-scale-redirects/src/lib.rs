@@ -1,82 +1,290 @@
-use std::fs::File;
-use std::io::{BufReader, Read};
-use std::str::from_utf8;
+use redirects_core::lookup::{resolve, LookupKey, Redirect};
+use redirects_core::methods::MethodCheck;
+use redirects_core::rule::QueryMode;
 use std::sync::OnceLock;
-use wasi::http::types::{Fields, IncomingRequest, OutgoingResponse, ResponseOutparam, StatusCode};
+use wasi::http::types::{
+    Fields, IncomingRequest, Method, OutgoingBody, OutgoingResponse, ResponseOutparam, StatusCode,
+};
 
-struct MyIncomingHandler;
+mod analytics;
+mod fallback;
+mod methods;
+mod tables;
+
+mod bindings {
+    wit_bindgen::generate!({
+        world: "redirects",
+        path: "wit",
+        generate_all,
+    });
+}
+
+/// The component's incoming handler. It's only exported when targeting Wasm, since the export's
+/// symbol names can't be linked natively, and is public so native builds still check it.
+pub struct MyIncomingHandler;
 
 impl wasi::exports::http::incoming_handler::Guest for MyIncomingHandler {
     fn handle(request: IncomingRequest, response_out: ResponseOutparam) {
-        let headers = Fields::new();
-        let mut code = 404;
-        let sources = SOURCES.get().unwrap();
-        match sources.get(request.path_with_query().unwrap()) {
-            Some(index) => {
-                let targets = TARGETS.get().unwrap();
-                let redirect = targets.decoder().run(index as usize);
-
-                // If the redirect target ends in " <status code>", we need to parse the status code
-                let target = if redirect.len() > 4 && redirect[redirect.len() - 4] == b' ' {
-                    code = from_utf8(&redirect[redirect.len() - 3..]).unwrap().parse::<StatusCode>().unwrap();
-                    redirect[0..redirect.len() - 4].to_vec()
-                } else {
-                    code = *DEFAULT_STATUS_CODE.get().unwrap();
-                    redirect
-                };
-                let header = String::from("Location");
-                let val = [target];
-                headers.set(&header, &val).unwrap();
+        let tables = match tables::current() {
+            Ok(tables) => tables,
+            Err(error) => {
+                eprintln!("{error}");
+                respond(response_out, 500, Fields::new(), &[]);
+                return;
+            }
+        };
+        let bundle = &tables.bundle;
+        let key = LookupKey::new(
+            bundle,
+            request.authority().as_deref(),
+            request.path_with_query().unwrap(),
+        );
+        // Scheduled rules only apply within their time window
+        let now = wasi::clocks::wall_clock::now().seconds;
+        let Some(Redirect {
+            record,
+            code,
+            target,
+            hit_key,
+            wildcard,
+        }) = resolve(bundle, &key, now, *QUERY_MODE.get().unwrap(), |name| {
+            request.headers().get(name)
+        })
+        else {
+            fallback::handle_miss(request, response_out, tables.bundle.default_status_code);
+            analytics::record_miss(key.path());
+            return;
+        };
+
+        match methods::check(&request.method(), record.methods, code) {
+            MethodCheck::Redirect => {}
+            MethodCheck::Options(allow) => {
+                let headers = Fields::new();
+                headers.set("Allow", &[allow.into_bytes()]).unwrap();
+                respond(response_out, 204, headers, &[]);
+                return;
             }
-            None => {}
+            MethodCheck::NotAllowed(allow) => {
+                let headers = Fields::new();
+                headers.set("Allow", &[allow.into_bytes()]).unwrap();
+                respond(response_out, 405, headers, &[]);
+                return;
+            }
+        }
+        let body = !matches!(request.method(), Method::Head);
+        // Clients and caches must not keep using the redirect after the rule expires
+        let expires_in = record.valid_until.map(|until| until.saturating_sub(now));
+        send_redirect(
+            response_out,
+            code,
+            &target,
+            &record.headers,
+            expires_in,
+            body,
+        );
+
+        analytics::record_hit(hit_key, wildcard);
+    }
+}
+
+/// Sends a redirect to `target`, with caching headers depending on the status code and limited to
+/// `expires_in` seconds, followed by the rule's header overrides. Unless `body` is false, e.g. for
+/// `HEAD` requests, the response has a short HTML body linking to the target for clients that
+/// don't follow redirects.
+fn send_redirect(
+    response_out: ResponseOutparam,
+    code: StatusCode,
+    target: &[u8],
+    overrides: &[(String, Vec<u8>)],
+    expires_in: Option<u64>,
+    body: bool,
+) {
+    let headers = Fields::new();
+    headers.set("Location", &[target.to_vec()]).unwrap();
+    let cache_control = CACHE_CONTROL.get_or_init(CacheControl::default);
+    let max_age = if matches!(code, 301 | 308) {
+        cache_control.permanent
+    } else {
+        cache_control.temporary
+    };
+    let max_age = expires_in.map_or(max_age, |expires_in| {
+        max_age.min(expires_in.try_into().unwrap_or(u32::MAX))
+    });
+    let cache_control = match max_age {
+        0 => "no-store".to_string(),
+        seconds => format!("max-age={seconds}"),
+    };
+    headers
+        .set("Cache-Control", &[cache_control.into_bytes()])
+        .unwrap();
+    if body {
+        headers
+            .set("Content-Type", &[b"text/html; charset=utf-8".to_vec()])
+            .unwrap();
+    }
+    // Overrides replace default headers, but repeated overrides of the same header are all kept
+    for (i, (name, value)) in overrides.iter().enumerate() {
+        let repeated = overrides[..i]
+            .iter()
+            .any(|(other, _)| other.eq_ignore_ascii_case(name));
+        let result = if repeated {
+            headers.append(name, value)
+        } else {
+            headers.set(name, std::slice::from_ref(value))
+        };
+        if result.is_err() {
+            eprintln!("Failed to set header '{name}'");
         }
+    }
+    if !body {
+        respond(response_out, code, headers, &[]);
+        return;
+    }
+    let target = escape_html(&String::from_utf8_lossy(target));
+    let body = format!(
+        "<!DOCTYPE html>\n<html><head><title>Redirecting</title></head>\
+         <body><p>Redirecting to <a href=\"{target}\">{target}</a></p></body></html>\n"
+    );
+    respond(response_out, code, headers, body.as_bytes());
+}
 
-        let resp = OutgoingResponse::new(headers);
-        let _ = resp.set_status_code(code);
-        ResponseOutparam::set(response_out, Ok(resp));
+/// Escapes `input` for use in HTML text and attribute values.
+fn escape_html(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Sends a response with the given status code, headers, and body.
+fn respond(response_out: ResponseOutparam, code: StatusCode, headers: Fields, body: &[u8]) {
+    if !body.is_empty() {
+        let length = body.len().to_string().into_bytes();
+        headers.set("Content-Length", &[length]).unwrap();
+    }
+    let resp = OutgoingResponse::new(headers);
+    let _ = resp.set_status_code(code);
+    let outgoing_body = resp.body().unwrap();
+    ResponseOutparam::set(response_out, Ok(resp));
+    if !body.is_empty() {
+        let stream = outgoing_body.write().unwrap();
+        // Writes are limited to 4096 bytes at a time
+        for chunk in body.chunks(4096) {
+            if stream.blocking_write_and_flush(chunk).is_err() {
+                return;
+            }
+        }
     }
+    let _ = OutgoingBody::finish(outgoing_body, None);
 }
 
+#[cfg(target_arch = "wasm32")]
 wasi::http::proxy::export!(MyIncomingHandler);
 
-static TARGETS: OnceLock<fcsd::Set> = OnceLock::new();
-static SOURCES: OnceLock<fst::Map<Vec<u8>>> = OnceLock::new();
-static DEFAULT_STATUS_CODE: OnceLock<u16> = OnceLock::new();
+static QUERY_MODE: OnceLock<QueryMode> = OnceLock::new();
+
+/// How long clients and caches may keep redirects, in seconds, with 0 disabling caching.
+#[derive(Debug)]
+struct CacheControl {
+    /// For 301 and 308 redirects
+    permanent: u32,
+    /// For all other redirects
+    temporary: u32,
+}
+
+impl Default for CacheControl {
+    fn default() -> Self {
+        Self {
+            permanent: 86400,
+            temporary: 0,
+        }
+    }
+}
+
+static CACHE_CONTROL: OnceLock<CacheControl> = OnceLock::new();
 
 #[export_name = "wizer.initialize"]
 pub extern "C" fn init() {
+    if let Err(error) = try_init() {
+        eprintln!("Failed to initialize redirects: {error}");
+        std::process::exit(1);
+    }
+}
+
+fn try_init() -> Result<(), String> {
     let mut args = String::new();
     std::io::stdin()
         .read_line(&mut args)
-        .expect("failed to read stdin");
-    let args = args.trim().split_whitespace().collect::<Vec<_>>();
-    match args[..] {
-        [sources_path, targets_path, default_status_code] => {
-            let default_status_code = match default_status_code.parse::<u16>() {
-                Ok(code) if (301..400).contains(&code) => code,
-                _ => panic!("Invalid default status code '{default_status_code}'"),
-            };
-            println!("Using default status code {default_status_code}");
-            DEFAULT_STATUS_CODE.set(default_status_code).unwrap();
-            
-            println!("Loading redirect sources from {sources_path}");
-            let mut sources_file =
-                File::open(sources_path).expect("Unable to read encoded redirect sources");
-            let size = sources_file.metadata().unwrap().len();
-            let mut sources_bytes = vec![0; size as usize];
-            sources_file.read_exact(&mut sources_bytes).unwrap();
-            let sources_fst = fst::Map::new(sources_bytes).unwrap();
-            SOURCES.set(sources_fst).unwrap();
-
-            println!("Loading redirect targets from {targets_path}");
-            let targets_file =
-                File::open(targets_path).expect("Unable to read encoded redirect targets");
-            let reader = BufReader::new(targets_file);
-            let set = fcsd::Set::deserialize_from(reader).unwrap();
-            let _ = TARGETS.set(set);
-            return;
+        .map_err(|e| format!("failed to read stdin: {e}"))?;
+    let args = args.split_whitespace().collect::<Vec<_>>();
+    let [bundle, ref options @ ..] = args[..] else {
+        return Err("Expected at least one argument: <redirects.bundle> | kv:<store label> [query=<exact|ignore|forward>] [miss=<not-found|body:<file>|redirect:<target>|proxy:<origin URL>>] [analytics=<log|kv:<store label>>] [sample=<n>] [strict-methods=<true|false>] [cache-permanent=<seconds>] [cache-temporary=<seconds>]".to_string());
+    };
+
+    let mut query_mode = QueryMode::Exact;
+    let mut analytics_sink = None;
+    let mut sample_rate = 1;
+    let mut cache_control = CacheControl::default();
+    for option in options {
+        match option.split_once('=') {
+            Some(("query", mode)) => {
+                query_mode =
+                    QueryMode::parse(mode).ok_or_else(|| format!("Invalid query mode '{mode}'"))?;
+            }
+            Some(("miss", fallback)) => fallback::configure(fallback::Fallback::parse(fallback)?),
+            Some(("analytics", sink)) => {
+                analytics_sink = Some(
+                    analytics::Sink::parse(sink)
+                        .ok_or_else(|| format!("Invalid analytics sink '{sink}'"))?,
+                );
+            }
+            Some(("sample", rate)) => {
+                sample_rate = rate
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|rate| *rate > 0)
+                    .ok_or_else(|| format!("Invalid sample rate '{rate}'"))?;
+            }
+            Some(("strict-methods", strict)) => methods::configure(
+                strict
+                    .parse::<bool>()
+                    .map_err(|_| format!("Invalid strict-methods value '{strict}'"))?,
+            ),
+            Some(("cache-permanent", seconds)) => {
+                cache_control.permanent = seconds
+                    .parse::<u32>()
+                    .map_err(|_| format!("Invalid cache duration '{seconds}'"))?;
+            }
+            Some(("cache-temporary", seconds)) => {
+                cache_control.temporary = seconds
+                    .parse::<u32>()
+                    .map_err(|_| format!("Invalid cache duration '{seconds}'"))?;
+            }
+            _ => return Err(format!("Invalid option '{option}'")),
+        }
+    }
+    println!("Using default query mode {query_mode:?}");
+    QUERY_MODE.set(query_mode).unwrap();
+    println!("Using cache durations {cache_control:?}");
+    CACHE_CONTROL.set(cache_control).unwrap();
+    if let Some(sink) = analytics_sink {
+        analytics::configure(sink, sample_rate);
+    }
+
+    // The bundle is either loaded from a file now, or from a key-value store when handling requests
+    match bundle.strip_prefix("kv:") {
+        Some(label) => {
+            tables::init_from_key_value(label);
+            Ok(())
         }
-        _ => {}
+        None => tables::init_from_file(bundle),
     }
-    panic!("Expected three arguments: <sources.fst> <targets.fcsd> <default status code>");
 }
@@ -0,0 +1,58 @@
+//! Request method handling for matched rules, see `redirects_core::methods`.
+//!
+//! Whether 301 and 302 rules only redirect `GET` and `HEAD` requests is configured for the whole
+//! component during pre-initialization, with the `strict-methods` option.
+
+use redirects_core::methods::{self, MethodCheck};
+use std::sync::OnceLock;
+use wasi::http::types::Method;
+
+/// Whether 301 and 302 rules only redirect `GET` and `HEAD` requests.
+static STRICT: OnceLock<bool> = OnceLock::new();
+
+pub(crate) fn configure(strict: bool) {
+    if strict {
+        println!("Only redirecting GET and HEAD requests for 301 and 302 rules");
+    }
+    let _ = STRICT.set(strict);
+}
+
+/// Checks `method` against the methods a rule with status `code` accepts, given as a bit mask over
+/// `METHODS`. A mask of 0 means the rule isn't restricted.
+pub(crate) fn check(method: &Method, rule_methods: u8, code: u16) -> MethodCheck {
+    let strict = *STRICT.get().unwrap_or(&false);
+    methods::check(name(method), rule_methods, code, strict)
+}
+
+fn name(method: &Method) -> &str {
+    match method {
+        Method::Get => "GET",
+        Method::Head => "HEAD",
+        Method::Post => "POST",
+        Method::Put => "PUT",
+        Method::Delete => "DELETE",
+        Method::Connect => "CONNECT",
+        Method::Options => "OPTIONS",
+        Method::Trace => "TRACE",
+        Method::Patch => "PATCH",
+        Method::Other(other) => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        assert_eq!(check(&Method::Patch, 1 << 5, 307), MethodCheck::Redirect);
+        assert_eq!(
+            check(&Method::Other("PROPFIND".to_string()), 1 << 5, 307),
+            MethodCheck::NotAllowed("PATCH, OPTIONS".to_string())
+        );
+        assert_eq!(
+            check(&Method::Options, 1 << 5, 307),
+            MethodCheck::Options("PATCH, OPTIONS".to_string())
+        );
+    }
+}
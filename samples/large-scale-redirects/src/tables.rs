@@ -0,0 +1,80 @@
+//! Loading of the redirect bundle, either from a file during pre-initialization, or from a Spin
+//! key-value store while handling requests.
+//!
+//! Spin creates a new instance of the component for every request, so nothing loaded while
+//! handling one is kept for the next. Bundles from a key-value store are loaded for every request
+//! instead, and only checked against their checksum, since `rules-manager publish` validates them
+//! fully before publishing them.
+
+use crate::bindings::fermyon::spin::key_value::Store;
+use redirects_core::bundle::Validation;
+use redirects_core::lookup::{self, Bundle};
+use std::sync::{Arc, OnceLock};
+
+/// Key the bundle is written to in the key-value store by `rules-manager publish`.
+const BUNDLE_KEY: &str = "redirects/bundle";
+
+pub(crate) struct Tables {
+    pub(crate) bundle: Bundle,
+}
+
+/// Where the redirect bundle comes from, as configured during pre-initialization.
+enum TableSource {
+    /// Bundle embedded into the component's snapshot
+    Snapshot(Arc<Tables>),
+    /// Bundle read from the key-value store with the given label
+    KeyValue(String),
+}
+
+static TABLE_SOURCE: OnceLock<TableSource> = OnceLock::new();
+
+/// Loads the bundle from a file, to be embedded into the pre-initialized snapshot.
+pub(crate) fn init_from_file(path: &str) -> Result<(), String> {
+    println!("Loading redirects bundle from {path}");
+    let bytes = std::fs::read(path).map_err(|e| format!("Unable to read {path}: {e}"))?;
+    let bundle = lookup::decode(bytes, Validation::Full)
+        .map_err(|e| format!("Invalid bundle {path}: {e}"))?;
+    println!(
+        "Loaded {} redirects, built at {} (seconds since the epoch), with default status code {}",
+        bundle.sources.len(),
+        bundle.timestamp,
+        bundle.default_status_code
+    );
+    if bundle.normalize_sources {
+        println!("Normalizing request paths");
+    }
+    let _ = TABLE_SOURCE.set(TableSource::Snapshot(Arc::new(Tables { bundle })));
+    Ok(())
+}
+
+/// Configures the component to read the bundle from the key-value store with the given label.
+pub(crate) fn init_from_key_value(label: &str) {
+    println!("Loading redirects from key-value store '{label}' at request time");
+    let _ = TABLE_SOURCE.set(TableSource::KeyValue(label.to_string()));
+}
+
+/// Returns the current redirect tables, loading them from the key-value store if configured.
+pub(crate) fn current() -> Result<Arc<Tables>, String> {
+    match TABLE_SOURCE
+        .get()
+        .expect("component must be pre-initialized")
+    {
+        TableSource::Snapshot(tables) => Ok(tables.clone()),
+        TableSource::KeyValue(label) => load_from_key_value(label),
+    }
+}
+
+fn load_from_key_value(label: &str) -> Result<Arc<Tables>, String> {
+    let store = Store::open(label)
+        .map_err(|e| format!("Failed to open key-value store '{label}': {e:?}"))?;
+    let bundle = lookup::decode(get(&store, BUNDLE_KEY)?, Validation::Checksum)
+        .map_err(|e| format!("Invalid bundle in key-value store '{label}': {e}"))?;
+    Ok(Arc::new(Tables { bundle }))
+}
+
+fn get(store: &Store, key: &str) -> Result<Vec<u8>, String> {
+    store
+        .get(key)
+        .map_err(|e| format!("Failed to read '{key}' from key-value store: {e:?}"))?
+        .ok_or_else(|| format!("Key '{key}' is missing from key-value store"))
+}